@@ -0,0 +1,103 @@
+//! Measures how many messages per second the broker delivers while several publishers and
+//! subscribers share it, and compares it with a baseline. Run with
+//! `cargo bench --bench throughput`, which fails if the broker is slower than the baseline.
+
+#[path = "../tests/support/mod.rs"]
+mod support;
+
+use server::config::Config;
+use std::env;
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+use support::{publish_packet, test_config, TestBroker};
+
+const PUBLISHERS: usize = 4;
+const SUBSCRIBERS: usize = 4;
+const MESSAGES_PER_PUBLISHER: u16 = 250;
+
+/// Messages per second delivered by this bench when the sessions, topics and messages were
+/// behind a single lock, before the core thread owned them. THROUGHPUT_BASELINE overrides it
+/// with a baseline measured on another machine.
+const BASELINE_MSGS_PER_SEC: f64 = 490.0;
+
+/// Fraction of the baseline the broker must reach, the rest is measurement noise
+const ALLOWED_RATIO: f64 = 0.9;
+
+fn main() {
+    let broker = TestBroker::start_with(|builder| {
+        builder.config(Config {
+            request_poll_interval: Duration::from_millis(1),
+            socket_read_timeout: Duration::from_millis(1),
+            ..test_config()
+        })
+    });
+
+    let topics: Vec<String> = (0..PUBLISHERS)
+        .map(|index| format!("bench/{}", index))
+        .collect();
+    let filters: Vec<(&str, u8)> = topics.iter().map(|topic| (topic.as_str(), 0)).collect();
+
+    let mut subscribers = Vec::new();
+    for index in 0..SUBSCRIBERS {
+        let mut subscriber = broker.connect(&format!("subscriber-{}", index));
+        subscriber.subscribe(1, &filters);
+        subscribers.push(subscriber);
+    }
+    let publishers: Vec<_> = (0..PUBLISHERS)
+        .map(|index| broker.connect(&format!("publisher-{}", index)))
+        .collect();
+
+    let expected = PUBLISHERS * MESSAGES_PER_PUBLISHER as usize;
+    let start = Instant::now();
+
+    let readers: Vec<_> = subscribers
+        .into_iter()
+        .map(|mut subscriber| {
+            thread::spawn(move || {
+                for _ in 0..expected {
+                    subscriber.expect_publish();
+                }
+            })
+        })
+        .collect();
+    let writers: Vec<_> = publishers
+        .into_iter()
+        .zip(topics)
+        .map(|(mut publisher, topic)| {
+            thread::spawn(move || {
+                for packet_id in 1..=MESSAGES_PER_PUBLISHER {
+                    publisher.publish(&publish_packet(&topic, "measurement", 1, packet_id));
+                }
+            })
+        })
+        .collect();
+
+    for handle in writers.into_iter().chain(readers) {
+        handle.join().unwrap();
+    }
+
+    let elapsed = start.elapsed();
+    let delivered = expected * SUBSCRIBERS;
+    let msgs_per_sec = delivered as f64 / elapsed.as_secs_f64();
+    println!(
+        "{} publishers, {} subscribers: {} messages delivered in {:?}, {:.0} msgs/s",
+        PUBLISHERS, SUBSCRIBERS, delivered, elapsed, msgs_per_sec
+    );
+
+    let baseline = match env::var("THROUGHPUT_BASELINE") {
+        Ok(baseline) => baseline
+            .parse::<f64>()
+            .expect("THROUGHPUT_BASELINE must be a number of messages per second"),
+        Err(_) => BASELINE_MSGS_PER_SEC,
+    };
+    let ratio = msgs_per_sec / baseline;
+    println!("{:.2} times the baseline of {:.0} msgs/s", ratio, baseline);
+    if ratio < ALLOWED_RATIO {
+        eprintln!(
+            "Throughput is below {:.0}% of the baseline",
+            ALLOWED_RATIO * 100.0
+        );
+        process::exit(1);
+    }
+}
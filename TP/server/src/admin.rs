@@ -0,0 +1,446 @@
+use crate::hooks::ClientInfo;
+use crate::http::{percent_decode, HttpRequest, HttpResponse};
+use crate::lockorder::LockName;
+use crate::managers::brokercore::CoreHandle;
+use crate::managers::sessionmanager::Socket;
+use crate::packages::disconnect::{remove_client, remove_stream};
+use crate::stats::BrokerStats;
+use crate::transport::client_stream::ConnectionId;
+use serde_json::{json, Value};
+use shared::packages::publish::Publish;
+use std::sync::{Arc, Mutex};
+use tracing::{event, Level};
+
+static CONTENT_TYPE: &str = "application/json";
+const SESSIONS_PATH: &str = "/api/sessions";
+const TOPICS_PATH: &str = "/api/topics";
+const RETAINED_PATH: &str = "/api/retained";
+const PUBLISH_PATH: &str = "/api/publish";
+
+/// This struct represents the admin HTTP API, which inspects and changes the broker managers
+pub struct AdminApi {
+    token: String,
+    core: CoreHandle,
+    active_streams: Arc<Mutex<Vec<Socket>>>,
+    stats: Arc<BrokerStats>,
+}
+
+impl AdminApi {
+    /// Returns an AdminApi over the broker managers
+    /// # Arguments
+    ///
+    /// * `token` - The token every request must send as Authorization: Bearer <token>
+    /// * `core` - The sessions, subscriptions, retained and pending messages of the broker
+    /// * `active_streams` - The sockets of the connected clients
+    /// * `stats` - The broker counters, which measure the wait for each lock
+    ///
+    pub fn new(
+        token: &str,
+        core: CoreHandle,
+        active_streams: Arc<Mutex<Vec<Socket>>>,
+        stats: Arc<BrokerStats>,
+    ) -> AdminApi {
+        AdminApi {
+            token: token.to_string(),
+            core,
+            active_streams,
+            stats,
+        }
+    }
+
+    /// Answers a request of the admin API. The routes are:
+    ///
+    /// * `GET /api/sessions` - Lists the sessions
+    /// * `GET /api/sessions/<client_id>` - Shows a session with its subscriptions and pending messages
+    /// * `DELETE /api/sessions/<client_id>` - Disconnects a client and removes its session
+    /// * `GET /api/topics` - Lists the topics
+    /// * `GET /api/retained` - Lists the retained messages
+    /// * `DELETE /api/retained/<topic>` - Deletes the retained message of a topic
+    /// * `POST /api/publish` - Publishes {"topic", "payload", "qos", "retain"} to the subscribers
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The HTTP request to answer
+    ///
+    pub fn handle_request(&self, request: &HttpRequest) -> HttpResponse {
+        if !self.is_authorized(request) {
+            return error(401, "Missing or invalid admin token");
+        }
+
+        let path = request.path.trim_end_matches('/');
+        let method = request.method.as_str();
+
+        if let Some(client_id) = path.strip_prefix(SESSIONS_PATH).and_then(strip_slash) {
+            let client_id = percent_decode(client_id);
+            return match method {
+                "GET" => self.session(&client_id),
+                "DELETE" => self.kick(&client_id),
+                _ => HttpResponse::method_not_allowed(),
+            };
+        }
+        if let Some(topic) = path.strip_prefix(RETAINED_PATH).and_then(strip_slash) {
+            return match method {
+                "DELETE" => self.delete_retained(&percent_decode(topic)),
+                _ => HttpResponse::method_not_allowed(),
+            };
+        }
+
+        match (path, method) {
+            (SESSIONS_PATH, "GET") => self.list_sessions(),
+            (TOPICS_PATH, "GET") => self.list_topics(),
+            (RETAINED_PATH, "GET") => self.list_retained(),
+            (PUBLISH_PATH, "POST") => self.publish(&request.body),
+            (SESSIONS_PATH, _) | (TOPICS_PATH, _) | (RETAINED_PATH, _) | (PUBLISH_PATH, _) => {
+                HttpResponse::method_not_allowed()
+            }
+            _ => HttpResponse::not_found(),
+        }
+    }
+
+    /// Compares the bearer token without stopping at the first different byte
+    fn is_authorized(&self, request: &HttpRequest) -> bool {
+        let token = match request
+            .headers
+            .get("authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+        {
+            Some(token) => token.trim().as_bytes(),
+            None => return false,
+        };
+        let expected = self.token.as_bytes();
+        token.len() == expected.len()
+            && token
+                .iter()
+                .zip(expected.iter())
+                .fold(0, |difference, (a, b)| difference | (a ^ b))
+                == 0
+    }
+
+    fn connected_clients(&self) -> Vec<ConnectionId> {
+        self.stats
+            .lock(LockName::Streams, &self.active_streams)
+            .iter()
+            .map(|socket| socket.connection_id)
+            .collect()
+    }
+
+    fn list_sessions(&self) -> HttpResponse {
+        let connected_clients = self.connected_clients();
+        let mut sessions: Vec<Value> = self.core.call(move |core| {
+            core.sessions
+                .get_sessions()
+                .map(|session| {
+                    json!({
+                        "client_id": session.client_id,
+                        "peer": session.socket.stream.peer_addr(),
+                        "listener": session.socket.stream.listener().name,
+                        "connected": connected_clients.contains(&session.socket.connection_id),
+                    })
+                })
+                .collect()
+        });
+
+        sessions.sort_by(|a, b| a["client_id"].as_str().cmp(&b["client_id"].as_str()));
+        respond(200, json!({ "sessions": sessions }))
+    }
+
+    fn session(&self, client_id: &str) -> HttpResponse {
+        let connected_clients = self.connected_clients();
+        let id = client_id.to_string();
+        let found = self.core.call(move |core| {
+            let session = core.sessions.get_client(&id)?;
+            let subscriptions: Vec<Value> = core
+                .topics
+                .get_client_filters(&id)
+                .iter()
+                .map(|(filter, subscription)| json!({ "filter": filter, "qos": subscription.qos }))
+                .collect();
+            let pending_messages: Vec<Value> = core
+                .messages
+                .get_messages(&id)
+                .iter()
+                .map(|message| {
+                    json!({
+                        "topic": message.topic_name,
+                        "payload": message.payload,
+                        "packet_id": message.packet_id,
+                        "qos": message.qos,
+                    })
+                })
+                .collect();
+            Some((session, subscriptions, pending_messages))
+        });
+        let (session, mut subscriptions, pending_messages) = match found {
+            Some(found) => found,
+            None => return error(404, "Session not found"),
+        };
+        subscriptions.sort_by(|a, b| a["filter"].as_str().cmp(&b["filter"].as_str()));
+
+        let last_will = session.last_will_testament.as_ref().map(|lwt| {
+            json!({
+                "topic": lwt.topic_name,
+                "payload": lwt.payload,
+                "qos": lwt.qos,
+                "retain": lwt.retain_flag == 1,
+            })
+        });
+
+        respond(
+            200,
+            json!({
+                "client_id": session.client_id,
+                "peer": session.socket.stream.peer_addr(),
+                "listener": session.socket.stream.listener().name,
+                "connected": connected_clients.contains(&session.socket.connection_id),
+                "last_will": last_will,
+                "subscriptions": subscriptions,
+                "pending_messages": pending_messages,
+            }),
+        )
+    }
+
+    /// Closes the connection of a client and removes it as a DISCONNECT would, so its will is not published
+    fn kick(&self, client_id: &str) -> HttpResponse {
+        let id = client_id.to_string();
+        let session = match self.core.call(move |core| {
+            let session = core.sessions.get_client(&id)?;
+            remove_client(&id, core);
+            Some(session)
+        }) {
+            Some(session) => session,
+            None => return error(404, "Session not found"),
+        };
+
+        remove_stream(
+            session.socket.connection_id,
+            &self.stats,
+            &self.active_streams,
+        );
+        let stream = &session.socket.stream;
+        stream
+            .hooks()
+            .on_disconnect(&ClientInfo::new(client_id, stream), false);
+        if let Err(e) = stream.shutdown() {
+            event!(
+                Level::WARN,
+                "Could not close the connection of client {}: {}",
+                client_id,
+                e
+            );
+        }
+
+        event!(Level::INFO, "Admin API disconnected client {}", client_id);
+        respond(200, json!({ "client_id": client_id, "disconnected": true }))
+    }
+
+    fn list_topics(&self) -> HttpResponse {
+        let topics: Vec<Value> = self.core.call(|core| {
+            let topic_manager = &mut core.topics;
+            let mut names = topic_manager.get_topics_available();
+            names.sort();
+            names
+                .iter()
+                .map(|name| {
+                    json!({
+                        "name": name,
+                        "subscriptions": topic_manager.get_subscriptions(name).len(),
+                        "retained": topic_manager.get_retained_message(name).is_some(),
+                    })
+                })
+                .collect()
+        });
+
+        respond(200, json!({ "topics": topics }))
+    }
+
+    fn list_retained(&self) -> HttpResponse {
+        let retained: Vec<Value> = self.core.call(|core| {
+            let topic_manager = &mut core.topics;
+            let mut names = topic_manager.get_topics_available();
+            names.sort();
+            names
+                .iter()
+                .filter_map(|name| topic_manager.get_retained_message(name))
+                .map(|message| json!({ "topic": message.topic_name, "payload": message.message }))
+                .collect()
+        });
+
+        respond(200, json!({ "retained": retained }))
+    }
+
+    fn delete_retained(&self, topic: &str) -> HttpResponse {
+        let retained_topic = topic.to_string();
+        let removed = self
+            .core
+            .call(move |core| core.topics.remove_retained_message(&retained_topic));
+        if !removed {
+            return error(404, "Topic has no retained message");
+        }
+
+        event!(
+            Level::INFO,
+            "Admin API deleted the retained message of {}",
+            topic
+        );
+        respond(200, json!({ "topic": topic, "deleted": true }))
+    }
+
+    fn publish(&self, body: &[u8]) -> HttpResponse {
+        let publish = match publish_from_json(body) {
+            Ok(publish) => publish,
+            Err(e) => return error(400, &e),
+        };
+
+        let message = publish.clone();
+        let subscribers = self.core.call(move |core| core.publish(&message));
+
+        event!(Level::INFO, "Admin API published {:?}", publish);
+        respond(
+            200,
+            json!({ "topic": publish.topic_name, "subscribers": subscribers }),
+        )
+    }
+}
+
+/// Returns the rest of a path after a slash, if there is a non empty one
+fn strip_slash(path: &str) -> Option<&str> {
+    path.strip_prefix('/').filter(|rest| !rest.is_empty())
+}
+
+/// Reads a publish packet from a body such as {"topic": "a/b", "payload": "on", "qos": 1, "retain": true}
+fn publish_from_json(body: &[u8]) -> Result<Publish, String> {
+    let value: Value =
+        serde_json::from_slice(body).map_err(|e| format!("Invalid JSON body: {}", e))?;
+
+    let topic_name = match value["topic"].as_str() {
+        Some(topic) if !topic.is_empty() && !topic.contains(['+', '#']) => topic,
+        _ => return Err("topic must be a topic name without wildcards".to_string()),
+    };
+    let payload = match &value["payload"] {
+        Value::Null => "",
+        Value::String(payload) => payload.as_str(),
+        _ => return Err("payload must be a string".to_string()),
+    };
+    let qos = match &value["qos"] {
+        Value::Null => 0,
+        qos => match qos.as_u64() {
+            Some(qos) if qos <= 1 => qos as u8,
+            _ => return Err("qos must be 0 or 1".to_string()),
+        },
+    };
+    let retain = match &value["retain"] {
+        Value::Null => false,
+        retain => retain
+            .as_bool()
+            .ok_or_else(|| "retain must be a boolean".to_string())?,
+    };
+
+    Ok(Publish {
+        topic_name: topic_name.to_string(),
+        payload: payload.to_string(),
+        packet_id: 1_u16, // TODO: generate ids from the server
+        qos,
+        retain_flag: retain as u8,
+        dup_flag: 0_u8,
+    })
+}
+
+fn respond(status: u16, body: Value) -> HttpResponse {
+    HttpResponse::new(status, CONTENT_TYPE, &body.to_string())
+}
+
+fn error(status: u16, message: &str) -> HttpResponse {
+    respond(status, json!({ "error": message }))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::admin::AdminApi;
+    use crate::config::{ExpirySettings, SharePolicy};
+    use crate::http::HttpRequest;
+    use crate::managers::brokercore::{BrokerCore, CoreHandle};
+    use crate::stats::BrokerStats;
+    use serde_json::Value;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    fn admin_api() -> AdminApi {
+        let stats = Arc::new(BrokerStats::new());
+        let core = BrokerCore::new(SharePolicy::RoundRobin, ExpirySettings::default());
+        AdminApi::new(
+            "secret",
+            CoreHandle::start(core, Arc::clone(&stats)),
+            Arc::new(Mutex::new(Vec::new())),
+            stats,
+        )
+    }
+
+    fn request(method: &str, path: &str, token: &str, body: &str) -> HttpRequest {
+        let mut headers = HashMap::new();
+        headers.insert("authorization".to_string(), format!("Bearer {}", token));
+        HttpRequest {
+            method: method.to_string(),
+            path: path.to_string(),
+            query: String::new(),
+            headers,
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    fn body(api: &AdminApi, request: &HttpRequest) -> (u16, Value) {
+        let response = api.handle_request(request);
+        (
+            response.status,
+            serde_json::from_slice(&response.body).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_requests_need_the_token() {
+        let api = admin_api();
+        let response = api.handle_request(&request("GET", "/api/sessions", "wrong", ""));
+        assert_eq!(response.status, 401);
+        let mut without_header = request("GET", "/api/sessions", "secret", "");
+        without_header.headers.clear();
+        assert_eq!(api.handle_request(&without_header).status, 401);
+        let response = api.handle_request(&request("GET", "/api/sessions", "secret", ""));
+        assert_eq!(response.status, 200);
+    }
+
+    #[test]
+    fn test_publish_and_delete_retained_message() {
+        let api = admin_api();
+        let (status, _) = body(
+            &api,
+            &request(
+                "POST",
+                "/api/publish",
+                "secret",
+                r#"{"topic": "$SYS/note", "payload": "on", "retain": true}"#,
+            ),
+        );
+        assert_eq!(status, 200);
+
+        let (_, retained) = body(&api, &request("GET", "/api/retained", "secret", ""));
+        assert_eq!(retained["retained"][0]["topic"], "$SYS/note");
+        assert_eq!(retained["retained"][0]["payload"], "on");
+
+        let delete = request("DELETE", "/api/retained/%24SYS/note", "secret", "");
+        assert_eq!(api.handle_request(&delete).status, 200);
+        assert_eq!(api.handle_request(&delete).status, 404);
+
+        let (_, topics) = body(&api, &request("GET", "/api/topics", "secret", ""));
+        assert_eq!(topics["topics"][0]["retained"], false);
+    }
+
+    #[test]
+    fn test_invalid_requests() {
+        let api = admin_api();
+        let invalid_publish = request("POST", "/api/publish", "secret", r#"{"topic": "a/#"}"#);
+        assert_eq!(api.handle_request(&invalid_publish).status, 400);
+        let unknown_session = request("GET", "/api/sessions/nobody", "secret", "");
+        assert_eq!(api.handle_request(&unknown_session).status, 404);
+        let wrong_method = request("PUT", "/api/topics", "secret", "");
+        assert_eq!(api.handle_request(&wrong_method).status, 405);
+    }
+}
@@ -0,0 +1,651 @@
+use crate::config::{BridgeDirection, BridgeSettings, BridgeTopic};
+use crate::managers::topicmanager::topic_matches;
+use crate::shutdown::Shutdown;
+use shared::packages::connack::Connack;
+use shared::packages::connect::Connect;
+use shared::packages::disconnect::Disconnect;
+use shared::packages::packet::{FixedHeader, PacketType, ReadablePacket, WritablePacket};
+use shared::packages::pingreq::Pingreq;
+use shared::packages::puback::Puback;
+use shared::packages::publish::Publish;
+use shared::packages::subscribe::Subscribe;
+use std::cmp;
+use std::collections::HashMap;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{Shutdown as SocketShutdown, TcpStream, ToSocketAddrs};
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use tracing::{event, Level};
+
+/// Time a read waits for a packet before checking the keep alive and the stop request
+static POLL_INTERVAL: Duration = Duration::from_millis(200);
+/// Time to wait for the TCP connection and the CONNACK of each broker
+static CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time a forwarded message is expected to come back before it is forgotten
+static ECHO_WINDOW: Duration = Duration::from_secs(30);
+
+/// A function that opens a connection to this broker, used for the local side of a bridge
+pub type LocalConnector = Box<dyn Fn() -> io::Result<UnixStream> + Send>;
+
+/// Starts the thread that keeps a bridge connected, reconnecting with an exponential backoff
+/// # Arguments
+///
+/// * `settings` - The settings of the bridge
+/// * `connect_local` - Opens the connection the bridge uses on this broker
+/// * `stop` - Requested when the bridge must disconnect
+///
+pub fn start(
+    settings: BridgeSettings,
+    connect_local: LocalConnector,
+    stop: Arc<Shutdown>,
+) -> io::Result<JoinHandle<()>> {
+    thread::Builder::new()
+        .name(format!("bridge-{}", settings.name))
+        .spawn(move || {
+            let settings = Arc::new(settings);
+            let mut delay = settings.reconnect_delay;
+            while !stop.is_requested() {
+                match BridgeSession::connect(&settings, &connect_local) {
+                    Ok(session) => {
+                        event!(
+                            Level::INFO,
+                            "Bridge {} connected to {}",
+                            settings.name,
+                            settings.address
+                        );
+                        delay = settings.reconnect_delay;
+                        match session.run(&settings, &stop) {
+                            Ok(()) => break,
+                            Err(e) => event!(
+                                Level::WARN,
+                                "Bridge {} lost its connection: {}",
+                                settings.name,
+                                e
+                            ),
+                        }
+                    }
+                    Err(e) => event!(
+                        Level::WARN,
+                        "Bridge {} could not connect to {}: {}",
+                        settings.name,
+                        settings.address,
+                        e
+                    ),
+                }
+
+                event!(
+                    Level::INFO,
+                    "Bridge {} reconnects in {:?}",
+                    settings.name,
+                    delay
+                );
+                if stop.wait_timeout(delay) {
+                    break;
+                }
+                delay = cmp::min(delay * 2, settings.max_reconnect_delay);
+            }
+            event!(Level::INFO, "Bridge {} stopped", settings.name);
+        })
+}
+
+/// Returns the topic a message gets on the other broker and the QoS it is forwarded with,
+/// or None when no topic of the bridge forwards it
+/// # Arguments
+///
+/// * `topics` - The topics of the bridge
+/// * `topic` - The topic the message was published to
+/// * `outgoing` - Whether the message goes from this broker to the remote one
+///
+pub fn map_topic(topics: &[BridgeTopic], topic: &str, outgoing: bool) -> Option<(String, u8)> {
+    let direction = if outgoing {
+        BridgeDirection::Out
+    } else {
+        BridgeDirection::In
+    };
+    topics
+        .iter()
+        .filter(|bridged| {
+            bridged.direction == direction || bridged.direction == BridgeDirection::Both
+        })
+        .find_map(|bridged| {
+            let (from, to) = if outgoing {
+                (&bridged.local_prefix, &bridged.remote_prefix)
+            } else {
+                (&bridged.remote_prefix, &bridged.local_prefix)
+            };
+            let rest = topic.strip_prefix(from.as_str())?;
+            if topic_matches(&bridged.pattern, rest) {
+                Some((format!("{}{}", to, rest), bridged.qos))
+            } else {
+                None
+            }
+        })
+}
+
+/// Returns the subscriptions the bridge makes on one side
+fn subscriptions(topics: &[BridgeTopic], local: bool) -> Vec<(String, u8)> {
+    let direction = if local {
+        BridgeDirection::Out
+    } else {
+        BridgeDirection::In
+    };
+    topics
+        .iter()
+        .filter(|bridged| {
+            bridged.direction == direction || bridged.direction == BridgeDirection::Both
+        })
+        .map(|bridged| {
+            let prefix = if local {
+                &bridged.local_prefix
+            } else {
+                &bridged.remote_prefix
+            };
+            (format!("{}{}", prefix, bridged.pattern), bridged.qos)
+        })
+        .collect()
+}
+
+/// This struct remembers the messages a bridge published on a broker that also match its own
+/// subscriptions there, so the copy the broker sends back is not forwarded again
+pub struct EchoGuard {
+    expected: HashMap<(String, String), Vec<Instant>>,
+}
+
+impl EchoGuard {
+    pub fn new() -> EchoGuard {
+        EchoGuard {
+            expected: HashMap::new(),
+        }
+    }
+
+    /// Remembers a message that will come back
+    /// # Arguments
+    ///
+    /// * `topic` - The topic the message was published to
+    /// * `payload` - The payload of the message
+    /// * `now` - The current instant
+    ///
+    pub fn expect(&mut self, topic: &str, payload: &str, now: Instant) {
+        self.expected
+            .entry((topic.to_string(), payload.to_string()))
+            .or_default()
+            .push(now);
+    }
+
+    /// Returns whether a received message is a copy of one the bridge published, forgetting it
+    /// # Arguments
+    ///
+    /// * `topic` - The topic of the received message
+    /// * `payload` - The payload of the received message
+    /// * `now` - The current instant
+    ///
+    pub fn is_echo(&mut self, topic: &str, payload: &str, now: Instant) -> bool {
+        self.expected.retain(|_, sent| {
+            sent.retain(|instant| now.saturating_duration_since(*instant) < ECHO_WINDOW);
+            !sent.is_empty()
+        });
+        match self
+            .expected
+            .get_mut(&(topic.to_string(), payload.to_string()))
+        {
+            Some(sent) => {
+                sent.remove(0);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// This trait represents the connections a bridge can use, to the remote broker or to this one
+trait BridgeStream: Read + Write + Send + Sized + 'static {
+    fn duplicate(&self) -> io::Result<Self>;
+    fn set_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    fn close(&self);
+}
+
+impl BridgeStream for TcpStream {
+    fn duplicate(&self) -> io::Result<Self> {
+        self.try_clone()
+    }
+
+    fn set_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.set_read_timeout(timeout)
+    }
+
+    fn close(&self) {
+        let _ = self.shutdown(SocketShutdown::Both);
+    }
+}
+
+impl BridgeStream for UnixStream {
+    fn duplicate(&self) -> io::Result<Self> {
+        self.try_clone()
+    }
+
+    fn set_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.set_read_timeout(timeout)
+    }
+
+    fn close(&self) {
+        let _ = self.shutdown(SocketShutdown::Both);
+    }
+}
+
+/// This enum represents the packets a bridge reacts to
+enum Incoming {
+    Connack(Connack),
+    Publish(Publish),
+    Other,
+}
+
+/// Reads a packet, returning None when none arrives before the read timeout
+fn read_packet(stream: &mut dyn Read) -> io::Result<Option<Incoming>> {
+    let mut first_byte = [0u8; 1];
+    match stream.read(&mut first_byte) {
+        Ok(0) => {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Connection closed",
+            ))
+        }
+        Ok(_) => {}
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ) =>
+        {
+            return Ok(None)
+        }
+        Err(e) => return Err(e),
+    }
+
+    let fixed_header = FixedHeader::read_fixed_header(&mut (&first_byte[..]).chain(&mut *stream))?;
+    match PacketType::from_u8(fixed_header.packet_type) {
+        Some(PacketType::Connack) => Ok(Some(Incoming::Connack(Connack::read_from(
+            stream,
+            fixed_header,
+        )?))),
+        Some(PacketType::Publish) => Ok(Some(Incoming::Publish(Publish::read_from(
+            stream,
+            fixed_header,
+        )?))),
+        _ => {
+            // PUBACK, SUBACK and PINGRESP need no answer, so their content is skipped
+            let length = fixed_header.remaining_length as u64;
+            io::copy(&mut (&mut *stream).take(length), &mut io::sink())?;
+            Ok(Some(Incoming::Other))
+        }
+    }
+}
+
+/// This struct represents one of the brokers a bridge is connected to
+struct Side<S: BridgeStream> {
+    name: &'static str,
+    writer: Mutex<S>,
+    next_packet_id: Mutex<u16>,
+    /// The filters the bridge subscribed to on this broker
+    filters: Vec<String>,
+    echoes: Mutex<EchoGuard>,
+}
+
+impl<S: BridgeStream> Side<S> {
+    /// Connects the bridge as a client and subscribes to its filters
+    /// # Arguments
+    ///
+    /// * `name` - local or remote, used in the logs
+    /// * `stream` - The connection to the broker
+    /// * `connect` - The CONNECT packet of the bridge
+    /// * `subscriptions` - The filters to subscribe to, with their QoS
+    ///
+    fn connect(
+        name: &'static str,
+        mut stream: S,
+        connect: &Connect,
+        subscriptions: Vec<(String, u8)>,
+    ) -> io::Result<(Side<S>, S)> {
+        connect.write_to(&mut stream)?;
+        stream.set_timeout(Some(CONNECT_TIMEOUT))?;
+        match read_packet(&mut stream)? {
+            Some(Incoming::Connack(connack)) if connack.return_code == 0 => {}
+            Some(Incoming::Connack(connack)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!(
+                        "The {} broker refused the connection with return code {}",
+                        name, connack.return_code
+                    ),
+                ))
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("The {} broker did not answer with a CONNACK", name),
+                ))
+            }
+        }
+        stream.set_timeout(Some(POLL_INTERVAL))?;
+
+        if !subscriptions.is_empty() {
+            let subscribe = Subscribe {
+                packet_id: 1,
+                topic_filters: subscriptions
+                    .iter()
+                    .map(|(filter, _)| filter.clone())
+                    .collect(),
+                requested_qos: subscriptions.iter().map(|(_, qos)| *qos).collect(),
+            };
+            subscribe.write_to(&mut stream)?;
+        }
+
+        let side = Side {
+            name,
+            writer: Mutex::new(stream.duplicate()?),
+            next_packet_id: Mutex::new(1),
+            filters: subscriptions
+                .into_iter()
+                .map(|(filter, _)| filter)
+                .collect(),
+            echoes: Mutex::new(EchoGuard::new()),
+        };
+        Ok((side, stream))
+    }
+
+    fn send(&self, packet: &dyn WritablePacket) -> io::Result<()> {
+        packet.write_to(&mut *self.writer.lock().unwrap())
+    }
+
+    /// Publishes a message forwarded from the other broker
+    fn publish(&self, topic: String, message: &Publish, qos: u8) -> io::Result<()> {
+        if self
+            .filters
+            .iter()
+            .any(|filter| topic_matches(filter, &topic))
+        {
+            self.echoes
+                .lock()
+                .unwrap()
+                .expect(&topic, &message.payload, Instant::now());
+        }
+        let packet_id = if qos > 0 {
+            let mut next_packet_id = self.next_packet_id.lock().unwrap();
+            let packet_id = *next_packet_id;
+            *next_packet_id = next_packet_id.checked_add(1).unwrap_or(1);
+            packet_id
+        } else {
+            0
+        };
+        self.send(&Publish {
+            topic_name: topic,
+            payload: message.payload.to_owned(),
+            packet_id,
+            qos,
+            retain_flag: message.retain_flag,
+            dup_flag: 0,
+        })
+    }
+
+    fn close(&self) {
+        self.writer.lock().unwrap().close();
+    }
+}
+
+/// This struct represents a bridge connected to both brokers
+struct BridgeSession {
+    local: Arc<Side<UnixStream>>,
+    local_reader: UnixStream,
+    remote: Arc<Side<TcpStream>>,
+    remote_reader: TcpStream,
+}
+
+impl BridgeSession {
+    /// Connects to the remote broker and then to this one
+    fn connect(
+        settings: &BridgeSettings,
+        connect_local: &LocalConnector,
+    ) -> io::Result<BridgeSession> {
+        let mut last_error = io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} did not resolve to any address", settings.address),
+        );
+        let mut remote_stream = None;
+        for address in settings.address.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&address, CONNECT_TIMEOUT) {
+                Ok(stream) => {
+                    remote_stream = Some(stream);
+                    break;
+                }
+                Err(e) => last_error = e,
+            }
+        }
+        let remote_stream = remote_stream.ok_or(last_error)?;
+
+        let connect = Connect {
+            client_id: settings.client_id.to_string(),
+            username: settings.username.to_string(),
+            password: settings.password.to_string(),
+            last_will_topic: String::new(),
+            last_will_message: String::new(),
+            keep_alive: settings.keep_alive.as_secs() as u16,
+            last_will_qos: 0,
+            clean_session: 1,
+            last_will_retain: 0,
+            last_will_flag: 0,
+        };
+        let (remote, remote_reader) = Side::connect(
+            "remote",
+            remote_stream,
+            &connect,
+            subscriptions(&settings.topics, false),
+        )?;
+
+        // This broker does not need a keep alive or credentials from its own bridge
+        let connect = Connect {
+            keep_alive: 0,
+            username: String::new(),
+            password: String::new(),
+            ..connect
+        };
+        let (local, local_reader) = Side::connect(
+            "local",
+            connect_local()?,
+            &connect,
+            subscriptions(&settings.topics, true),
+        )?;
+
+        Ok(BridgeSession {
+            local: Arc::new(local),
+            local_reader,
+            remote: Arc::new(remote),
+            remote_reader,
+        })
+    }
+
+    /// Forwards messages in both directions until the stop is requested or a connection fails
+    fn run(self, settings: &Arc<BridgeSettings>, stop: &Arc<Shutdown>) -> io::Result<()> {
+        let BridgeSession {
+            local,
+            mut local_reader,
+            remote,
+            mut remote_reader,
+        } = self;
+
+        let outgoing = {
+            let (local, remote) = (Arc::clone(&local), Arc::clone(&remote));
+            let (settings, stop) = (Arc::clone(settings), Arc::clone(stop));
+            thread::spawn(move || {
+                let result = forward(
+                    &mut local_reader,
+                    &local,
+                    &remote,
+                    &settings.topics,
+                    None,
+                    &stop,
+                );
+                if result.is_err() {
+                    // Closing both sides ends the thread that reads the remote broker
+                    local.close();
+                    remote.close();
+                }
+                result
+            })
+        };
+
+        let keep_alive = match settings.keep_alive.as_secs() {
+            0 => None,
+            _ => Some(settings.keep_alive / 2),
+        };
+        let result = forward(
+            &mut remote_reader,
+            &remote,
+            &local,
+            &settings.topics,
+            keep_alive,
+            stop,
+        );
+        if result.is_ok() {
+            let _ = remote.send(&Disconnect {});
+            let _ = local.send(&Disconnect {});
+        }
+        remote.close();
+        local.close();
+
+        let outgoing = outgoing.join().unwrap_or_else(|_| {
+            Err(io::Error::other(
+                "The thread that forwards to the remote broker panicked",
+            ))
+        });
+        match (result, outgoing) {
+            (Ok(()), _) if stop.is_requested() => Ok(()),
+            (Err(e), _) | (_, Err(e)) => Err(e),
+            (Ok(()), Ok(())) => Ok(()),
+        }
+    }
+}
+
+/// Forwards the messages received on one side of a bridge to the other one
+/// # Arguments
+///
+/// * `reader` - The connection the messages are read from
+/// * `from` - The side the messages are read from, which also gets their PUBACKs
+/// * `to` - The side the messages are published to
+/// * `topics` - The topics of the bridge
+/// * `keep_alive` - Time between PINGREQs sent on the read side, None sends none
+/// * `stop` - Requested when the bridge must disconnect
+///
+fn forward<A: BridgeStream, B: BridgeStream>(
+    reader: &mut A,
+    from: &Side<A>,
+    to: &Side<B>,
+    topics: &[BridgeTopic],
+    keep_alive: Option<Duration>,
+    stop: &Shutdown,
+) -> io::Result<()> {
+    let outgoing = from.name == "local";
+    let mut last_ping = Instant::now();
+    while !stop.is_requested() {
+        if let Some(keep_alive) = keep_alive {
+            if last_ping.elapsed() >= keep_alive {
+                from.send(&Pingreq {})?;
+                last_ping = Instant::now();
+            }
+        }
+
+        let message = match read_packet(reader)? {
+            Some(Incoming::Publish(message)) => message,
+            _ => continue,
+        };
+        if message.qos > 0 {
+            from.send(&Puback {
+                acknowledged_packet_id: message.packet_id,
+            })?;
+        }
+
+        let now = Instant::now();
+        if from
+            .echoes
+            .lock()
+            .unwrap()
+            .is_echo(&message.topic_name, &message.payload, now)
+        {
+            continue;
+        }
+        if let Some((topic, qos)) = map_topic(topics, &message.topic_name, outgoing) {
+            event!(
+                Level::DEBUG,
+                "Bridge forwards {:?} from the {} broker as {:?}",
+                message.topic_name,
+                from.name,
+                topic
+            );
+            to.publish(topic, &message, qos)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bridge::{map_topic, EchoGuard};
+    use crate::config::{BridgeDirection, BridgeTopic};
+    use std::time::{Duration, Instant};
+
+    fn topic(pattern: &str, direction: BridgeDirection, local: &str, remote: &str) -> BridgeTopic {
+        BridgeTopic {
+            pattern: pattern.to_string(),
+            direction,
+            qos: 1,
+            local_prefix: local.to_string(),
+            remote_prefix: remote.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_map_topic_replaces_prefixes() {
+        let topics = vec![
+            topic("sensors/#", BridgeDirection::Out, "", "plant1/"),
+            topic("commands/+", BridgeDirection::In, "local/", "central/"),
+            topic("status", BridgeDirection::Both, "", ""),
+        ];
+
+        assert_eq!(
+            map_topic(&topics, "sensors/temp", true),
+            Some(("plant1/sensors/temp".to_string(), 1))
+        );
+        assert_eq!(map_topic(&topics, "plant1/sensors/temp", false), None);
+        assert_eq!(
+            map_topic(&topics, "central/commands/stop", false),
+            Some(("local/commands/stop".to_string(), 1))
+        );
+        assert_eq!(map_topic(&topics, "local/commands/stop", true), None);
+        assert_eq!(
+            map_topic(&topics, "status", true),
+            Some(("status".to_string(), 1))
+        );
+        assert_eq!(
+            map_topic(&topics, "status", false),
+            Some(("status".to_string(), 1))
+        );
+    }
+
+    #[test]
+    fn test_echo_guard_forgets_each_copy_once() {
+        let start = Instant::now();
+        let mut guard = EchoGuard::new();
+        guard.expect("status", "on", start);
+        guard.expect("status", "on", start);
+
+        assert!(!guard.is_echo("status", "off", start));
+        assert!(guard.is_echo("status", "on", start));
+        assert!(guard.is_echo("status", "on", start));
+        assert!(!guard.is_echo("status", "on", start));
+
+        guard.expect("status", "on", start);
+        assert!(!guard.is_echo("status", "on", start + Duration::from_secs(60)));
+    }
+}
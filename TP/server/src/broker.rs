@@ -0,0 +1,1428 @@
+use crate::admin::AdminApi;
+use crate::bridge::{self, LocalConnector};
+use crate::config::{
+    BindAddress, BridgeSettings, Config, ConfigError, LimitPolicy, ListenerProtocol,
+    ListenerSettings, OutboundSettings, RateLimitSettings, RateLimits, TenancySettings,
+    MAX_SUPPORTED_QOS,
+};
+use crate::hooks::{BrokerHook, ClientInfo, HookVerdict, Hooks};
+use crate::lockorder::LockName;
+use crate::managers::brokercore::{BrokerCore, CoreHandle};
+use crate::managers::credentialmanager::{Authenticator, CredentialManager};
+use crate::managers::delayedmanager::DelayedManager;
+use crate::managers::sessionmanager::Socket;
+use crate::packages::disconnect::{end_session, remove_stream};
+use crate::packages::packet_dispatcher::dispatch_packet;
+use crate::packages::publish::disconnect_client;
+use crate::packages::server_packet::PacketError;
+use crate::ratelimit::RateLimiter;
+use crate::shutdown::Shutdown;
+use crate::stats::BrokerStats;
+use crate::sys::SysSnapshot;
+use crate::threadpool;
+use crate::transport::client_stream::{ClientStream, Transport};
+use crate::transport::tls::{build_server_config, TlsStream};
+use crate::transport::websocket::WsStream;
+use crate::{http, metrics, schedule};
+use shared::packages::publish::Publish;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{event, field, info_span, Level, Span};
+
+/// Time between checks for new connections, which is also how long a listener takes to stop
+static ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Handshakes a listener runs at the same time, each one holds a thread until it ends
+static MAX_PENDING_HANDSHAKES: usize = 64;
+
+/// An alias for the config shared by the threads, replaced when SIGHUP reloads the config file
+type SharedConfig = Arc<RwLock<Config>>;
+
+/// This struct represents a broker that is configured but not started yet
+#[derive(Default)]
+pub struct Broker {
+    config: Config,
+    /// The file SIGHUP reloads the config from
+    config_file: Option<String>,
+    /// Whether the process signals stop the broker and reload its config
+    handle_signals: bool,
+    /// Checks the clients instead of the credentials file
+    authenticator: Option<Arc<Mutex<dyn Authenticator>>>,
+    /// Called after the hooks named in the config, in the order they were added
+    hooks: Vec<Box<dyn BrokerHook>>,
+}
+
+/// This struct builds a Broker, starting from the defaults of an empty config file
+#[derive(Default)]
+pub struct BrokerBuilder {
+    broker: Broker,
+}
+
+impl BrokerBuilder {
+    /// Replaces every setting, including the listeners added before
+    /// # Arguments
+    ///
+    /// * `config` - The settings of the broker, usually read from a config file
+    ///
+    pub fn config(mut self, config: Config) -> BrokerBuilder {
+        self.broker.config = config;
+        self
+    }
+
+    /// Adds a listener after the ones already configured
+    /// # Arguments
+    ///
+    /// * `settings` - The settings of the listener, a port 0 binds a free port
+    ///
+    pub fn listener(mut self, settings: ListenerSettings) -> BrokerBuilder {
+        self.broker.config.listeners.push(settings);
+        self
+    }
+
+    /// Checks the clients with an authenticator instead of the credentials file
+    /// # Arguments
+    ///
+    /// * `authenticator` - The source of the valid credentials
+    ///
+    pub fn authenticator(mut self, authenticator: impl Authenticator + 'static) -> BrokerBuilder {
+        self.broker.authenticator = Some(Arc::new(Mutex::new(authenticator)));
+        self
+    }
+
+    /// Adds a hook after the ones named in the config and the ones already added
+    /// # Arguments
+    ///
+    /// * `hook` - The hook to add
+    ///
+    pub fn hook(mut self, hook: impl BrokerHook + 'static) -> BrokerBuilder {
+        self.broker.hooks.push(Box::new(hook));
+        self
+    }
+
+    /// Sets the file SIGHUP reloads the config from
+    /// # Arguments
+    ///
+    /// * `config_file` - The path of the config file
+    ///
+    pub fn config_file(mut self, config_file: &str) -> BrokerBuilder {
+        self.broker.config_file = Some(config_file.to_string());
+        self
+    }
+
+    /// Makes SIGTERM and SIGINT stop the broker and SIGHUP reload its config file
+    pub fn handle_signals(mut self) -> BrokerBuilder {
+        self.broker.handle_signals = true;
+        self
+    }
+
+    /// Returns the Broker, or an error if it has no listener
+    pub fn build(self) -> Result<Broker, ConfigError> {
+        self.broker.config.check_listeners()?;
+        Ok(self.broker)
+    }
+}
+
+/// This struct represents a running broker
+pub struct BrokerHandle {
+    /// The address each listener bound to an IP address is listening on
+    local_addrs: Vec<(String, SocketAddr)>,
+    /// Requested to stop the listeners, the bridges and the HTTP endpoints
+    stop_accepting: Arc<Shutdown>,
+    /// Requested once the listeners are closed, stops the threads that serve the clients
+    stop_processing: Arc<Shutdown>,
+    listeners: Vec<thread::JoinHandle<()>>,
+    bridges: Vec<thread::JoinHandle<()>>,
+    /// The HTTP endpoints, stopped with the listeners
+    endpoints: Vec<thread::JoinHandle<()>>,
+    /// The threads stopped by stop_processing
+    workers: Vec<thread::JoinHandle<()>>,
+    active_streams: Arc<Mutex<Vec<Socket>>>,
+    core: CoreHandle,
+    /// Where the delayed messages are saved when the broker stops, if anywhere
+    delayed_messages_file: Option<String>,
+    stats: Arc<BrokerStats>,
+}
+
+impl BrokerHandle {
+    /// Returns the port of the first listener bound to an IP address
+    pub fn port(&self) -> Option<u16> {
+        self.local_addrs.first().map(|(_, address)| address.port())
+    }
+
+    /// Returns the address a listener is listening on, None for Unix domain socket listeners
+    /// # Arguments
+    ///
+    /// * `listener` - The name of the listener
+    ///
+    pub fn local_addr(&self, listener: &str) -> Option<SocketAddr> {
+        self.local_addrs
+            .iter()
+            .find(|(name, _)| name == listener)
+            .map(|(_, address)| *address)
+    }
+
+    /// Stops the broker and waits until its threads finish
+    pub fn shutdown(self) {
+        self.stop_accepting.request();
+        self.wait();
+    }
+
+    /// Waits until the broker is stopped, then stops it in order: listeners first, then the
+    /// request loop and its thread pool, and finally the client connections
+    pub fn wait(self) {
+        for listener in self.listeners {
+            listener.join().unwrap();
+        }
+        for bridge in self.bridges {
+            bridge.join().unwrap();
+        }
+        for endpoint in self.endpoints {
+            endpoint.join().unwrap();
+        }
+        event!(Level::INFO, "Listeners closed, stopping the request loop");
+
+        self.stop_processing.request();
+        for worker in self.workers {
+            worker.join().unwrap();
+        }
+
+        // Sessions, subscriptions and retained messages only live in memory. The delayed
+        // messages are saved once the request loop can no longer add to them
+        if let Some(delayed_messages_file) = &self.delayed_messages_file {
+            schedule::save_delayed_messages(&self.core, delayed_messages_file);
+        }
+        close_connections(&self.active_streams, &self.stats);
+        event!(Level::INFO, "Broker stopped");
+    }
+}
+
+impl Broker {
+    /// Returns a BrokerBuilder with the defaults of an empty config file
+    pub fn builder() -> BrokerBuilder {
+        BrokerBuilder::default()
+    }
+
+    /// Binds the listeners and starts the threads of the broker
+    pub fn start(self) -> io::Result<BrokerHandle> {
+        let startup_config = &self.config;
+        let config: SharedConfig = Arc::new(RwLock::new(self.config.clone()));
+
+        let mut broker_core =
+            BrokerCore::new(startup_config.share_policy, startup_config.expiry.clone());
+        if let Some(delayed_messages_file) = &startup_config.delayed_messages_file {
+            match DelayedManager::load(delayed_messages_file) {
+                Ok(delayed) => {
+                    event!(
+                        Level::INFO,
+                        "Loaded {} delayed messages from {}",
+                        delayed.len(),
+                        delayed_messages_file
+                    );
+                    broker_core.delayed = delayed;
+                }
+                Err(e) => event!(
+                    Level::ERROR,
+                    "Could not load the delayed messages from {}: {}",
+                    delayed_messages_file,
+                    e
+                ),
+            }
+        }
+        let stats = Arc::new(BrokerStats::new());
+        let core = CoreHandle::start(broker_core, Arc::clone(&stats));
+
+        let mut handle = BrokerHandle {
+            local_addrs: Vec::new(),
+            stop_accepting: Arc::new(Shutdown::new()),
+            stop_processing: Arc::new(Shutdown::new()),
+            listeners: Vec::new(),
+            bridges: Vec::new(),
+            endpoints: Vec::new(),
+            workers: Vec::new(),
+            active_streams: Arc::new(Mutex::new(Vec::<Socket>::new())),
+            core,
+            delayed_messages_file: startup_config.delayed_messages_file.clone(),
+            stats,
+        };
+        match self.start_threads(config, &mut handle) {
+            Ok(()) => Ok(handle),
+            Err(e) => {
+                // The threads started before the failure are stopped again. Nothing changed
+                // the delayed messages, so their file is left as it was
+                handle.delayed_messages_file = None;
+                handle.shutdown();
+                Err(e)
+            }
+        }
+    }
+
+    /// Starts the threads of the broker and binds its listeners, adding them to its handle
+    /// so they are stopped even if a later one fails. The signals are handled last, once
+    /// nothing else can fail.
+    /// # Arguments
+    ///
+    /// * `config` - The config shared by the threads
+    /// * `handle` - The handle of the broker being started
+    ///
+    fn start_threads(self, config: SharedConfig, handle: &mut BrokerHandle) -> io::Result<()> {
+        let startup_config = self.config;
+        let stats = Arc::clone(&handle.stats);
+        let core = handle.core.clone();
+        let streams_arc_mutex = Arc::clone(&handle.active_streams);
+        let stop_accepting = Arc::clone(&handle.stop_accepting);
+        let stop_processing = Arc::clone(&handle.stop_processing);
+        let hnr_streams = Arc::clone(&streams_arc_mutex);
+
+        // The credentials file is only read when no authenticator was given
+        let (credentials, file_credentials) = match self.authenticator {
+            Some(authenticator) => (authenticator, None),
+            None => {
+                let credential_manager = Arc::new(Mutex::new(CredentialManager::new()));
+                let authenticator: Arc<Mutex<dyn Authenticator>> =
+                    Arc::clone(&credential_manager) as Arc<Mutex<dyn Authenticator>>;
+                (authenticator, Some(credential_manager))
+            }
+        };
+
+        handle.workers = vec![
+            handle_new_requests(
+                hnr_streams,
+                credentials,
+                core.clone(),
+                Arc::clone(&stats),
+                Arc::clone(&config),
+                Arc::clone(&stop_processing),
+            ),
+            handle_pending_messages(
+                core.clone(),
+                Arc::clone(&stats),
+                Arc::clone(&config),
+                Arc::clone(&stop_processing),
+            ),
+            schedule::start(
+                core.clone(),
+                startup_config.schedules.clone(),
+                startup_config.delayed_messages_file.clone(),
+                Arc::clone(&stop_processing),
+            ),
+        ];
+        if let Some(file_credentials) = &file_credentials {
+            handle.workers.push(update_credentials(
+                Arc::clone(file_credentials),
+                Arc::clone(&stats),
+                Arc::clone(&config),
+                Arc::clone(&stop_processing),
+            ));
+        }
+        if !startup_config.sys_interval.is_zero() {
+            handle.workers.push(publish_sys_topics(
+                core.clone(),
+                Arc::clone(&streams_arc_mutex),
+                Arc::clone(&stats),
+                startup_config.sys_interval,
+                Arc::clone(&stop_processing),
+            ));
+        }
+
+        if let Some(address) = &startup_config.metrics_bind {
+            let metrics_stats = Arc::clone(&stats);
+            let metrics_streams = Arc::clone(&streams_arc_mutex);
+            handle.endpoints.push(http::serve(
+                "Metrics",
+                address,
+                Arc::new(move |request| {
+                    metrics::handle_request(request, &metrics_stats, &metrics_streams)
+                }),
+                Arc::clone(&stop_accepting),
+            )?);
+        }
+        if let Some(admin_settings) = &startup_config.admin {
+            let admin_api = AdminApi::new(
+                &admin_settings.token,
+                core.clone(),
+                Arc::clone(&streams_arc_mutex),
+                Arc::clone(&stats),
+            );
+            handle.endpoints.push(http::serve(
+                "Admin",
+                &admin_settings.bind,
+                Arc::new(move |request| admin_api.handle_request(request)),
+                Arc::clone(&stop_accepting),
+            )?);
+        }
+
+        let rate_limits = Arc::new(RateLimiter::new(startup_config.rate_limits.clone()));
+        let tenancy = Arc::new(startup_config.tenancy.clone());
+        let mut hooks = Hooks::from_names(&startup_config.hooks);
+        for hook in self.hooks {
+            hooks.register(hook);
+        }
+        let hooks = Arc::new(hooks);
+
+        for listener_settings in startup_config.listeners.iter() {
+            let hnc_streams = Arc::clone(&streams_arc_mutex);
+            let (listener, local_addr) = handle_new_connections(
+                listener_settings,
+                hnc_streams,
+                Arc::clone(&stats),
+                Arc::clone(&rate_limits),
+                Arc::clone(&tenancy),
+                startup_config.outbound,
+                Arc::clone(&hooks),
+                Arc::clone(&stop_accepting),
+            )?;
+            handle.listeners.push(listener);
+            if let Some(local_addr) = local_addr {
+                handle
+                    .local_addrs
+                    .push((listener_settings.name.to_string(), local_addr));
+            }
+        }
+
+        for bridge_settings in startup_config.bridges.iter() {
+            handle.bridges.push(bridge::start(
+                bridge_settings.clone(),
+                bridge_connector(
+                    bridge_settings,
+                    Arc::clone(&streams_arc_mutex),
+                    Arc::clone(&stats),
+                    startup_config.outbound,
+                    Arc::clone(&hooks),
+                ),
+                Arc::clone(&stop_accepting),
+            )?);
+        }
+
+        if self.handle_signals {
+            handle_signals(
+                self.config_file,
+                config,
+                file_credentials,
+                stats,
+                stop_accepting,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks the parts of the config that can only be validated by loading other files
+/// # Arguments
+///
+/// * `config` - The config read from the config file
+///
+pub fn check_config(config: &Config) -> Result<(), String> {
+    for listener_settings in config.listeners.iter() {
+        if let Some(tls_settings) = &listener_settings.tls {
+            build_server_config(tls_settings)
+                .map_err(|e| format!("Listener {}: {}", listener_settings.name, e))?;
+        }
+    }
+    File::open(&config.credentials_file).map_err(|e| {
+        format!(
+            "Could not read credentials file {}: {}",
+            config.credentials_file, e
+        )
+    })?;
+    Ok(())
+}
+
+/// Starts the thread that handles the process signals. SIGTERM and SIGINT stop the broker,
+/// a second one exits right away. SIGHUP reloads the config file and the credentials.
+/// # Arguments
+///
+/// * `config_file` - The path of the config file, SIGHUP is ignored without one
+/// * `config` - The config shared by the threads
+/// * `credentials` - The credentials read again after the config, none when an authenticator is used
+/// * `stats` - The broker counters, which measure the wait for each lock
+/// * `stop_accepting` - Requested when a stop signal arrives
+///
+fn handle_signals(
+    config_file: Option<String>,
+    config: SharedConfig,
+    credentials: Option<Arc<Mutex<CredentialManager>>>,
+    stats: Arc<BrokerStats>,
+    stop_accepting: Arc<Shutdown>,
+) -> io::Result<thread::JoinHandle<()>> {
+    let mut signals = Signals::new([SIGTERM, SIGINT, SIGHUP])?;
+
+    Ok(thread::spawn(move || {
+        for signal in signals.forever() {
+            if signal == SIGHUP {
+                match &config_file {
+                    Some(config_file) => {
+                        event!(Level::INFO, "Received SIGHUP, reloading {}", config_file);
+                        reload_config(config_file, &config, credentials.as_deref(), &stats);
+                    }
+                    None => event!(Level::WARN, "Received SIGHUP without a config file"),
+                }
+            } else if stop_accepting.is_requested() {
+                event!(Level::WARN, "Received signal {} again, exiting now", signal);
+                process::exit(1);
+            } else {
+                event!(Level::INFO, "Received signal {}, shutting down", signal);
+                stop_accepting.request();
+            }
+        }
+    }))
+}
+
+/// Reads the config file again and replaces the shared config. Settings used only on
+/// startup, such as the listeners, keep their old values until the broker is restarted.
+/// # Arguments
+///
+/// * `config_file` - The path of the config file
+/// * `config` - The config shared by the threads
+/// * `credentials` - The credentials read again from the new credentials file, if any
+/// * `stats` - The broker counters, which measure the wait for each lock
+///
+fn reload_config(
+    config_file: &str,
+    config: &RwLock<Config>,
+    credentials: Option<&Mutex<CredentialManager>>,
+    stats: &BrokerStats,
+) {
+    let new_config = match Config::from_file(config_file) {
+        Ok(new_config) => new_config,
+        Err(e) => {
+            event!(Level::ERROR, "Config file was not reloaded. {}", e);
+            return;
+        }
+    };
+
+    let mut current_config = config.write().unwrap();
+    for setting in startup_only_changes(&current_config, &new_config) {
+        event!(
+            Level::WARN,
+            "Changes to {} are applied after a restart",
+            setting
+        );
+    }
+    *current_config = new_config;
+    let credentials_file = current_config.credentials_file.to_string();
+    drop(current_config);
+
+    if let Some(credentials) = credentials {
+        reload_credentials(&credentials_file, credentials, stats);
+    }
+    event!(Level::INFO, "Config file {} reloaded", config_file);
+}
+
+/// Returns the settings that changed between two configs and are only read on startup
+fn startup_only_changes(current: &Config, new: &Config) -> Vec<&'static str> {
+    let mut changes = Vec::new();
+    if current.listeners != new.listeners {
+        changes.push("the listeners");
+    }
+    if current.log != new.log {
+        changes.push("the log settings");
+    }
+    if current.pool_size != new.pool_size {
+        changes.push("poolSize");
+    }
+    if current.sys_interval != new.sys_interval {
+        changes.push("sysInterval");
+    }
+    if current.metrics_bind != new.metrics_bind {
+        changes.push("metricsBind");
+    }
+    if current.rate_limits != new.rate_limits {
+        changes.push("the rate limits");
+    }
+    if current.tenancy != new.tenancy {
+        changes.push("mountPoint and the tenants");
+    }
+    if current.share_policy != new.share_policy {
+        changes.push("sharedSubscriptionPolicy");
+    }
+    if current.outbound != new.outbound {
+        changes.push("outboundQueueSize and slowConsumerPolicy");
+    }
+    if current.expiry != new.expiry {
+        changes.push("messageExpiry and topicExpiry");
+    }
+    if current.delayed_messages_file != new.delayed_messages_file {
+        changes.push("delayedMessagesFile");
+    }
+    if current.schedules != new.schedules {
+        changes.push("the schedules");
+    }
+    if current.bridges != new.bridges {
+        changes.push("the bridges");
+    }
+    if current.hooks != new.hooks {
+        changes.push("hooks");
+    }
+    if current.admin != new.admin {
+        changes.push("adminBind and adminToken");
+    }
+    changes
+}
+
+/// Replaces the credentials with the ones in the credentials file, keeping the old ones if it can not be read
+/// # Arguments
+///
+/// * `credentials_file` - The file with the username,password lines
+/// * `credentials` - The credentials to replace
+/// * `stats` - The broker counters, which measure the wait for each lock
+///
+fn reload_credentials(
+    credentials_file: &str,
+    credentials: &Mutex<CredentialManager>,
+    stats: &BrokerStats,
+) {
+    match read_credentials(credentials_file) {
+        Ok(credential_manager) => {
+            *stats.lock(LockName::Credentials, credentials) = credential_manager
+        }
+        Err(e) => event!(
+            Level::ERROR,
+            "Could not read credentials file {}: {}",
+            credentials_file,
+            e
+        ),
+    }
+}
+
+fn read_credentials(credentials_file: &str) -> io::Result<CredentialManager> {
+    let reader = BufReader::new(File::open(credentials_file)?);
+    let mut credential_manager = CredentialManager::new();
+
+    for line in reader.lines().map_while(Result::ok) {
+        if line.is_empty() {
+            continue;
+        }
+
+        match line.split_once(',') {
+            Some((username, password)) => {
+                if !credential_manager.has_username(username) {
+                    credential_manager.add_credential(username, password);
+                }
+            }
+            None => event!(Level::WARN, "Invalid credentials line {:?}", line),
+        }
+    }
+
+    Ok(credential_manager)
+}
+
+fn update_credentials(
+    credentials: Arc<Mutex<CredentialManager>>,
+    stats: Arc<BrokerStats>,
+    config: SharedConfig,
+    stop_processing: Arc<Shutdown>,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || loop {
+        let (credentials_file, reload_interval) = {
+            let config = config.read().unwrap();
+            (
+                config.credentials_file.to_string(),
+                config.credentials_reload_interval,
+            )
+        };
+
+        reload_credentials(&credentials_file, &credentials, &stats);
+
+        if stop_processing.wait_timeout(reload_interval) {
+            break;
+        }
+    })
+}
+
+/// Closes the sockets of every connected client
+/// # Arguments
+///
+/// * `active_streams` - The sockets of the connected clients
+/// * `stats` - The broker counters, which measure the wait for each lock
+///
+fn close_connections(active_streams: &Mutex<Vec<Socket>>, stats: &BrokerStats) {
+    let mut streams = stats.lock(LockName::Streams, active_streams);
+    for socket in streams.iter() {
+        if let Err(e) = socket.stream.shutdown() {
+            event!(
+                Level::DEBUG,
+                "Could not close the connection of {}: {}",
+                socket.stream.peer_addr(),
+                e
+            );
+        }
+    }
+    event!(Level::INFO, "Closed {} client connections", streams.len());
+    streams.clear();
+}
+
+#[allow(clippy::too_many_arguments)]
+fn handle_new_requests(
+    active_streams: Arc<Mutex<Vec<Socket>>>,
+    credentials: Arc<Mutex<dyn Authenticator>>,
+    core: CoreHandle,
+    stats: Arc<BrokerStats>,
+    config: SharedConfig,
+    stop_processing: Arc<Shutdown>,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let pool = threadpool::ThreadPool::new(config.read().unwrap().pool_size).unwrap();
+
+        loop {
+            event!(Level::DEBUG, "HNR: Checking for new requests");
+
+            let (socket_read_timeout, request_poll_interval, connect_timeout) = {
+                let config = config.read().unwrap();
+                (
+                    config.socket_read_timeout,
+                    config.request_poll_interval,
+                    config.connect_timeout,
+                )
+            };
+
+            stats.set_queue_depth(pool.queue_depth());
+            let streams = stats.lock(LockName::Streams, &active_streams);
+
+            event!(Level::DEBUG, "HNR: Amount active sockets {}", streams.len());
+
+            // Disconnecting waits for the core, which goes before the streams lock, so it
+            // waits until the loop is over
+            let mut slow_consumers = Vec::new();
+            let mut broken_connections = Vec::new();
+            let now = Instant::now();
+            for index in 0..streams.len() {
+                event!(Level::DEBUG, "HNR: Run socket: {:?}", index);
+
+                if streams[index].stream.is_slow_consumer() {
+                    slow_consumers.push(streams[index].stream.try_clone().unwrap());
+                    continue;
+                }
+
+                // A worker is reading from it, the next packet waits until it is done. So the
+                // packets of a connection are handled one at a time, in the order they were sent
+                if streams[index].stream.is_busy() {
+                    continue;
+                }
+
+                // A throttled client is not read until its publishes are within its limits
+                if streams[index].stream.is_paused(now) {
+                    continue;
+                }
+
+                let credential_manager = Arc::clone(&credentials);
+                let broker_core = core.clone();
+                let actual_streams = Arc::clone(&active_streams);
+
+                let mut buf = [0u8; 100];
+                let socket_to_process = match streams[index].stream.try_clone() {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        event!(Level::ERROR, "HNR: Could not clone socket {}: {}", index, e);
+                        continue;
+                    }
+                };
+
+                let peeked = socket_to_process
+                    .set_read_timeout(Some(socket_read_timeout))
+                    .and_then(|_| socket_to_process.peek(&mut buf));
+                match peeked {
+                    Err(e) => match e.kind() {
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => {
+                            event!(
+                                Level::DEBUG,
+                                "HNR: Socket {:?} would have blocked.",
+                                socket_to_process
+                            );
+                            if socket_to_process.keep_alive_expired(now) {
+                                broken_connections
+                                    .push((socket_to_process, "keep alive expired".to_string()));
+                            } else if socket_to_process.connect_expired(now, connect_timeout) {
+                                broken_connections
+                                    .push((socket_to_process, "no CONNECT received".to_string()));
+                            }
+                            continue;
+                        }
+                        _ => {
+                            event!(
+                                Level::DEBUG,
+                                "HNR: Socket {:?} connection is broken. Reason: {:?}",
+                                socket_to_process,
+                                e
+                            );
+                            broken_connections.push((socket_to_process, e.to_string()));
+                            continue;
+                        }
+                    },
+                    Ok(0) => {
+                        broken_connections.push((
+                            socket_to_process,
+                            "connection closed by the peer".to_string(),
+                        ));
+                    }
+                    Ok(_) => {
+                        let handling = socket_to_process.start_handling();
+                        pool.execute(move || {
+                            // Dropped when the task ends or panics, so the client is read again
+                            let _handling = handling;
+                            event!(Level::DEBUG, "HNR: New task");
+
+                            let mut mystream = socket_to_process.try_clone().unwrap();
+                            if let Err(e) = handle_client(
+                                &mut mystream,
+                                credential_manager,
+                                broker_core.clone(),
+                                Arc::clone(&actual_streams),
+                            ) {
+                                event!(Level::ERROR, "HNR: Error de handler client: {}", e);
+                                close_ungracefully(
+                                    &mystream,
+                                    &broker_core,
+                                    &actual_streams,
+                                    &e.to_string(),
+                                );
+                            }
+                        });
+                    }
+                };
+            }
+
+            drop(streams);
+
+            for (stream, reason) in broken_connections {
+                close_ungracefully(&stream, &core, &active_streams, &reason);
+            }
+
+            for stream in slow_consumers {
+                event!(
+                    Level::WARN,
+                    "HNR: Disconnecting {}, its outbound queue is full",
+                    stream.peer_addr()
+                );
+                stats.add_slow_consumer_disconnect();
+                disconnect_client(&stream, &core, &active_streams);
+            }
+
+            if stop_processing.wait_timeout(request_poll_interval) {
+                break;
+            }
+        }
+
+        event!(
+            Level::INFO,
+            "HNR: Stopped reading requests, finishing {} queued packets",
+            pool.queue_depth()
+        );
+        // Dropping the pool lets the workers finish the queued jobs before they terminate
+        drop(pool);
+    })
+}
+
+/// Starts accepting connections on a listener, returning its thread and the address it is
+/// bound to, which is None for Unix domain sockets
+/// # Arguments
+///
+/// * `listener_settings` - The settings of the listener to start
+/// * `stream_new` - The active streams where established connections are added
+/// * `stats` - The broker counters updated by the accepted connections
+/// * `rate_limits` - The limits applied to the accepted connections
+/// * `tenancy` - The tenants the users of the accepted connections are mounted in
+/// * `outbound` - The outbound queue of each accepted connection
+/// * `hooks` - The hooks called for the accepted connections
+/// * `stop_accepting` - Requested when the listener must close
+///
+#[allow(clippy::too_many_arguments)]
+fn handle_new_connections(
+    listener_settings: &ListenerSettings,
+    stream_new: Arc<Mutex<Vec<Socket>>>,
+    stats: Arc<BrokerStats>,
+    rate_limits: Arc<RateLimiter>,
+    tenancy: Arc<TenancySettings>,
+    outbound: OutboundSettings,
+    hooks: Arc<Hooks>,
+    stop_accepting: Arc<Shutdown>,
+) -> std::io::Result<(thread::JoinHandle<()>, Option<SocketAddr>)> {
+    let listener_settings = Arc::new(listener_settings.clone());
+
+    match &listener_settings.bind {
+        BindAddress::Inet(address) => {
+            let listener = TcpListener::bind(address)?;
+            // Accept does not block, so the listener can notice stop_accepting
+            listener.set_nonblocking(true)?;
+            let local_addr = listener.local_addr()?;
+            event!(
+                Level::INFO,
+                "Listener {} ({:?}) listening on {}",
+                listener_settings.name,
+                listener_settings.protocol,
+                local_addr
+            );
+            let upgrade = transport_upgrade(&listener_settings)?;
+            let handshakes = Arc::new(AtomicUsize::new(0));
+
+            let thread = thread::spawn(move || {
+                loop {
+                    match listener.accept() {
+                        Ok((stream, peer_addr)) => {
+                            // Connections still in their handshake count against the limit,
+                            // so the clients cannot hold more threads than it allows
+                            let pending = handshakes.load(Ordering::SeqCst);
+                            let active = listener_connections(
+                                &stats.lock(LockName::Streams, &stream_new),
+                                &listener_settings.name,
+                            );
+                            let full = listener_settings
+                                .max_connections
+                                .is_some_and(|max_connections| active + pending >= max_connections);
+                            if full || pending >= MAX_PENDING_HANDSHAKES {
+                                event!(
+                                    Level::WARN,
+                                    "Listener {} has {} connections and {} handshakes, rejecting a connection from {}",
+                                    listener_settings.name,
+                                    active,
+                                    pending,
+                                    peer_addr
+                                );
+                                stats.add_connection_rejected();
+                                continue;
+                            }
+                            if let Err(e) = stream.set_nonblocking(false) {
+                                event!(Level::ERROR, "Failed connection: {}", e);
+                                continue;
+                            }
+                            handshakes.fetch_add(1, Ordering::SeqCst);
+                            let handshakes = Arc::clone(&handshakes);
+                            let upgrade = Arc::clone(&upgrade);
+                            let listener_settings = Arc::clone(&listener_settings);
+                            let stream_new = Arc::clone(&stream_new);
+                            let stats = Arc::clone(&stats);
+                            let rate_limits = Arc::clone(&rate_limits);
+                            let tenancy = Arc::clone(&tenancy);
+                            let hooks = Arc::clone(&hooks);
+                            // Handshakes run on their own thread so a slow client does not block accept
+                            thread::spawn(move || {
+                                match upgrade(stream) {
+                                    Ok(transport) => add_connection(
+                                        transport,
+                                        listener_settings,
+                                        stream_new,
+                                        stats,
+                                        rate_limits,
+                                        tenancy,
+                                        outbound,
+                                        hooks,
+                                    ),
+                                    Err(e) => event!(Level::WARN, "Handshake failed: {}", e),
+                                }
+                                // Released once the connection is counted among the streams
+                                handshakes.fetch_sub(1, Ordering::SeqCst);
+                            });
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                            if stop_accepting.wait_timeout(ACCEPT_POLL_INTERVAL) {
+                                break;
+                            }
+                        }
+                        Err(e) => {
+                            event!(Level::ERROR, "Failed connection: {}", e);
+                        }
+                    }
+                }
+                event!(Level::INFO, "Listener {} closed", listener_settings.name);
+            });
+            Ok((thread, Some(local_addr)))
+        }
+        BindAddress::Unix(path) => {
+            // A socket file left by a previous run would make bind fail
+            let _ = fs::remove_file(path);
+            let listener = UnixListener::bind(path)?;
+            listener.set_nonblocking(true)?;
+            let path = path.to_string();
+            event!(
+                Level::INFO,
+                "Listener {} listening on unix:{}",
+                listener_settings.name,
+                path
+            );
+
+            let thread = thread::spawn(move || {
+                loop {
+                    match listener.accept() {
+                        Ok((stream, _)) => match stream.set_nonblocking(false) {
+                            Ok(_) => add_connection(
+                                Transport::Unix(stream),
+                                Arc::clone(&listener_settings),
+                                Arc::clone(&stream_new),
+                                Arc::clone(&stats),
+                                Arc::clone(&rate_limits),
+                                Arc::clone(&tenancy),
+                                outbound,
+                                Arc::clone(&hooks),
+                            ),
+                            Err(e) => event!(Level::ERROR, "Failed connection: {}", e),
+                        },
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                            if stop_accepting.wait_timeout(ACCEPT_POLL_INTERVAL) {
+                                break;
+                            }
+                        }
+                        Err(e) => {
+                            event!(Level::ERROR, "Failed connection: {}", e);
+                        }
+                    }
+                }
+                let _ = fs::remove_file(&path);
+                event!(Level::INFO, "Listener {} closed", listener_settings.name);
+            });
+            Ok((thread, None))
+        }
+    }
+}
+
+/// An alias for a function that turns an accepted TcpStream into an established transport
+type Upgrade = dyn Fn(TcpStream) -> io::Result<Transport> + Send + Sync;
+
+/// Returns the handshake to perform on connections accepted by a listener
+fn transport_upgrade(listener_settings: &ListenerSettings) -> std::io::Result<Arc<Upgrade>> {
+    match listener_settings.protocol {
+        ListenerProtocol::Tcp => Ok(Arc::new(|stream| Ok(Transport::Tcp(stream)))),
+        ListenerProtocol::Ws => Ok(Arc::new(|stream| {
+            WsStream::accept(stream).map(Transport::Ws)
+        })),
+        ListenerProtocol::Tls => {
+            let tls_settings = match &listener_settings.tls {
+                Some(tls_settings) => tls_settings,
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "TLS listener without TLS settings",
+                    ))
+                }
+            };
+            let tls_config = build_server_config(tls_settings)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            let username_field = tls_settings.username_field.clone();
+            Ok(Arc::new(move |stream| {
+                TlsStream::accept(stream, Arc::clone(&tls_config), &username_field)
+                    .map(Transport::Tls)
+            }))
+        }
+    }
+}
+
+/// Returns the function a bridge uses to connect to this broker. Each call adds one end of
+/// a socket pair to the active streams and returns the other end, which skips authentication
+/// and the rate limits.
+/// # Arguments
+///
+/// * `settings` - The settings of the bridge
+/// * `stream_new` - The active streams the connection is added to
+/// * `stats` - The broker counters updated by the connection
+/// * `outbound` - The outbound queue of the connection
+/// * `hooks` - The hooks called for the connection
+///
+fn bridge_connector(
+    settings: &BridgeSettings,
+    stream_new: Arc<Mutex<Vec<Socket>>>,
+    stats: Arc<BrokerStats>,
+    outbound: OutboundSettings,
+    hooks: Arc<Hooks>,
+) -> LocalConnector {
+    let listener_settings = Arc::new(ListenerSettings {
+        name: format!("bridge.{}", settings.name),
+        bind: BindAddress::Unix(String::new()),
+        protocol: ListenerProtocol::Tcp,
+        max_connections: None,
+        require_auth: false,
+        max_qos: MAX_SUPPORTED_QOS,
+        tls: None,
+    });
+    let rate_limits = Arc::new(RateLimiter::new(RateLimitSettings {
+        policy: LimitPolicy::Drop,
+        default: RateLimits::default(),
+        users: HashMap::new(),
+    }));
+    // Bridged topics are not mounted in any tenant
+    let tenancy = Arc::new(TenancySettings::default());
+    Box::new(move || {
+        let (broker_end, bridge_end) = UnixStream::pair()?;
+        add_connection(
+            Transport::Unix(broker_end),
+            Arc::clone(&listener_settings),
+            Arc::clone(&stream_new),
+            Arc::clone(&stats),
+            Arc::clone(&rate_limits),
+            Arc::clone(&tenancy),
+            outbound,
+            Arc::clone(&hooks),
+        );
+        Ok(bridge_end)
+    })
+}
+
+/// Adds an established connection to the active streams, unless its listener is full
+#[allow(clippy::too_many_arguments)]
+fn add_connection(
+    transport: Transport,
+    listener_settings: Arc<ListenerSettings>,
+    stream_new: Arc<Mutex<Vec<Socket>>>,
+    stats: Arc<BrokerStats>,
+    rate_limits: Arc<RateLimiter>,
+    tenancy: Arc<TenancySettings>,
+    outbound: OutboundSettings,
+    hooks: Arc<Hooks>,
+) {
+    let stream = match ClientStream::new(
+        transport,
+        listener_settings,
+        Arc::clone(&stats),
+        rate_limits,
+        tenancy,
+        outbound,
+        hooks,
+    ) {
+        Ok(stream) => stream,
+        Err(e) => {
+            event!(Level::ERROR, "Failed connection: {}", e);
+            return;
+        }
+    };
+
+    let mut streams = stats.lock(LockName::Streams, &stream_new);
+    if let Some(max_connections) = stream.listener().max_connections {
+        if listener_connections(&streams, &stream.listener().name) >= max_connections {
+            event!(
+                Level::WARN,
+                "Listener {} reached its limit of {} connections, rejecting connection {} from {}",
+                stream.listener().name,
+                max_connections,
+                stream.connection_id(),
+                stream.peer_addr()
+            );
+            stats.add_connection_rejected();
+            return;
+        }
+    }
+
+    stats.add_connection_accepted();
+    streams.push(Socket {
+        connection_id: stream.connection_id(),
+        stream,
+    });
+}
+
+/// Returns how many of the active streams were accepted by a listener
+/// # Arguments
+///
+/// * `streams` - The active streams
+/// * `listener` - The name of the listener
+///
+fn listener_connections(streams: &[Socket], listener: &str) -> usize {
+    streams
+        .iter()
+        .filter(|socket| socket.stream.listener().name == listener)
+        .count()
+}
+
+fn handle_client(
+    stream: &mut ClientStream,
+    credential_manager: Arc<Mutex<dyn Authenticator>>,
+    core: CoreHandle,
+    actual_streams: Arc<Mutex<Vec<Socket>>>,
+) -> Result<(), PacketError> {
+    // The client id is recorded by CONNECT when the peer has no session yet
+    let span = info_span!("client", peer = %stream.peer_addr(), client_id = field::Empty);
+    let connection_id = stream.connection_id();
+    let client_id = core.call(move |core| core.sessions.get_client_id(&connection_id));
+    if let Ok(client_id) = &client_id {
+        span.record("client_id", client_id.as_str());
+    }
+    let _entered = span.enter();
+
+    let packet = dispatch_packet(stream, client_id.is_ok())?;
+    event!(Level::INFO, "Server received a package {:?}", packet);
+
+    packet.handle_packet(stream, credential_manager, core, actual_streams)
+}
+
+fn handle_pending_messages(
+    core: CoreHandle,
+    stats: Arc<BrokerStats>,
+    config: SharedConfig,
+    stop_processing: Arc<Shutdown>,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || loop {
+        event!(Level::DEBUG, "MSGMGR: Resending unacknowledged messages");
+
+        let core_stats = Arc::clone(&stats);
+        core.call(move |core_state| resend_pending_messages(core_state, &core_stats));
+        event!(
+            Level::DEBUG,
+            "MSGMGR: Finished resending unacknowledged messages"
+        );
+
+        let retry_interval = config.read().unwrap().retry_interval;
+        if stop_processing.wait_timeout(retry_interval) {
+            break;
+        }
+    })
+}
+
+/// Drops the expired messages and resends the ones still waiting for an acknowledgement
+/// # Arguments
+///
+/// * `core_state` - The sessions and pending messages of the broker
+/// * `stats` - The traffic counters of the broker
+///
+fn resend_pending_messages(core_state: &mut BrokerCore, stats: &BrokerStats) {
+    let expired = core_state.remove_expired(Instant::now());
+    if expired > 0 {
+        stats.add_expired_messages(expired);
+        event!(Level::INFO, "MSGMGR: Dropped {} expired messages", expired);
+    }
+    for (client_id, pending_messages) in core_state.messages.get_all() {
+        for pending_message in pending_messages {
+            let stream = match core_state.sessions.get_stream(client_id) {
+                Some(stream) => stream,
+                None => {
+                    event!(Level::WARN, "Could not get client {:?}", client_id);
+                    continue;
+                }
+            };
+            let publish = pending_message.to_publish_packet();
+            let _entered = info_span!(
+                "client",
+                peer = %stream.peer_addr(),
+                client_id = %client_id
+            )
+            .entered();
+
+            match stream.send_message(&publish) {
+                Ok(_) => {
+                    stats.add_publish_sent(publish.qos);
+                    stats.add_retransmission();
+                    event!(
+                        Level::INFO,
+                        "{:?} was re-sent to client {}",
+                        publish,
+                        client_id
+                    )
+                }
+                Err(e) => event!(
+                    Level::WARN,
+                    "{:?} could not be sent to client {:?}. Reason: {:?}",
+                    publish,
+                    client_id,
+                    e
+                ),
+            }
+        }
+    }
+}
+
+/// Closes a connection that did not end with a DISCONNECT, publishing the will of its client
+/// once. The session is removed too unless it is persistent.
+/// # Arguments
+///
+/// * `stream` - The connection to close
+/// * `core` - The sessions, subscriptions and pending messages of the broker
+/// * `actual_streams` - The sockets of the connected clients
+/// * `reason` - Why the connection is closed, for the logs
+///
+fn close_ungracefully(
+    stream: &ClientStream,
+    core: &CoreHandle,
+    actual_streams: &Mutex<Vec<Socket>>,
+    reason: &str,
+) {
+    let _entered = info_span!(
+        "client",
+        peer = %stream.peer_addr(),
+        client_id = field::Empty
+    )
+    .entered();
+    let connection_id = stream.connection_id();
+    let session = core.call(move |core| {
+        let client_id = core.sessions.get_client_id(&connection_id).ok()?;
+        // Taking the will out of the session makes sure it is published only once
+        let lwt = core.sessions.take_last_will(&client_id);
+        end_session(&client_id, connection_id, core);
+        Some((client_id, lwt))
+    });
+
+    match session {
+        Some((client_id, lwt)) => {
+            Span::current().record("client_id", client_id.as_str());
+            event!(
+                Level::WARN,
+                "Client: {:?} disconnected ungracefully: {}",
+                client_id,
+                reason
+            );
+            if let Some(lwt) = lwt {
+                let mut lwt_publish = Publish {
+                    topic_name: lwt.topic_name,
+                    payload: lwt.payload,
+                    packet_id: 1_u16, // TODO: generate ids from the server
+                    qos: lwt.qos,
+                    retain_flag: lwt.retain_flag,
+                    dup_flag: 0_u8,
+                };
+                // The will is published on behalf of the client, so its hooks apply
+                let verdict = stream
+                    .hooks()
+                    .on_publish(&ClientInfo::new(&client_id, stream), &mut lwt_publish);
+                if verdict == HookVerdict::Continue {
+                    core.cast(move |core| {
+                        core.publish(&lwt_publish);
+                    });
+                }
+            }
+            remove_stream(connection_id, stream.stats(), actual_streams);
+            stream
+                .hooks()
+                .on_disconnect(&ClientInfo::new(&client_id, stream), false);
+        }
+        None => {
+            event!(Level::DEBUG, "Connection closed before CONNECT: {}", reason);
+            remove_stream(connection_id, stream.stats(), actual_streams);
+        }
+    }
+    if let Err(e) = stream.shutdown() {
+        event!(Level::DEBUG, "Could not close the connection: {}", e);
+    }
+}
+
+/// Publishes the broker statistics on the $SYS topics at a fixed interval
+/// # Arguments
+///
+/// * `core` - The sessions used to reach subscribers, and the topics where the statistics are retained
+/// * `active_streams` - The sockets of the connected clients
+/// * `stats` - The traffic counters of the broker
+/// * `interval` - The time between publishes
+/// * `stop_processing` - Requested when the broker is stopping
+///
+fn publish_sys_topics(
+    core: CoreHandle,
+    active_streams: Arc<Mutex<Vec<Socket>>>,
+    stats: Arc<BrokerStats>,
+    interval: Duration,
+    stop_processing: Arc<Shutdown>,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || loop {
+        let snapshot = SysSnapshot::collect(&core, &active_streams, &stats);
+
+        let publishes = snapshot.to_publish_packets(&stats);
+        core.cast(move |core| {
+            for publish in publishes.iter() {
+                core.publish(publish);
+            }
+        });
+        event!(Level::DEBUG, "SYS: Published broker statistics");
+
+        if stop_processing.wait_timeout(interval) {
+            break;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::broker::Broker;
+    use crate::config::{Config, ListenerSettings};
+    use crate::managers::credentialmanager::CredentialManager;
+    use shared::packages::connack::Connack;
+    use shared::packages::connect::Connect;
+    use shared::packages::packet::{FixedHeader, ReadablePacket, WritablePacket};
+    use std::net::{TcpListener, TcpStream};
+    use std::time::Duration;
+
+    fn connect(port: u16, password: &str) -> Connack {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let connect = Connect {
+            client_id: "embedded".to_string(),
+            username: "jleyes".to_string(),
+            password: password.to_string(),
+            last_will_topic: String::new(),
+            last_will_message: String::new(),
+            keep_alive: 60,
+            last_will_qos: 0,
+            clean_session: 1,
+            last_will_retain: 0,
+            last_will_flag: 0,
+        };
+        connect.write_to(&mut stream).unwrap();
+        let header = FixedHeader::read_fixed_header(&mut stream).unwrap();
+        Connack::read_from(&mut stream, header).unwrap()
+    }
+
+    #[test]
+    fn test_embedded_broker_on_a_free_port() {
+        let mut credentials = CredentialManager::new();
+        credentials.add_credential("jleyes", "1234");
+        let config = Config {
+            request_poll_interval: Duration::from_millis(10),
+            sys_interval: Duration::ZERO,
+            ..Config::default()
+        };
+
+        assert!(Broker::builder().config(config.clone()).build().is_err());
+
+        let broker = Broker::builder()
+            .config(config)
+            .listener(ListenerSettings::tcp("default", "127.0.0.1:0"))
+            .authenticator(credentials)
+            .build()
+            .unwrap()
+            .start()
+            .unwrap();
+        let port = broker.port().unwrap();
+        assert_ne!(port, 0);
+        assert_eq!(broker.local_addr("default").unwrap().port(), port);
+
+        assert_eq!(connect(port, "1234").return_code, 0);
+        assert_eq!(connect(port, "wrong").return_code, 4);
+
+        broker.shutdown();
+        assert!(TcpStream::connect(("127.0.0.1", port)).is_err());
+    }
+
+    #[test]
+    fn test_failed_bind_stops_what_was_started() {
+        let busy = TcpListener::bind("127.0.0.1:0").unwrap();
+        let metrics_port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let config = Config {
+            metrics_bind: Some(format!("127.0.0.1:{}", metrics_port)),
+            sys_interval: Duration::ZERO,
+            ..Config::default()
+        };
+
+        let started = Broker::builder()
+            .config(config)
+            .listener(ListenerSettings::tcp(
+                "busy",
+                &busy.local_addr().unwrap().to_string(),
+            ))
+            .authenticator(CredentialManager::new())
+            .build()
+            .unwrap()
+            .start();
+        assert!(started.is_err());
+        assert!(TcpStream::connect(("127.0.0.1", metrics_port)).is_err());
+    }
+}
@@ -1,49 +1,1959 @@
+use crate::hooks::BUILTIN_HOOKS;
+use crate::managers::topicmanager::{topic_matches, MountPoint};
+use crate::schedule::CronSchedule;
+use shared::packages::publish::Publish;
 use std::collections::HashMap;
 use std::fmt;
 use std::fs::File;
 use std::io::{prelude::*, BufReader};
 use std::result::Result;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tracing_subscriber::EnvFilter;
+
+/// Settings that can appear outside of a listener block
+static GLOBAL_SETTINGS: [&str; 38] = [
+    "logFile",
+    "logDirectory",
+    "logRotation",
+    "logRetention",
+    "logFormat",
+    "logLevel",
+    "poolSize",
+    "credentialsFile",
+    "credentialsReloadInterval",
+    "requestPollInterval",
+    "socketReadTimeout",
+    "connectTimeout",
+    "retryInterval",
+    "sysInterval",
+    "metricsBind",
+    "adminBind",
+    "adminToken",
+    "maxPublishRate",
+    "maxByteRate",
+    "maxSubscriptions",
+    "maxQos",
+    "mountPoint",
+    "rateLimitPolicy",
+    "sharedSubscriptionPolicy",
+    "outboundQueueSize",
+    "slowConsumerPolicy",
+    "messageExpiry",
+    "topicExpiry",
+    "delayedMessagesFile",
+    "hooks",
+    "port",
+    "tlsPort",
+    "wsPort",
+    "tlsCertFile",
+    "tlsKeyFile",
+    "tlsClientCaFile",
+    "tlsRequireClientCert",
+    "tlsCertUsername",
+];
+
+/// Settings that can appear in a listener.<name>.<setting> key
+static LISTENER_SETTINGS: [&str; 10] = [
+    "bind",
+    "protocol",
+    "maxConnections",
+    "requireAuth",
+    "maxQos",
+    "certFile",
+    "keyFile",
+    "clientCaFile",
+    "requireClientCert",
+    "certUsername",
+];
+
+/// Settings that can appear in a bridge.<name>.<setting> key
+static BRIDGE_SETTINGS: [&str; 8] = [
+    "address",
+    "clientId",
+    "username",
+    "password",
+    "keepAlive",
+    "reconnectDelay",
+    "maxReconnectDelay",
+    "topics",
+];
+
+/// Settings that can appear in a schedule.<name>.<setting> key
+static SCHEDULE_SETTINGS: [&str; 5] = ["cron", "topic", "payload", "qos", "retain"];
+
+/// Settings that can appear in a user.<username>.<setting> key, overriding the global limits
+static USER_SETTINGS: [&str; 3] = ["maxPublishRate", "maxByteRate", "maxSubscriptions"];
+
+/// Settings that can appear in a tenant.<name>.<setting> key
+static TENANT_SETTINGS: [&str; 1] = ["users"];
+
+/// Placeholder of a mount point replaced by the username of the client
+const MOUNT_POINT_USERNAME: &str = "%u";
+
+/// The highest QoS the broker delivers messages with, it does not implement the QoS 2 flow
+pub const MAX_SUPPORTED_QOS: u8 = 1;
+
 /// This struct represents a config error
-#[derive(Debug, Clone)]
-pub struct ConfigError;
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigError {
+    /// The line of the config file that caused the error, if any
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl ConfigError {
+    fn new(message: &str) -> ConfigError {
+        ConfigError {
+            line: None,
+            message: message.to_string(),
+        }
+    }
+
+    fn at(line: usize, message: &str) -> ConfigError {
+        ConfigError {
+            line: Some(line),
+            message: message.to_string(),
+        }
+    }
+}
 
-/// This struct represents a thread pool
 impl fmt::Display for ConfigError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Error creating config")
+        match self.line {
+            Some(line) => write!(f, "Config error at line {}: {}", line, self.message),
+            None => write!(f, "Config error: {}", self.message),
+        }
     }
 }
 
+/// This enum represents the client certificate field used as MQTT username
+#[derive(Debug, Clone, PartialEq)]
+pub enum CertificateUsername {
+    CommonName,
+    SubjectAltName,
+}
+
+/// This struct represents the TLS settings of a listener
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlsSettings {
+    pub cert_file: String,
+    pub key_file: String,
+    pub client_ca_file: Option<String>,
+    pub require_client_cert: bool,
+    pub username_field: CertificateUsername,
+}
+
+/// This enum represents the protocol spoken by a listener
+#[derive(Debug, Clone, PartialEq)]
+pub enum ListenerProtocol {
+    Tcp,
+    Tls,
+    Ws,
+}
+
+/// This enum represents the address a listener binds to
+#[derive(Debug, Clone, PartialEq)]
+pub enum BindAddress {
+    /// An IPv4 or IPv6 socket address, such as 0.0.0.0:1883 or [::1]:1883
+    Inet(String),
+    /// The path of a Unix domain socket, written as unix:/path/to/socket
+    Unix(String),
+}
+
+/// This struct represents the settings of a listener
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListenerSettings {
+    pub name: String,
+    pub bind: BindAddress,
+    pub protocol: ListenerProtocol,
+    /// Maximum amount of simultaneous connections, None means unlimited
+    pub max_connections: Option<usize>,
+    /// Whether clients must present valid credentials to connect
+    pub require_auth: bool,
+    /// Highest QoS granted to the subscriptions of its clients, requests for more are downgraded
+    pub max_qos: u8,
+    pub tls: Option<TlsSettings>,
+}
+
+impl ListenerSettings {
+    /// Returns the settings of a plain TCP listener that requires authentication
+    /// # Arguments
+    ///
+    /// * `name` - The name of the listener, used in the logs and the metrics
+    /// * `address` - The address to bind, port 0 picks a free port
+    ///
+    pub fn tcp(name: &str, address: &str) -> ListenerSettings {
+        ListenerSettings {
+            name: name.to_string(),
+            bind: BindAddress::Inet(address.to_string()),
+            protocol: ListenerProtocol::Tcp,
+            max_connections: None,
+            require_auth: true,
+            max_qos: MAX_SUPPORTED_QOS,
+            tls: None,
+        }
+    }
+
+    /// Returns the QoS granted to a subscription of one of its clients, None for the QoS
+    /// values MQTT 3.1.1 reserves
+    /// # Arguments
+    ///
+    /// * `requested_qos` - The QoS requested by the client
+    ///
+    pub fn granted_qos(&self, requested_qos: u8) -> Option<u8> {
+        match requested_qos {
+            0..=2 => Some(requested_qos.min(self.max_qos)),
+            _ => None,
+        }
+    }
+}
+
+/// This enum represents how often the log file is rotated
+#[derive(Debug, Clone, PartialEq)]
+pub enum LogRotation {
+    Hourly,
+    Daily,
+    Never,
+}
+
+/// This enum represents where the broker writes its logs
+#[derive(Debug, Clone, PartialEq)]
+pub enum LogOutput {
+    Stdout,
+    File {
+        directory: String,
+        /// The name of the log file, rotated files get the date appended
+        prefix: String,
+        rotation: LogRotation,
+        /// Maximum amount of log files kept, None means all of them
+        retention: Option<usize>,
+    },
+}
+
+/// This enum represents the format of each log line
+#[derive(Debug, Clone, PartialEq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+/// This struct represents the logging settings
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogSettings {
+    pub output: LogOutput,
+    pub format: LogFormat,
+    /// Level directives, such as info or warn,server::transport=debug
+    pub level: String,
+}
+
+/// This struct represents the settings of the admin HTTP API
+#[derive(Debug, Clone, PartialEq)]
+pub struct AdminSettings {
+    pub bind: String,
+    /// The token every request must send as Authorization: Bearer <token>
+    pub token: String,
+}
+
+/// This enum represents what happens to a client that goes over one of its limits
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LimitPolicy {
+    /// Publishes wait until the client is back under its rate
+    Throttle,
+    /// Publishes over the rate are not delivered, subscriptions over the limit are refused
+    Drop,
+    /// The client is disconnected
+    Disconnect,
+}
+
+/// This enum represents how a member of a shared subscription group is chosen for each message
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SharePolicy {
+    /// Members take turns
+    RoundRobin,
+    /// The member with the fewest unacknowledged messages, taking turns on ties
+    LeastInflight,
+}
+
+/// This enum represents what happens to a client whose outbound queue is full
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SlowConsumerPolicy {
+    /// The oldest queued message is dropped to make room for the new one
+    DropOldest,
+    /// The client is disconnected
+    Disconnect,
+}
+
+/// This struct represents the queue of packets waiting to be written to each connection
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OutboundSettings {
+    /// Messages a connection can have queued before the policy applies
+    pub queue_size: usize,
+    pub policy: SlowConsumerPolicy,
+}
+
+/// This struct represents how long messages wait for their subscribers, counted from the moment
+/// the broker receives them. It applies to unacknowledged messages and to retained messages.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExpirySettings {
+    /// The expiry of messages whose topic matches no pattern, None keeps them until delivered
+    pub default: Option<Duration>,
+    /// Topic filters and the expiry of the messages published on them, the first match wins
+    pub topics: Vec<(String, Option<Duration>)>,
+}
+
+impl ExpirySettings {
+    /// Returns how long the messages of a topic are kept, None if they never expire
+    /// # Arguments
+    ///
+    /// * `topic` - A string slice containing the topic of the message
+    ///
+    pub fn expiry_for(&self, topic: &str) -> Option<Duration> {
+        match self
+            .topics
+            .iter()
+            .find(|(filter, _)| topic_matches(filter, topic))
+        {
+            Some((_, expiry)) => *expiry,
+            None => self.default,
+        }
+    }
+
+    /// Returns whether a message received at some moment has expired
+    /// # Arguments
+    ///
+    /// * `topic` - A string slice containing the topic of the message
+    /// * `received_at` - When the broker received the message
+    /// * `now` - The current time
+    ///
+    pub fn is_expired(&self, topic: &str, received_at: Instant, now: Instant) -> bool {
+        match self.expiry_for(topic) {
+            Some(expiry) => now.saturating_duration_since(received_at) >= expiry,
+            None => false,
+        }
+    }
+}
+
+/// This struct represents the limits of a client, None means unlimited
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RateLimits {
+    /// PUBLISH packets per second
+    pub publish_rate: Option<u32>,
+    /// Payload bytes per second
+    pub byte_rate: Option<u32>,
+    /// Topics a client can be subscribed to at the same time
+    pub max_subscriptions: Option<usize>,
+}
+
+/// This struct represents the limits of every client and the ones that apply to some users
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimitSettings {
+    pub policy: LimitPolicy,
+    /// The limits of clients whose user has no limits of its own
+    pub default: RateLimits,
+    /// The limits of each user, settings a user leaves out are taken from the default
+    pub users: HashMap<String, RateLimits>,
+}
+
+impl RateLimitSettings {
+    /// Returns the limits of the clients that connect as a user
+    /// # Arguments
+    ///
+    /// * `username` - The username of the client, empty for anonymous clients
+    ///
+    pub fn limits_for(&self, username: &str) -> &RateLimits {
+        self.users.get(username).unwrap_or(&self.default)
+    }
+}
+
+/// This struct represents the tenants whose tenant/<name>/ namespace the topics of the clients
+/// are mounted in
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TenancySettings {
+    /// Tenant of the users that belong to none, %u stands for the username. None lets them
+    /// reach every topic.
+    pub mount_point: Option<String>,
+    /// The tenant of each user listed in a tenant.<name>.users setting
+    pub users: HashMap<String, String>,
+}
+
+impl TenancySettings {
+    /// Returns the namespace the topics of a user are mounted in. Returns Err for anonymous
+    /// clients while there are tenants, and when the mount point needs a username the client
+    /// does not have or cannot be named after.
+    /// # Arguments
+    ///
+    /// * `username` - The verified username of the client, empty for anonymous clients
+    ///
+    pub fn mount_point_for(&self, username: &str) -> Result<Option<MountPoint>, String> {
+        // Without a tenant, an anonymous client could reach the topics of every tenant
+        if username.is_empty() && !self.users.is_empty() {
+            return Err("Anonymous clients are refused while there are tenants".to_string());
+        }
+        // Tenant names were checked when the config was read
+        if let Some(tenant) = self.users.get(username) {
+            return Ok(MountPoint::new(tenant));
+        }
+        let mount_point = match &self.mount_point {
+            Some(mount_point) => mount_point,
+            None => return Ok(None),
+        };
+        let tenant = mount_point.replace(MOUNT_POINT_USERNAME, username);
+        match MountPoint::new(&tenant) {
+            Some(mount_point) => Ok(Some(mount_point)),
+            None => Err(format!(
+                "The username {:?} is not a valid tenant for the mount point {:?}",
+                username, mount_point
+            )),
+        }
+    }
+}
+
+/// This enum represents the way messages of a bridged topic travel
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BridgeDirection {
+    /// From the remote broker to this one
+    In,
+    /// From this broker to the remote one
+    Out,
+    Both,
+}
+
+/// This struct represents a topic pattern mirrored by a bridge
+#[derive(Debug, Clone, PartialEq)]
+pub struct BridgeTopic {
+    /// The topic filter, without the prefixes
+    pub pattern: String,
+    pub direction: BridgeDirection,
+    /// The QoS of the subscriptions and publishes made by the bridge
+    pub qos: u8,
+    /// Prefix of the topics on this broker, replaced by the remote prefix when forwarding out
+    pub local_prefix: String,
+    /// Prefix of the topics on the remote broker, replaced by the local prefix when forwarding in
+    pub remote_prefix: String,
+}
+
+/// This struct represents a connection to a remote broker that mirrors some topics
+#[derive(Debug, Clone, PartialEq)]
+pub struct BridgeSettings {
+    pub name: String,
+    /// The host:port of the remote broker
+    pub address: String,
+    pub client_id: String,
+    pub username: String,
+    pub password: String,
+    pub keep_alive: Duration,
+    /// Time to wait before the first reconnection, doubled after each failed attempt
+    pub reconnect_delay: Duration,
+    pub max_reconnect_delay: Duration,
+    pub topics: Vec<BridgeTopic>,
+}
+
+/// This struct represents a message the broker publishes on a schedule
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScheduleSettings {
+    pub name: String,
+    /// The minutes the message is published on, in UTC
+    pub cron: CronSchedule,
+    pub topic: String,
+    pub payload: String,
+    pub qos: u8,
+    pub retain: bool,
+}
+
+impl ScheduleSettings {
+    /// Returns the message published on each minute of the schedule
+    pub fn to_publish_packet(&self) -> Publish {
+        Publish {
+            topic_name: self.topic.to_owned(),
+            payload: self.payload.to_owned(),
+            packet_id: 0,
+            qos: self.qos,
+            retain_flag: self.retain as u8,
+            dup_flag: 0,
+        }
+    }
+}
+
+/// This struct represents a value read from the config file and the line it was found on
+#[derive(Debug, Clone)]
+struct ConfigEntry {
+    value: String,
+    line: usize,
+}
+
+type ConfigEntries = HashMap<String, ConfigEntry>;
+
+/// This struct represents the broker configuration
+#[derive(Debug, Clone)]
 pub struct Config {
-    pub port: String,
-    pub log_file: String,
+    pub log: LogSettings,
+    pub listeners: Vec<ListenerSettings>,
+    /// Amount of threads that handle client packets
+    pub pool_size: usize,
+    /// File with the username,password pairs allowed to connect
+    pub credentials_file: String,
+    /// Time between reloads of the credentials file
+    pub credentials_reload_interval: Duration,
+    /// Time between checks of the active sockets for new packets
+    pub request_poll_interval: Duration,
+    /// Time to wait for data on each socket while polling
+    pub socket_read_timeout: Duration,
+    /// Time a new connection has to send its CONNECT before it is closed
+    pub connect_timeout: Duration,
+    /// Time between resends of unacknowledged messages
+    pub retry_interval: Duration,
+    /// Time between publishes of the $SYS topics, zero disables them
+    pub sys_interval: Duration,
+    /// Address of the HTTP listener that exposes the Prometheus metrics, none disables it
+    pub metrics_bind: Option<String>,
+    /// Settings of the admin HTTP API, none disables it
+    pub admin: Option<AdminSettings>,
+    pub rate_limits: RateLimitSettings,
+    pub tenancy: TenancySettings,
+    pub share_policy: SharePolicy,
+    pub outbound: OutboundSettings,
+    pub expiry: ExpirySettings,
+    /// File where the delayed messages are kept across restarts, none keeps them in memory
+    pub delayed_messages_file: Option<String>,
+    pub schedules: Vec<ScheduleSettings>,
+    pub bridges: Vec<BridgeSettings>,
+    /// Names of the built-in hooks to register, in the order they are called
+    pub hooks: Vec<String>,
+}
+
+impl Default for Config {
+    /// Returns the configuration of an empty config file, which has no listeners
+    fn default() -> Config {
+        Config::from_entries(&ConfigEntries::new()).expect("the default settings are valid")
+    }
 }
 
 impl Config {
+    /// Returns the configuration read from a file
+    /// # Arguments
+    ///
+    /// * `filename` - The path of the config file
+    ///
     pub fn from_file(filename: &str) -> Result<Self, ConfigError> {
-        let file = File::open(filename).expect("Failed to read config file");
-        let reader = BufReader::new(file);
-        let config_entries = Config::read_entries(reader);
-        let port = config_entries.get("port").expect("No port was provided");
-        let log_file = config_entries
-            .get("logFile")
-            .expect("No logFile was provided");
+        let file = File::open(filename).map_err(|e| {
+            ConfigError::new(&format!("Could not read config file {}: {}", filename, e))
+        })?;
+        Config::from_reader(BufReader::new(file))
+    }
+
+    /// Returns the configuration read from any source of key=value lines
+    /// # Arguments
+    ///
+    /// * `reader` - The source of the config lines
+    ///
+    pub fn from_reader<R: BufRead>(reader: R) -> Result<Self, ConfigError> {
+        let entries = Config::read_entries(reader)?;
+        Config::check_settings(&entries)?;
+        let config = Config::from_entries(&entries)?;
+        config.check_listeners()?;
+        Ok(config)
+    }
+
+    /// Returns an error when no listener was configured
+    pub fn check_listeners(&self) -> Result<(), ConfigError> {
+        if self.listeners.is_empty() {
+            return Err(ConfigError::new(
+                "No listener was configured, set port or a listener.<name>.bind",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns the configuration of the given entries, using the default of each missing setting
+    fn from_entries(entries: &ConfigEntries) -> Result<Self, ConfigError> {
+        let mut listeners = Config::read_legacy_listeners(entries)?;
+        listeners.extend(Config::read_listeners(entries)?);
+
+        let pool_size = parse_value(entries, "poolSize", 4)?;
+        if pool_size == 0 {
+            let line = entries["poolSize"].line;
+            return Err(ConfigError::at(line, "poolSize must be greater than 0"));
+        }
+
+        let metrics_bind = match entries.get("metricsBind") {
+            Some(entry) => {
+                check_socket_address(entry)?;
+                Some(entry.value.clone())
+            }
+            None => None,
+        };
+
         Ok(Config {
-            port: port.to_string(),
-            log_file: log_file.to_string(),
+            log: Config::read_log_settings(entries)?,
+            listeners,
+            pool_size,
+            credentials_file: parse_value(
+                entries,
+                "credentialsFile",
+                "credentials.txt".to_string(),
+            )?,
+            credentials_reload_interval: parse_interval(
+                entries,
+                "credentialsReloadInterval",
+                Duration::from_secs(30),
+            )?,
+            request_poll_interval: parse_interval(
+                entries,
+                "requestPollInterval",
+                Duration::from_secs(1),
+            )?,
+            socket_read_timeout: parse_interval(
+                entries,
+                "socketReadTimeout",
+                Duration::from_millis(100),
+            )?,
+            connect_timeout: parse_interval(entries, "connectTimeout", Duration::from_secs(10))?,
+            retry_interval: parse_interval(entries, "retryInterval", Duration::from_secs(20))?,
+            sys_interval: parse_duration(entries, "sysInterval", Duration::from_secs(10))?,
+            metrics_bind,
+            admin: Config::read_admin_settings(entries)?,
+            rate_limits: Config::read_rate_limits(entries)?,
+            tenancy: Config::read_tenancy(entries)?,
+            share_policy: parse_choice(
+                entries,
+                "sharedSubscriptionPolicy",
+                &[
+                    ("round_robin", SharePolicy::RoundRobin),
+                    ("least_inflight", SharePolicy::LeastInflight),
+                ],
+                SharePolicy::RoundRobin,
+            )?,
+            outbound: Config::read_outbound_settings(entries)?,
+            expiry: Config::read_expiry_settings(entries)?,
+            delayed_messages_file: entries
+                .get("delayedMessagesFile")
+                .map(|entry| entry.value.to_string()),
+            schedules: Config::read_schedules(entries)?,
+            bridges: Config::read_bridges(entries)?,
+            hooks: Config::read_hooks(entries)?,
+        })
+    }
+
+    /// Reads the size of the outbound queues and what happens when one is full
+    fn read_outbound_settings(entries: &ConfigEntries) -> Result<OutboundSettings, ConfigError> {
+        let queue_size = parse_value(entries, "outboundQueueSize", 1000)?;
+        if queue_size == 0 {
+            let line = entries["outboundQueueSize"].line;
+            return Err(ConfigError::at(
+                line,
+                "outboundQueueSize must be greater than 0",
+            ));
+        }
+        Ok(OutboundSettings {
+            queue_size,
+            policy: parse_choice(
+                entries,
+                "slowConsumerPolicy",
+                &[
+                    ("drop_oldest", SlowConsumerPolicy::DropOldest),
+                    ("disconnect", SlowConsumerPolicy::Disconnect),
+                ],
+                SlowConsumerPolicy::DropOldest,
+            )?,
+        })
+    }
+
+    /// Reads the default expiry of messages and the comma separated <filter> <duration> pairs
+    /// of topicExpiry. A zero duration means the messages never expire.
+    fn read_expiry_settings(entries: &ConfigEntries) -> Result<ExpirySettings, ConfigError> {
+        let never_if_zero = |expiry: Duration| Some(expiry).filter(|expiry| !expiry.is_zero());
+        let default = never_if_zero(parse_duration(entries, "messageExpiry", Duration::ZERO)?);
+
+        let mut topics = Vec::new();
+        if let Some(entry) = entries.get("topicExpiry") {
+            for pair in entry.value.split(',').map(str::trim) {
+                let expiry = match pair.split_whitespace().collect::<Vec<&str>>()[..] {
+                    [filter, expiry] => duration_from_str(expiry).map(|e| (filter, e)),
+                    _ => None,
+                };
+                match expiry {
+                    Some((filter, expiry)) => {
+                        topics.push((filter.to_string(), never_if_zero(expiry)))
+                    }
+                    None => {
+                        return Err(ConfigError::at(
+                            entry.line,
+                            &format!(
+                                "Invalid topic expiry {:?}, expected <filter> <duration>",
+                                pair
+                            ),
+                        ))
+                    }
+                }
+            }
+        }
+
+        Ok(ExpirySettings { default, topics })
+    }
+
+    /// Reads the global limits and the user.<username>.<setting> overrides
+    fn read_rate_limits(entries: &ConfigEntries) -> Result<RateLimitSettings, ConfigError> {
+        let policy = parse_choice(
+            entries,
+            "rateLimitPolicy",
+            &[
+                ("throttle", LimitPolicy::Throttle),
+                ("drop", LimitPolicy::Drop),
+                ("disconnect", LimitPolicy::Disconnect),
+            ],
+            LimitPolicy::Drop,
+        )?;
+        let default = Config::read_limits(entries, "", &RateLimits::default())?;
+
+        let mut usernames: Vec<&str> = entries
+            .keys()
+            .filter_map(|key| key.strip_prefix("user."))
+            .filter_map(|key| key.rsplit_once('.'))
+            .map(|(username, _)| username)
+            .collect();
+        usernames.sort_unstable();
+        usernames.dedup();
+
+        let mut users = HashMap::new();
+        for username in usernames {
+            let prefix = format!("user.{}.", username);
+            let limits = Config::read_limits(entries, &prefix, &default)?;
+            users.insert(username.to_string(), limits);
+        }
+
+        Ok(RateLimitSettings {
+            policy,
+            default,
+            users,
         })
     }
 
-    fn read_entries(reader: BufReader<File>) -> HashMap<String, String> {
-        let mut entries: HashMap<String, String> = HashMap::new();
-        for (_, read_line) in reader.lines().enumerate() {
-            if let Ok(line) = read_line {
-                let split_line: Vec<&str> = line.split('=').collect();
-                let key = split_line[0];
-                let value = split_line[1];
-                entries.insert(key.to_string(), value.to_string());
+    /// Reads the limit settings that start with a prefix, taking the missing ones from a default
+    fn read_limits(
+        entries: &ConfigEntries,
+        prefix: &str,
+        default: &RateLimits,
+    ) -> Result<RateLimits, ConfigError> {
+        let key = |setting: &str| prefix.to_owned() + setting;
+        Ok(RateLimits {
+            publish_rate: parse_limit(entries, &key("maxPublishRate"), default.publish_rate)?,
+            byte_rate: parse_limit(entries, &key("maxByteRate"), default.byte_rate)?,
+            max_subscriptions: parse_limit(
+                entries,
+                &key("maxSubscriptions"),
+                default.max_subscriptions,
+            )?,
+        })
+    }
+
+    /// Reads mountPoint and the tenant.<name>.users lists, a user can belong to one tenant
+    fn read_tenancy(entries: &ConfigEntries) -> Result<TenancySettings, ConfigError> {
+        let mount_point = match entries.get("mountPoint") {
+            Some(entry) => {
+                let tenant = entry.value.replace(MOUNT_POINT_USERNAME, "user");
+                if MountPoint::new(&tenant).is_none() {
+                    return Err(ConfigError::at(
+                        entry.line,
+                        &format!(
+                            "Invalid mount point {:?}, expected a tenant name without /, + or #",
+                            entry.value
+                        ),
+                    ));
+                }
+                Some(entry.value.to_string())
+            }
+            None => None,
+        };
+
+        let mut tenants: Vec<(&str, &ConfigEntry)> = entries
+            .iter()
+            .filter_map(|(key, entry)| {
+                let tenant = key.strip_prefix("tenant.")?.strip_suffix(".users")?;
+                Some((tenant, entry))
+            })
+            .collect();
+        tenants.sort_by_key(|(_, entry)| entry.line);
+
+        let mut users = HashMap::new();
+        for (tenant, entry) in tenants {
+            if MountPoint::new(tenant).is_none() {
+                return Err(ConfigError::at(
+                    entry.line,
+                    &format!(
+                        "Invalid tenant name {:?}, expected a name without /, + or #",
+                        tenant
+                    ),
+                ));
+            }
+            for username in entry.value.split(',').map(str::trim) {
+                if let Some(other) = users.insert(username.to_string(), tenant.to_string()) {
+                    return Err(ConfigError::at(
+                        entry.line,
+                        &format!(
+                            "User {:?} of tenant {} already belongs to tenant {}",
+                            username, tenant, other
+                        ),
+                    ));
+                }
             }
         }
-        entries
+
+        Ok(TenancySettings { mount_point, users })
+    }
+
+    /// Reads the comma separated names of the hooks setting
+    fn read_hooks(entries: &ConfigEntries) -> Result<Vec<String>, ConfigError> {
+        let entry = match entries.get("hooks") {
+            Some(entry) => entry,
+            None => return Ok(Vec::new()),
+        };
+        let mut hooks = Vec::new();
+        for name in entry.value.split(',').map(str::trim) {
+            if !BUILTIN_HOOKS.contains(&name) {
+                return Err(ConfigError::at(
+                    entry.line,
+                    &format!(
+                        "Unknown hook {:?}, expected one of {}",
+                        name,
+                        BUILTIN_HOOKS.join(", ")
+                    ),
+                ));
+            }
+            hooks.push(name.to_string());
+        }
+        Ok(hooks)
+    }
+
+    /// Reads adminBind and adminToken, the API is only started with both of them
+    fn read_admin_settings(entries: &ConfigEntries) -> Result<Option<AdminSettings>, ConfigError> {
+        let bind = match entries.get("adminBind") {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        check_socket_address(bind)?;
+        match entries.get("adminToken") {
+            Some(token) if !token.value.is_empty() => Ok(Some(AdminSettings {
+                bind: bind.value.to_string(),
+                token: token.value.to_string(),
+            })),
+            Some(token) => Err(ConfigError::at(token.line, "adminToken can not be empty")),
+            None => Err(ConfigError::at(
+                bind.line,
+                "adminBind requires an adminToken",
+            )),
+        }
+    }
+
+    /// Reads the logFile and the other log settings. A logFile of stdout disables the file.
+    fn read_log_settings(entries: &ConfigEntries) -> Result<LogSettings, ConfigError> {
+        let log_file = parse_value(entries, "logFile", "stdout".to_string())?;
+        let output = if log_file == "stdout" {
+            LogOutput::Stdout
+        } else {
+            let rotation = parse_choice(
+                entries,
+                "logRotation",
+                &[
+                    ("hourly", LogRotation::Hourly),
+                    ("daily", LogRotation::Daily),
+                    ("never", LogRotation::Never),
+                ],
+                LogRotation::Daily,
+            )?;
+            let retention = match entries.get("logRetention") {
+                Some(entry) => match parse_value(entries, "logRetention", 0)? {
+                    0 => {
+                        return Err(ConfigError::at(
+                            entry.line,
+                            "logRetention must be greater than 0",
+                        ))
+                    }
+                    files => Some(files),
+                },
+                None => None,
+            };
+            LogOutput::File {
+                directory: parse_value(entries, "logDirectory", "logs".to_string())?,
+                prefix: log_file,
+                rotation,
+                retention,
+            }
+        };
+        let format = parse_choice(
+            entries,
+            "logFormat",
+            &[("text", LogFormat::Text), ("json", LogFormat::Json)],
+            LogFormat::Text,
+        )?;
+        let level = parse_value(entries, "logLevel", "info".to_string())?;
+        if let Err(e) = EnvFilter::try_new(&level) {
+            let line = entries["logLevel"].line;
+            return Err(ConfigError::at(
+                line,
+                &format!("Invalid logLevel {:?}: {}", level, e),
+            ));
+        }
+
+        Ok(LogSettings {
+            output,
+            format,
+            level,
+        })
+    }
+
+    /// Fails on the first setting, in file order, that the broker does not know
+    fn check_settings(entries: &ConfigEntries) -> Result<(), ConfigError> {
+        let mut keys: Vec<(&String, &ConfigEntry)> = entries.iter().collect();
+        keys.sort_by_key(|(_, entry)| entry.line);
+        for (key, entry) in keys {
+            let known = if let Some(listener_key) = key.strip_prefix("listener.") {
+                match listener_key.split_once('.') {
+                    Some((name, setting)) => {
+                        !name.is_empty() && LISTENER_SETTINGS.contains(&setting)
+                    }
+                    None => false,
+                }
+            } else if let Some(bridge_key) = key.strip_prefix("bridge.") {
+                match bridge_key.split_once('.') {
+                    Some((name, setting)) => !name.is_empty() && BRIDGE_SETTINGS.contains(&setting),
+                    None => false,
+                }
+            } else if let Some(schedule_key) = key.strip_prefix("schedule.") {
+                match schedule_key.split_once('.') {
+                    Some((name, setting)) => {
+                        !name.is_empty() && SCHEDULE_SETTINGS.contains(&setting)
+                    }
+                    None => false,
+                }
+            } else if let Some(tenant_key) = key.strip_prefix("tenant.") {
+                match tenant_key.split_once('.') {
+                    Some((name, setting)) => !name.is_empty() && TENANT_SETTINGS.contains(&setting),
+                    None => false,
+                }
+            } else if let Some(user_key) = key.strip_prefix("user.") {
+                // Usernames may contain dots, so the setting is what follows the last one
+                match user_key.rsplit_once('.') {
+                    Some((username, setting)) => {
+                        !username.is_empty() && USER_SETTINGS.contains(&setting)
+                    }
+                    None => false,
+                }
+            } else {
+                GLOBAL_SETTINGS.contains(&key.as_str())
+            };
+            if !known {
+                return Err(ConfigError::at(
+                    entry.line,
+                    &format!("Unknown setting {}", key),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the listeners declared with the port, tlsPort and wsPort keys. Their names cannot
+    /// be taken by a listener.<name> block too.
+    fn read_legacy_listeners(
+        entries: &ConfigEntries,
+    ) -> Result<Vec<ListenerSettings>, ConfigError> {
+        let mut listeners = Vec::new();
+        let max_qos = parse_max_qos(entries, "maxQos", MAX_SUPPORTED_QOS)?;
+        let ports = [
+            ("default", "port", ListenerProtocol::Tcp),
+            ("tls", "tlsPort", ListenerProtocol::Tls),
+            ("ws", "wsPort", ListenerProtocol::Ws),
+        ];
+        for (name, key, protocol) in ports.iter() {
+            if let Some(entry) = entries.get(*key) {
+                let prefix = format!("listener.{}.", name);
+                if let Some(clash) = entries
+                    .iter()
+                    .filter(|(key, _)| key.starts_with(&prefix))
+                    .map(|(_, entry)| entry.line)
+                    .min()
+                {
+                    return Err(ConfigError::at(
+                        clash,
+                        &format!(
+                            "Listener {} is already declared by {} on line {}",
+                            name, key, entry.line
+                        ),
+                    ));
+                }
+                let port: u16 = parse_value(entries, key, 0)?;
+                let tls = match protocol {
+                    ListenerProtocol::Tls => {
+                        Some(Config::read_tls_settings(entries, "tls", entry.line)?)
+                    }
+                    _ => None,
+                };
+                listeners.push(ListenerSettings {
+                    name: name.to_string(),
+                    bind: BindAddress::Inet(format!("0.0.0.0:{}", port)),
+                    protocol: protocol.clone(),
+                    max_connections: None,
+                    require_auth: true,
+                    max_qos,
+                    tls,
+                });
+            }
+        }
+        Ok(listeners)
+    }
+
+    /// Reads the listeners declared with listener.<name>.<setting> keys
+    fn read_listeners(entries: &ConfigEntries) -> Result<Vec<ListenerSettings>, ConfigError> {
+        let mut names: Vec<&str> = entries
+            .keys()
+            .filter_map(|key| key.strip_prefix("listener."))
+            .filter_map(|key| key.split('.').next())
+            .collect();
+        names.sort_unstable();
+        names.dedup();
+
+        let default_max_qos = parse_max_qos(entries, "maxQos", MAX_SUPPORTED_QOS)?;
+        let mut listeners = Vec::new();
+        for name in names {
+            let prefix = format!("listener.{}.", name);
+            let key = |setting: &str| prefix.to_owned() + setting;
+            // Errors about the listener as a whole point to its first line
+            let first_line = entries
+                .iter()
+                .filter(|(key, _)| key.starts_with(&prefix))
+                .map(|(_, entry)| entry.line)
+                .min()
+                .unwrap_or_default();
+
+            let bind = match entries.get(&key("bind")) {
+                Some(entry) => match entry.value.strip_prefix("unix:") {
+                    Some(path) => BindAddress::Unix(path.to_string()),
+                    None => {
+                        check_socket_address(entry)?;
+                        BindAddress::Inet(entry.value.to_string())
+                    }
+                },
+                None => {
+                    return Err(ConfigError::at(
+                        first_line,
+                        &format!("Listener {} has no {}", name, key("bind")),
+                    ))
+                }
+            };
+            let protocol = parse_choice(
+                entries,
+                &key("protocol"),
+                &[
+                    ("tcp", ListenerProtocol::Tcp),
+                    ("tls", ListenerProtocol::Tls),
+                    ("ws", ListenerProtocol::Ws),
+                ],
+                ListenerProtocol::Tcp,
+            )?;
+            if matches!(bind, BindAddress::Unix(_)) && protocol != ListenerProtocol::Tcp {
+                let line = entries[&key("protocol")].line;
+                return Err(ConfigError::at(
+                    line,
+                    "Unix socket listeners only support the tcp protocol",
+                ));
+            }
+            let max_connections = match entries.get(&key("maxConnections")) {
+                Some(entry) => match parse_value(entries, &key("maxConnections"), 0)? {
+                    0 => {
+                        return Err(ConfigError::at(
+                            entry.line,
+                            "maxConnections must be greater than 0",
+                        ))
+                    }
+                    max => Some(max),
+                },
+                None => None,
+            };
+            let require_auth = parse_bool(entries, &key("requireAuth"), true)?;
+            let max_qos = parse_max_qos(entries, &key("maxQos"), default_max_qos)?;
+            let tls = match protocol {
+                ListenerProtocol::Tls => {
+                    Some(Config::read_tls_settings(entries, &prefix, first_line)?)
+                }
+                _ => None,
+            };
+
+            listeners.push(ListenerSettings {
+                name: name.to_string(),
+                bind,
+                protocol,
+                max_connections,
+                require_auth,
+                max_qos,
+                tls,
+            });
+        }
+        Ok(listeners)
+    }
+
+    /// Reads the bridges declared with bridge.<name>.<setting> keys
+    fn read_bridges(entries: &ConfigEntries) -> Result<Vec<BridgeSettings>, ConfigError> {
+        let mut names: Vec<&str> = entries
+            .keys()
+            .filter_map(|key| key.strip_prefix("bridge."))
+            .filter_map(|key| key.split('.').next())
+            .collect();
+        names.sort_unstable();
+        names.dedup();
+
+        let mut bridges = Vec::new();
+        for name in names {
+            let prefix = format!("bridge.{}.", name);
+            let key = |setting: &str| prefix.to_owned() + setting;
+            let first_line = entries
+                .iter()
+                .filter(|(key, _)| key.starts_with(&prefix))
+                .map(|(_, entry)| entry.line)
+                .min()
+                .unwrap_or_default();
+            let required = |setting: &str| match entries.get(&key(setting)) {
+                Some(entry) => Ok(entry),
+                None => Err(ConfigError::at(
+                    first_line,
+                    &format!("Bridge {} has no {}", name, key(setting)),
+                )),
+            };
+
+            let address = required("address")?;
+            check_socket_address(address)?;
+            let topics = required("topics")?;
+
+            let keep_alive = parse_duration(entries, &key("keepAlive"), Duration::from_secs(60))?;
+            if keep_alive.as_secs() > u16::MAX as u64 {
+                let line = entries[&key("keepAlive")].line;
+                return Err(ConfigError::at(
+                    line,
+                    &format!("{} can not be longer than {}s", key("keepAlive"), u16::MAX),
+                ));
+            }
+
+            bridges.push(BridgeSettings {
+                name: name.to_string(),
+                address: address.value.to_string(),
+                client_id: parse_value(entries, &key("clientId"), format!("bridge-{}", name))?,
+                username: parse_value(entries, &key("username"), String::new())?,
+                password: parse_value(entries, &key("password"), String::new())?,
+                keep_alive,
+                reconnect_delay: parse_interval(
+                    entries,
+                    &key("reconnectDelay"),
+                    Duration::from_secs(1),
+                )?,
+                max_reconnect_delay: parse_interval(
+                    entries,
+                    &key("maxReconnectDelay"),
+                    Duration::from_secs(60),
+                )?,
+                topics: parse_bridge_topics(topics)?,
+            });
+        }
+        Ok(bridges)
+    }
+
+    /// Reads the messages published on a schedule, declared with schedule.<name>.<setting> keys
+    fn read_schedules(entries: &ConfigEntries) -> Result<Vec<ScheduleSettings>, ConfigError> {
+        let mut names: Vec<&str> = entries
+            .keys()
+            .filter_map(|key| key.strip_prefix("schedule."))
+            .filter_map(|key| key.split('.').next())
+            .collect();
+        names.sort_unstable();
+        names.dedup();
+
+        let mut schedules = Vec::new();
+        for name in names {
+            let prefix = format!("schedule.{}.", name);
+            let key = |setting: &str| prefix.to_owned() + setting;
+            let first_line = entries
+                .iter()
+                .filter(|(key, _)| key.starts_with(&prefix))
+                .map(|(_, entry)| entry.line)
+                .min()
+                .unwrap_or_default();
+            let required = |setting: &str| match entries.get(&key(setting)) {
+                Some(entry) => Ok(entry),
+                None => Err(ConfigError::at(
+                    first_line,
+                    &format!("Schedule {} has no {}", name, key(setting)),
+                )),
+            };
+
+            let cron_entry = required("cron")?;
+            let cron = cron_entry
+                .value
+                .parse::<CronSchedule>()
+                .map_err(|e| ConfigError::at(cron_entry.line, &e))?;
+            let topic = required("topic")?;
+            if topic.value.is_empty() || topic.value.contains(['+', '#']) {
+                return Err(ConfigError::at(
+                    topic.line,
+                    &format!("Invalid topic {:?} for {}", topic.value, key("topic")),
+                ));
+            }
+            let qos = parse_value(entries, &key("qos"), 0_u8)?;
+            if qos > MAX_SUPPORTED_QOS {
+                let line = entries[&key("qos")].line;
+                return Err(ConfigError::at(
+                    line,
+                    &format!("{} must be between 0 and {}", key("qos"), MAX_SUPPORTED_QOS),
+                ));
+            }
+
+            schedules.push(ScheduleSettings {
+                name: name.to_string(),
+                cron,
+                topic: topic.value.to_string(),
+                payload: parse_value(entries, &key("payload"), String::new())?,
+                qos,
+                retain: parse_bool(entries, &key("retain"), false)?,
+            });
+        }
+        Ok(schedules)
+    }
+
+    /// Reads TLS settings, either legacy ones (tlsCertFile) or from a listener (listener.x.certFile)
+    /// # Arguments
+    ///
+    /// * `entries` - The entries of the config file
+    /// * `prefix` - tls for legacy settings, or listener.<name>. for a listener
+    /// * `line` - The line reported when a required setting is missing
+    ///
+    fn read_tls_settings(
+        entries: &ConfigEntries,
+        prefix: &str,
+        line: usize,
+    ) -> Result<TlsSettings, ConfigError> {
+        let key = |setting: &str| {
+            if prefix.ends_with('.') {
+                prefix.to_owned() + setting
+            } else {
+                prefix.to_owned() + &setting[..1].to_uppercase() + &setting[1..]
+            }
+        };
+        let required = |setting: &str| match entries.get(&key(setting)) {
+            Some(entry) => Ok(entry.value.to_string()),
+            None => Err(ConfigError::at(
+                line,
+                &format!("TLS listener requires {}", key(setting)),
+            )),
+        };
+
+        let username_field = parse_choice(
+            entries,
+            &key("certUsername"),
+            &[
+                ("cn", CertificateUsername::CommonName),
+                ("san", CertificateUsername::SubjectAltName),
+            ],
+            CertificateUsername::CommonName,
+        )?;
+        Ok(TlsSettings {
+            cert_file: required("certFile")?,
+            key_file: required("keyFile")?,
+            client_ca_file: entries
+                .get(&key("clientCaFile"))
+                .map(|entry| entry.value.to_string()),
+            require_client_cert: parse_bool(entries, &key("requireClientCert"), false)?,
+            username_field,
+        })
+    }
+
+    /// Reads key=value lines, skipping blank lines and lines starting with #
+    fn read_entries<R: BufRead>(reader: R) -> Result<ConfigEntries, ConfigError> {
+        let mut entries = ConfigEntries::new();
+        for (index, line) in reader.lines().enumerate() {
+            let number = index + 1;
+            let line = line.map_err(|e| ConfigError::at(number, &e.to_string()))?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) if !key.trim().is_empty() => (key.trim(), value.trim()),
+                _ => {
+                    return Err(ConfigError::at(
+                        number,
+                        &format!("Expected key=value, found {:?}", line),
+                    ))
+                }
+            };
+            if let Some(previous) = entries.get(key) {
+                return Err(ConfigError::at(
+                    number,
+                    &format!("{} was already set at line {}", key, previous.line),
+                ));
+            }
+            entries.insert(
+                key.to_string(),
+                ConfigEntry {
+                    value: value.to_string(),
+                    line: number,
+                },
+            );
+        }
+        Ok(entries)
+    }
+}
+
+/// Parses a setting, or returns its default when the setting is absent
+fn parse_value<T: FromStr>(
+    entries: &ConfigEntries,
+    key: &str,
+    default: T,
+) -> Result<T, ConfigError> {
+    match entries.get(key) {
+        Some(entry) => entry.value.parse::<T>().map_err(|_| {
+            ConfigError::at(
+                entry.line,
+                &format!("Invalid value {:?} for {}", entry.value, key),
+            )
+        }),
+        None => Ok(default),
+    }
+}
+
+/// Parses a limit, which must be greater than 0 when present
+/// # Arguments
+///
+/// * `entries` - The entries of the config file
+/// * `key` - The setting to parse
+/// * `default` - The value returned when the setting is absent
+///
+fn parse_limit<T: FromStr + Default + PartialEq>(
+    entries: &ConfigEntries,
+    key: &str,
+    default: Option<T>,
+) -> Result<Option<T>, ConfigError> {
+    match entries.get(key) {
+        Some(entry) => match parse_value(entries, key, T::default())? {
+            limit if limit == T::default() => Err(ConfigError::at(
+                entry.line,
+                &format!("{} must be greater than 0", key),
+            )),
+            limit => Ok(Some(limit)),
+        },
+        None => Ok(default),
+    }
+}
+
+/// Parses a maximum QoS, which can not be higher than the QoS the broker supports
+/// # Arguments
+///
+/// * `entries` - The entries of the config file
+/// * `key` - The setting to parse
+/// * `default` - The value returned when the setting is absent
+///
+fn parse_max_qos(entries: &ConfigEntries, key: &str, default: u8) -> Result<u8, ConfigError> {
+    match entries.get(key) {
+        Some(entry) => match parse_value(entries, key, 0_u8)? {
+            qos if qos <= MAX_SUPPORTED_QOS => Ok(qos),
+            _ => Err(ConfigError::at(
+                entry.line,
+                &format!("{} must be between 0 and {}", key, MAX_SUPPORTED_QOS),
+            )),
+        },
+        None => Ok(default),
+    }
+}
+
+/// Parses a setting that takes one of a fixed set of values
+/// # Arguments
+///
+/// * `entries` - The entries of the config file
+/// * `key` - The setting to parse
+/// * `choices` - The accepted values and what each one maps to
+/// * `default` - The value returned when the setting is absent
+///
+fn parse_choice<T: Clone>(
+    entries: &ConfigEntries,
+    key: &str,
+    choices: &[(&str, T)],
+    default: T,
+) -> Result<T, ConfigError> {
+    let entry = match entries.get(key) {
+        Some(entry) => entry,
+        None => return Ok(default),
+    };
+    match choices.iter().find(|(name, _)| *name == entry.value) {
+        Some((_, value)) => Ok(value.clone()),
+        None => {
+            let names: Vec<&str> = choices.iter().map(|(name, _)| *name).collect();
+            Err(ConfigError::at(
+                entry.line,
+                &format!(
+                    "Invalid value {:?} for {}, expected one of {}",
+                    entry.value,
+                    key,
+                    names.join(", ")
+                ),
+            ))
+        }
+    }
+}
+
+/// Parses a true/false setting, or returns its default when the setting is absent
+fn parse_bool(entries: &ConfigEntries, key: &str, default: bool) -> Result<bool, ConfigError> {
+    match entries.get(key) {
+        Some(entry) => match entry.value.as_str() {
+            "true" => Ok(true),
+            "false" => Ok(false),
+            _ => Err(ConfigError::at(
+                entry.line,
+                &format!("{} must be true or false, found {:?}", key, entry.value),
+            )),
+        },
+        None => Ok(default),
+    }
+}
+
+/// Parses a duration setting such as 500ms, 30s, 5m or 1h. Plain numbers are milliseconds.
+fn parse_duration(
+    entries: &ConfigEntries,
+    key: &str,
+    default: Duration,
+) -> Result<Duration, ConfigError> {
+    let entry = match entries.get(key) {
+        Some(entry) => entry,
+        None => return Ok(default),
+    };
+    let value = entry.value.as_str();
+    match duration_from_str(value) {
+        Some(duration) => Ok(duration),
+        None => Err(ConfigError::at(
+            entry.line,
+            &format!(
+                "Invalid duration {:?} for {}, expected a number followed by ms, s, m or h",
+                value, key
+            ),
+        )),
+    }
+}
+
+/// Parses a duration that must be greater than zero, such as the period of a loop or a timeout
+/// # Arguments
+///
+/// * `entries` - The entries of the config file
+/// * `key` - The setting to parse
+/// * `default` - The value returned when the setting is absent
+///
+fn parse_interval(
+    entries: &ConfigEntries,
+    key: &str,
+    default: Duration,
+) -> Result<Duration, ConfigError> {
+    let duration = parse_duration(entries, key, default)?;
+    if duration.is_zero() {
+        return Err(ConfigError::at(
+            entries[key].line,
+            &format!("{} must be greater than 0", key),
+        ));
+    }
+    Ok(duration)
+}
+
+/// Parses a duration such as 500ms, 30s, 5m or 1h, plain numbers are milliseconds
+fn duration_from_str(value: &str) -> Option<Duration> {
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (amount, unit) = value.split_at(split);
+    let millis = match (amount.parse::<u64>(), unit) {
+        (Ok(amount), "ms") | (Ok(amount), "") => Some(amount),
+        (Ok(amount), "s") => amount.checked_mul(1000),
+        (Ok(amount), "m") => amount.checked_mul(60 * 1000),
+        (Ok(amount), "h") => amount.checked_mul(60 * 60 * 1000),
+        _ => None,
+    };
+    millis.map(Duration::from_millis)
+}
+
+/// Parses the comma separated topics of a bridge. Each one is written as
+/// <pattern> [in|out|both] [qos] [local prefix] [remote prefix], like the topics of a Mosquitto bridge.
+/// An empty prefix is written as "".
+fn parse_bridge_topics(entry: &ConfigEntry) -> Result<Vec<BridgeTopic>, ConfigError> {
+    let invalid = |topic: &str, reason: &str| {
+        ConfigError::at(
+            entry.line,
+            &format!("Invalid bridge topic {:?}: {}", topic, reason),
+        )
+    };
+
+    let mut topics = Vec::new();
+    for topic in entry.value.split(',').map(str::trim) {
+        let fields: Vec<&str> = topic.split_whitespace().collect();
+        if fields.is_empty() || fields.len() > 5 {
+            return Err(invalid(
+                topic,
+                "expected <pattern> [in|out|both] [qos] [local prefix] [remote prefix]",
+            ));
+        }
+        let direction = match fields.get(1) {
+            None | Some(&"out") => BridgeDirection::Out,
+            Some(&"in") => BridgeDirection::In,
+            Some(&"both") => BridgeDirection::Both,
+            Some(_) => return Err(invalid(topic, "the direction must be in, out or both")),
+        };
+        let qos = match fields.get(2) {
+            None => 0,
+            Some(&"0") => 0,
+            Some(&"1") => 1,
+            Some(_) => return Err(invalid(topic, "the qos must be 0 or 1")),
+        };
+        let prefix = |index: usize| match fields.get(index) {
+            None | Some(&"\"\"") => String::new(),
+            Some(prefix) => prefix.to_string(),
+        };
+        topics.push(BridgeTopic {
+            pattern: fields[0].to_string(),
+            direction,
+            qos,
+            local_prefix: prefix(3),
+            remote_prefix: prefix(4),
+        });
+    }
+    Ok(topics)
+}
+
+/// Checks that an address has the host:port form expected by TcpListener::bind
+fn check_socket_address(entry: &ConfigEntry) -> Result<(), ConfigError> {
+    match entry.value.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => Ok(()),
+        _ => Err(ConfigError::at(
+            entry.line,
+            &format!("Invalid bind address {:?}, expected host:port", entry.value),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{
+        BindAddress, BridgeDirection, Config, ConfigError, LimitPolicy, ListenerProtocol,
+        LogFormat, LogOutput, LogRotation, RateLimits, SharePolicy, SlowConsumerPolicy,
+    };
+    use std::time::{Duration, Instant};
+
+    fn config(text: &str) -> Result<Config, ConfigError> {
+        Config::from_reader(text.as_bytes())
+    }
+
+    #[test]
+    fn test_port_creates_default_listener() {
+        let listeners = config("port=1883").unwrap().listeners;
+        assert_eq!(listeners.len(), 1);
+        assert_eq!(
+            listeners[0].bind,
+            BindAddress::Inet("0.0.0.0:1883".to_string())
+        );
+        assert_eq!(listeners[0].protocol, ListenerProtocol::Tcp);
+        assert!(listeners[0].require_auth);
+    }
+
+    #[test]
+    fn test_read_listeners() {
+        let listeners = config(
+            "listener.external.bind=[::]:8883\n\
+             listener.external.protocol=ws\n\
+             listener.external.maxConnections=100\n\
+             listener.local.bind=unix:/tmp/broker.sock\n\
+             listener.local.requireAuth=false\n",
+        )
+        .unwrap()
+        .listeners;
+
+        assert_eq!(listeners.len(), 2);
+        assert_eq!(listeners[0].name, "external");
+        assert_eq!(
+            listeners[0].bind,
+            BindAddress::Inet("[::]:8883".to_string())
+        );
+        assert_eq!(listeners[0].protocol, ListenerProtocol::Ws);
+        assert_eq!(listeners[0].max_connections, Some(100));
+        assert!(listeners[0].require_auth);
+        assert_eq!(listeners[1].name, "local");
+        assert_eq!(
+            listeners[1].bind,
+            BindAddress::Unix("/tmp/broker.sock".to_string())
+        );
+        assert!(!listeners[1].require_auth);
+    }
+
+    #[test]
+    fn test_max_qos_of_the_listeners() {
+        let listeners = config(
+            "port=1883\n\
+             maxQos=0\n\
+             listener.local.bind=unix:/tmp/broker.sock\n\
+             listener.display.bind=127.0.0.1:1884\n\
+             listener.display.maxQos=1\n",
+        )
+        .unwrap()
+        .listeners;
+        let max_qos: Vec<(&str, u8)> = listeners
+            .iter()
+            .map(|listener| (listener.name.as_str(), listener.max_qos))
+            .collect();
+        assert_eq!(max_qos, vec![("default", 0), ("display", 1), ("local", 0)]);
+
+        assert_eq!(listeners[1].granted_qos(0), Some(0));
+        assert_eq!(listeners[1].granted_qos(2), Some(1));
+        assert_eq!(listeners[1].granted_qos(3), None);
+        assert_eq!(config("port=1883").unwrap().listeners[0].max_qos, 1);
+
+        let result = config("port=1883\nmaxQos=2\n");
+        assert_eq!(result.unwrap_err().line, Some(2));
+        let result = config("port=1883\nuser.display.maxQos=0\n");
+        assert_eq!(result.unwrap_err().line, Some(2));
+    }
+
+    #[test]
+    fn test_legacy_listener_names_cannot_be_redeclared() {
+        let result = config(
+            "port=1883\n\
+             listener.default.bind=127.0.0.1:1884\n",
+        );
+        assert_eq!(result.unwrap_err().line, Some(2));
+        let result = config(
+            "wsPort=8080\n\
+             listener.tls.bind=127.0.0.1:8883\n\
+             listener.ws.bind=127.0.0.1:8081\n",
+        );
+        assert_eq!(result.unwrap_err().line, Some(3));
+    }
+
+    #[test]
+    fn test_unix_listener_only_supports_tcp() {
+        let result = config(
+            "listener.local.bind=unix:/tmp/broker.sock\n\
+             listener.local.protocol=ws\n",
+        );
+        assert_eq!(result.unwrap_err().line, Some(2));
+    }
+
+    #[test]
+    fn test_defaults_and_comments() {
+        let config = config("# broker settings\n\n  port = 1883  \n").unwrap();
+        assert_eq!(config.log.output, LogOutput::Stdout);
+        assert_eq!(config.log.format, LogFormat::Text);
+        assert_eq!(config.log.level, "info");
+        assert_eq!(config.pool_size, 4);
+        assert_eq!(config.credentials_file, "credentials.txt");
+        assert_eq!(config.credentials_reload_interval, Duration::from_secs(30));
+        assert_eq!(config.request_poll_interval, Duration::from_secs(1));
+        assert_eq!(config.socket_read_timeout, Duration::from_millis(100));
+        assert_eq!(config.connect_timeout, Duration::from_secs(10));
+        assert_eq!(config.retry_interval, Duration::from_secs(20));
+        assert_eq!(config.sys_interval, Duration::from_secs(10));
+        assert_eq!(config.metrics_bind, None);
+        assert!(config.admin.is_none());
+        assert_eq!(config.rate_limits.policy, LimitPolicy::Drop);
+        assert_eq!(config.rate_limits.default, RateLimits::default());
+        assert_eq!(config.share_policy, SharePolicy::RoundRobin);
+        assert_eq!(config.outbound.queue_size, 1000);
+        assert_eq!(config.outbound.policy, SlowConsumerPolicy::DropOldest);
+        assert!(config.bridges.is_empty());
+        assert!(config.hooks.is_empty());
+    }
+
+    #[test]
+    fn test_intervals_must_be_greater_than_zero() {
+        for key in [
+            "socketReadTimeout",
+            "connectTimeout",
+            "requestPollInterval",
+            "retryInterval",
+            "credentialsReloadInterval",
+        ] {
+            let error = config(&format!("port=1883\n{}=0s\n", key)).unwrap_err();
+            assert_eq!(error.line, Some(2));
+            assert!(error.to_string().contains(key));
+        }
+        assert!(config("port=1883\nsysInterval=0\n").is_ok());
+    }
+
+    #[test]
+    fn test_typed_values() {
+        let config = config(
+            "port=1883\n\
+             poolSize=8\n\
+             credentialsFile=/etc/broker/credentials.txt\n\
+             credentialsReloadInterval=5m\n\
+             requestPollInterval=250ms\n\
+             socketReadTimeout=50\n\
+             connectTimeout=3s\n\
+             retryInterval=10s\n\
+             metricsBind=127.0.0.1:9090\n\
+             sharedSubscriptionPolicy=least_inflight\n\
+             outboundQueueSize=50\n\
+             slowConsumerPolicy=disconnect\n\
+             hooks=deny_sys_publish, log\n",
+        )
+        .unwrap();
+        assert_eq!(config.pool_size, 8);
+        assert_eq!(config.credentials_file, "/etc/broker/credentials.txt");
+        assert_eq!(config.credentials_reload_interval, Duration::from_secs(300));
+        assert_eq!(config.request_poll_interval, Duration::from_millis(250));
+        assert_eq!(config.metrics_bind, Some("127.0.0.1:9090".to_string()));
+        assert_eq!(config.socket_read_timeout, Duration::from_millis(50));
+        assert_eq!(config.connect_timeout, Duration::from_secs(3));
+        assert_eq!(config.retry_interval, Duration::from_secs(10));
+        assert_eq!(config.share_policy, SharePolicy::LeastInflight);
+        assert_eq!(config.outbound.queue_size, 50);
+        assert_eq!(config.outbound.policy, SlowConsumerPolicy::Disconnect);
+        assert_eq!(config.hooks, vec!["deny_sys_publish", "log"]);
+    }
+
+    #[test]
+    fn test_log_settings() {
+        let config = config(
+            "port=1883\n\
+             logFile=broker\n\
+             logDirectory=/var/log/broker\n\
+             logRotation=hourly\n\
+             logRetention=24\n\
+             logFormat=json\n\
+             logLevel=warn,server::transport=debug\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config.log.output,
+            LogOutput::File {
+                directory: "/var/log/broker".to_string(),
+                prefix: "broker".to_string(),
+                rotation: LogRotation::Hourly,
+                retention: Some(24),
+            }
+        );
+        assert_eq!(config.log.format, LogFormat::Json);
+        assert_eq!(config.log.level, "warn,server::transport=debug");
+    }
+
+    #[test]
+    fn test_errors_name_the_line() {
+        let cases = [
+            ("port=1883\nthis line has no separator\n", 2),
+            ("port=1883\npoolSize=many\n", 2),
+            ("port=1883\npoolSize=0\n", 2),
+            ("# comment\nport=1883\nretryInterval=10 days\n", 3),
+            ("port=1883\nlogFil=broker\n", 2),
+            ("port=1883\nport=1884\n", 2),
+            ("port=70000\n", 1),
+            ("listener.a.bind=localhost\n", 1),
+            (
+                "listener.a.bind=0.0.0.0:1883\nlistener.a.requireAuth=yes\n",
+                2,
+            ),
+            ("listener.a.protocol=tls\nlistener.a.bind=0.0.0.0:8883\n", 1),
+            ("port=1883\nlogFile=broker\nlogRotation=weekly\n", 3),
+            ("port=1883\nlogLevel=server=loud\n", 2),
+        ];
+        for (text, line) in cases.iter() {
+            let error = config(text).unwrap_err();
+            assert_eq!(error.line, Some(*line), "{}", error);
+            assert!(error.to_string().contains(&format!("line {}", line)));
+        }
+    }
+
+    #[test]
+    fn test_admin_settings() {
+        let admin = config("port=1883\nadminBind=127.0.0.1:8081\nadminToken=secret\n")
+            .unwrap()
+            .admin
+            .unwrap();
+        assert_eq!(admin.bind, "127.0.0.1:8081");
+        assert_eq!(admin.token, "secret");
+
+        let result = config("port=1883\nadminBind=127.0.0.1:8081\n");
+        assert_eq!(result.unwrap_err().line, Some(2));
+        let result = config("port=1883\nadminBind=127.0.0.1:8081\nadminToken=\n");
+        assert_eq!(result.unwrap_err().line, Some(3));
+    }
+
+    #[test]
+    fn test_rate_limits() {
+        let rate_limits = config(
+            "port=1883\n\
+             maxPublishRate=10\n\
+             maxSubscriptions=20\n\
+             rateLimitPolicy=throttle\n\
+             user.sensor.01.maxPublishRate=100\n\
+             user.sensor.01.maxByteRate=4096\n",
+        )
+        .unwrap()
+        .rate_limits;
+        assert_eq!(rate_limits.policy, LimitPolicy::Throttle);
+        assert_eq!(rate_limits.limits_for("anyone").publish_rate, Some(10));
+        assert_eq!(rate_limits.limits_for("anyone").byte_rate, None);
+        let sensor = rate_limits.limits_for("sensor.01");
+        assert_eq!(sensor.publish_rate, Some(100));
+        assert_eq!(sensor.byte_rate, Some(4096));
+        assert_eq!(sensor.max_subscriptions, Some(20));
+
+        let result = config("port=1883\nuser.sensor.maxPublishRate=0\n");
+        assert_eq!(result.unwrap_err().line, Some(2));
+        let result = config("port=1883\nuser.sensor.bind=1\n");
+        assert_eq!(result.unwrap_err().line, Some(2));
+    }
+
+    #[test]
+    fn test_tenancy() {
+        let tenancy = config(
+            "port=1883\n\
+             mountPoint=%u\n\
+             tenant.acme.users=ops.1, ops.2\n",
+        )
+        .unwrap()
+        .tenancy;
+        let mount_point_for = |username| {
+            tenancy
+                .mount_point_for(username)
+                .map(|mount_point| mount_point.and_then(|m| m.mount("lights")))
+        };
+        assert_eq!(
+            mount_point_for("bob"),
+            Ok(Some("tenant/bob/lights".to_string()))
+        );
+        assert_eq!(
+            mount_point_for("ops.1"),
+            Ok(Some("tenant/acme/lights".to_string()))
+        );
+        assert_eq!(
+            mount_point_for("ops.2"),
+            Ok(Some("tenant/acme/lights".to_string()))
+        );
+        // Anonymous clients and usernames that are not a topic level have no tenant
+        assert!(mount_point_for("").is_err());
+        assert!(mount_point_for("a/b").is_err());
+        assert_eq!(
+            config("port=1883\n")
+                .unwrap()
+                .tenancy
+                .mount_point_for("bob"),
+            Ok(None)
+        );
+        let tenancy = config("port=1883\ntenant.acme.users=ops\n")
+            .unwrap()
+            .tenancy;
+        assert_eq!(tenancy.mount_point_for("bob"), Ok(None));
+        assert!(tenancy.mount_point_for("").is_err());
+
+        let result = config("port=1883\nmountPoint=a/b\n");
+        assert_eq!(result.unwrap_err().line, Some(2));
+        let result = config("port=1883\ntenant.a+.users=bob\n");
+        assert_eq!(result.unwrap_err().line, Some(2));
+        let result = config("port=1883\ntenant.acme.users=bob\ntenant.globex.users=ann, bob\n");
+        assert_eq!(result.unwrap_err().line, Some(3));
+        let result = config("port=1883\nuser.bob.mountPoint=acme\n");
+        assert_eq!(result.unwrap_err().line, Some(2));
+    }
+
+    #[test]
+    fn test_message_expiry() {
+        let expiry = config(
+            "port=1883\n\
+             messageExpiry=1h\n\
+             topicExpiry=sensors/+/temperature 30s, sensors/# 5m, logs/# 0\n",
+        )
+        .unwrap()
+        .expiry;
+        let expiry_for = |topic| expiry.expiry_for(topic);
+        assert_eq!(
+            expiry_for("sensors/kitchen/temperature"),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            expiry_for("sensors/kitchen/humidity"),
+            Some(Duration::from_secs(300))
+        );
+        assert_eq!(expiry_for("logs/app"), None);
+        assert_eq!(expiry_for("alarms"), Some(Duration::from_secs(3600)));
+
+        let now = Instant::now();
+        let received_at = now - Duration::from_secs(31);
+        assert!(expiry.is_expired("sensors/kitchen/temperature", received_at, now));
+        assert!(!expiry.is_expired("sensors/kitchen/humidity", received_at, now));
+
+        assert_eq!(config("port=1883\n").unwrap().expiry.expiry_for("a"), None);
+        let result = config("port=1883\ntopicExpiry=sensors/# soon\n");
+        assert_eq!(result.unwrap_err().line, Some(2));
+    }
+
+    #[test]
+    fn test_bridges() {
+        let bridges = config(
+            "port=1883\n\
+             bridge.central.address=10.0.0.1:1883\n\
+             bridge.central.username=plant1\n\
+             bridge.central.password=secret\n\
+             bridge.central.topics=sensors/# out 1 \"\" plant1/, commands/+ in 0 plant/ central/\n",
+        )
+        .unwrap()
+        .bridges;
+        assert_eq!(bridges.len(), 1);
+        let central = &bridges[0];
+        assert_eq!(central.address, "10.0.0.1:1883");
+        assert_eq!(central.client_id, "bridge-central");
+        assert_eq!(central.username, "plant1");
+        assert_eq!(central.keep_alive, Duration::from_secs(60));
+        assert_eq!(central.topics.len(), 2);
+        assert_eq!(central.topics[0].direction, BridgeDirection::Out);
+        assert_eq!(central.topics[0].qos, 1);
+        assert_eq!(central.topics[0].local_prefix, "");
+        assert_eq!(central.topics[0].remote_prefix, "plant1/");
+        assert_eq!(central.topics[1].pattern, "commands/+");
+        assert_eq!(central.topics[1].direction, BridgeDirection::In);
+        assert_eq!(central.topics[1].local_prefix, "plant/");
+        assert_eq!(central.topics[1].remote_prefix, "central/");
+
+        let result = config("port=1883\nbridge.a.topics=a/#\n");
+        assert_eq!(result.unwrap_err().line, Some(2));
+        let result = config("port=1883\nbridge.a.address=h:1883\nbridge.a.topics=a/# sideways\n");
+        assert_eq!(result.unwrap_err().line, Some(3));
+    }
+
+    #[test]
+    fn test_schedules() {
+        let settings = config(
+            "port=1883\n\
+             delayedMessagesFile=delayed.json\n\
+             schedule.lights-off.cron=0 22 * * 1-5\n\
+             schedule.lights-off.topic=lights/all\n\
+             schedule.lights-off.payload=off\n\
+             schedule.lights-off.retain=true\n",
+        )
+        .unwrap();
+        assert_eq!(
+            settings.delayed_messages_file,
+            Some("delayed.json".to_string())
+        );
+        assert_eq!(settings.schedules.len(), 1);
+        let lights_off = &settings.schedules[0];
+        assert_eq!(lights_off.name, "lights-off");
+        assert_eq!(lights_off.cron, "0 22 * * 1-5".parse().unwrap());
+        let publish = lights_off.to_publish_packet();
+        assert_eq!(
+            (publish.topic_name.as_str(), publish.payload.as_str()),
+            ("lights/all", "off")
+        );
+        assert_eq!((publish.qos, publish.retain_flag), (0, 1));
+
+        let result = config("port=1883\nschedule.a.topic=a\n");
+        assert_eq!(result.unwrap_err().line, Some(2));
+        let result = config("port=1883\nschedule.a.topic=a\nschedule.a.cron=* * *\n");
+        assert_eq!(result.unwrap_err().line, Some(3));
+        let result = config("port=1883\nschedule.a.cron=* * * * *\nschedule.a.topic=a/#\n");
+        assert_eq!(result.unwrap_err().line, Some(3));
+    }
+
+    #[test]
+    fn test_missing_listener_is_an_error() {
+        let error = config("logFile=broker\n").unwrap_err();
+        assert_eq!(error.line, None);
     }
 }
@@ -0,0 +1,361 @@
+use crate::transport::client_stream::ClientStream;
+use shared::packages::publish::Publish;
+use std::fmt;
+use tracing::{event, Level};
+
+/// Names of the hooks that can be registered from the hooks setting of the config file
+pub static BUILTIN_HOOKS: [&str; 2] = ["log", "deny_sys_publish"];
+
+/// Prefix of the topics the broker publishes its statistics on
+const SYS_PREFIX: &str = "$SYS/";
+
+/// This enum represents whether a hook lets the broker go on with a packet
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HookVerdict {
+    Continue,
+    Deny,
+}
+
+/// This struct represents the client a hook is called for
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientInfo {
+    pub client_id: String,
+    /// The user the client authenticated as, empty for anonymous clients
+    pub username: String,
+    pub peer_addr: String,
+    /// The name of the listener that accepted the connection
+    pub listener: String,
+}
+
+impl ClientInfo {
+    /// Returns the ClientInfo of a client connected through a stream
+    /// # Arguments
+    ///
+    /// * `client_id` - A string slice containing the id of the client
+    /// * `stream` - The connection of the client
+    ///
+    pub fn new(client_id: &str, stream: &ClientStream) -> ClientInfo {
+        ClientInfo {
+            client_id: client_id.to_string(),
+            username: stream.username(),
+            peer_addr: stream.peer_addr().to_string(),
+            listener: stream.listener().name.to_string(),
+        }
+    }
+}
+
+/// This trait is implemented by the extensions of the broker. Every callback lets the
+/// packet through by default, so a hook only implements the ones it needs.
+pub trait BrokerHook: Send + Sync {
+    /// Returns the name used for the hook in the logs
+    fn name(&self) -> &str;
+
+    /// Called once the credentials of a client are valid, Deny refuses it as not authorized
+    fn on_connect(&self, _client: &ClientInfo) -> HookVerdict {
+        HookVerdict::Continue
+    }
+
+    /// Called when a client is removed, graceful is false unless it sent a DISCONNECT
+    fn on_disconnect(&self, _client: &ClientInfo, _graceful: bool) {}
+
+    /// Called for each topic filter of a SUBSCRIBE. The filter and QoS can be rewritten,
+    /// Deny refuses the filter.
+    fn on_subscribe(
+        &self,
+        _client: &ClientInfo,
+        _filter: &mut String,
+        _qos: &mut u8,
+    ) -> HookVerdict {
+        HookVerdict::Continue
+    }
+
+    /// Called for each message a client publishes, before it is retained or delivered. The
+    /// message can be modified, changing its topic reroutes it, Deny drops it.
+    fn on_publish(&self, _client: &ClientInfo, _publish: &mut Publish) -> HookVerdict {
+        HookVerdict::Continue
+    }
+
+    /// Called before a message is sent to a subscriber, Deny skips that subscriber
+    fn on_deliver(&self, _subscriber: &ClientInfo, _publish: &mut Publish) -> HookVerdict {
+        HookVerdict::Continue
+    }
+}
+
+/// This struct represents the hooks of the broker, called in the order they were registered.
+/// The first hook that denies a packet stops the rest from being called.
+#[derive(Default)]
+pub struct Hooks {
+    hooks: Vec<Box<dyn BrokerHook>>,
+}
+
+impl Hooks {
+    /// Returns Hooks without any hook registered
+    pub fn new() -> Hooks {
+        Hooks { hooks: Vec::new() }
+    }
+
+    /// Returns Hooks with the built-in hooks of the given names, in that order
+    /// # Arguments
+    ///
+    /// * `names` - The names of the hooks, each one of BUILTIN_HOOKS
+    ///
+    pub fn from_names(names: &[String]) -> Hooks {
+        let mut hooks = Hooks::new();
+        for name in names {
+            match builtin_hook(name) {
+                Some(hook) => hooks.register(hook),
+                None => event!(Level::ERROR, "Unknown hook {:?} was not registered", name),
+            }
+        }
+        hooks
+    }
+
+    /// Adds a hook after the ones already registered
+    /// # Arguments
+    ///
+    /// * `hook` - The hook to add
+    ///
+    pub fn register(&mut self, hook: Box<dyn BrokerHook>) {
+        event!(Level::INFO, "Hook {} registered", hook.name());
+        self.hooks.push(hook);
+    }
+
+    /// Returns the names of the registered hooks, in the order they are called
+    pub fn names(&self) -> Vec<&str> {
+        self.hooks.iter().map(|hook| hook.name()).collect()
+    }
+
+    pub fn on_connect(&self, client: &ClientInfo) -> HookVerdict {
+        self.until_denied("CONNECT", client, |hook| hook.on_connect(client))
+    }
+
+    pub fn on_disconnect(&self, client: &ClientInfo, graceful: bool) {
+        for hook in self.hooks.iter() {
+            hook.on_disconnect(client, graceful);
+        }
+    }
+
+    pub fn on_subscribe(
+        &self,
+        client: &ClientInfo,
+        filter: &mut String,
+        qos: &mut u8,
+    ) -> HookVerdict {
+        self.until_denied("SUBSCRIBE", client, |hook| {
+            hook.on_subscribe(client, filter, qos)
+        })
+    }
+
+    pub fn on_publish(&self, client: &ClientInfo, publish: &mut Publish) -> HookVerdict {
+        self.until_denied("PUBLISH", client, |hook| hook.on_publish(client, publish))
+    }
+
+    pub fn on_deliver(&self, subscriber: &ClientInfo, publish: &mut Publish) -> HookVerdict {
+        self.until_denied("delivery", subscriber, |hook| {
+            hook.on_deliver(subscriber, publish)
+        })
+    }
+
+    /// Calls a callback of each hook until one of them denies the packet
+    fn until_denied(
+        &self,
+        packet: &str,
+        client: &ClientInfo,
+        mut callback: impl FnMut(&dyn BrokerHook) -> HookVerdict,
+    ) -> HookVerdict {
+        for hook in self.hooks.iter() {
+            if callback(hook.as_ref()) == HookVerdict::Deny {
+                event!(
+                    Level::INFO,
+                    "Hook {} denied the {} of client {:?}",
+                    hook.name(),
+                    packet,
+                    client.client_id
+                );
+                return HookVerdict::Deny;
+            }
+        }
+        HookVerdict::Continue
+    }
+}
+
+impl fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.names()).finish()
+    }
+}
+
+/// Returns the built-in hook with the given name, if there is one
+fn builtin_hook(name: &str) -> Option<Box<dyn BrokerHook>> {
+    match name {
+        "log" => Some(Box::new(LogHook)),
+        "deny_sys_publish" => Some(Box::new(DenySysPublishHook)),
+        _ => None,
+    }
+}
+
+/// Logs every event the hooks are called for
+struct LogHook;
+
+impl BrokerHook for LogHook {
+    fn name(&self) -> &str {
+        "log"
+    }
+
+    fn on_connect(&self, client: &ClientInfo) -> HookVerdict {
+        event!(
+            Level::INFO,
+            "Client {:?} connected as {:?} from {} on listener {}",
+            client.client_id,
+            client.username,
+            client.peer_addr,
+            client.listener
+        );
+        HookVerdict::Continue
+    }
+
+    fn on_disconnect(&self, client: &ClientInfo, graceful: bool) {
+        event!(
+            Level::INFO,
+            "Client {:?} disconnected, graceful: {}",
+            client.client_id,
+            graceful
+        );
+    }
+
+    fn on_subscribe(&self, client: &ClientInfo, filter: &mut String, qos: &mut u8) -> HookVerdict {
+        event!(
+            Level::INFO,
+            "Client {:?} subscribes to {:?} with QoS {}",
+            client.client_id,
+            filter,
+            qos
+        );
+        HookVerdict::Continue
+    }
+
+    fn on_publish(&self, client: &ClientInfo, publish: &mut Publish) -> HookVerdict {
+        event!(
+            Level::INFO,
+            "Client {:?} publishes {} bytes to {:?}",
+            client.client_id,
+            publish.payload.len(),
+            publish.topic_name
+        );
+        HookVerdict::Continue
+    }
+
+    fn on_deliver(&self, subscriber: &ClientInfo, publish: &mut Publish) -> HookVerdict {
+        event!(
+            Level::INFO,
+            "Message to {:?} delivered to client {:?}",
+            publish.topic_name,
+            subscriber.client_id
+        );
+        HookVerdict::Continue
+    }
+}
+
+/// Drops the messages clients publish on the $SYS topics, which only the broker writes
+struct DenySysPublishHook;
+
+impl BrokerHook for DenySysPublishHook {
+    fn name(&self) -> &str {
+        "deny_sys_publish"
+    }
+
+    fn on_publish(&self, _client: &ClientInfo, publish: &mut Publish) -> HookVerdict {
+        if publish.topic_name.starts_with(SYS_PREFIX) {
+            HookVerdict::Deny
+        } else {
+            HookVerdict::Continue
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hooks::{BrokerHook, ClientInfo, HookVerdict, Hooks};
+    use shared::packages::publish::Publish;
+
+    /// Moves every message under a prefix, and denies the filters that contain deny
+    struct PrefixHook;
+
+    impl BrokerHook for PrefixHook {
+        fn name(&self) -> &str {
+            "prefix"
+        }
+
+        fn on_subscribe(
+            &self,
+            _client: &ClientInfo,
+            filter: &mut String,
+            qos: &mut u8,
+        ) -> HookVerdict {
+            if filter.contains("deny") {
+                return HookVerdict::Deny;
+            }
+            *filter = format!("prefix/{}", filter);
+            *qos = 0;
+            HookVerdict::Continue
+        }
+
+        fn on_publish(&self, _client: &ClientInfo, publish: &mut Publish) -> HookVerdict {
+            publish.topic_name = format!("prefix/{}", publish.topic_name);
+            HookVerdict::Continue
+        }
+    }
+
+    fn client() -> ClientInfo {
+        ClientInfo {
+            client_id: "sensor".to_string(),
+            username: "jleyes".to_string(),
+            peer_addr: "127.0.0.1:50000".to_string(),
+            listener: "default".to_string(),
+        }
+    }
+
+    fn publish(topic: &str) -> Publish {
+        Publish {
+            topic_name: topic.to_string(),
+            payload: "22".to_string(),
+            packet_id: 1,
+            qos: 1,
+            retain_flag: 0,
+            dup_flag: 0,
+        }
+    }
+
+    #[test]
+    fn test_hooks_are_called_in_order_until_one_denies() {
+        let mut hooks = Hooks::from_names(&["deny_sys_publish".to_string()]);
+        hooks.register(Box::new(PrefixHook));
+        assert_eq!(hooks.names(), vec!["deny_sys_publish", "prefix"]);
+        assert_eq!(hooks.on_connect(&client()), HookVerdict::Continue);
+
+        let mut message = publish("house/temperature");
+        assert_eq!(
+            hooks.on_publish(&client(), &mut message),
+            HookVerdict::Continue
+        );
+        assert_eq!(message.topic_name, "prefix/house/temperature");
+
+        // The prefix hook runs after the one that denies, so the topic is left as it was
+        let mut message = publish("$SYS/broker/uptime");
+        assert_eq!(hooks.on_publish(&client(), &mut message), HookVerdict::Deny);
+        assert_eq!(message.topic_name, "$SYS/broker/uptime");
+
+        let mut filter = "house/#".to_string();
+        let mut qos = 1;
+        assert_eq!(
+            hooks.on_subscribe(&client(), &mut filter, &mut qos),
+            HookVerdict::Continue
+        );
+        assert_eq!((filter.as_str(), qos), ("prefix/house/#", 0));
+        let mut filter = "deny/#".to_string();
+        assert_eq!(
+            hooks.on_subscribe(&client(), &mut filter, &mut qos),
+            HookVerdict::Deny
+        );
+    }
+}
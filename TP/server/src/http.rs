@@ -0,0 +1,351 @@
+use crate::shutdown::Shutdown;
+use std::collections::HashMap;
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tracing::{event, Level};
+
+static READ_TIMEOUT: Duration = Duration::from_secs(5);
+static ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+static MAX_BODY_SIZE: usize = 1024 * 1024;
+/// Longest request line and headers, counted together
+static MAX_HEAD_SIZE: u64 = 16 * 1024;
+/// Connections answered at the same time by one endpoint, the next ones are closed unanswered
+static MAX_CONNECTIONS: usize = 16;
+
+/// This struct represents an HTTP request sent to one of the broker endpoints
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpRequest {
+    pub method: String,
+    /// The path of the request, without the query string
+    pub path: String,
+    pub query: String,
+    /// Header names are stored in lowercase
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+/// This struct represents the response to an HTTP request
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub content_type: String,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    /// Returns a response with a status code and a body
+    /// # Arguments
+    ///
+    /// * `status` - The HTTP status code
+    /// * `content_type` - The media type of the body
+    /// * `body` - The content of the response
+    ///
+    pub fn new(status: u16, content_type: &str, body: &str) -> HttpResponse {
+        HttpResponse {
+            status,
+            content_type: content_type.to_string(),
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    pub fn not_found() -> HttpResponse {
+        HttpResponse::new(404, "text/plain", "Not Found\n")
+    }
+
+    pub fn method_not_allowed() -> HttpResponse {
+        HttpResponse::new(405, "text/plain", "Method Not Allowed\n")
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            201 => "Created",
+            204 => "No Content",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            409 => "Conflict",
+            413 => "Payload Too Large",
+            _ => "Internal Server Error",
+        }
+    }
+
+    /// Writes the response, closing the connection afterwards
+    /// # Arguments
+    ///
+    /// * `stream` - The stream to write the response to
+    ///
+    pub fn write_to(&self, stream: &mut dyn Write) -> io::Result<()> {
+        let head = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.status,
+            self.reason(),
+            self.content_type,
+            self.body.len()
+        );
+        stream.write_all(head.as_bytes())?;
+        stream.write_all(&self.body)?;
+        stream.flush()
+    }
+}
+
+impl HttpRequest {
+    /// Reads a request line, its headers and a body of Content-Length bytes
+    /// # Arguments
+    ///
+    /// * `stream` - The stream to read the request from
+    ///
+    pub fn read_from(stream: &mut dyn Read) -> io::Result<HttpRequest> {
+        let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message);
+        let mut reader = BufReader::new(stream.take(MAX_HEAD_SIZE));
+        let mut read_head_line = |line: &mut String| {
+            reader.read_line(line)?;
+            if !line.ends_with('\n') && reader.get_ref().limit() == 0 {
+                return Err(invalid("Headers too large"));
+            }
+            Ok(line.len())
+        };
+
+        let mut request_line = String::new();
+        read_head_line(&mut request_line)?;
+        let mut parts = request_line.split_whitespace();
+        let method = parts.next().ok_or_else(|| invalid("Missing method"))?;
+        let target = parts.next().ok_or_else(|| invalid("Missing path"))?;
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+        let mut headers = HashMap::new();
+        loop {
+            let mut line = String::new();
+            if read_head_line(&mut line)? == 0 {
+                return Err(invalid("Connection closed before the end of the headers"));
+            }
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+            }
+        }
+
+        let length = match headers.get("content-length") {
+            Some(length) => length
+                .parse::<usize>()
+                .map_err(|_| invalid("Invalid Content-Length"))?,
+            None => 0,
+        };
+        if length > MAX_BODY_SIZE {
+            return Err(invalid("Body too large"));
+        }
+        let mut body = vec![0; length];
+        reader.get_mut().set_limit(length as u64);
+        reader.read_exact(&mut body)?;
+
+        Ok(HttpRequest {
+            method: method.to_string(),
+            path: path.to_string(),
+            query: query.to_string(),
+            headers,
+            body,
+        })
+    }
+}
+
+/// Decodes the %XX escapes of a path segment, such as %24SYS for $SYS
+/// # Arguments
+///
+/// * `text` - The text to decode
+///
+pub fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        let escaped = bytes
+            .get(index + 1..index + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[index], escaped) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                index += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                index += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// An alias for a function that answers the requests of an HTTP listener
+pub type Handler = dyn Fn(&HttpRequest) -> HttpResponse + Send + Sync;
+
+/// Starts answering HTTP requests on an address, each connection on its own thread, up to
+/// MAX_CONNECTIONS at the same time
+/// # Arguments
+///
+/// * `name` - The name of the endpoint, used in the logs
+/// * `address` - The address to bind, such as 127.0.0.1:9090
+/// * `handler` - The function that answers each request
+/// * `stop` - Requested to close the endpoint
+///
+pub fn serve(
+    name: &str,
+    address: &str,
+    handler: Arc<Handler>,
+    stop: Arc<Shutdown>,
+) -> io::Result<thread::JoinHandle<()>> {
+    let listener = TcpListener::bind(address)?;
+    // Accept does not block, so the endpoint can notice the stop
+    listener.set_nonblocking(true)?;
+    event!(Level::INFO, "{} endpoint listening on {}", name, address);
+
+    let name = name.to_string();
+    let active = Arc::new(AtomicUsize::new(0));
+    Ok(thread::spawn(move || {
+        loop {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    if active.load(Ordering::SeqCst) >= MAX_CONNECTIONS {
+                        event!(
+                            Level::WARN,
+                            "{} endpoint is answering {} connections, closing a new one",
+                            name,
+                            MAX_CONNECTIONS
+                        );
+                        continue;
+                    }
+                    if let Err(e) = stream.set_nonblocking(false) {
+                        event!(Level::ERROR, "Failed HTTP connection: {}", e);
+                        continue;
+                    }
+                    let slot = ConnectionSlot::take(&active);
+                    let handler = Arc::clone(&handler);
+                    thread::spawn(move || {
+                        handle_connection(stream, handler);
+                        drop(slot);
+                    });
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    if stop.wait_timeout(ACCEPT_POLL_INTERVAL) {
+                        break;
+                    }
+                }
+                Err(e) => event!(Level::ERROR, "Failed HTTP connection: {}", e),
+            }
+        }
+        event!(Level::INFO, "{} endpoint closed", name);
+    }))
+}
+
+/// This struct represents a connection counted against MAX_CONNECTIONS, released when
+/// dropped even if the handler panics
+struct ConnectionSlot {
+    active: Arc<AtomicUsize>,
+}
+
+impl ConnectionSlot {
+    fn take(active: &Arc<AtomicUsize>) -> ConnectionSlot {
+        active.fetch_add(1, Ordering::SeqCst);
+        ConnectionSlot {
+            active: Arc::clone(active),
+        }
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn handle_connection(mut stream: TcpStream, handler: Arc<Handler>) {
+    if let Err(e) = stream.set_read_timeout(Some(READ_TIMEOUT)) {
+        event!(Level::WARN, "Could not set HTTP read timeout: {}", e);
+    }
+    let response = match HttpRequest::read_from(&mut stream) {
+        Ok(request) => {
+            event!(Level::DEBUG, "HTTP {} {}", request.method, request.path);
+            handler(&request)
+        }
+        Err(e) => HttpResponse::new(400, "text/plain", &format!("{}\n", e)),
+    };
+    if let Err(e) = response.write_to(&mut stream) {
+        event!(Level::WARN, "Could not write HTTP response: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::http::{percent_decode, serve, HttpRequest, HttpResponse, MAX_HEAD_SIZE};
+    use crate::shutdown::Shutdown;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_read_request() {
+        let raw = "POST /api/clients?limit=2 HTTP/1.1\r\nHost: localhost\r\nContent-Length: 4\r\n\r\nbody";
+        let request = HttpRequest::read_from(&mut raw.as_bytes()).unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/api/clients");
+        assert_eq!(request.query, "limit=2");
+        assert_eq!(request.headers["host"], "localhost");
+        assert_eq!(request.body, b"body");
+    }
+
+    #[test]
+    fn test_read_request_with_headers_over_the_limit() {
+        let raw = format!(
+            "GET / HTTP/1.1\r\nCookie: {}\r\n\r\n",
+            "a".repeat(MAX_HEAD_SIZE as usize)
+        );
+        let error = HttpRequest::read_from(&mut raw.as_bytes()).unwrap_err();
+        assert_eq!(error.to_string(), "Headers too large");
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(
+            percent_decode("%24SYS%2Fbroker/uptime"),
+            "$SYS/broker/uptime"
+        );
+        assert_eq!(percent_decode("a%20b%zz%4"), "a b%zz%4");
+    }
+
+    #[test]
+    fn test_write_response() {
+        let mut output = Vec::new();
+        HttpResponse::new(200, "text/plain", "ok")
+            .write_to(&mut output)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok"
+        );
+    }
+
+    #[test]
+    fn test_serve_stops_when_requested() {
+        let stop = Arc::new(Shutdown::new());
+        let endpoint = serve(
+            "Test",
+            "127.0.0.1:0",
+            Arc::new(|_| HttpResponse::not_found()),
+            Arc::clone(&stop),
+        )
+        .unwrap();
+
+        let start = Instant::now();
+        stop.request();
+        endpoint.join().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+}
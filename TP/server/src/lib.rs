@@ -0,0 +1,36 @@
+//! An MQTT 3.1.1 broker. The server binary runs it from a config file, other programs can
+//! embed it with `Broker::builder()`, for example on a free port:
+//!
+//! ```ignore
+//! let broker = Broker::builder()
+//!     .listener(ListenerSettings::tcp("default", "127.0.0.1:0"))
+//!     .authenticator(credentials)
+//!     .build()?
+//!     .start()?;
+//! let port = broker.port();
+//! broker.shutdown();
+//! ```
+
+mod admin;
+mod bridge;
+mod broker;
+pub mod config;
+pub mod hooks;
+mod http;
+mod lockorder;
+pub mod logging;
+mod managers;
+mod metrics;
+mod packages;
+mod ratelimit;
+mod schedule;
+mod shutdown;
+mod stats;
+mod sys;
+mod tests;
+mod threadpool;
+mod transport;
+
+pub use crate::broker::{check_config, Broker, BrokerBuilder, BrokerHandle};
+pub use crate::managers::credentialmanager::{Authenticator, CredentialManager};
+pub use shared::packages::publish::Publish;
@@ -0,0 +1,98 @@
+use std::cell::Cell;
+use std::marker::PhantomData;
+
+/// This enum represents the shared state a thread can wait for. A thread may only wait for one
+/// while it holds the ones that come before it, so no two threads can wait for each other.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LockName {
+    /// The broker core thread, which owns the sessions, subscriptions and pending messages
+    Core = 0,
+    Credentials = 1,
+    Streams = 2,
+}
+
+impl LockName {
+    pub const ALL: [LockName; 3] = [LockName::Core, LockName::Credentials, LockName::Streams];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LockName::Core => "core",
+            LockName::Credentials => "credentials",
+            LockName::Streams => "streams",
+        }
+    }
+}
+
+thread_local! {
+    /// The locks held by the current thread, one bit for each LockName
+    static HELD_LOCKS: Cell<u8> = const { Cell::new(0) };
+}
+
+/// This struct represents a lock held by the current thread, released when dropped
+#[derive(Debug)]
+pub struct HeldLock {
+    name: LockName,
+    /// Held locks belong to the thread that took them
+    _thread: PhantomData<*const ()>,
+}
+
+impl Drop for HeldLock {
+    fn drop(&mut self) {
+        let bit = 1 << self.name as u8;
+        HELD_LOCKS.with(|held| held.set(held.get() & !bit));
+    }
+}
+
+/// Records that the current thread holds a lock until the returned HeldLock is dropped. Call it
+/// before waiting for the lock: waiting out of order could deadlock, so it panics instead.
+/// # Arguments
+///
+/// * `name` - The lock about to be waited for
+///
+pub fn acquire(name: LockName) -> HeldLock {
+    let held = HELD_LOCKS.with(|held| held.get());
+    assert!(
+        held >> name as u8 == 0,
+        "Lock {} taken while holding {:?}",
+        name.as_str(),
+        LockName::ALL
+            .iter()
+            .filter(|lock| held & (1 << **lock as u8) != 0)
+            .map(|lock| lock.as_str())
+            .collect::<Vec<&str>>()
+    );
+    HELD_LOCKS.with(|held| held.set(held.get() | 1 << name as u8));
+    HeldLock {
+        name,
+        _thread: PhantomData,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lockorder::{acquire, LockName};
+
+    #[test]
+    fn test_locks_taken_in_order_are_allowed() {
+        let core = acquire(LockName::Core);
+        drop(acquire(LockName::Streams));
+        drop(core);
+        // Released locks no longer count
+        drop(acquire(LockName::Streams));
+        drop(acquire(LockName::Core));
+    }
+
+    #[test]
+    #[should_panic(expected = "Lock credentials taken while holding [\"streams\"]")]
+    fn test_lock_taken_out_of_order_panics() {
+        let _streams = acquire(LockName::Streams);
+        let _credentials = acquire(LockName::Credentials);
+    }
+
+    #[test]
+    #[should_panic(expected = "Lock core taken while holding [\"core\"]")]
+    fn test_lock_taken_twice_panics() {
+        let _core = acquire(LockName::Core);
+        let _again = acquire(LockName::Core);
+    }
+}
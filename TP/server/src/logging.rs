@@ -0,0 +1,55 @@
+use crate::config::{LogFormat, LogOutput, LogRotation, LogSettings};
+use std::fs;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::EnvFilter;
+
+/// Installs the global subscriber described by the log settings.
+/// The returned guard flushes pending log lines when dropped, so it must live until the broker stops.
+/// # Arguments
+///
+/// * `settings` - The log settings read from the config file
+///
+pub fn init(settings: &LogSettings) -> Result<WorkerGuard, String> {
+    let (writer, guard) = match &settings.output {
+        LogOutput::Stdout => tracing_appender::non_blocking(std::io::stdout()),
+        LogOutput::File {
+            directory,
+            prefix,
+            rotation,
+            retention,
+        } => {
+            let rotation = match rotation {
+                LogRotation::Hourly => Rotation::HOURLY,
+                LogRotation::Daily => Rotation::DAILY,
+                LogRotation::Never => Rotation::NEVER,
+            };
+            // The appender prunes old files before creating the directory, so it must exist first
+            fs::create_dir_all(directory)
+                .map_err(|e| format!("Could not create log directory {}: {}", directory, e))?;
+            let mut builder = RollingFileAppender::builder()
+                .rotation(rotation)
+                .filename_prefix(prefix);
+            if let Some(retention) = retention {
+                builder = builder.max_log_files(*retention);
+            }
+            let appender = builder
+                .build(directory)
+                .map_err(|e| format!("Could not open log directory {}: {}", directory, e))?;
+            tracing_appender::non_blocking(appender)
+        }
+    };
+    let filter = EnvFilter::try_new(&settings.level).map_err(|e| e.to_string())?;
+
+    let builder = tracing_subscriber::fmt()
+        .with_writer(writer)
+        .with_env_filter(filter)
+        .with_ansi(settings.output == LogOutput::Stdout);
+    let result = match settings.format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().try_init(),
+    };
+    result.map_err(|e| e.to_string())?;
+
+    Ok(guard)
+}
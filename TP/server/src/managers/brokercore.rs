@@ -0,0 +1,225 @@
+use crate::config::{ExpirySettings, SharePolicy};
+use crate::lockorder::{self, LockName};
+use crate::managers::delayedmanager::DelayedManager;
+use crate::managers::messagemanager::MessageManager;
+use crate::managers::sessionmanager::SessionManager;
+use crate::managers::topicmanager::TopicManager;
+use crate::packages::publish::{get_receivers, send_to_subscribers};
+use crate::stats::BrokerStats;
+use shared::packages::publish::Publish;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+use tracing::{event, Level};
+
+/// Commands waiting for the core thread. Once it is full the senders wait, so a flood of
+/// publishes slows down their clients instead of growing the queue without bound.
+const COMMAND_QUEUE_SIZE: usize = 1024;
+
+/// A command run by the core thread, with the instant it was sent
+type Command = (Instant, Box<dyn FnOnce(&mut BrokerCore) + Send>);
+
+/// This struct represents the state of the broker: the sessions, the subscriptions, the
+/// messages waiting for an acknowledgement and the ones waiting to be published. It is owned
+/// by the core thread, so a command sees and changes all of them at once and no two commands
+/// can wait for each other.
+pub struct BrokerCore {
+    pub sessions: SessionManager,
+    pub topics: TopicManager,
+    pub messages: MessageManager,
+    /// Messages published to $delayed/<seconds>/<topic>, waiting to be published to <topic>
+    pub delayed: DelayedManager,
+    /// How long pending and retained messages wait for their subscribers
+    pub expiry: ExpirySettings,
+}
+
+impl BrokerCore {
+    /// Returns a BrokerCore without sessions, subscriptions or messages
+    /// # Arguments
+    ///
+    /// * `share_policy` - How a member of a shared subscription group is chosen for each message
+    /// * `expiry` - How long pending and retained messages wait for their subscribers
+    ///
+    pub fn new(share_policy: SharePolicy, expiry: ExpirySettings) -> BrokerCore {
+        let mut topics = TopicManager::new();
+        topics.set_share_policy(share_policy);
+        BrokerCore {
+            sessions: SessionManager::new(),
+            topics,
+            messages: MessageManager::new(),
+            delayed: DelayedManager::new(),
+            expiry,
+        }
+    }
+
+    /// Drops the pending and retained messages that expired, returning how many there were
+    /// # Arguments
+    ///
+    /// * `now` - The current time
+    ///
+    pub fn remove_expired(&mut self, now: Instant) -> usize {
+        let expiry = &self.expiry;
+        let pending = self.messages.remove_expired(|message| {
+            expiry.is_expired(&message.topic_name, message.received_at, now)
+        });
+        let retained = self.topics.remove_expired_retained(|message| {
+            expiry.is_expired(&message.topic_name, message.received_at, now)
+        });
+        pending + retained
+    }
+
+    /// Publishes a message to the subscribers of its topic, retaining it if it has the retain
+    /// flag, and returns how many subscriptions it was sent to
+    /// # Arguments
+    ///
+    /// * `publish` - The message to publish
+    ///
+    pub fn publish(&mut self, publish: &Publish) -> usize {
+        let subscriptions = get_receivers(
+            &mut self.topics,
+            &publish.topic_name,
+            &self.sessions,
+            &self.messages,
+        );
+        self.topics.update_topic(publish);
+        send_to_subscribers(publish, &subscriptions, &self.sessions, &mut self.messages);
+        subscriptions.len()
+    }
+}
+
+/// This struct represents a handle to the core thread, the only owner of the BrokerCore. Packet
+/// handlers and timers send it commands instead of sharing the state behind locks, and only
+/// wait for it while holding no other lock, so the broker cannot deadlock.
+#[derive(Clone)]
+pub struct CoreHandle {
+    commands: SyncSender<Command>,
+}
+
+impl CoreHandle {
+    /// Starts the core thread, which runs the commands in the order they are sent and stops
+    /// once every handle is dropped
+    /// # Arguments
+    ///
+    /// * `core` - The state owned by the thread
+    /// * `stats` - The broker counters, which measure how long the commands wait
+    ///
+    pub fn start(mut core: BrokerCore, stats: Arc<BrokerStats>) -> CoreHandle {
+        let (commands, received) = mpsc::sync_channel::<Command>(COMMAND_QUEUE_SIZE);
+        thread::spawn(move || {
+            // Commands may take the locks after the core, but never call back into it
+            let _held = lockorder::acquire(LockName::Core);
+            for (sent, command) in received {
+                stats.add_lock_wait(LockName::Core, sent.elapsed());
+                if panic::catch_unwind(AssertUnwindSafe(|| command(&mut core))).is_err() {
+                    event!(Level::ERROR, "CORE: A command panicked");
+                }
+            }
+        });
+        CoreHandle { commands }
+    }
+
+    /// Runs a command on the core thread and waits for its result. A panic of the command is
+    /// resumed in the caller.
+    /// # Arguments
+    ///
+    /// * `command` - The function given the BrokerCore
+    ///
+    pub fn call<R, F>(&self, command: F) -> R
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut BrokerCore) -> R + Send + 'static,
+    {
+        let _waiting = lockorder::acquire(LockName::Core);
+        let (reply, result) = mpsc::channel();
+        self.send(move |core| {
+            let _ = reply.send(panic::catch_unwind(AssertUnwindSafe(|| command(core))));
+        });
+        match result.recv() {
+            Ok(Ok(value)) => value,
+            Ok(Err(payload)) => panic::resume_unwind(payload),
+            Err(_) => panic!("The core thread stopped"),
+        }
+    }
+
+    /// Sends a command to the core thread without waiting for it to run. The commands of a
+    /// thread still run in the order they were sent.
+    /// # Arguments
+    ///
+    /// * `command` - The function given the BrokerCore
+    ///
+    pub fn cast<F>(&self, command: F)
+    where
+        F: FnOnce(&mut BrokerCore) + Send + 'static,
+    {
+        // Sending waits while the queue is full, so it follows the lock order too
+        let _waiting = lockorder::acquire(LockName::Core);
+        self.send(command);
+    }
+
+    fn send<F>(&self, command: F)
+    where
+        F: FnOnce(&mut BrokerCore) + Send + 'static,
+    {
+        if self
+            .commands
+            .send((Instant::now(), Box::new(command)))
+            .is_err()
+        {
+            event!(
+                Level::ERROR,
+                "CORE: The core thread stopped, command dropped"
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{ExpirySettings, SharePolicy};
+    use crate::lockorder::LockName;
+    use crate::managers::brokercore::{BrokerCore, CoreHandle};
+    use crate::managers::topicmanager::ClientSubscription;
+    use crate::stats::BrokerStats;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::Arc;
+
+    fn start_core() -> (CoreHandle, Arc<BrokerStats>) {
+        let stats = Arc::new(BrokerStats::new());
+        let core = BrokerCore::new(SharePolicy::RoundRobin, ExpirySettings::default());
+        (CoreHandle::start(core, Arc::clone(&stats)), stats)
+    }
+
+    #[test]
+    fn test_commands_run_in_order() {
+        let (core, stats) = start_core();
+        for filter in ["a", "b", "c"].iter() {
+            core.cast(move |core| {
+                core.topics
+                    .subscribe(filter, &ClientSubscription::new("sensor", 0))
+            });
+        }
+        core.cast(|core| core.topics.unsubscribe("b", "sensor"));
+        assert_eq!(core.call(|core| core.topics.subscription_count()), 2);
+        assert_eq!(stats.lock_acquisitions(LockName::Core), 5);
+    }
+
+    #[test]
+    fn test_panicking_command_does_not_stop_the_core() {
+        let (core, _) = start_core();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            core.call(|_| -> usize { panic!("broken command") })
+        }));
+        assert!(result.is_err());
+        assert_eq!(core.call(|core| core.sessions.session_count()), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Lock core taken while holding [\"core\"]")]
+    fn test_calling_the_core_from_a_command_panics_instead_of_deadlocking() {
+        let (core, _) = start_core();
+        let inner = core.clone();
+        core.call(move |_| inner.call(|core| core.sessions.session_count()));
+    }
+}
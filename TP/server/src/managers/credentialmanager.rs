@@ -1,6 +1,16 @@
 use std::collections::HashMap;
 
+/// This trait is implemented by the sources of credentials the broker checks clients against
+pub trait Authenticator: Send {
+    /// Returns whether a username and password are valid
+    fn is_valid(&self, username: &str, password: &str) -> bool;
+
+    /// Returns whether a user exists, used for clients authenticated by their certificate
+    fn has_username(&self, username: &str) -> bool;
+}
+
 /// This struct represents a storage of client id and their properties
+#[derive(Default)]
 pub struct CredentialManager {
     credentials: HashMap<String, String>,
 }
@@ -44,6 +54,16 @@ impl CredentialManager {
     }
 }
 
+impl Authenticator for CredentialManager {
+    fn is_valid(&self, username: &str, password: &str) -> bool {
+        CredentialManager::is_valid(self, username, password)
+    }
+
+    fn has_username(&self, username: &str) -> bool {
+        CredentialManager::has_username(self, username)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::managers::credentialmanager;
@@ -55,7 +75,7 @@ mod tests {
         let password = "pass".to_string();
 
         credential_manager.add_credential(&username, &password);
-        assert_eq!(credential_manager.is_valid(&username, &password), true);
+        assert!(credential_manager.is_valid(&username, &password));
     }
 
     #[test]
@@ -66,9 +86,6 @@ mod tests {
         let user_not_existing = "user_false".to_string();
 
         credential_manager.add_credential(&username, &password);
-        assert_eq!(
-            credential_manager.is_valid(&user_not_existing, &password),
-            false
-        );
+        assert!(!credential_manager.is_valid(&user_not_existing, &password));
     }
 }
@@ -0,0 +1,234 @@
+use serde_json::{json, Value};
+use shared::packages::publish::Publish;
+use std::fs;
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Prefix of the topics whose messages are published later, as $delayed/<seconds>/<topic>
+pub const DELAYED_PREFIX: &str = "$delayed/";
+
+/// Splits a $delayed/<seconds>/<topic> topic into its delay and the topic the message is
+/// published to. Returns None for topics that are not delayed and for invalid ones.
+/// # Arguments
+///
+/// * `topic` - A string slice containing the topic of the message
+///
+pub fn split_delayed_topic(topic: &str) -> Option<(Duration, &str)> {
+    let (seconds, topic) = topic.strip_prefix(DELAYED_PREFIX)?.split_once('/')?;
+    let seconds = seconds.parse::<u64>().ok()?;
+    if topic.is_empty() || topic.contains(['+', '#']) || topic.starts_with(DELAYED_PREFIX) {
+        return None;
+    }
+    Some((Duration::from_secs(seconds), topic))
+}
+
+/// This struct represents a message waiting to be published
+#[derive(Clone, Debug, PartialEq)]
+pub struct DelayedMessage {
+    /// The message, already addressed to its final topic
+    pub publish: Publish,
+    /// When the message is published. It is wall clock time, so it still holds after a restart
+    pub release_at: SystemTime,
+}
+
+/// This struct represents a storage of messages published after a delay
+pub struct DelayedManager {
+    messages: Vec<DelayedMessage>,
+    /// Whether the messages changed since take_changed was last called
+    changed: bool,
+}
+
+impl DelayedManager {
+    /// Returns an empty DelayedManager
+    pub fn new() -> DelayedManager {
+        DelayedManager {
+            messages: Vec::new(),
+            changed: false,
+        }
+    }
+
+    /// Keeps a message until it is due
+    /// # Arguments
+    ///
+    /// * `publish` - The message, addressed to the topic it is published to
+    /// * `release_at` - When the message is published
+    ///
+    pub fn add(&mut self, publish: Publish, release_at: SystemTime) {
+        self.messages.push(DelayedMessage {
+            publish,
+            release_at,
+        });
+        self.changed = true;
+    }
+
+    /// Removes the messages that are due and returns them, the earliest first
+    /// # Arguments
+    ///
+    /// * `now` - The current time
+    ///
+    pub fn take_due(&mut self, now: SystemTime) -> Vec<Publish> {
+        let (mut due, waiting): (Vec<DelayedMessage>, Vec<DelayedMessage>) = self
+            .messages
+            .drain(..)
+            .partition(|message| message.release_at <= now);
+        self.messages = waiting;
+        if due.is_empty() {
+            return Vec::new();
+        }
+        self.changed = true;
+        due.sort_by_key(|message| message.release_at);
+        due.into_iter().map(|message| message.publish).collect()
+    }
+
+    /// Returns the amount of messages waiting
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    /// Returns whether the messages changed since the last call
+    pub fn take_changed(&mut self) -> bool {
+        std::mem::replace(&mut self.changed, false)
+    }
+
+    /// Returns the messages as a JSON array
+    pub fn to_json(&self) -> String {
+        let messages: Vec<Value> = self
+            .messages
+            .iter()
+            .map(|message| {
+                let release_at = message
+                    .release_at
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default();
+                json!({
+                    "topic": message.publish.topic_name,
+                    "payload": message.publish.payload,
+                    "packet_id": message.publish.packet_id,
+                    "qos": message.publish.qos,
+                    "retain": message.publish.retain_flag == 1,
+                    "release_at_ms": release_at.as_millis() as u64,
+                })
+            })
+            .collect();
+        Value::Array(messages).to_string()
+    }
+
+    /// Returns the messages of a JSON array written by to_json
+    /// # Arguments
+    ///
+    /// * `contents` - A string slice containing the JSON array
+    ///
+    pub fn from_json(contents: &str) -> Result<DelayedManager, String> {
+        let value: Value = serde_json::from_str(contents).map_err(|e| e.to_string())?;
+        let mut delayed = DelayedManager::new();
+        for message in value.as_array().ok_or("Expected an array of messages")? {
+            let invalid = || format!("Invalid delayed message {}", message);
+            let publish = Publish {
+                topic_name: message["topic"].as_str().ok_or_else(invalid)?.to_string(),
+                payload: message["payload"].as_str().ok_or_else(invalid)?.to_string(),
+                packet_id: message["packet_id"].as_u64().ok_or_else(invalid)? as u16,
+                qos: message["qos"].as_u64().ok_or_else(invalid)? as u8,
+                retain_flag: message["retain"].as_bool().ok_or_else(invalid)? as u8,
+                dup_flag: 0,
+            };
+            let release_at_ms = message["release_at_ms"].as_u64().ok_or_else(invalid)?;
+            delayed.messages.push(DelayedMessage {
+                publish,
+                release_at: UNIX_EPOCH + Duration::from_millis(release_at_ms),
+            });
+        }
+        Ok(delayed)
+    }
+
+    /// Reads the messages saved in a file, none if the file does not exist yet
+    /// # Arguments
+    ///
+    /// * `filename` - The path of the file
+    ///
+    pub fn load(filename: &str) -> Result<DelayedManager, String> {
+        match fs::read_to_string(filename) {
+            Ok(contents) => DelayedManager::from_json(&contents),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(DelayedManager::new()),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    /// Writes the messages returned by to_json to a file, replacing it at once so a crash
+    /// never leaves half of it
+    /// # Arguments
+    ///
+    /// * `filename` - The path of the file
+    /// * `contents` - The messages as a JSON array
+    ///
+    pub fn save(filename: &str, contents: &str) -> io::Result<()> {
+        let temporary = format!("{}.tmp", filename);
+        fs::write(&temporary, contents)?;
+        fs::rename(&temporary, filename)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::managers::delayedmanager::{split_delayed_topic, DelayedManager};
+    use shared::packages::publish::Publish;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn publish(topic: &str) -> Publish {
+        Publish {
+            topic_name: topic.to_string(),
+            payload: "off".to_string(),
+            packet_id: 3,
+            qos: 1,
+            retain_flag: 1,
+            dup_flag: 0,
+        }
+    }
+
+    #[test]
+    fn test_split_delayed_topic() {
+        assert_eq!(
+            split_delayed_topic("$delayed/600/lights/kitchen"),
+            Some((Duration::from_secs(600), "lights/kitchen"))
+        );
+        assert_eq!(split_delayed_topic("lights/kitchen"), None);
+        assert_eq!(split_delayed_topic("$delayed/soon/lights"), None);
+        assert_eq!(split_delayed_topic("$delayed/10/"), None);
+        assert_eq!(split_delayed_topic("$delayed/10/lights/#"), None);
+    }
+
+    #[test]
+    fn test_take_due_messages() {
+        let start = UNIX_EPOCH + Duration::from_secs(1_000);
+        let mut sut = DelayedManager::new();
+        sut.add(publish("later"), start + Duration::from_secs(20));
+        sut.add(publish("soon"), start + Duration::from_secs(10));
+        assert!(sut.take_changed());
+        assert!(sut.take_due(start).is_empty());
+        assert!(!sut.take_changed());
+
+        let due = sut.take_due(start + Duration::from_secs(30));
+        let topics: Vec<&str> = due.iter().map(|p| p.topic_name.as_str()).collect();
+        assert_eq!(topics, vec!["soon", "later"]);
+        assert_eq!(sut.len(), 0);
+        assert!(sut.take_changed());
+    }
+
+    #[test]
+    fn test_messages_survive_a_save_and_load() {
+        let filename = std::env::temp_dir()
+            .join(format!("delayed-{}.json", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        let release_at = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        let mut sut = DelayedManager::new();
+        sut.add(publish("lights/kitchen"), release_at);
+
+        assert_eq!(DelayedManager::load(&filename).unwrap().len(), 0);
+        DelayedManager::save(&filename, &sut.to_json()).unwrap();
+        let mut loaded = DelayedManager::load(&filename).unwrap();
+        std::fs::remove_file(&filename).unwrap();
+        assert_eq!(loaded.take_due(release_at), vec![publish("lights/kitchen")]);
+
+        assert!(DelayedManager::from_json("[{\"topic\": 1}]").is_err());
+    }
+}
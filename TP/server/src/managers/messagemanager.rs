@@ -1,5 +1,6 @@
 use shared::packages::publish::Publish;
 use std::collections::HashMap;
+use std::time::Instant;
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct PendingMessage {
@@ -13,6 +14,10 @@ pub struct PendingMessage {
     pub qos: u8,
     /// A retain_flag that indicates if the message should be retained or not
     pub retain_flag: u8,
+    /// The shared subscription group the message was delivered through, if any
+    pub share_group: Option<String>,
+    /// When the broker received the message, its expiry counts from here
+    pub received_at: Instant,
 }
 
 impl PendingMessage {
@@ -23,6 +28,8 @@ impl PendingMessage {
             packet_id: packet.packet_id,
             qos: packet.qos,
             retain_flag: packet.retain_flag,
+            share_group: None,
+            received_at: Instant::now(),
         }
     }
 
@@ -59,10 +66,7 @@ impl MessageManager {
     /// * `message` - A pending message to re-send
     ///
     pub fn add_message(&mut self, client_id: &str, message: &PendingMessage) {
-        let messages = self
-            .messages
-            .entry(client_id.to_string())
-            .or_insert_with(Vec::new);
+        let messages = self.messages.entry(client_id.to_string()).or_default();
         messages.push(message.clone());
     }
 
@@ -80,7 +84,7 @@ impl MessageManager {
     ///
     /// * `client_id` - A string slice containing the client to get the messages for
     ///
-    fn _get_messages(&self, client_id: &str) -> Vec<PendingMessage> {
+    pub fn get_messages(&self, client_id: &str) -> Vec<PendingMessage> {
         if self._has_client(client_id) {
             self.messages[client_id].clone()
         } else {
@@ -104,10 +108,41 @@ impl MessageManager {
 
     /// Returns an iterator of clients and pending messages
     ///
-    pub fn get_all(&mut self) -> std::collections::hash_map::Iter<String, Vec<PendingMessage>> {
+    pub fn get_all(&mut self) -> std::collections::hash_map::Iter<'_, String, Vec<PendingMessage>> {
         self.messages.iter()
     }
 
+    /// Drops the messages that expired, returning how many there were
+    /// # Arguments
+    ///
+    /// * `is_expired` - Returns whether a message expired
+    ///
+    pub fn remove_expired(&mut self, is_expired: impl Fn(&PendingMessage) -> bool) -> usize {
+        let mut removed = 0;
+        for messages in self.messages.values_mut() {
+            let before = messages.len();
+            messages.retain(|message| !is_expired(message));
+            removed += before - messages.len();
+        }
+        removed
+    }
+
+    /// Returns the amount of messages waiting for an acknowledgement of a client
+    /// # Arguments
+    ///
+    /// * `client_id` - A string slice containing the client
+    ///
+    pub fn inflight_count(&self, client_id: &str) -> usize {
+        self.messages
+            .get(client_id)
+            .map_or(0, |messages| messages.len())
+    }
+
+    /// Returns the amount of messages waiting for an acknowledgement across all clients
+    pub fn pending_count(&self) -> usize {
+        self.messages.values().map(|messages| messages.len()).sum()
+    }
+
     /// Delete a client from the message manager
     /// # Arguments
     ///
@@ -124,12 +159,14 @@ impl MessageManager {
 mod tests {
     use crate::managers::messagemanager::MessageManager;
     use crate::managers::messagemanager::PendingMessage;
+    use std::time::Instant;
+
     #[test]
     fn test_add_message_successful() {
         let mut sut = MessageManager::new();
         let packet = get_dummy_publish();
         sut.add_message("some_client", &packet);
-        assert_eq!(sut._get_messages("some_client"), vec![packet])
+        assert_eq!(sut.get_messages("some_client"), vec![packet])
     }
 
     #[test]
@@ -139,10 +176,31 @@ mod tests {
         let client = "some_client";
         let packet_id = packet.packet_id;
         sut.add_message(client, &packet);
-        assert_eq!(sut._get_messages(client), vec![packet]);
+        assert_eq!(sut.get_messages(client), vec![packet]);
         sut.remove_message(client, packet_id);
     }
 
+    #[test]
+    fn test_remove_expired_messages() {
+        let mut sut = MessageManager::new();
+        let stale = get_dummy_publish();
+        let fresh = PendingMessage {
+            topic_name: "other_topic".to_string(),
+            packet_id: 2,
+            ..get_dummy_publish()
+        };
+        sut.add_message("some_client", &stale);
+        sut.add_message("some_client", &fresh);
+        sut.add_message("other_client", &stale);
+
+        assert_eq!(
+            sut.remove_expired(|message| message.topic_name == "some_topic"),
+            2
+        );
+        assert_eq!(sut.get_messages("some_client"), vec![fresh]);
+        assert_eq!(sut.pending_count(), 1);
+    }
+
     fn get_dummy_publish() -> PendingMessage {
         PendingMessage {
             topic_name: "some_topic".to_string(),
@@ -150,6 +208,8 @@ mod tests {
             packet_id: 1_u16,
             qos: 1_u8,
             retain_flag: 0_u8,
+            share_group: None,
+            received_at: Instant::now(),
         }
     }
 }
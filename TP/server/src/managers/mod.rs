@@ -1,4 +1,6 @@
+pub mod brokercore;
 pub mod credentialmanager;
+pub mod delayedmanager;
 pub mod messagemanager;
 pub mod sessionmanager;
 pub mod topicmanager;
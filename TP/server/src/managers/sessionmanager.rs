@@ -1,5 +1,5 @@
-use std::collections::HashMap;
-use std::net::TcpStream;
+use crate::transport::client_stream::{ClientStream, ConnectionId};
+use std::collections::{HashMap, HashSet};
 use tracing::{event, Level};
 
 #[derive(Debug, Clone)]
@@ -18,18 +18,24 @@ pub struct Session {
     pub client_id: String,
     pub socket: Socket,
     pub last_will_testament: Option<LastWillTestament>,
+    /// Whether the session ends with the connection, otherwise it waits for the client to return
+    pub clean_session: bool,
+    /// Packet ids of the QoS 1 messages received from the client, kept until a new message
+    /// reuses them, so a message it resends with the dup flag is not delivered twice
+    pub received_packet_ids: HashSet<u16>,
 }
 
 #[derive(Debug)]
 pub struct Socket {
-    pub stream: TcpStream,
-    pub peer: u16,
+    pub stream: ClientStream,
+    pub connection_id: ConnectionId,
 }
 
 /// This struct represents a storage of client id and their properties
 pub struct SessionManager {
     sessions: HashMap<String, Session>,
-    peer_client: HashMap<u16, String>,
+    /// The client id of each connection that sent a CONNECT
+    connection_client: HashMap<ConnectionId, String>,
 }
 
 impl SessionManager {
@@ -37,7 +43,7 @@ impl SessionManager {
     pub fn new() -> SessionManager {
         SessionManager {
             sessions: HashMap::new(),
-            peer_client: HashMap::new(),
+            connection_client: HashMap::new(),
         }
     }
 
@@ -46,36 +52,37 @@ impl SessionManager {
     ///
     /// * `client_id` - A string slice containing the client_id to add
     /// * `stream` - A stream
+    /// * `lwt` - The message published if the connection is lost
+    /// * `clean_session` - Whether the session ends with the connection
     ///
     pub fn add_client(
         &mut self,
         client_id: &str,
-        stream: TcpStream,
+        stream: ClientStream,
         lwt: Option<LastWillTestament>,
+        clean_session: bool,
     ) {
         match stream.try_clone() {
-            Ok(stream) => match stream.peer_addr() {
-                Ok(peer_address) => {
-                    let socket = Socket {
-                        stream,
-                        peer: peer_address.port(),
-                    };
-
-                    self.sessions.insert(
-                        client_id.to_string(),
-                        Session {
-                            client_id: client_id.to_string(),
-                            socket,
-                            last_will_testament: lwt,
-                        },
-                    );
-                    self.peer_client
-                        .insert(peer_address.port(), client_id.to_string());
-                }
-                Err(e) => {
-                    event!(Level::ERROR, "Client could not be added. Reason: {:?}", e)
-                }
-            },
+            Ok(stream) => {
+                let connection_id = stream.connection_id();
+                let socket = Socket {
+                    stream,
+                    connection_id,
+                };
+
+                self.sessions.insert(
+                    client_id.to_string(),
+                    Session {
+                        client_id: client_id.to_string(),
+                        socket,
+                        last_will_testament: lwt,
+                        clean_session,
+                        received_packet_ids: HashSet::new(),
+                    },
+                );
+                self.connection_client
+                    .insert(connection_id, client_id.to_string());
+            }
             Err(e) => {
                 event!(Level::ERROR, "Client could not be added. Reason: {:?}", e)
             }
@@ -91,13 +98,23 @@ impl SessionManager {
         self.sessions.contains_key(client_id)
     }
 
-    /// Checks if a given peer exists in the SessionManager
+    /// Returns the amount of sessions, connected or not
+    pub fn session_count(&self) -> usize {
+        self.sessions.len()
+    }
+
+    /// Returns an iterator over every session, connected or not
+    pub fn get_sessions(&self) -> std::collections::hash_map::Values<'_, String, Session> {
+        self.sessions.values()
+    }
+
+    /// Checks if a given connection belongs to a client of the SessionManager
     /// # Arguments
     ///
-    /// * `peer` - A string slice containing the cpeer to search
+    /// * `connection_id` - The id of the connection to search
     ///
-    pub fn has_peer(&self, peer: &u16) -> bool {
-        self.peer_client.contains_key(peer)
+    pub fn has_connection(&self, connection_id: &ConnectionId) -> bool {
+        self.connection_client.contains_key(connection_id)
     }
 
     /// Returns the stream
@@ -113,9 +130,11 @@ impl SessionManager {
                         client_id: session.client_id.to_string(),
                         socket: Socket {
                             stream: cloned_stream,
-                            peer: session.socket.peer,
+                            connection_id: session.socket.connection_id,
                         },
                         last_will_testament: session.last_will_testament.clone(),
+                        clean_session: session.clean_session,
+                        received_packet_ids: session.received_packet_ids.clone(),
                     }),
                     Err(e) => {
                         event!(
@@ -133,16 +152,27 @@ impl SessionManager {
         }
     }
 
+    /// Returns the connection of a client, without cloning it
+    /// # Arguments
+    ///
+    /// * `client_id` - A string slice containing the client_id to get the stream for
+    ///
+    pub fn get_stream(&self, client_id: &str) -> Option<&ClientStream> {
+        self.sessions
+            .get(client_id)
+            .map(|session| &session.socket.stream)
+    }
+
     /// Returns the clientid
     /// # Arguments
     ///
-    /// * `peer` - A string slice containing the peer to get the clientid for
+    /// * `connection_id` - The id of the connection to get the clientid for
     ///
-    pub fn get_client_id(&self, peer: &u16) -> Result<String, String> {
-        if self.has_peer(peer) {
-            match self.peer_client.get(peer) {
-                Some(peer) => Ok(peer.to_string()),
-                _ => Err("This client does not have an active peer".to_string()),
+    pub fn get_client_id(&self, connection_id: &ConnectionId) -> Result<String, String> {
+        if self.has_connection(connection_id) {
+            match self.connection_client.get(connection_id) {
+                Some(client_id) => Ok(client_id.to_string()),
+                _ => Err("This client does not have an active connection".to_string()),
             }
         } else {
             Err("Failed getting client id...".to_string())
@@ -156,21 +186,10 @@ impl SessionManager {
     ///
     pub fn delete(&mut self, client_id: &str) {
         if self.has_client(client_id) {
-            match self.sessions.get(client_id) {
-                Some(session) => match session.socket.stream.try_clone() {
-                    Ok(stream) => match stream.peer_addr() {
-                        Ok(peer) => {
-                            self.sessions.remove(client_id);
-                            self.peer_client.remove(&peer.port());
-                        }
-                        Err(e) => {
-                            event!(Level::ERROR, "There was a problem in delete {:?}", e);
-                        }
-                    },
-                    Err(e) => {
-                        event!(Level::ERROR, "There was a problem in delete {:?}", e);
-                    }
-                },
+            match self.sessions.remove(client_id) {
+                Some(session) => {
+                    self.connection_client.remove(&session.socket.connection_id);
+                }
                 None => {
                     event!(Level::ERROR, "There was a problem in delete");
                 }
@@ -182,23 +201,21 @@ impl SessionManager {
     /// # Arguments
     ///
     /// * `client_id` - A string slice containing the client id
-    /// * `stream` - A TcpStream
+    /// * `stream` - A ClientStream
+    /// * `lwt` - The message published if the new connection is lost
     ///
-    pub fn replace_stream(&mut self, client_id: &str, new_stream: TcpStream) {
+    pub fn replace_stream(
+        &mut self,
+        client_id: &str,
+        new_stream: ClientStream,
+        lwt: Option<LastWillTestament>,
+    ) {
         if self.has_client(client_id) {
             match self.sessions.get_mut(client_id) {
                 Some(session) => {
-                    self.peer_client.remove(&session.socket.peer);
-
-                    match new_stream.peer_addr() {
-                        Ok(peer_address) => {
-                            self.peer_client
-                                .insert(peer_address.port(), client_id.to_string());
-                        }
-                        Err(e) => {
-                            event!(Level::ERROR, "Client could not be replace. Reason: {:?}", e);
-                        }
-                    }
+                    self.connection_client.remove(&session.socket.connection_id);
+                    self.connection_client
+                        .insert(new_stream.connection_id(), client_id.to_string());
                 }
                 _ => event!(
                     Level::ERROR,
@@ -208,24 +225,86 @@ impl SessionManager {
             }
 
             if let Some(removed_session) = self.sessions.remove(client_id) {
-                let last_will_testament = removed_session.last_will_testament;
-                self.add_client(client_id, new_stream, last_will_testament);
+                self.add_client(client_id, new_stream, lwt, removed_session.clean_session);
+                if let Some(session) = self.sessions.get_mut(client_id) {
+                    session.received_packet_ids = removed_session.received_packet_ids;
+                }
             }
         } else {
             event!(Level::ERROR, "The client {:?} does not exist", client_id);
         }
     }
 
-    /// Get the old peer associate to client
+    /// Removes the will of a client and returns it, so it is published at most once
+    /// # Arguments
+    ///
+    /// * `client_id` - A string slice containing the client id
+    ///
+    pub fn take_last_will(&mut self, client_id: &str) -> Option<LastWillTestament> {
+        self.sessions
+            .get_mut(client_id)
+            .and_then(|session| session.last_will_testament.take())
+    }
+
+    /// Records a QoS 1 message received from a client and returns whether it is a duplicate: a
+    /// message resent with the dup flag whose packet id was already received. A message
+    /// without the dup flag is always new, it takes over the packet id of the previous one.
+    /// # Arguments
+    ///
+    /// * `client_id` - A string slice containing the client id
+    /// * `packet_id` - The packet id of the message
+    /// * `dup_flag` - Whether the client flagged the message as a resend
+    ///
+    pub fn is_duplicate_publish(&mut self, client_id: &str, packet_id: u16, dup_flag: u8) -> bool {
+        match self.sessions.get_mut(client_id) {
+            Some(session) => !session.received_packet_ids.insert(packet_id) && dup_flag == 1,
+            None => false,
+        }
+    }
+
+    /// Forgets the packet id of a QoS 1 message that was dropped without a PUBACK, so its
+    /// resend is delivered even if it has the dup flag
+    /// # Arguments
+    ///
+    /// * `client_id` - A string slice containing the client id
+    /// * `packet_id` - The packet id of the acknowledged message
+    ///
+    pub fn release_packet_id(&mut self, client_id: &str, packet_id: u16) {
+        if let Some(session) = self.sessions.get_mut(client_id) {
+            session.received_packet_ids.remove(&packet_id);
+        }
+    }
+
+    /// Checks if the session of a client outlives its connection
+    /// # Arguments
+    ///
+    /// * `client_id` - A string slice containing the client id
+    ///
+    pub fn is_persistent(&self, client_id: &str) -> bool {
+        self.sessions
+            .get(client_id)
+            .is_some_and(|session| !session.clean_session)
+    }
+
+    /// Forgets a closed connection, the session it belonged to is kept
+    /// # Arguments
+    ///
+    /// * `connection_id` - The id of the closed connection
+    ///
+    pub fn remove_connection(&mut self, connection_id: &ConnectionId) {
+        self.connection_client.remove(connection_id);
+    }
+
+    /// Get the old connection associate to client
     /// # Arguments
     ///
     /// * `client_id` - A string slice containing the client id
     ///
-    pub fn get_old_peer(&mut self, client_id: &str) -> Result<u16, String> {
+    pub fn get_old_connection(&mut self, client_id: &str) -> Result<ConnectionId, String> {
         if self.has_client(client_id) {
             match self.sessions.get(client_id) {
-                Some(session) => Ok(session.socket.peer),
-                None => Err("Get old peer failed.".to_string()),
+                Some(session) => Ok(session.socket.connection_id),
+                None => Err("Get old connection failed.".to_string()),
             }
         } else {
             Err("The client id doesn't exist".to_string())
@@ -1,6 +1,9 @@
+use crate::config::SharePolicy;
+use crate::managers::delayedmanager::DELAYED_PREFIX;
 use shared::packages::publish::Publish;
 use std::collections::HashMap;
 use std::fmt;
+use std::time::Instant;
 
 /// This struct represents an client subscription
 #[derive(Clone, Debug, PartialEq)]
@@ -9,15 +12,24 @@ pub struct ClientSubscription {
     pub client_id: String,
     /// ClientSubscription has a Quality of Service
     pub qos: u8,
+    /// The group of a $share/<group>/<filter> subscription, which gets each message once
+    pub share_group: Option<String>,
 }
 
 impl fmt::Display for ClientSubscription {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "(Subscription for client {}, qos {})",
-            self.client_id, self.qos
-        )
+        match &self.share_group {
+            Some(group) => write!(
+                f,
+                "(Subscription for client {} in group {}, qos {})",
+                self.client_id, group, self.qos
+            ),
+            None => write!(
+                f,
+                "(Subscription for client {}, qos {})",
+                self.client_id, self.qos
+            ),
+        }
     }
 }
 
@@ -27,8 +39,99 @@ impl ClientSubscription {
         ClientSubscription {
             client_id: client_id.to_string(),
             qos,
+            share_group: None,
         }
     }
+
+    /// Creates a subscription that shares the messages with the rest of its group
+    pub fn shared(client_id: &str, qos: u8, group: &str) -> ClientSubscription {
+        ClientSubscription {
+            client_id: client_id.to_string(),
+            qos,
+            share_group: Some(group.to_string()),
+        }
+    }
+}
+
+/// Prefix of the filters that share their messages among a group of clients
+pub const SHARED_PREFIX: &str = "$share/";
+
+/// Splits a $share/<group>/<filter> subscription into its group and filter. Returns None for
+/// filters that are not shared and for shared ones without a valid group or filter.
+/// # Arguments
+///
+/// * `filter` - A string slice containing the filter sent by the client
+///
+pub fn split_shared_filter(filter: &str) -> Option<(&str, &str)> {
+    let (group, filter) = filter.strip_prefix(SHARED_PREFIX)?.split_once('/')?;
+    if group.is_empty() || group.contains(['+', '#']) || filter.is_empty() {
+        return None;
+    }
+    Some((group, filter))
+}
+
+/// Prefix of the topics of each tenant, as tenant/<name>/<topic>
+pub const TENANT_PREFIX: &str = "tenant/";
+
+/// This struct represents the namespace a client is confined to. Its topics are prefixed with
+/// tenant/<name>/ when it publishes or subscribes and stripped when it receives a message, so
+/// its wildcards never reach the topics of other tenants.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MountPoint {
+    prefix: String,
+}
+
+impl MountPoint {
+    /// Returns the MountPoint of a tenant, None if its name is empty or has a / or a wildcard
+    /// # Arguments
+    ///
+    /// * `tenant` - A string slice containing the name of the tenant
+    ///
+    pub fn new(tenant: &str) -> Option<MountPoint> {
+        if tenant.is_empty() || tenant.contains(['/', '+', '#']) {
+            return None;
+        }
+        Some(MountPoint {
+            prefix: format!("{}{}/", TENANT_PREFIX, tenant),
+        })
+    }
+
+    /// Returns the topic or filter with the prefix of the tenant. Shared filters keep their
+    /// $share/<group>/ and delayed topics their $delayed/<seconds>/ in front of it. Returns None
+    /// for invalid shared filters, which have no place in the namespace to be mounted at.
+    /// # Arguments
+    ///
+    /// * `topic` - A string slice containing the topic or filter sent by the client
+    ///
+    pub fn mount(&self, topic: &str) -> Option<String> {
+        if topic.starts_with(SHARED_PREFIX) {
+            let (group, filter) = split_shared_filter(topic)?;
+            return Some(format!(
+                "{}{}/{}{}",
+                SHARED_PREFIX, group, self.prefix, filter
+            ));
+        }
+        if let Some((delay, topic)) = topic
+            .strip_prefix(DELAYED_PREFIX)
+            .and_then(|rest| rest.split_once('/'))
+        {
+            return Some(format!(
+                "{}{}/{}{}",
+                DELAYED_PREFIX, delay, self.prefix, topic
+            ));
+        }
+        Some(format!("{}{}", self.prefix, topic))
+    }
+
+    /// Returns the topic of a message without the prefix of the tenant, None if the topic is
+    /// outside the namespace of the tenant
+    /// # Arguments
+    ///
+    /// * `topic` - A string slice containing the mounted topic
+    ///
+    pub fn unmount<'a>(&self, topic: &'a str) -> Option<&'a str> {
+        topic.strip_prefix(&self.prefix)
+    }
 }
 
 /// This struct represents a retained message
@@ -40,6 +143,8 @@ pub struct RetainedMessage {
     pub message: String,
     /// A numeric packet identifier
     pub packet_id: u16,
+    /// When the broker received the message, its expiry counts from here
+    pub received_at: Instant,
 }
 
 impl RetainedMessage {
@@ -48,6 +153,7 @@ impl RetainedMessage {
             topic_name: packet.topic_name.to_owned(),
             message: packet.payload.to_owned(),
             packet_id: packet.packet_id,
+            received_at: Instant::now(),
         }
     }
 
@@ -68,8 +174,6 @@ impl RetainedMessage {
 pub struct Topic {
     /// Topic has its name
     pub name: String,
-    /// Topic has ClientSubscriptions that represent clients subscribed to it
-    pub subscriptions: Vec<ClientSubscription>,
     /// Topic might have a retained message
     pub retained_message: Option<RetainedMessage>,
 }
@@ -79,7 +183,6 @@ impl Topic {
     pub fn new(name: String) -> Topic {
         Topic {
             name,
-            subscriptions: Vec::new(),
             retained_message: None,
         }
     }
@@ -87,8 +190,16 @@ impl Topic {
 
 /// This struct represents a storage of topics and their subscribed clients
 pub struct TopicManager {
-    /// Topics have their name and their clients
+    /// Topics have their name and their retained message
     topics: HashMap<String, Topic>,
+    /// Each subscription with the filter it was made with, in the order they were made.
+    /// Filters are matched against the topic of every message, so they also cover topics
+    /// created after the subscription
+    subscriptions: Vec<(String, ClientSubscription)>,
+    /// Position of the next member of each shared subscription group to receive a message
+    share_cursors: HashMap<String, usize>,
+    /// How a member of a shared subscription group is chosen for each message
+    share_policy: SharePolicy,
 }
 
 impl TopicManager {
@@ -96,70 +207,40 @@ impl TopicManager {
     pub fn new() -> TopicManager {
         TopicManager {
             topics: HashMap::new(),
+            subscriptions: Vec::new(),
+            share_cursors: HashMap::new(),
+            share_policy: SharePolicy::RoundRobin,
         }
     }
 
-    /// Subscribes a client to a topic or multiple topics if the client used wildcards
-    /// Creates each topic if needed
-    ///
-    /// # Arguments
-    ///
-    /// * `topic` - A string slice containing the topic to subscribe
-    /// * `client` - A string slice containing the client that will subscribe to the topic
-    ///
-    pub fn subscribe(&mut self, topic: &str, subscription: &ClientSubscription) {
-        if topic == "#" || topic == "/#" {
-            for available_topic in &self.get_topics_available() {
-                self.subscribe_to_topic(available_topic, subscription);
-            }
-        } else if topic.contains('#') {
-            let available_topics = self.get_topics_available();
-            let topic_substring = &mut topic.split('#');
-            let topic = topic_substring.next().unwrap();
-
-            for available_topic in &available_topics {
-                if available_topic.starts_with(topic) {
-                    self.subscribe_to_topic(available_topic, subscription);
-                }
-            }
-        } else if topic.contains('+') {
-            let available_topics = self.get_topics_available();
-            let topic_substring = &mut topic.split('+');
-            let topic_first_half = topic_substring.next().unwrap();
-            let topic_second_half = topic_substring.next().unwrap();
-
-            for available_topic in &available_topics {
-                if available_topic.starts_with(topic_first_half)
-                    && available_topic.ends_with(topic_second_half)
-                {
-                    self.subscribe_to_topic(available_topic, subscription);
-                }
-            }
-        } else {
-            self.subscribe_to_topic(topic, subscription);
-        }
+    pub fn set_share_policy(&mut self, share_policy: SharePolicy) {
+        self.share_policy = share_policy;
     }
 
-    /// Private function that subscribes a client to a specific topic and creates the topic if needed
+    /// Subscribes a client to a topic filter, replacing the qos if the client already
+    /// subscribed with the same filter. Creates the topic if the filter has no wildcards
+    ///
     /// # Arguments
     ///
-    /// * `topic` - A string slice containing the topic to subscribe
-    /// * `client` - A string slice containing the client that will subscribe to the topic
+    /// * `filter` - A string slice containing the topic filter to subscribe
+    /// * `subscription` - The client subscribing and the qos it requested
     ///
-    fn subscribe_to_topic(&mut self, topic: &str, subscription: &ClientSubscription) {
-        let clients = self
-            .topics
-            .entry(topic.to_string())
-            .or_insert_with(|| Topic::new(topic.to_string()));
+    pub fn subscribe(&mut self, filter: &str, subscription: &ClientSubscription) {
+        if !filter.contains(['+', '#']) && !self.has_topic(filter) {
+            self.topics
+                .insert(filter.to_string(), Topic::new(filter.to_string()));
+        }
 
-        if let Some(position) = clients
-            .subscriptions
-            .iter()
-            .position(|sub| sub.client_id == subscription.client_id)
-        {
-            clients.subscriptions.remove(position);
+        match self.subscriptions.iter_mut().find(|(sub_filter, sub)| {
+            sub_filter == filter
+                && sub.client_id == subscription.client_id
+                && sub.share_group == subscription.share_group
+        }) {
+            Some((_, existing)) => existing.qos = subscription.qos,
+            None => self
+                .subscriptions
+                .push((filter.to_string(), subscription.clone())),
         }
-        clients.subscriptions.push(subscription.clone());
     }
 
     /// Checks if a given topic exists in the TopicManager
@@ -171,52 +252,181 @@ impl TopicManager {
         self.topics.contains_key(topic)
     }
 
-    /// Returns the clients subscribed to a given topic
+    /// Returns the clients subscribed to a given topic. A client whose filters overlap
+    /// gets a single subscription with the highest qos among them
     /// # Arguments
     ///
     /// * `topic` - A string slice containing the topic to get the clients for
     ///
     pub fn get_subscriptions(&self, topic: &str) -> Vec<ClientSubscription> {
-        if self.has_topic(topic) {
-            self.topics[topic].subscriptions.clone()
-        } else {
-            Vec::new()
+        let mut matching: Vec<ClientSubscription> = Vec::new();
+        for (filter, subscription) in self.subscriptions.iter() {
+            if !topic_matches(filter, topic) {
+                continue;
+            }
+            match matching.iter_mut().find(|sub| {
+                sub.client_id == subscription.client_id
+                    && sub.share_group == subscription.share_group
+            }) {
+                Some(existing) => existing.qos = existing.qos.max(subscription.qos),
+                None => matching.push(subscription.clone()),
+            }
         }
+        matching
     }
 
-    /// Returns all topics that the specified client is subscribed to
+    /// Returns the subscriptions a message published to a topic is delivered to: every
+    /// subscription that is not shared, and one member of each shared subscription group
     /// # Arguments
     ///
-    /// * `client_id` - client identifier
+    /// * `topic` - A string slice containing the topic of the message
+    /// * `load` - Returns the messages a client has not acknowledged, None if it is not connected
     ///
-    pub fn get_client_subscriptions(&self, client_id: &str) -> Vec<String> {
-        let mut client_topics = Vec::new();
-        for (topic_name, topic) in self.topics.iter() {
-            if topic.subscriptions.iter().any(|s| s.client_id == client_id) {
-                client_topics.push(topic_name.to_owned());
+    pub fn get_receivers(
+        &mut self,
+        topic: &str,
+        load: impl Fn(&str) -> Option<usize>,
+    ) -> Vec<ClientSubscription> {
+        let subscriptions = self.get_subscriptions(topic);
+        let mut receivers = Vec::new();
+        let mut groups = Vec::new();
+        for subscription in subscriptions {
+            match &subscription.share_group {
+                None => receivers.push(subscription),
+                Some(group) if !groups.contains(group) => groups.push(group.to_string()),
+                Some(_) => {}
             }
         }
-
-        client_topics
+        for group in groups {
+            if let Some(member) = self.pick_group_member(topic, &group, &load) {
+                receivers.push(member);
+            }
+        }
+        receivers
     }
 
-    /// Unsubscribes a client from the given topic
+    /// Returns the member of a shared subscription group that gets the next message of a topic,
+    /// or None when no member is connected
     /// # Arguments
     ///
-    /// * `topic` - A string slice containing the topic to unsubscribe a client
-    /// * `client_to_unsubscribe` - A string slice containing the client to unsubscribe
+    /// * `topic` - A string slice containing the topic of the message
+    /// * `group` - A string slice containing the shared subscription group
+    /// * `load` - Returns the messages a client has not acknowledged, None if it is not connected
     ///
-    pub fn unsubscribe(&mut self, topic: &str, client_to_unsubscribe: &str) {
-        // aca puede ir un Result o un enum que represente el resultado de la operacion
-        if let Some(clients) = self.topics.get_mut(topic) {
-            if let Some(index) = clients
-                .subscriptions
-                .iter()
-                .position(|cl| cl.client_id == client_to_unsubscribe)
-            {
-                clients.subscriptions.remove(index);
+    pub fn pick_group_member(
+        &mut self,
+        topic: &str,
+        group: &str,
+        load: impl Fn(&str) -> Option<usize>,
+    ) -> Option<ClientSubscription> {
+        let share_policy = self.share_policy;
+        let members: Vec<ClientSubscription> = self
+            .get_subscriptions(topic)
+            .into_iter()
+            .filter(|sub| sub.share_group.as_deref() == Some(group))
+            .collect();
+        if members.is_empty() {
+            return None;
+        }
+
+        let cursor = self.share_cursors.get(group).copied().unwrap_or(0);
+        let mut chosen: Option<(usize, usize)> = None;
+        // Members are tried in turn from the cursor, so ties go to the next one in the rotation
+        for offset in 0..members.len() {
+            let index = (cursor + offset) % members.len();
+            let member_load = match load(&members[index].client_id) {
+                Some(member_load) => member_load,
+                None => continue,
+            };
+            match (share_policy, chosen) {
+                (SharePolicy::RoundRobin, None) => {
+                    chosen = Some((index, member_load));
+                    break;
+                }
+                (SharePolicy::LeastInflight, None) => chosen = Some((index, member_load)),
+                (SharePolicy::LeastInflight, Some((_, lowest))) if member_load < lowest => {
+                    chosen = Some((index, member_load))
+                }
+                _ => {}
             }
         }
+
+        let (index, _) = chosen?;
+        let member = members[index].clone();
+        self.share_cursors
+            .insert(group.to_string(), (index + 1) % members.len());
+        Some(member)
+    }
+
+    /// Returns the filters the specified client subscribed with, shared ones with their
+    /// $share/<group>/ prefix
+    /// # Arguments
+    ///
+    /// * `client_id` - client identifier
+    ///
+    pub fn get_client_subscriptions(&self, client_id: &str) -> Vec<String> {
+        self.get_client_filters(client_id)
+            .into_iter()
+            .map(|(filter, _)| filter)
+            .collect()
+    }
+
+    /// Returns the filters the specified client subscribed with along with each subscription
+    /// # Arguments
+    ///
+    /// * `client_id` - client identifier
+    ///
+    pub fn get_client_filters(&self, client_id: &str) -> Vec<(String, ClientSubscription)> {
+        self.subscriptions
+            .iter()
+            .filter(|(_, sub)| sub.client_id == client_id)
+            .map(|(filter, sub)| match &sub.share_group {
+                Some(group) => (
+                    format!("{}{}/{}", SHARED_PREFIX, group, filter),
+                    sub.clone(),
+                ),
+                None => (filter.to_owned(), sub.clone()),
+            })
+            .collect()
+    }
+
+    /// Removes the subscription a client made with exactly the given filter
+    /// # Arguments
+    ///
+    /// * `filter` - A string slice containing the filter the client subscribed with
+    /// * `client_to_unsubscribe` - A string slice containing the client to unsubscribe
+    ///
+    pub fn unsubscribe(&mut self, filter: &str, client_to_unsubscribe: &str) {
+        self.remove_subscription(filter, client_to_unsubscribe, None);
+    }
+
+    /// Removes the subscription a client made with a filter as a member of a shared subscription group
+    /// # Arguments
+    ///
+    /// * `filter` - A string slice containing the filter the client subscribed with
+    /// * `client_to_unsubscribe` - A string slice containing the client to unsubscribe
+    /// * `group` - A string slice containing the shared subscription group
+    ///
+    pub fn unsubscribe_shared(&mut self, filter: &str, client_to_unsubscribe: &str, group: &str) {
+        self.remove_subscription(filter, client_to_unsubscribe, Some(group));
+    }
+
+    fn remove_subscription(&mut self, filter: &str, client_id: &str, group: Option<&str>) {
+        self.subscriptions.retain(|(sub_filter, sub)| {
+            !(sub_filter == filter
+                && sub.client_id == client_id
+                && sub.share_group.as_deref() == group)
+        });
+    }
+
+    /// Removes every subscription of a client, shared or not
+    /// # Arguments
+    ///
+    /// * `client_id` - A string slice containing the client to unsubscribe
+    ///
+    pub fn remove_client(&mut self, client_id: &str) {
+        self.subscriptions
+            .retain(|(_, sub)| sub.client_id != client_id);
     }
 
     pub fn get_topics_available(&mut self) -> Vec<String> {
@@ -242,6 +452,19 @@ impl TopicManager {
         }
     }
 
+    /// Returns the amount of topics that have a retained message
+    pub fn retained_count(&self) -> usize {
+        self.topics
+            .values()
+            .filter(|topic| topic.retained_message.is_some())
+            .count()
+    }
+
+    /// Returns the amount of subscriptions, one for each client and filter
+    pub fn subscription_count(&self) -> usize {
+        self.subscriptions.len()
+    }
+
     /// Get the retained message for a topic
     ///
     /// # Arguments
@@ -254,10 +477,84 @@ impl TopicManager {
             None => None,
         }
     }
+
+    /// Returns the retained messages of the topics a filter matches, sorted by topic
+    ///
+    /// # Arguments
+    ///
+    /// * `filter` - A string slice containing the topic filter
+    ///
+    pub fn get_retained_messages(&self, filter: &str) -> Vec<RetainedMessage> {
+        let mut retained: Vec<RetainedMessage> = self
+            .topics
+            .values()
+            .filter(|topic| topic_matches(filter, &topic.name))
+            .filter_map(|topic| topic.retained_message.clone())
+            .collect();
+        retained.sort_by(|a, b| a.topic_name.cmp(&b.topic_name));
+        retained
+    }
+
+    /// Drops the retained messages that expired, returning how many there were
+    /// # Arguments
+    ///
+    /// * `is_expired` - Returns whether a retained message expired
+    ///
+    pub fn remove_expired_retained(
+        &mut self,
+        is_expired: impl Fn(&RetainedMessage) -> bool,
+    ) -> usize {
+        let mut removed = 0;
+        for topic in self.topics.values_mut() {
+            if topic.retained_message.as_ref().is_some_and(&is_expired) {
+                topic.retained_message = None;
+                removed += 1;
+            }
+        }
+        removed
+    }
+
+    /// Removes the retained message of a topic, returning whether there was one
+    ///
+    /// # Arguments
+    ///
+    /// * `topic` - A string slice containing the topic to clear
+    ///
+    pub fn remove_retained_message(&mut self, topic: &str) -> bool {
+        match self.topics.get_mut(topic) {
+            Some(topic) => topic.retained_message.take().is_some(),
+            None => false,
+        }
+    }
+}
+
+/// Returns whether a topic matches a filter with the + and # wildcards. Filters starting
+/// with a wildcard do not match topics starting with $, such as the $SYS topics
+/// # Arguments
+///
+/// * `filter` - A string slice containing the topic filter
+/// * `topic` - A string slice containing the topic name
+///
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+    let mut filter_levels = filter.split('/');
+    let mut topic_levels = topic.split('/');
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => {}
+            (Some(filter_level), Some(topic_level)) if filter_level == topic_level => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::config::SharePolicy;
     use crate::managers::topicmanager;
     use crate::managers::topicmanager::RetainedMessage;
     use shared::packages::publish::Publish;
@@ -267,7 +564,7 @@ mod tests {
         let mut sut = topicmanager::TopicManager::new();
         let client_sub = topicmanager::ClientSubscription::new("someclient", 0);
         sut.subscribe("sometopic", &client_sub);
-        assert_eq!(sut.has_topic("sometopic"), true);
+        assert!(sut.has_topic("sometopic"));
         assert_eq!(sut.get_subscriptions("sometopic"), vec![client_sub])
     }
 
@@ -277,7 +574,7 @@ mod tests {
         let client_sub = topicmanager::ClientSubscription::new("someclient", 0);
         sut.subscribe("sometopic", &client_sub);
         sut.subscribe("sometopic", &client_sub);
-        assert_eq!(sut.has_topic("sometopic"), true);
+        assert!(sut.has_topic("sometopic"));
         assert_eq!(sut.get_subscriptions("sometopic"), vec![client_sub])
     }
 
@@ -307,9 +604,9 @@ mod tests {
     fn test_has_topic() {
         let mut sut = topicmanager::TopicManager::new();
         let client_sub = topicmanager::ClientSubscription::new("someclient", 0);
-        assert_eq!(sut.has_topic("sometopic"), false);
+        assert!(!sut.has_topic("sometopic"));
         sut.subscribe("sometopic", &client_sub);
-        assert_eq!(sut.has_topic("sometopic"), true);
+        assert!(sut.has_topic("sometopic"));
     }
 
     #[test]
@@ -356,15 +653,51 @@ mod tests {
             dup_flag: 0_u8,
         };
         topic_manager.update_topic(&source_packet);
+        let retained_message = topic_manager.get_retained_message(TOPIC_NAME).unwrap();
         let expected_retained_message = RetainedMessage {
             topic_name: TOPIC_NAME.to_owned(),
             message: MESSAGE.to_owned(),
             packet_id: PACKET_ID,
+            received_at: retained_message.received_at,
         };
-        assert_eq!(
-            topic_manager.get_retained_message(TOPIC_NAME),
-            Some(expected_retained_message)
-        )
+        assert_eq!(retained_message, expected_retained_message)
+    }
+
+    #[test]
+    fn test_remove_retained_message() {
+        let mut topic_manager = topicmanager::TopicManager::new();
+        topic_manager.update_topic(&Publish {
+            topic_name: "/foo".to_owned(),
+            payload: "est".to_owned(),
+            packet_id: 1_u16,
+            qos: 0_u8,
+            retain_flag: 1_u8,
+            dup_flag: 0_u8,
+        });
+        assert!(topic_manager.remove_retained_message("/foo"));
+        assert_eq!(topic_manager.get_retained_message("/foo"), None);
+        assert!(topic_manager.has_topic("/foo"));
+        assert!(!topic_manager.remove_retained_message("/foo"));
+    }
+
+    #[test]
+    fn test_remove_expired_retained_messages() {
+        let mut topic_manager = topicmanager::TopicManager::new();
+        for topic in ["sensors/a", "sensors/b", "logs"].iter() {
+            topic_manager.update_topic(&Publish {
+                topic_name: topic.to_string(),
+                payload: "est".to_owned(),
+                packet_id: 1_u16,
+                qos: 0_u8,
+                retain_flag: 1_u8,
+                dup_flag: 0_u8,
+            });
+        }
+        let removed = topic_manager
+            .remove_expired_retained(|message| message.topic_name.starts_with("sensors/"));
+        assert_eq!(removed, 2);
+        assert_eq!(topic_manager.retained_count(), 1);
+        assert!(topic_manager.get_retained_message("logs").is_some());
     }
 
     #[test]
@@ -396,6 +729,138 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_unsubscribe_removes_exactly_the_filter() {
+        let mut sut = topicmanager::TopicManager::new();
+        let wildcard_sub = topicmanager::ClientSubscription::new("someclient", 0);
+        let exact_sub = topicmanager::ClientSubscription::new("someclient", 1);
+        sut.subscribe("sensors/#", &wildcard_sub);
+        sut.subscribe("sensors/temp", &exact_sub);
+        assert_eq!(
+            sut.get_client_subscriptions("someclient"),
+            vec!["sensors/#".to_string(), "sensors/temp".to_string()]
+        );
+        // Overlapping filters deliver once with the highest qos, also to topics created later
+        assert_eq!(sut.get_subscriptions("sensors/temp"), vec![exact_sub]);
+        assert_eq!(
+            sut.get_subscriptions("sensors/humidity"),
+            vec![wildcard_sub.clone()]
+        );
+
+        sut.unsubscribe("sensors/temp", "someclient");
+        assert_eq!(
+            sut.get_subscriptions("sensors/temp"),
+            vec![wildcard_sub.clone()]
+        );
+        sut.unsubscribe("sensors/#", "someclient");
+        assert_eq!(sut.get_subscriptions("sensors/humidity"), vec![]);
+        assert!(sut.get_client_subscriptions("someclient").is_empty());
+    }
+
+    #[test]
+    fn test_wildcards_do_not_match_system_topics() {
+        let mut sut = topicmanager::TopicManager::new();
+        let client_sub = topicmanager::ClientSubscription::new("someclient", 0);
+        for topic in [
+            "$SYS/broker/uptime",
+            "$SYS/broker/clients/total",
+            "home/temperature",
+        ] {
+            sut.update_topic(&Publish {
+                topic_name: topic.to_string(),
+                payload: "1".to_string(),
+                packet_id: 0_u16,
+                qos: 0_u8,
+                retain_flag: 1_u8,
+                dup_flag: 0_u8,
+            });
+        }
+
+        sut.subscribe("#", &client_sub);
+        sut.subscribe("+/broker/uptime", &client_sub);
+        assert_eq!(sut.get_subscriptions("$SYS/broker/uptime"), vec![]);
+        assert_eq!(
+            sut.get_subscriptions("home/temperature"),
+            vec![client_sub.clone()]
+        );
+
+        sut.subscribe("$SYS/broker/#", &client_sub);
+        assert_eq!(
+            sut.get_subscriptions("$SYS/broker/uptime"),
+            vec![client_sub.clone()]
+        );
+        assert_eq!(
+            sut.get_subscriptions("$SYS/broker/clients/total"),
+            vec![client_sub]
+        );
+        assert_eq!(sut.retained_count(), 3);
+        assert_eq!(sut.subscription_count(), 3);
+    }
+
+    #[test]
+    fn test_shared_subscriptions_deliver_to_one_member() {
+        let mut sut = topicmanager::TopicManager::new();
+        let plain = topicmanager::ClientSubscription::new("monitor", 0);
+        let first = topicmanager::ClientSubscription::shared("worker-1", 1, "workers");
+        let second = topicmanager::ClientSubscription::shared("worker-2", 1, "workers");
+        sut.subscribe("jobs", &plain);
+        sut.subscribe("jobs", &first);
+        sut.subscribe("jobs", &second);
+        assert_eq!(
+            topicmanager::split_shared_filter("$share/workers/jobs/#"),
+            Some(("workers", "jobs/#"))
+        );
+        assert_eq!(topicmanager::split_shared_filter("$share/workers"), None);
+
+        let connected = |_: &str| Some(0);
+        assert_eq!(
+            sut.get_receivers("jobs", connected),
+            vec![plain.clone(), first.clone()]
+        );
+        assert_eq!(
+            sut.get_receivers("jobs", connected),
+            vec![plain.clone(), second.clone()]
+        );
+        // Members that are not connected are skipped
+        let only_first = |client_id: &str| (client_id != "worker-2").then_some(0);
+        assert_eq!(sut.get_receivers("jobs", only_first), vec![plain, first]);
+
+        sut.set_share_policy(SharePolicy::LeastInflight);
+        let busy_first = |client_id: &str| Some(if client_id == "worker-1" { 5 } else { 1 });
+        for _ in 0..3 {
+            assert_eq!(
+                sut.pick_group_member("jobs", "workers", busy_first),
+                Some(second.clone())
+            );
+        }
+
+        sut.unsubscribe_shared("jobs", "worker-2", "workers");
+        sut.remove_client("monitor");
+        assert_eq!(
+            sut.get_subscriptions("jobs"),
+            vec![topicmanager::ClientSubscription::shared(
+                "worker-1", 1, "workers"
+            )]
+        );
+    }
+
+    #[test]
+    fn test_topic_matches() {
+        assert!(topicmanager::topic_matches(
+            "home/+/temp",
+            "home/kitchen/temp"
+        ));
+        assert!(!topicmanager::topic_matches(
+            "home/+/temp",
+            "home/kitchen/hum"
+        ));
+        assert!(topicmanager::topic_matches("home/#", "home"));
+        assert!(topicmanager::topic_matches("home/#", "home/a/b"));
+        assert!(!topicmanager::topic_matches("home/+", "home/a/b"));
+        assert!(!topicmanager::topic_matches("#", "$SYS/broker/uptime"));
+        assert!(topicmanager::topic_matches("$SYS/#", "$SYS/broker/uptime"));
+    }
+
     #[test]
     fn test_subcribe_using_wildcard_single_level() {
         let mut sut = topicmanager::TopicManager::new();
@@ -421,4 +886,37 @@ mod tests {
         );
         assert_eq!(sut.get_subscriptions("topic/bedroom/ligth"), vec![]);
     }
+
+    #[test]
+    fn test_mount_point() {
+        let sut = topicmanager::MountPoint::new("acme").unwrap();
+        assert_eq!(sut.mount("lights/#").unwrap(), "tenant/acme/lights/#");
+        assert_eq!(
+            sut.mount("$share/g/lights/+").unwrap(),
+            "$share/g/tenant/acme/lights/+"
+        );
+        assert_eq!(
+            sut.mount("$delayed/60/lights").unwrap(),
+            "$delayed/60/tenant/acme/lights"
+        );
+        assert_eq!(sut.mount("$share/lights"), None);
+        assert_eq!(sut.mount("$share//lights"), None);
+        assert_eq!(
+            sut.unmount("tenant/acme/lights/kitchen"),
+            Some("lights/kitchen")
+        );
+        assert_eq!(sut.unmount("tenant/other/lights/kitchen"), None);
+        assert_eq!(sut.unmount("$share/g/tenant/acme/lights"), None);
+
+        // Another tenant's wildcards do not reach these topics
+        let other = topicmanager::MountPoint::new("other").unwrap();
+        assert!(!topicmanager::topic_matches(
+            &other.mount("#").unwrap(),
+            "tenant/acme/lights/kitchen"
+        ));
+
+        assert_eq!(topicmanager::MountPoint::new("a/b"), None);
+        assert_eq!(topicmanager::MountPoint::new("+"), None);
+        assert_eq!(topicmanager::MountPoint::new(""), None);
+    }
 }
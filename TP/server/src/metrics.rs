@@ -0,0 +1,281 @@
+use crate::http::{HttpRequest, HttpResponse};
+use crate::lockorder::LockName;
+use crate::managers::sessionmanager::Socket;
+use crate::ratelimit::LimitKind;
+use crate::stats::BrokerStats;
+use shared::packages::packet::PacketType;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+
+static CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// This struct represents a document in the Prometheus text exposition format
+struct Exposition {
+    text: String,
+}
+
+impl Exposition {
+    /// Adds the HELP and TYPE lines of a metric family
+    fn family(&mut self, name: &str, kind: &str, help: &str) -> &mut Self {
+        let _ = writeln!(self.text, "# HELP {} {}", name, help);
+        let _ = writeln!(self.text, "# TYPE {} {}", name, kind);
+        self
+    }
+
+    /// Adds a sample of the last family, labels are written as name="value"
+    fn sample<V: std::fmt::Display>(
+        &mut self,
+        name: &str,
+        labels: &[(&str, &str)],
+        value: V,
+    ) -> &mut Self {
+        if labels.is_empty() {
+            let _ = writeln!(self.text, "{} {}", name, value);
+        } else {
+            let labels: Vec<String> = labels
+                .iter()
+                .map(|(label, value)| format!("{}=\"{}\"", label, value))
+                .collect();
+            let _ = writeln!(self.text, "{}{{{}}} {}", name, labels.join(","), value);
+        }
+        self
+    }
+}
+
+/// Returns the broker metrics in the Prometheus text exposition format
+/// # Arguments
+///
+/// * `stats` - The counters of the broker
+/// * `active_connections` - The amount of open client connections
+///
+pub fn render(stats: &BrokerStats, active_connections: usize) -> String {
+    let mut exposition = Exposition {
+        text: String::new(),
+    };
+
+    exposition
+        .family(
+            "mqtt_connections_active",
+            "gauge",
+            "Client connections currently open",
+        )
+        .sample("mqtt_connections_active", &[], active_connections)
+        .family(
+            "mqtt_connections_accepted_total",
+            "counter",
+            "Client connections accepted by the listeners",
+        )
+        .sample(
+            "mqtt_connections_accepted_total",
+            &[],
+            stats.connections_accepted(),
+        )
+        .family(
+            "mqtt_connections_rejected_total",
+            "counter",
+            "Client connections rejected because a listener was full",
+        )
+        .sample(
+            "mqtt_connections_rejected_total",
+            &[],
+            stats.connections_rejected(),
+        );
+
+    exposition.family(
+        "mqtt_packets_received_total",
+        "counter",
+        "Packets received from clients by packet type",
+    );
+    for packet_type in 0..16 {
+        if let Some(name) = PacketType::from_u8(packet_type) {
+            let name = format!("{:?}", name).to_lowercase();
+            exposition.sample(
+                "mqtt_packets_received_total",
+                &[("type", &name)],
+                stats.packets_received(packet_type),
+            );
+        }
+    }
+
+    exposition.family(
+        "mqtt_publishes_received_total",
+        "counter",
+        "PUBLISH packets received from clients by QoS",
+    );
+    for qos in 0..3 {
+        exposition.sample(
+            "mqtt_publishes_received_total",
+            &[("qos", &qos.to_string())],
+            stats.publishes_received(qos),
+        );
+    }
+    exposition.family(
+        "mqtt_publishes_sent_total",
+        "counter",
+        "PUBLISH packets delivered to subscribers by QoS",
+    );
+    for qos in 0..3 {
+        exposition.sample(
+            "mqtt_publishes_sent_total",
+            &[("qos", &qos.to_string())],
+            stats.publishes_sent(qos),
+        );
+    }
+
+    exposition
+        .family(
+            "mqtt_auth_failures_total",
+            "counter",
+            "CONNECT packets refused because of bad credentials",
+        )
+        .sample("mqtt_auth_failures_total", &[], stats.auth_failures())
+        .family(
+            "mqtt_retransmissions_total",
+            "counter",
+            "Unacknowledged messages sent again",
+        )
+        .sample("mqtt_retransmissions_total", &[], stats.retransmissions())
+        .family(
+            "mqtt_outbound_dropped_total",
+            "counter",
+            "Messages dropped because the outbound queue of the subscriber was full",
+        )
+        .sample("mqtt_outbound_dropped_total", &[], stats.outbound_dropped())
+        .family(
+            "mqtt_slow_consumer_disconnects_total",
+            "counter",
+            "Clients disconnected because their outbound queue was full",
+        )
+        .sample(
+            "mqtt_slow_consumer_disconnects_total",
+            &[],
+            stats.slow_consumer_disconnects(),
+        )
+        .family(
+            "mqtt_expired_messages_total",
+            "counter",
+            "Pending and retained messages dropped because they expired",
+        )
+        .sample("mqtt_expired_messages_total", &[], stats.expired_messages())
+        .family(
+            "mqtt_duplicate_publishes_total",
+            "counter",
+            "QoS 1 messages resent by their publisher that were not delivered again",
+        )
+        .sample(
+            "mqtt_duplicate_publishes_total",
+            &[],
+            stats.duplicate_publishes(),
+        );
+
+    exposition.family(
+        "mqtt_rate_limit_violations_total",
+        "counter",
+        "Times a client went over one of its limits",
+    );
+    for kind in LimitKind::ALL.iter() {
+        exposition.sample(
+            "mqtt_rate_limit_violations_total",
+            &[("limit", kind.as_str())],
+            stats.rate_limit_violations(*kind),
+        );
+    }
+
+    exposition
+        .family(
+            "mqtt_bytes_received_total",
+            "counter",
+            "Bytes read from clients",
+        )
+        .sample("mqtt_bytes_received_total", &[], stats.bytes_received())
+        .family(
+            "mqtt_bytes_sent_total",
+            "counter",
+            "Bytes written to clients",
+        )
+        .sample("mqtt_bytes_sent_total", &[], stats.bytes_sent())
+        .family(
+            "mqtt_threadpool_queue_depth",
+            "gauge",
+            "Packets waiting for a thread of the pool",
+        )
+        .sample("mqtt_threadpool_queue_depth", &[], stats.queue_depth());
+
+    exposition.family(
+        "mqtt_lock_wait_seconds_total",
+        "counter",
+        "Time spent waiting for each shared lock, or by commands waiting for the core thread",
+    );
+    for lock in LockName::ALL.iter() {
+        exposition.sample(
+            "mqtt_lock_wait_seconds_total",
+            &[("lock", lock.as_str())],
+            stats.lock_wait(*lock).as_secs_f64(),
+        );
+    }
+    exposition.family(
+        "mqtt_lock_acquisitions_total",
+        "counter",
+        "Times each shared lock was taken, or commands run by the core thread",
+    );
+    for lock in LockName::ALL.iter() {
+        exposition.sample(
+            "mqtt_lock_acquisitions_total",
+            &[("lock", lock.as_str())],
+            stats.lock_acquisitions(*lock),
+        );
+    }
+
+    exposition.text
+}
+
+/// Answers GET /metrics with the broker metrics
+/// # Arguments
+///
+/// * `request` - The HTTP request to answer
+/// * `stats` - The counters of the broker
+/// * `active_streams` - The sockets of the connected clients
+///
+pub fn handle_request(
+    request: &HttpRequest,
+    stats: &BrokerStats,
+    active_streams: &Arc<Mutex<Vec<Socket>>>,
+) -> HttpResponse {
+    if request.path != "/metrics" {
+        return HttpResponse::not_found();
+    }
+    if request.method != "GET" {
+        return HttpResponse::method_not_allowed();
+    }
+    let active_connections = stats.lock(LockName::Streams, active_streams).len();
+    HttpResponse::new(200, CONTENT_TYPE, &render(stats, active_connections))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lockorder::LockName;
+    use crate::metrics::render;
+    use crate::stats::BrokerStats;
+    use std::time::Duration;
+
+    #[test]
+    fn test_render_metrics() {
+        let stats = BrokerStats::new();
+        stats.add_packet_received(1);
+        stats.add_packet_received(3);
+        stats.add_publish_received(1);
+        stats.add_auth_failure();
+        stats.set_queue_depth(4);
+        stats.add_lock_wait(LockName::Core, Duration::ZERO);
+
+        let text = render(&stats, 2);
+        assert!(text.contains("# TYPE mqtt_connections_active gauge\nmqtt_connections_active 2\n"));
+        assert!(text.contains("mqtt_packets_received_total{type=\"connect\"} 1\n"));
+        assert!(text.contains("mqtt_packets_received_total{type=\"publish\"} 1\n"));
+        assert!(text.contains("mqtt_packets_received_total{type=\"pingreq\"} 0\n"));
+        assert!(text.contains("mqtt_publishes_received_total{qos=\"1\"} 1\n"));
+        assert!(text.contains("mqtt_auth_failures_total 1\n"));
+        assert!(text.contains("mqtt_threadpool_queue_depth 4\n"));
+        assert!(text.contains("mqtt_lock_acquisitions_total{lock=\"core\"} 1\n"));
+    }
+}
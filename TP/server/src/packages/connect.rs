@@ -1,20 +1,25 @@
-use crate::managers::credentialmanager::CredentialManager;
-use crate::managers::messagemanager::MessageManager;
-use crate::managers::sessionmanager::{LastWillTestament, SessionManager, Socket};
-use crate::managers::topicmanager::TopicManager;
+use crate::hooks::{ClientInfo, HookVerdict};
+use crate::lockorder::LockName;
+use crate::managers::brokercore::{BrokerCore, CoreHandle};
+use crate::managers::credentialmanager::Authenticator;
+use crate::managers::sessionmanager::{LastWillTestament, Socket};
+use crate::packages::disconnect::remove_stream;
 use crate::packages::server_packet::PacketError;
 use crate::packages::server_packet::ServerPacket;
+use crate::transport::client_stream::ClientStream;
 use shared::packages::connack::Connack;
 use shared::packages::connect::Connect;
-use shared::packages::packet::WritablePacket;
-use std::net::TcpStream;
 use std::sync::Arc;
 use std::sync::Mutex;
-use tracing::{event, Level};
+use std::time::Duration;
+use tracing::{event, Level, Span};
 
+/// Return codes of a CONNACK, named as in the MQTT specification
+#[allow(clippy::enum_variant_names)]
 enum ConnectReturnCode {
     ConnectionAccepted = 0,
     ConnectionRefusedBadUsernameOrPassword = 4,
+    ConnectionRefusedNotAuthorized = 5,
 }
 
 enum SessionPresent {
@@ -25,78 +30,132 @@ enum SessionPresent {
 impl ServerPacket for Connect {
     fn handle_packet(
         &self,
-        stream: &mut TcpStream,
-        credentials: Arc<Mutex<CredentialManager>>,
-        sessions: Arc<Mutex<SessionManager>>,
-        topics: Arc<Mutex<TopicManager>>,
-        _messages: Arc<Mutex<MessageManager>>,
+        stream: &mut ClientStream,
+        credentials: Arc<Mutex<dyn Authenticator>>,
+        core: CoreHandle,
         actual_streams: Arc<Mutex<Vec<Socket>>>,
     ) -> Result<(), PacketError> {
-        let credential_manager = credentials.lock().unwrap();
-        let is_valid = credential_manager.is_valid(&self.username, &self.password);
+        Span::current().record("client_id", self.client_id.as_str());
+
+        let credential_manager = stream.stats().lock(LockName::Credentials, &credentials);
+        // The user the client is verified as, None if it is refused. Only verified usernames
+        // pick the tenant and the limits of the client, any other one connects anonymously.
+        let verified_username = match stream.certificate_username() {
+            // The client certificate was already verified, so only the username is checked
+            Some(username) => {
+                event!(
+                    Level::INFO,
+                    "Client {:?} authenticated by certificate as {:?}",
+                    self.client_id,
+                    username
+                );
+                Some(username.to_string()).filter(|u| credential_manager.has_username(u))
+            }
+            None if credential_manager.is_valid(&self.username, &self.password) => {
+                Some(self.username.to_string())
+            }
+            // Listeners without authentication accept anonymous clients
+            None if !stream.listener().require_auth => Some(String::new()),
+            None => None,
+        };
         drop(credential_manager);
+        let is_valid = verified_username.is_some();
+        let username = verified_username.unwrap_or_default();
 
         let mut return_code = ConnectReturnCode::ConnectionRefusedBadUsernameOrPassword as u8;
         let mut session_present = SessionPresent::No as u8;
 
-        if is_valid {
-            let mut session_manager = sessions.lock().unwrap();
+        let is_allowed = is_valid && {
+            stream.set_username(&username);
+            if let Err(e) = stream.apply_mount_point(&username) {
+                event!(Level::WARN, "Client {:?} refused: {}", self.client_id, e);
+                false
+            } else if self.last_will_flag == 1 && stream.mount(&self.last_will_topic).is_none() {
+                event!(
+                    Level::WARN,
+                    "Client {:?} refused: its will topic {:?} cannot be mounted",
+                    self.client_id,
+                    self.last_will_topic
+                );
+                false
+            } else {
+                let client = ClientInfo::new(&self.client_id, stream);
+                stream.hooks().on_connect(&client) == HookVerdict::Continue
+            }
+        };
+
+        if is_allowed {
+            stream.apply_rate_limits(&username);
+            stream.set_keep_alive(Duration::from_secs(self.keep_alive.into()));
+
             let lwt = match self.last_will_flag {
                 0 => None,
-                1 => Some(LastWillTestament {
-                    topic_name: self.last_will_topic.to_string(),
-                    payload: self.last_will_message.to_string(),
-                    qos: self.last_will_qos,
-                    retain_flag: self.last_will_retain,
-                }),
+                1 => stream
+                    .mount(&self.last_will_topic)
+                    .map(|topic_name| LastWillTestament {
+                        topic_name,
+                        payload: self.last_will_message.to_string(),
+                        qos: self.last_will_qos,
+                        retain_flag: self.last_will_retain,
+                    }),
                 _ => panic!("Invalid last will flag!"),
             };
-            if !session_manager.has_client(&self.client_id) {
-                session_manager.add_client(&self.client_id, stream.try_clone().unwrap(), lwt);
-                session_present = SessionPresent::No as u8;
-            } else {
-                // persistent session
+            let client_id = self.client_id.to_string();
+            let clean_session = self.clean_session == 1;
+            let session_stream = stream.try_clone()?;
+            let (present, previous_connection) = core.call(move |core| {
+                let BrokerCore {
+                    sessions: session_manager,
+                    topics: topic_manager,
+                    ..
+                } = core;
+                if !session_manager.has_client(&client_id) {
+                    session_manager.add_client(&client_id, session_stream, lwt, clean_session);
+                    return (SessionPresent::No, None);
+                }
 
-                // delete actual streams
-                match session_manager.get_old_peer(&self.client_id) {
-                    Ok(previous_peer) => {
-                        remove_stream(previous_peer, actual_streams);
-                    }
+                // persistent session, the previous connection is replaced
+                let previous_connection = match session_manager.get_old_connection(&client_id) {
+                    Ok(previous_connection) => Some(previous_connection),
                     Err(e) => {
                         event!(Level::ERROR, "Failed replacing socket: reason {:?}", e);
+                        None
                     }
-                }
-
-                if self.clean_session == 0 {
-                    session_present = SessionPresent::Yes as u8;
+                };
 
+                if !clean_session {
                     // session manager
-                    session_manager.replace_stream(&self.client_id, stream.try_clone().unwrap());
+                    session_manager.replace_stream(&client_id, session_stream, lwt);
+                    (SessionPresent::Yes, previous_connection)
                 } else {
                     // Non persistent session
-                    session_present = SessionPresent::No as u8;
 
                     // delete old session
-                    session_manager.delete(&self.client_id);
+                    session_manager.delete(&client_id);
 
                     // delete subscriptions
-                    let mut topic_manager = topics.lock().unwrap();
-                    let topics = topic_manager.get_topics_available();
-                    for topic in topics.iter() {
-                        topic_manager.unsubscribe(topic, &self.client_id);
-                    }
-                    drop(topic_manager);
+                    topic_manager.remove_client(&client_id);
 
                     // add new client
-                    session_manager.add_client(&self.client_id, stream.try_clone().unwrap(), lwt);
-                };
-            }
+                    session_manager.add_client(&client_id, session_stream, lwt, true);
+                    (SessionPresent::No, previous_connection)
+                }
+            });
+            session_present = present as u8;
 
-            drop(session_manager);
+            // delete actual streams
+            if let Some(previous_connection) = previous_connection {
+                remove_stream(previous_connection, stream.stats(), &actual_streams);
+            }
 
             return_code = ConnectReturnCode::ConnectionAccepted as u8;
         } else {
-            remove_stream(stream.peer_addr().unwrap().port(), actual_streams);
+            if is_valid {
+                return_code = ConnectReturnCode::ConnectionRefusedNotAuthorized as u8;
+            } else {
+                stream.stats().add_auth_failure();
+            }
+            remove_stream(stream.connection_id(), stream.stats(), &actual_streams);
         };
 
         let connack = Connack {
@@ -104,25 +163,8 @@ impl ServerPacket for Connect {
             session_present,
         };
 
-        connack.write_to(stream)?;
+        stream.send(&connack)?;
 
         Ok(())
     }
 }
-
-fn remove_stream(peer: u16, actual_streams: Arc<Mutex<Vec<Socket>>>) {
-    let mut index = 0;
-    let mut active_streams = actual_streams.lock().unwrap();
-
-    for _ in 0..active_streams.len() {
-        if peer == active_streams[index].peer {
-            break;
-        }
-
-        index += 1;
-    }
-
-    active_streams.remove(index);
-
-    drop(active_streams);
-}
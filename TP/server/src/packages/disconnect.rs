@@ -1,66 +1,111 @@
-use crate::managers::credentialmanager::CredentialManager;
-use crate::managers::messagemanager::MessageManager;
-use crate::managers::sessionmanager::{SessionManager, Socket};
-use crate::managers::topicmanager::TopicManager;
+use crate::hooks::ClientInfo;
+use crate::lockorder::LockName;
+use crate::managers::brokercore::{BrokerCore, CoreHandle};
+use crate::managers::credentialmanager::Authenticator;
+use crate::managers::sessionmanager::Socket;
+use crate::packages::publish::redistribute_shared_messages;
 use crate::packages::server_packet::PacketError;
 use crate::packages::server_packet::ServerPacket;
+use crate::stats::BrokerStats;
+use crate::transport::client_stream::{ClientStream, ConnectionId};
 use shared::packages::disconnect::Disconnect;
-use std::net::TcpStream;
 use std::sync::Arc;
 use std::sync::Mutex;
+use tracing::{event, Level};
 
 impl ServerPacket for Disconnect {
     fn handle_packet(
         &self,
-        stream: &mut TcpStream,
-        _credentials: Arc<Mutex<CredentialManager>>,
-        sessions: Arc<Mutex<SessionManager>>,
-        topics: Arc<Mutex<TopicManager>>,
-        messages: Arc<Mutex<MessageManager>>,
+        stream: &mut ClientStream,
+        _credentials: Arc<Mutex<dyn Authenticator>>,
+        core: CoreHandle,
         actual_streams: Arc<Mutex<Vec<Socket>>>,
     ) -> Result<(), PacketError> {
-        // delete session
-        let peer = stream.peer_addr().unwrap().port();
-
-        let mut session_manager = sessions.lock().unwrap();
-        let client_id = session_manager.get_client_id(&peer).unwrap();
-
-        session_manager.delete(&client_id);
-        drop(session_manager);
-
-        // remove client from message manager
-        let mut message_manager = messages.lock().unwrap();
-        message_manager.delete(&client_id);
-        drop(message_manager);
-
-        // unsubscribe topics
-        let mut topic_manager = topics.lock().unwrap();
-        let topics = topic_manager.get_topics_available();
-
-        for topic in topics.iter() {
-            topic_manager.unsubscribe(topic, &client_id);
+        let connection_id = stream.connection_id();
+        let client_id = core.call(move |core| {
+            let client_id = core.sessions.get_client_id(&connection_id).ok()?;
+            // A client that says goodbye does not get its will published
+            core.sessions.take_last_will(&client_id);
+            end_session(&client_id, connection_id, core);
+            Some(client_id)
+        });
+        if let Some(client_id) = client_id {
+            remove_stream(connection_id, stream.stats(), &actual_streams);
+            stream
+                .hooks()
+                .on_disconnect(&ClientInfo::new(&client_id, stream), true);
+        }
+        // The session may outlive the connection, the connection itself always ends here
+        if let Err(e) = stream.shutdown() {
+            event!(Level::DEBUG, "Could not close the connection: {}", e);
         }
 
-        drop(topic_manager);
+        Ok(())
+    }
+}
 
-        // remove socket from actual_streams
-        let peer = stream.peer_addr().unwrap().port();
+/// Ends the session of a client whose connection ended. A persistent session keeps its
+/// subscriptions and pending messages for the next connection, any other session is removed.
+/// # Arguments
+///
+/// * `client_id` - A string slice containing the client whose connection ended
+/// * `connection_id` - The id of the connection that ended
+/// * `core` - The sessions, subscriptions and pending messages of the broker
+///
+pub fn end_session(client_id: &str, connection_id: ConnectionId, core: &mut BrokerCore) {
+    if !core.sessions.is_persistent(client_id) {
+        remove_client(client_id, core);
+        return;
+    }
 
-        let mut index = 0;
-        let mut active_streams = actual_streams.lock().unwrap();
+    // hand the unacknowledged shared subscription messages to the rest of their groups
+    redistribute_shared_messages(
+        client_id,
+        &mut core.topics,
+        &core.sessions,
+        &mut core.messages,
+    );
+    core.sessions.remove_connection(&connection_id);
+}
 
-        for _ in 0..active_streams.len() {
-            if peer == active_streams[index].peer {
-                break;
-            }
+/// Deletes the session, pending messages and subscriptions of a client
+/// # Arguments
+///
+/// * `client_id` - A string slice containing the client to remove
+/// * `core` - The sessions, subscriptions and pending messages of the broker
+///
+pub fn remove_client(client_id: &str, core: &mut BrokerCore) {
+    // hand the unacknowledged shared subscription messages to the rest of their groups
+    redistribute_shared_messages(
+        client_id,
+        &mut core.topics,
+        &core.sessions,
+        &mut core.messages,
+    );
 
-            index += 1;
-        }
+    // delete session
+    core.sessions.delete(client_id);
 
-        active_streams.remove(index);
+    // remove client from message manager
+    core.messages.delete(client_id);
 
-        drop(active_streams);
+    // unsubscribe topics
+    core.topics.remove_client(client_id);
+}
 
-        Ok(())
-    }
+/// Drops the socket of a connection, so the broker no longer reads from it
+/// # Arguments
+///
+/// * `connection_id` - The id of the connection
+/// * `stats` - The broker counters, which measure the wait for each lock
+/// * `actual_streams` - The sockets of the connected clients
+///
+pub fn remove_stream(
+    connection_id: ConnectionId,
+    stats: &BrokerStats,
+    actual_streams: &Mutex<Vec<Socket>>,
+) {
+    stats
+        .lock(LockName::Streams, actual_streams)
+        .retain(|socket| socket.connection_id != connection_id);
 }
@@ -1,5 +1,6 @@
 use crate::packages::server_packet::PacketError;
 use crate::packages::server_packet::ServerPacket;
+use crate::transport::client_stream::ClientStream;
 use shared::packages::connect::Connect;
 use shared::packages::disconnect::Disconnect;
 use shared::packages::packet::FixedHeader;
@@ -10,23 +11,45 @@ use shared::packages::puback::Puback;
 use shared::packages::publish::Publish;
 use shared::packages::subscribe::Subscribe;
 use shared::packages::unsubscribe::Unsubscribe;
-use std::io::Read;
 
-/// Returns an heap-allocated mqtt packet from a readable object
+/// Returns an heap-allocated mqtt packet read from a client, counting it by packet type.
+/// A connection must start with a CONNECT and send only one, any unknown packet type or
+/// a packet out of that order is a protocol violation.
 /// # Arguments
 ///
-/// * `stream` - a readable object
+/// * `stream` - the client stream to read from
+/// * `connected` - Whether the connection already sent its CONNECT
 ///
 /// # Examples
 ///
-/// ```
+/// ```ignore
 /// // This gets a Box with a ServerPacket
-/// let packet = read_utf8_string(my_stream)?;
+/// let packet = dispatch_packet(my_stream, false)?;
 /// ```
-pub fn dispatch_packet(stream: &mut dyn Read) -> Result<Box<dyn ServerPacket>, PacketError> {
+pub fn dispatch_packet(
+    stream: &mut ClientStream,
+    connected: bool,
+) -> Result<Box<dyn ServerPacket>, PacketError> {
     let fixed_header = FixedHeader::read_fixed_header(stream)?;
+    stream.stats().add_packet_received(fixed_header.packet_type);
+
+    let packet_type = PacketType::from_u8(fixed_header.packet_type);
+    match (&packet_type, connected) {
+        (Some(PacketType::Connect), true) => {
+            return Err(PacketError::ProtocolViolation(
+                "a second CONNECT was sent".to_string(),
+            ))
+        }
+        (Some(PacketType::Connect), false) => {}
+        (_, false) => {
+            return Err(PacketError::ProtocolViolation(
+                "the first packet was not a CONNECT".to_string(),
+            ))
+        }
+        _ => {}
+    }
 
-    match PacketType::from_u8(fixed_header.packet_type) {
+    match packet_type {
         Some(PacketType::Connect) => Ok(Box::new(Connect::read_from(stream, fixed_header)?)),
         Some(PacketType::Publish) => Ok(Box::new(Publish::read_from(stream, fixed_header)?)),
         Some(PacketType::Puback) => Ok(Box::new(Puback::read_from(stream, fixed_header)?)),
@@ -36,6 +59,9 @@ pub fn dispatch_packet(stream: &mut dyn Read) -> Result<Box<dyn ServerPacket>, P
         }
         Some(PacketType::Pingreq) => Ok(Box::new(Pingreq::read_from(stream, fixed_header)?)),
         Some(PacketType::Disconnect) => Ok(Box::new(Disconnect::read_from(stream, fixed_header)?)),
-        _ => Ok(Box::new(Connect::read_from(stream, fixed_header)?)),
+        _ => Err(PacketError::ProtocolViolation(format!(
+            "unexpected packet type {}",
+            fixed_header.packet_type
+        ))),
     }
 }
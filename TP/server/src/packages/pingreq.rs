@@ -1,24 +1,25 @@
-use crate::managers::credentialmanager::CredentialManager;
-use crate::managers::messagemanager::MessageManager;
-use crate::managers::sessionmanager::{SessionManager, Socket};
-use crate::managers::topicmanager::TopicManager;
+use crate::managers::brokercore::CoreHandle;
+use crate::managers::credentialmanager::Authenticator;
+use crate::managers::sessionmanager::Socket;
 use crate::packages::server_packet::PacketError;
 use crate::packages::server_packet::ServerPacket;
+use crate::transport::client_stream::ClientStream;
 use shared::packages::pingreq::Pingreq;
-use std::net::TcpStream;
+use shared::packages::pingresp::Pingresp;
 use std::sync::Arc;
 use std::sync::Mutex;
 
 impl ServerPacket for Pingreq {
     fn handle_packet(
         &self,
-        _stream: &mut TcpStream,
-        _credentials: Arc<Mutex<CredentialManager>>,
-        _sessions: Arc<Mutex<SessionManager>>,
-        _topics: Arc<Mutex<TopicManager>>,
-        _messages: Arc<Mutex<MessageManager>>,
+        stream: &mut ClientStream,
+        _credentials: Arc<Mutex<dyn Authenticator>>,
+        _core: CoreHandle,
         _actual_streams: Arc<Mutex<Vec<Socket>>>,
     ) -> Result<(), PacketError> {
+        let response = Pingresp {};
+        stream.send(&response)?;
+
         Ok(())
     }
 }
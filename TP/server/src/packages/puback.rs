@@ -1,42 +1,29 @@
-use crate::managers::credentialmanager::CredentialManager;
-use crate::managers::messagemanager::MessageManager;
-use crate::managers::sessionmanager::{SessionManager, Socket};
-use crate::managers::topicmanager::TopicManager;
+use crate::managers::brokercore::CoreHandle;
+use crate::managers::credentialmanager::Authenticator;
+use crate::managers::sessionmanager::Socket;
 use crate::packages::server_packet::PacketError;
 use crate::packages::server_packet::ServerPacket;
+use crate::transport::client_stream::ClientStream;
 use shared::packages::puback::Puback;
-use std::net::TcpStream;
 use std::sync::Arc;
 use std::sync::Mutex;
-use tracing::{event, Level};
 
 impl ServerPacket for Puback {
     fn handle_packet(
         &self,
-        stream: &mut TcpStream,
-        _credentials: Arc<Mutex<CredentialManager>>,
-        sessions: Arc<Mutex<SessionManager>>,
-        _topics: Arc<Mutex<TopicManager>>,
-        messages: Arc<Mutex<MessageManager>>,
+        stream: &mut ClientStream,
+        _credentials: Arc<Mutex<dyn Authenticator>>,
+        core: CoreHandle,
         _actual_streams: Arc<Mutex<Vec<Socket>>>,
     ) -> Result<(), PacketError> {
-        match stream.peer_addr() {
-            Ok(peer) => {
-                let session_manager = sessions.lock().unwrap();
-                let mut message_manager = messages.lock().unwrap();
-                let peer_port = peer.port();
-                if session_manager.has_peer(&peer_port) {
-                    let clientid = session_manager.get_client_id(&peer_port).unwrap();
-                    message_manager.remove_message(&clientid, self.acknowledged_packet_id);
-                }
-                drop(session_manager);
-                drop(message_manager);
-                Ok(())
+        let connection_id = stream.connection_id();
+        let packet_id = self.acknowledged_packet_id;
+        // Nothing is sent back, so the client does not wait for the core
+        core.cast(move |core| {
+            if let Ok(clientid) = core.sessions.get_client_id(&connection_id) {
+                core.messages.remove_message(&clientid, packet_id);
             }
-            Err(e) => {
-                event!(Level::ERROR, "Failed processing puback. Reason: {:?}", e);
-                Err(PacketError::ExecuteError(e.to_string()))
-            }
-        }
+        });
+        Ok(())
     }
 }
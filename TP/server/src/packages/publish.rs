@@ -1,101 +1,369 @@
-use crate::managers::credentialmanager::CredentialManager;
+use crate::hooks::{ClientInfo, HookVerdict};
+use crate::managers::brokercore::CoreHandle;
+use crate::managers::credentialmanager::Authenticator;
+use crate::managers::delayedmanager::{split_delayed_topic, DELAYED_PREFIX};
 use crate::managers::messagemanager::{MessageManager, PendingMessage};
 use crate::managers::sessionmanager::{SessionManager, Socket};
 use crate::managers::topicmanager::{ClientSubscription, TopicManager};
+use crate::packages::disconnect::{remove_client, remove_stream};
 use crate::packages::server_packet::PacketError;
 use crate::packages::server_packet::ServerPacket;
-use shared::packages::packet::WritablePacket;
+use crate::ratelimit::QuotaDecision;
+use crate::transport::client_stream::ClientStream;
 use shared::packages::puback::Puback;
 use shared::packages::publish::Publish;
 use std::cmp;
-use std::net::TcpStream;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::{Instant, SystemTime};
 use tracing::{event, Level};
 
 impl ServerPacket for Publish {
     fn handle_packet(
         &self,
-        stream: &mut TcpStream,
-        _credentials: Arc<Mutex<CredentialManager>>,
-        sessions: Arc<Mutex<SessionManager>>,
-        topics: Arc<Mutex<TopicManager>>,
-        messages: Arc<Mutex<MessageManager>>,
-        _actual_streams: Arc<Mutex<Vec<Socket>>>,
+        stream: &mut ClientStream,
+        _credentials: Arc<Mutex<dyn Authenticator>>,
+        core: CoreHandle,
+        actual_streams: Arc<Mutex<Vec<Socket>>>,
     ) -> Result<(), PacketError> {
-        let mut topic_manager = topics.lock().unwrap();
-        let subscriptions: Vec<ClientSubscription> =
-            topic_manager.get_subscriptions(&self.topic_name);
-
-        let session_manager = sessions.lock().unwrap();
-        let mut message_manager = messages.lock().unwrap();
-        topic_manager.update_topic(self);
-        drop(topic_manager);
-
-        for sub in subscriptions.iter() {
-            let client_id = sub.client_id.to_string();
-            let mut session = match session_manager.get_client(&client_id) {
-                Some(result) => result,
-                None => {
-                    event!(Level::WARN, "Could not get client {:?}", client_id);
-                    continue;
-                }
-            };
-
-            let qos_publish = cmp::min(sub.qos, self.qos);
+        stream.stats().add_publish_received(self.qos);
 
-            let publish = Publish {
-                topic_name: self.topic_name.to_owned(),
-                payload: self.payload.to_owned(),
-                packet_id: self.packet_id,
-                qos: qos_publish,
-                retain_flag: self.retain_flag,
-                dup_flag: self.dup_flag,
-            };
+        let connection_id = stream.connection_id();
+        let (qos, packet_id, dup_flag) = (self.qos, self.packet_id, self.dup_flag);
+        let (client_id, duplicate) =
+            core.call(
+                move |core| match core.sessions.get_client_id(&connection_id) {
+                    Ok(client_id) => {
+                        let duplicate = qos == 1
+                            && core
+                                .sessions
+                                .is_duplicate_publish(&client_id, packet_id, dup_flag);
+                        (client_id, duplicate)
+                    }
+                    Err(_) => (String::new(), false),
+                },
+            );
+        // The PUBACK of the first copy may have been lost, so it is sent again
+        if duplicate {
+            stream.stats().add_duplicate_publish();
+            event!(
+                Level::INFO,
+                "Publish {} to {:?} was already received, it is not delivered again",
+                self.packet_id,
+                self.topic_name
+            );
+            return send_puback(stream, self.packet_id);
+        }
 
-            if qos_publish != 0 {
-                let pending_message = PendingMessage::from_publish_packet(self);
-                message_manager.add_message(&client_id, &pending_message);
+        let now = Instant::now();
+        let decision = stream
+            .quota()
+            .lock()
+            .unwrap()
+            .check_publish(self.payload.len(), now);
+        let deliver = match decision {
+            QuotaDecision::Allow => true,
+            QuotaDecision::Throttle(kind, delay) => {
+                stream.stats().add_rate_limit_violation(kind);
+                event!(
+                    Level::WARN,
+                    "Publish to {:?} over the {} limit, throttled for {:?}",
+                    self.topic_name,
+                    kind.as_str(),
+                    delay
+                );
+                // The publish goes through, but the next packets of the client wait until it
+                // is back within its limits, without holding a worker of the pool meanwhile
+                stream.pause_reading(now + delay);
+                true
             }
-
-            match publish.write_to(&mut session.socket.stream) {
-                Ok(_) => event!(Level::INFO, "{:?} sent to client {}", publish, client_id),
-                Err(e) => event!(
+            QuotaDecision::Drop(kind) => {
+                stream.stats().add_rate_limit_violation(kind);
+                event!(
                     Level::WARN,
-                    "{:?} could not be sent to client {:?}. Reason: {:?}",
-                    publish,
-                    client_id,
-                    e
-                ),
+                    "Publish to {:?} over the {} limit was dropped",
+                    self.topic_name,
+                    kind.as_str()
+                );
+                false
             }
-        }
-        drop(session_manager);
-        drop(message_manager);
+            QuotaDecision::Disconnect(kind) => {
+                stream.stats().add_rate_limit_violation(kind);
+                event!(
+                    Level::WARN,
+                    "Publish to {:?} over the {} limit, disconnecting the client",
+                    self.topic_name,
+                    kind.as_str()
+                );
+                // The message is dropped, so a resend is a new message
+                if self.qos == 1 {
+                    let packet_id = self.packet_id;
+                    core.cast(move |core| core.sessions.release_packet_id(&client_id, packet_id));
+                }
+                disconnect_client(stream, &core, &actual_streams);
+                return Ok(());
+            }
+        };
 
-        if self.qos == 1 {
-            let puback = Puback {
-                acknowledged_packet_id: self.packet_id,
-            };
-            match puback.write_to(stream) {
-                Ok(_) => {
+        // The hooks may modify the message, the acknowledgement is for the one received. They
+        // see the topic inside the namespace of the client, as every subscriber does
+        let mut publish = self.clone();
+        let deliver = deliver
+            && match stream.mount(&self.topic_name) {
+                Some(topic_name) => {
+                    publish.topic_name = topic_name;
+                    true
+                }
+                None => {
                     event!(
-                        Level::INFO,
-                        "Puback for packet id {} was succesfully sent",
-                        self.packet_id
-                    )
+                        Level::WARN,
+                        "Publish to {:?} is outside the namespace of the client, it was dropped",
+                        self.topic_name
+                    );
+                    false
                 }
-                Err(e) => {
+            };
+        let deliver = deliver && {
+            let client = ClientInfo::new(&client_id, stream);
+            stream.hooks().on_publish(&client, &mut publish) == HookVerdict::Continue
+        };
+
+        if deliver && publish.topic_name.starts_with(DELAYED_PREFIX) {
+            match split_delayed_topic(&publish.topic_name) {
+                Some((delay, topic)) => {
                     event!(
-                        Level::ERROR,
-                        "Puback for packet id {} failed. Reason: {:?}",
-                        self.packet_id,
-                        e
+                        Level::INFO,
+                        "Publish to {:?} is held for {:?}",
+                        publish.topic_name,
+                        delay
                     );
-                    return Err(PacketError::ExecuteError(e.to_string()));
+                    publish.topic_name = topic.to_string();
+                    let due = SystemTime::now() + delay;
+                    core.cast(move |core| core.delayed.add(publish, due));
                 }
+                None => event!(
+                    Level::WARN,
+                    "Invalid delayed topic {:?}, expected {}<seconds>/<topic>",
+                    publish.topic_name,
+                    DELAYED_PREFIX
+                ),
             }
+        } else if deliver {
+            // The message is owned by the core once sent to it, so the client is acknowledged
+            // without waiting for the delivery
+            core.cast(move |core| {
+                core.publish(&publish);
+            });
+        }
+
+        if self.qos == 1 {
+            send_puback(stream, self.packet_id)?;
         }
 
         Ok(())
     }
 }
+
+/// Acknowledges a QoS 1 message received from a client
+/// # Arguments
+///
+/// * `stream` - The connection of the client
+/// * `packet_id` - The packet id of the message
+///
+fn send_puback(stream: &mut ClientStream, packet_id: u16) -> Result<(), PacketError> {
+    let puback = Puback {
+        acknowledged_packet_id: packet_id,
+    };
+    match stream.send(&puback) {
+        Ok(_) => {
+            event!(
+                Level::INFO,
+                "Puback for packet id {} was succesfully sent",
+                packet_id
+            );
+            Ok(())
+        }
+        Err(e) => {
+            event!(
+                Level::ERROR,
+                "Puback for packet id {} failed. Reason: {:?}",
+                packet_id,
+                e
+            );
+            Err(PacketError::ExecuteError(e.to_string()))
+        }
+    }
+}
+
+/// Removes a client that went over its limits and closes its connection
+/// # Arguments
+///
+/// * `stream` - The connection of the client
+/// * `core` - The sessions, subscriptions and pending messages of the broker
+/// * `actual_streams` - The sockets of the connected clients
+///
+pub fn disconnect_client(
+    stream: &ClientStream,
+    core: &CoreHandle,
+    actual_streams: &Mutex<Vec<Socket>>,
+) {
+    let connection_id = stream.connection_id();
+    let client_id = core.call(move |core| {
+        let client_id = core.sessions.get_client_id(&connection_id).ok()?;
+        remove_client(&client_id, core);
+        Some(client_id)
+    });
+    if let Some(client_id) = client_id {
+        remove_stream(connection_id, stream.stats(), actual_streams);
+        stream
+            .hooks()
+            .on_disconnect(&ClientInfo::new(&client_id, stream), false);
+    }
+    if let Err(e) = stream.shutdown() {
+        event!(Level::WARN, "Could not close the connection: {:?}", e);
+    }
+}
+
+/// Returns the subscriptions a message published to a topic is delivered to, choosing one
+/// connected member of each shared subscription group
+/// # Arguments
+///
+/// * `topic_manager` - The topics and their subscriptions
+/// * `topic` - A string slice containing the topic of the message
+/// * `session_manager` - The sessions, used to skip group members that are not connected
+/// * `message_manager` - The messages each group member has not acknowledged yet
+///
+pub fn get_receivers(
+    topic_manager: &mut TopicManager,
+    topic: &str,
+    session_manager: &SessionManager,
+    message_manager: &MessageManager,
+) -> Vec<ClientSubscription> {
+    topic_manager.get_receivers(topic, |client_id| {
+        if session_manager.has_client(client_id) {
+            Some(message_manager.inflight_count(client_id))
+        } else {
+            None
+        }
+    })
+}
+
+/// Sends the unacknowledged messages a client got through shared subscriptions to other
+/// members of their groups, so they are not lost when the client leaves
+/// # Arguments
+///
+/// * `client_id` - A string slice containing the client that is leaving
+/// * `topic_manager` - The topics and their subscriptions
+/// * `session_manager` - The sessions used to find the other group members
+/// * `message_manager` - The storage of messages waiting for an acknowledgement
+///
+pub fn redistribute_shared_messages(
+    client_id: &str,
+    topic_manager: &mut TopicManager,
+    session_manager: &SessionManager,
+    message_manager: &mut MessageManager,
+) {
+    let shared_messages: Vec<PendingMessage> = message_manager
+        .get_messages(client_id)
+        .into_iter()
+        .filter(|message| message.share_group.is_some())
+        .collect();
+
+    for message in shared_messages {
+        message_manager.remove_message(client_id, message.packet_id);
+        let group = message.share_group.as_deref().unwrap_or_default();
+        let receiver = topic_manager.pick_group_member(&message.topic_name, group, |member| {
+            if member != client_id && session_manager.has_client(member) {
+                Some(message_manager.inflight_count(member))
+            } else {
+                None
+            }
+        });
+        match receiver {
+            Some(receiver) => {
+                event!(
+                    Level::INFO,
+                    "Message to {:?} of client {:?} goes to {:?} of group {}",
+                    message.topic_name,
+                    client_id,
+                    receiver.client_id,
+                    group
+                );
+                let mut publish = message.to_publish_packet();
+                // It is the first delivery to the new receiver
+                publish.dup_flag = 0;
+                send_to_subscribers(&publish, &[receiver], session_manager, message_manager);
+            }
+            None => event!(
+                Level::WARN,
+                "Message to {:?} of client {:?} was lost, group {} has no other connected member",
+                message.topic_name,
+                client_id,
+                group
+            ),
+        }
+    }
+}
+
+/// Sends a message to the given subscriptions, keeping a pending copy for the ones with QoS above 0
+/// # Arguments
+///
+/// * `message` - The publish packet to send
+/// * `subscriptions` - The subscriptions of the message topic
+/// * `session_manager` - The sessions used to find the stream of each subscriber
+/// * `message_manager` - The storage of messages waiting for an acknowledgement
+///
+pub fn send_to_subscribers(
+    message: &Publish,
+    subscriptions: &[ClientSubscription],
+    session_manager: &SessionManager,
+    message_manager: &mut MessageManager,
+) {
+    for sub in subscriptions.iter() {
+        let client_id = sub.client_id.to_string();
+        let stream = match session_manager.get_stream(&client_id) {
+            Some(stream) => stream,
+            None => {
+                event!(Level::WARN, "Could not get client {:?}", client_id);
+                continue;
+            }
+        };
+
+        let qos_publish = cmp::min(sub.qos, message.qos);
+
+        let mut publish = Publish {
+            topic_name: message.topic_name.to_owned(),
+            payload: message.payload.to_owned(),
+            packet_id: message.packet_id,
+            qos: qos_publish,
+            retain_flag: message.retain_flag,
+            dup_flag: message.dup_flag,
+        };
+
+        let subscriber = ClientInfo::new(&client_id, stream);
+        if stream.hooks().on_deliver(&subscriber, &mut publish) == HookVerdict::Deny {
+            continue;
+        }
+
+        if publish.qos != 0 {
+            let mut pending_message = PendingMessage::from_publish_packet(&publish);
+            pending_message.share_group = sub.share_group.clone();
+            message_manager.add_message(&client_id, &pending_message);
+        }
+
+        // Only queued here, the writer of the subscriber sends it without holding any lock
+        match stream.send_message(&publish) {
+            Ok(_) => {
+                stream.stats().add_publish_sent(publish.qos);
+                event!(Level::INFO, "{:?} sent to client {}", publish, client_id)
+            }
+            Err(e) => event!(
+                Level::WARN,
+                "{:?} could not be sent to client {:?}. Reason: {:?}",
+                publish,
+                client_id,
+                e
+            ),
+        }
+    }
+}
@@ -1,10 +1,8 @@
-use crate::CredentialManager;
-use crate::MessageManager;
-use crate::SessionManager;
-use crate::Socket;
-use crate::TopicManager;
+use crate::managers::brokercore::CoreHandle;
+use crate::managers::credentialmanager::Authenticator;
+use crate::managers::sessionmanager::Socket;
+use crate::transport::client_stream::ClientStream;
 use std::fmt;
-use std::net::TcpStream;
 use std::sync::Arc;
 use std::sync::Mutex;
 
@@ -13,6 +11,8 @@ use std::sync::Mutex;
 pub enum PacketError {
     IOError(std::io::Error),
     ExecuteError(String),
+    /// The client broke the MQTT protocol, so its connection must be closed
+    ProtocolViolation(String),
 }
 
 impl fmt::Display for PacketError {
@@ -20,6 +20,7 @@ impl fmt::Display for PacketError {
         match *self {
             PacketError::IOError(ref err) => write!(f, "IO error: {}", err),
             PacketError::ExecuteError(ref err) => write!(f, "Packet Execution Error: {}", err),
+            PacketError::ProtocolViolation(ref err) => write!(f, "Protocol violation: {}", err),
         }
     }
 }
@@ -35,11 +36,9 @@ impl From<std::io::Error> for PacketError {
 pub trait ServerPacket: std::fmt::Debug {
     fn handle_packet(
         &self,
-        stream: &mut TcpStream,
-        credentials: Arc<Mutex<CredentialManager>>,
-        sessions: Arc<Mutex<SessionManager>>,
-        topics: Arc<Mutex<TopicManager>>,
-        messages: Arc<Mutex<MessageManager>>,
+        stream: &mut ClientStream,
+        credentials: Arc<Mutex<dyn Authenticator>>,
+        core: CoreHandle,
         actual_streams: Arc<Mutex<Vec<Socket>>>,
     ) -> Result<(), PacketError>;
 }
@@ -1,90 +1,247 @@
-use crate::managers::credentialmanager::CredentialManager;
-use crate::managers::messagemanager::MessageManager;
-use crate::managers::sessionmanager::{SessionManager, Socket};
-use crate::managers::topicmanager::{ClientSubscription, TopicManager};
+use crate::config::LimitPolicy;
+use crate::hooks::{ClientInfo, HookVerdict};
+use crate::managers::brokercore::{BrokerCore, CoreHandle};
+use crate::managers::credentialmanager::Authenticator;
+use crate::managers::sessionmanager::Socket;
+use crate::managers::topicmanager::{split_shared_filter, ClientSubscription, SHARED_PREFIX};
+use crate::packages::publish::{disconnect_client, send_to_subscribers};
 use crate::packages::server_packet::PacketError;
 use crate::packages::server_packet::ServerPacket;
-use shared::packages::packet::WritablePacket;
+use crate::ratelimit::LimitKind;
+use crate::transport::client_stream::ClientStream;
 use shared::packages::suback::Suback;
 use shared::packages::subscribe::Subscribe;
-use std::net::TcpStream;
+use std::slice;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::Instant;
 use tracing::{event, Level};
 
+/// Return code of a SUBACK for a refused topic filter
+const SUBSCRIPTION_FAILURE: u8 = 0x80;
+
 impl ServerPacket for Subscribe {
     fn handle_packet(
         &self,
-        stream: &mut TcpStream,
-        _credentials: Arc<Mutex<CredentialManager>>,
-        sessions: Arc<Mutex<SessionManager>>,
-        topics: Arc<Mutex<TopicManager>>,
-        _messages: Arc<Mutex<MessageManager>>,
-        _actual_streams: Arc<Mutex<Vec<Socket>>>,
+        stream: &mut ClientStream,
+        _credentials: Arc<Mutex<dyn Authenticator>>,
+        core: CoreHandle,
+        actual_streams: Arc<Mutex<Vec<Socket>>>,
     ) -> Result<(), PacketError> {
-        let session_manager = sessions.lock().unwrap();
-        let peer = stream.peer_addr().unwrap().port();
+        let connection_id = stream.connection_id();
+        let (max_subscriptions, policy) = {
+            let quota = stream.quota();
+            let quota = quota.lock().unwrap();
+            (quota.max_subscriptions(), quota.policy())
+        };
 
         let mut response_qos = Vec::new();
-        if session_manager.has_peer(&peer) {
-            let mut topic_manager = topics.lock().unwrap();
-            let client_id = session_manager.get_client_id(&peer).unwrap();
-
-            let topic_amount = self.topic_filters.len();
-
-            for index in 0..topic_amount {
-                let requested_qos = self.requested_qos[index];
-                let subscription = ClientSubscription::new(&client_id, requested_qos);
-                topic_manager.subscribe(&self.topic_filters[index], &subscription);
-
-                let final_subscriptions = topic_manager.get_client_subscriptions(&client_id);
-
-                for sub in final_subscriptions.iter() {
-                    match topic_manager.get_retained_message(sub) {
-                        Some(retained_message) => {
-                            event!(Level::INFO, "LLEGUE con retained {:?}", &retained_message);
-                            let publish_packet =
-                                retained_message.to_publish_packet(self.requested_qos[index]);
-                            match publish_packet.write_to(stream) {
-                                Ok(_) => {
-                                    event!(
-                                        Level::DEBUG,
-                                        "SEND retained message {:?}",
-                                        &retained_message
-                                    );
-                                }
-                                Err(e) => {
-                                    event!(
-                                        Level::WARN,
-                                        "FAIL sending retained message {:?}. Reason {:?}",
-                                        &retained_message,
-                                        e
-                                    );
-                                }
-                            }
-                        }
-                        None => {
-                            event!(
-                                Level::DEBUG,
-                                "No retained message for topic {:?}",
-                                &self.topic_filters[index]
-                            );
-                        }
+        let mut over_limit = false;
+        if let Some(client_id) =
+            core.call(move |core| core.sessions.get_client_id(&connection_id).ok())
+        {
+            let client = ClientInfo::new(&client_id, stream);
+
+            // The hooks run before the subscriptions are handed to the core, so a slow hook
+            // only delays this client
+            let mut requests = Vec::new();
+            for (filter, requested_qos) in self.topic_filters.iter().zip(self.requested_qos.iter())
+            {
+                let mut filter = match stream.mount(filter) {
+                    Some(filter) => filter,
+                    None => {
+                        event!(
+                            Level::WARN,
+                            "Invalid shared subscription {:?} of client {:?}",
+                            filter,
+                            client_id
+                        );
+                        requests.push(None);
+                        continue;
+                    }
+                };
+                let mut requested_qos = *requested_qos;
+                if stream.listener().granted_qos(requested_qos).is_none() {
+                    event!(
+                        Level::WARN,
+                        "Client {:?} requested the invalid qos {} for {:?}",
+                        client_id,
+                        requested_qos,
+                        filter
+                    );
+                    requests.push(None);
+                    continue;
+                }
+                if stream
+                    .hooks()
+                    .on_subscribe(&client, &mut filter, &mut requested_qos)
+                    == HookVerdict::Deny
+                {
+                    requests.push(None);
+                    continue;
+                }
+
+                // A hook may have asked for a reserved QoS, which is refused too
+                let granted_qos = match stream.listener().granted_qos(requested_qos) {
+                    Some(granted_qos) => granted_qos,
+                    None => {
+                        requests.push(None);
+                        continue;
                     }
+                };
+                if granted_qos < requested_qos {
+                    event!(
+                        Level::DEBUG,
+                        "Subscription of client {:?} to {:?} granted qos {} instead of {}",
+                        client_id,
+                        filter,
+                        granted_qos,
+                        requested_qos
+                    );
                 }
-                response_qos.push(self.requested_qos[index]);
+                requests.push(Some((filter, granted_qos)));
             }
-            drop(topic_manager);
+
+            let limits = SubscriptionLimits {
+                max_subscriptions,
+                policy,
+            };
+            let (return_codes, disconnect) =
+                core.call(move |core| subscribe_filters(core, &client_id, requests, limits));
+            response_qos = return_codes;
+            over_limit = disconnect;
         }
 
-        drop(session_manager);
+        if over_limit {
+            disconnect_client(stream, &core, &actual_streams);
+            return Ok(());
+        }
 
         let response = Suback {
             packet_id: self.packet_id,
             return_codes: response_qos,
         };
-        response.write_to(stream)?;
+        stream.send(&response)?;
 
         Ok(())
     }
 }
+
+/// This struct represents the subscription limits of the client that sent a SUBSCRIBE
+#[derive(Debug, Clone, Copy)]
+struct SubscriptionLimits {
+    max_subscriptions: Option<usize>,
+    policy: LimitPolicy,
+}
+
+/// Subscribes a client to the filters its hooks allowed, sending it the retained messages of
+/// each one. Returns the return codes of the SUBACK and whether the client must be
+/// disconnected for going over its limit.
+/// # Arguments
+///
+/// * `core` - The state of the broker
+/// * `client_id` - A string slice containing the client that subscribes
+/// * `requests` - The filter and granted QoS of each topic filter, None for refused ones
+/// * `limits` - The subscription limits of the client
+///
+fn subscribe_filters(
+    core: &mut BrokerCore,
+    client_id: &str,
+    requests: Vec<Option<(String, u8)>>,
+    limits: SubscriptionLimits,
+) -> (Vec<u8>, bool) {
+    let BrokerCore {
+        sessions: session_manager,
+        topics: topic_manager,
+        messages: message_manager,
+        expiry,
+        ..
+    } = core;
+    let stream = match session_manager.get_stream(client_id) {
+        Some(stream) => stream,
+        None => return (Vec::new(), false),
+    };
+
+    let mut response_qos = Vec::new();
+    for request in requests {
+        let (filter, granted_qos) = match request {
+            Some(request) => request,
+            None => {
+                response_qos.push(SUBSCRIPTION_FAILURE);
+                continue;
+            }
+        };
+
+        if let Some(max) = limits.max_subscriptions {
+            let current = topic_manager.get_client_subscriptions(client_id);
+            if current.len() >= max && !current.contains(&filter) {
+                stream
+                    .stats()
+                    .add_rate_limit_violation(LimitKind::Subscriptions);
+                event!(
+                    Level::WARN,
+                    "Client {:?} is over the limit of {} subscriptions, {:?} was refused",
+                    client_id,
+                    max,
+                    filter
+                );
+                if limits.policy == LimitPolicy::Disconnect {
+                    return (response_qos, true);
+                }
+                response_qos.push(SUBSCRIPTION_FAILURE);
+                continue;
+            }
+        }
+
+        if filter.starts_with(SHARED_PREFIX) {
+            match split_shared_filter(&filter) {
+                Some((group, filter)) => {
+                    let subscription = ClientSubscription::shared(client_id, granted_qos, group);
+                    topic_manager.subscribe(filter, &subscription);
+                    // Retained messages are not sent to shared subscriptions
+                    response_qos.push(granted_qos);
+                }
+                None => {
+                    event!(
+                        Level::WARN,
+                        "Invalid shared subscription {:?} of client {:?}",
+                        filter,
+                        client_id
+                    );
+                    response_qos.push(SUBSCRIPTION_FAILURE);
+                }
+            }
+            continue;
+        }
+
+        let subscription = ClientSubscription::new(client_id, granted_qos);
+        topic_manager.subscribe(&filter, &subscription);
+
+        let retained_messages = topic_manager.get_retained_messages(&filter);
+        if retained_messages.is_empty() {
+            event!(Level::DEBUG, "No retained message for filter {:?}", &filter);
+        }
+        for retained_message in retained_messages {
+            // Not swept yet, it is dropped instead of sent
+            if expiry.is_expired(
+                &retained_message.topic_name,
+                retained_message.received_at,
+                Instant::now(),
+            ) {
+                topic_manager.remove_retained_message(&retained_message.topic_name);
+                stream.stats().add_expired_messages(1);
+                continue;
+            }
+            // Delivered like any other message, so the hooks apply and QoS 1 copies are resent
+            let publish_packet = retained_message.to_publish_packet(granted_qos);
+            send_to_subscribers(
+                &publish_packet,
+                slice::from_ref(&subscription),
+                session_manager,
+                message_manager,
+            );
+        }
+        response_qos.push(granted_qos);
+    }
+    (response_qos, false)
+}
@@ -1,46 +1,47 @@
-use crate::managers::credentialmanager::CredentialManager;
-use crate::managers::messagemanager::MessageManager;
-use crate::managers::sessionmanager::{SessionManager, Socket};
-use crate::managers::topicmanager::TopicManager;
+use crate::managers::brokercore::CoreHandle;
+use crate::managers::credentialmanager::Authenticator;
+use crate::managers::sessionmanager::Socket;
+use crate::managers::topicmanager::split_shared_filter;
 use crate::packages::server_packet::PacketError;
 use crate::packages::server_packet::ServerPacket;
-use shared::packages::packet::WritablePacket;
+use crate::transport::client_stream::ClientStream;
 use shared::packages::unsuback::Unsuback;
 use shared::packages::unsubscribe::Unsubscribe;
-use std::net::TcpStream;
 use std::sync::Arc;
 use std::sync::Mutex;
 
 impl ServerPacket for Unsubscribe {
     fn handle_packet(
         &self,
-        stream: &mut TcpStream,
-        _credentials: Arc<Mutex<CredentialManager>>,
-        sessions: Arc<Mutex<SessionManager>>,
-        topics: Arc<Mutex<TopicManager>>,
-        _messages: Arc<Mutex<MessageManager>>,
+        stream: &mut ClientStream,
+        _credentials: Arc<Mutex<dyn Authenticator>>,
+        core: CoreHandle,
         _actual_streams: Arc<Mutex<Vec<Socket>>>,
     ) -> Result<(), PacketError> {
-        let session_manager = sessions.lock().unwrap();
-        let peer = stream.peer_addr().unwrap().port();
-
-        if session_manager.has_peer(&peer) {
-            let mut topic_manager = topics.lock().unwrap();
-            let clientid = session_manager.get_client_id(&peer).unwrap();
-
-            for index in 0..self.topic_filters.len() {
-                topic_manager.unsubscribe(&self.topic_filters[index], &clientid);
+        let connection_id = stream.connection_id();
+        // Filters the namespace refuses cannot have been subscribed to
+        let filters: Vec<String> = self
+            .topic_filters
+            .iter()
+            .filter_map(|filter| stream.mount(filter))
+            .collect();
+        core.call(move |core| {
+            if let Ok(clientid) = core.sessions.get_client_id(&connection_id) {
+                for filter in filters.iter() {
+                    match split_shared_filter(filter) {
+                        Some((group, filter)) => {
+                            core.topics.unsubscribe_shared(filter, &clientid, group)
+                        }
+                        None => core.topics.unsubscribe(filter, &clientid),
+                    }
+                }
             }
-
-            drop(topic_manager);
-        }
-
-        drop(session_manager);
+        });
 
         let response = Unsuback {
             packet_id: self.packet_id,
         };
-        response.write_to(stream)?;
+        stream.send(&response)?;
 
         Ok(())
     }
@@ -0,0 +1,382 @@
+use crate::config::{LimitPolicy, RateLimitSettings, RateLimits};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Longest time a throttled client is not read, publishes that would make it wait longer are dropped
+static MAX_THROTTLE_DELAY: Duration = Duration::from_secs(2);
+
+/// This enum represents the limits a client can go over
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LimitKind {
+    Publishes = 0,
+    Bytes = 1,
+    Subscriptions = 2,
+}
+
+impl LimitKind {
+    pub const ALL: [LimitKind; 3] = [
+        LimitKind::Publishes,
+        LimitKind::Bytes,
+        LimitKind::Subscriptions,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LimitKind::Publishes => "publishes",
+            LimitKind::Bytes => "bytes",
+            LimitKind::Subscriptions => "subscriptions",
+        }
+    }
+}
+
+/// This struct represents a token bucket that refills at a fixed rate and holds one second of tokens
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    /// Tokens added per second, which is also the capacity of the bucket
+    rate: f64,
+    /// Tokens available, negative while throttled callers pay back what they took early
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    /// Returns a full TokenBucket
+    /// # Arguments
+    ///
+    /// * `rate` - The tokens added per second
+    /// * `now` - The current instant
+    ///
+    pub fn new(rate: u32, now: Instant) -> TokenBucket {
+        TokenBucket {
+            rate: rate as f64,
+            tokens: rate as f64,
+            refilled: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.refilled = now;
+    }
+
+    /// Returns whether an amount of tokens can be taken now. Amounts above the
+    /// capacity only need a full bucket, otherwise they could never be taken.
+    /// # Arguments
+    ///
+    /// * `amount` - The tokens needed
+    /// * `now` - The current instant
+    ///
+    pub fn has(&mut self, amount: usize, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= (amount as f64).min(self.rate)
+    }
+
+    /// Takes tokens, leaving the bucket in debt if there were not enough of them
+    pub fn take(&mut self, amount: usize) {
+        self.tokens -= amount as f64;
+    }
+
+    /// Returns how long to wait until an amount of tokens is available
+    /// # Arguments
+    ///
+    /// * `amount` - The tokens needed
+    /// * `now` - The current instant
+    ///
+    pub fn delay_for(&mut self, amount: usize, now: Instant) -> Duration {
+        self.refill(now);
+        let missing = amount as f64 - self.tokens;
+        if missing > 0.0 {
+            Duration::from_secs_f64(missing / self.rate)
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+/// This enum represents what the broker does with a publish of a client
+#[derive(Debug, Clone, PartialEq)]
+pub enum QuotaDecision {
+    Allow,
+    /// The publish is delivered, and the client is not read until the delay is over
+    Throttle(LimitKind, Duration),
+    Drop(LimitKind),
+    Disconnect(LimitKind),
+}
+
+/// This struct represents the limits of a connection and the tokens it has left
+#[derive(Debug, Clone)]
+pub struct ClientQuota {
+    policy: LimitPolicy,
+    publishes: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+    max_subscriptions: Option<usize>,
+}
+
+impl ClientQuota {
+    /// Returns a ClientQuota without limits, used until the client authenticates
+    pub fn unlimited() -> ClientQuota {
+        ClientQuota {
+            policy: LimitPolicy::Drop,
+            publishes: None,
+            bytes: None,
+            max_subscriptions: None,
+        }
+    }
+
+    /// Returns a ClientQuota with full buckets
+    /// # Arguments
+    ///
+    /// * `limits` - The limits of the client
+    /// * `policy` - What happens when the client goes over a limit
+    /// * `now` - The current instant
+    ///
+    pub fn new(limits: &RateLimits, policy: LimitPolicy, now: Instant) -> ClientQuota {
+        ClientQuota {
+            policy,
+            publishes: limits.publish_rate.map(|rate| TokenBucket::new(rate, now)),
+            bytes: limits.byte_rate.map(|rate| TokenBucket::new(rate, now)),
+            max_subscriptions: limits.max_subscriptions,
+        }
+    }
+
+    pub fn policy(&self) -> LimitPolicy {
+        self.policy
+    }
+
+    pub fn max_subscriptions(&self) -> Option<usize> {
+        self.max_subscriptions
+    }
+
+    /// Takes the tokens of a publish and returns what to do with it
+    /// # Arguments
+    ///
+    /// * `payload_size` - The bytes of the publish payload
+    /// * `now` - The current instant
+    ///
+    pub fn check_publish(&mut self, payload_size: usize, now: Instant) -> QuotaDecision {
+        let publishes_left = match &mut self.publishes {
+            Some(publishes) => publishes.has(1, now),
+            None => true,
+        };
+        let bytes_left = match &mut self.bytes {
+            Some(bytes) => bytes.has(payload_size, now),
+            None => true,
+        };
+        let exceeded = if !publishes_left {
+            Some(LimitKind::Publishes)
+        } else if !bytes_left {
+            Some(LimitKind::Bytes)
+        } else {
+            None
+        };
+
+        let decision = match (exceeded, self.policy) {
+            (None, _) => QuotaDecision::Allow,
+            (Some(kind), LimitPolicy::Throttle) => {
+                let delay = self.throttle_delay(payload_size, now);
+                if delay > MAX_THROTTLE_DELAY {
+                    QuotaDecision::Drop(kind)
+                } else {
+                    QuotaDecision::Throttle(kind, delay)
+                }
+            }
+            (Some(kind), LimitPolicy::Drop) => QuotaDecision::Drop(kind),
+            (Some(kind), LimitPolicy::Disconnect) => QuotaDecision::Disconnect(kind),
+        };
+
+        if matches!(
+            decision,
+            QuotaDecision::Allow | QuotaDecision::Throttle(_, _)
+        ) {
+            if let Some(publishes) = &mut self.publishes {
+                publishes.take(1);
+            }
+            if let Some(bytes) = &mut self.bytes {
+                bytes.take(payload_size);
+            }
+        }
+        decision
+    }
+
+    /// Returns how long a publish has to wait until both buckets have its tokens
+    fn throttle_delay(&mut self, payload_size: usize, now: Instant) -> Duration {
+        let publishes = match &mut self.publishes {
+            Some(publishes) => publishes.delay_for(1, now),
+            None => Duration::ZERO,
+        };
+        let bytes = match &mut self.bytes {
+            Some(bytes) => bytes.delay_for(payload_size, now),
+            None => Duration::ZERO,
+        };
+        publishes.max(bytes)
+    }
+}
+
+/// This struct represents the configured limits and the quotas of the users with limits of
+/// their own. Each of those users has a single quota, shared by all of its connections.
+#[derive(Debug)]
+pub struct RateLimiter {
+    settings: RateLimitSettings,
+    users: Mutex<HashMap<String, Arc<Mutex<ClientQuota>>>>,
+}
+
+impl RateLimiter {
+    /// Returns a RateLimiter that has not handed out any quota yet
+    /// # Arguments
+    ///
+    /// * `settings` - The limits of every client and of each user
+    ///
+    pub fn new(settings: RateLimitSettings) -> RateLimiter {
+        RateLimiter {
+            settings,
+            users: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn settings(&self) -> &RateLimitSettings {
+        &self.settings
+    }
+
+    /// Returns the quota of a connection. Users with limits of their own get the quota of the
+    /// user, any other client gets one of its own with full buckets.
+    /// # Arguments
+    ///
+    /// * `username` - The user the client authenticated as, empty for anonymous clients
+    /// * `now` - The current instant
+    ///
+    pub fn quota_for(&self, username: &str, now: Instant) -> Arc<Mutex<ClientQuota>> {
+        let policy = self.settings.policy;
+        match self.settings.users.get(username) {
+            Some(limits) => Arc::clone(
+                self.users
+                    .lock()
+                    .unwrap()
+                    .entry(username.to_string())
+                    .or_insert_with(|| Arc::new(Mutex::new(ClientQuota::new(limits, policy, now)))),
+            ),
+            None => Arc::new(Mutex::new(ClientQuota::new(
+                &self.settings.default,
+                policy,
+                now,
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{LimitPolicy, RateLimitSettings, RateLimits};
+    use crate::ratelimit::{ClientQuota, LimitKind, QuotaDecision, RateLimiter, TokenBucket};
+    use std::collections::HashMap;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_token_bucket_refills() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2, start);
+        assert!(bucket.has(2, start));
+        bucket.take(2);
+        assert!(!bucket.has(1, start));
+        assert_eq!(bucket.delay_for(1, start), Duration::from_millis(500));
+        assert!(bucket.has(1, start + Duration::from_millis(500)));
+        // Refills never go over one second of tokens
+        let much_later = start + Duration::from_secs(10);
+        assert!(bucket.has(2, much_later));
+        bucket.take(2);
+        assert!(!bucket.has(1, much_later));
+        // Amounts over the capacity only need a full bucket
+        assert!(bucket.has(100, much_later + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_publishes_over_the_rate() {
+        let limits = RateLimits {
+            publish_rate: Some(1),
+            byte_rate: Some(100),
+            max_subscriptions: None,
+        };
+        let start = Instant::now();
+
+        let mut quota = ClientQuota::new(&limits, LimitPolicy::Drop, start);
+        assert_eq!(quota.check_publish(10, start), QuotaDecision::Allow);
+        assert_eq!(
+            quota.check_publish(10, start),
+            QuotaDecision::Drop(LimitKind::Publishes)
+        );
+
+        let bytes_only = RateLimits {
+            publish_rate: None,
+            ..limits.clone()
+        };
+        let mut quota = ClientQuota::new(&bytes_only, LimitPolicy::Drop, start);
+        assert_eq!(quota.check_publish(60, start), QuotaDecision::Allow);
+        assert_eq!(
+            quota.check_publish(60, start),
+            QuotaDecision::Drop(LimitKind::Bytes)
+        );
+
+        let mut quota = ClientQuota::new(&limits, LimitPolicy::Throttle, start);
+        assert_eq!(quota.check_publish(10, start), QuotaDecision::Allow);
+        assert_eq!(
+            quota.check_publish(10, start),
+            QuotaDecision::Throttle(LimitKind::Publishes, Duration::from_secs(1))
+        );
+        // The throttled publish was paid in advance, so the next one waits longer
+        assert_eq!(
+            quota.check_publish(10, start),
+            QuotaDecision::Throttle(LimitKind::Publishes, Duration::from_secs(2))
+        );
+        assert_eq!(
+            quota.check_publish(10, start),
+            QuotaDecision::Drop(LimitKind::Publishes)
+        );
+
+        let mut quota = ClientQuota::new(&limits, LimitPolicy::Disconnect, start);
+        assert_eq!(quota.check_publish(10, start), QuotaDecision::Allow);
+        assert_eq!(
+            quota.check_publish(10, start),
+            QuotaDecision::Disconnect(LimitKind::Publishes)
+        );
+    }
+
+    #[test]
+    fn test_connections_of_a_user_share_its_quota() {
+        let limits = RateLimits {
+            publish_rate: Some(1),
+            ..RateLimits::default()
+        };
+        let mut users = HashMap::new();
+        users.insert("sensor".to_string(), limits.clone());
+        let limiter = RateLimiter::new(RateLimitSettings {
+            policy: LimitPolicy::Drop,
+            default: limits,
+            users,
+        });
+        let start = Instant::now();
+
+        let first = limiter.quota_for("sensor", start);
+        let second = limiter.quota_for("sensor", start);
+        assert_eq!(
+            first.lock().unwrap().check_publish(10, start),
+            QuotaDecision::Allow
+        );
+        assert_eq!(
+            second.lock().unwrap().check_publish(10, start),
+            QuotaDecision::Drop(LimitKind::Publishes)
+        );
+
+        // Clients under the default limits have a quota each
+        let anonymous = limiter.quota_for("", start);
+        let other = limiter.quota_for("", start);
+        assert_eq!(
+            anonymous.lock().unwrap().check_publish(10, start),
+            QuotaDecision::Allow
+        );
+        assert_eq!(
+            other.lock().unwrap().check_publish(10, start),
+            QuotaDecision::Allow
+        );
+    }
+}
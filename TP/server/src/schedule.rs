@@ -0,0 +1,286 @@
+use crate::config::ScheduleSettings;
+use crate::managers::brokercore::CoreHandle;
+use crate::managers::delayedmanager::DelayedManager;
+use crate::shutdown::Shutdown;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{event, Level};
+
+/// Time between checks for delayed messages and schedules that are due
+const SCHEDULER_TICK: Duration = Duration::from_secs(1);
+
+/// This struct represents a cron expression with five fields: minute, hour, day of month, month
+/// and day of week. Each field is *, a value, a range a-b, a step */n or a-b/n, or a comma
+/// separated list of them. Days of week go from 0 (Sunday) to 6, 7 is Sunday too.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CronSchedule {
+    /// The allowed values of each field, one bit per value
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Whether the day of month and the day of week fields were restricted, when both are a
+    /// day matching either of them is enough
+    days_restricted: bool,
+    weekdays_restricted: bool,
+}
+
+impl FromStr for CronSchedule {
+    type Err = String;
+
+    fn from_str(expression: &str) -> Result<CronSchedule, String> {
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!(
+                "Invalid cron expression {:?}, expected <minute> <hour> <day> <month> <weekday>",
+                expression
+            ));
+        }
+        let weekdays = parse_field(fields[4], 0, 7)?;
+        Ok(CronSchedule {
+            minutes: parse_field(fields[0], 0, 59)?,
+            hours: parse_field(fields[1], 0, 23)?,
+            days: parse_field(fields[2], 1, 31)?,
+            months: parse_field(fields[3], 1, 12)?,
+            // 7 is another name for Sunday
+            weekdays: (weekdays | weekdays >> 7) & 0x7f,
+            days_restricted: fields[2] != "*",
+            weekdays_restricted: fields[4] != "*",
+        })
+    }
+}
+
+impl CronSchedule {
+    /// Returns whether the schedule fires on the minute of a moment, in UTC
+    /// # Arguments
+    ///
+    /// * `time` - The moment to check
+    ///
+    pub fn matches(&self, time: SystemTime) -> bool {
+        let time = UtcTime::from(time);
+        let has = |values: u64, value: u32| values & (1 << value) != 0;
+        let day = has(self.days, time.day);
+        let weekday = has(self.weekdays, time.weekday);
+        let day_matches = if self.days_restricted && self.weekdays_restricted {
+            day || weekday
+        } else {
+            day && weekday
+        };
+        has(self.minutes, time.minute)
+            && has(self.hours, time.hour)
+            && has(self.months, time.month)
+            && day_matches
+    }
+}
+
+/// Parses a field of a cron expression into a bit for each allowed value
+/// # Arguments
+///
+/// * `field` - A string slice containing the field
+/// * `min` - The lowest value of the field
+/// * `max` - The highest value of the field
+///
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+    let invalid = || format!("Invalid cron field {:?}", field);
+    let mut values = 0_u64;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().map_err(|_| invalid())?),
+            None => (part, 1),
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (
+                start.parse::<u32>().map_err(|_| invalid())?,
+                end.parse::<u32>().map_err(|_| invalid())?,
+            ),
+            None => {
+                let value = range.parse::<u32>().map_err(|_| invalid())?;
+                (value, value)
+            }
+        };
+        if step == 0 || start < min || end > max || start > end {
+            return Err(invalid());
+        }
+        for value in (start..=end).step_by(step as usize) {
+            values |= 1 << value;
+        }
+    }
+    Ok(values)
+}
+
+/// This struct represents the fields of a moment in UTC that a cron expression looks at
+#[derive(Debug, PartialEq)]
+struct UtcTime {
+    minute: u32,
+    hour: u32,
+    day: u32,
+    month: u32,
+    /// Days since Sunday
+    weekday: u32,
+}
+
+impl From<SystemTime> for UtcTime {
+    fn from(time: SystemTime) -> UtcTime {
+        let seconds = time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let days = seconds / 86400;
+        // Converts the days since 1970-01-01 to a civil date, see
+        // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+        let shifted = days + 719_468;
+        let era = shifted / 146_097;
+        let day_of_era = shifted - era * 146_097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month_index = (5 * day_of_year + 2) / 153;
+        UtcTime {
+            minute: (seconds / 60 % 60) as u32,
+            hour: (seconds / 3600 % 24) as u32,
+            day: (day_of_year - (153 * month_index + 2) / 5 + 1) as u32,
+            month: if month_index < 10 {
+                month_index + 3
+            } else {
+                month_index - 9
+            } as u32,
+            // 1970-01-01 was a Thursday
+            weekday: ((days + 4) % 7) as u32,
+        }
+    }
+}
+
+/// Starts the thread that publishes the delayed messages when they are due and the scheduled
+/// messages on each minute their cron expression matches
+/// # Arguments
+///
+/// * `core` - The delayed messages, and the sessions and topics the messages are published to
+/// * `schedules` - The messages published on a schedule
+/// * `delayed_file` - The file where the delayed messages are saved, if any
+/// * `stop_processing` - Requested when the broker is stopping
+///
+pub fn start(
+    core: CoreHandle,
+    schedules: Vec<ScheduleSettings>,
+    delayed_file: Option<String>,
+    stop_processing: Arc<Shutdown>,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        // Schedules fire when their minute starts, not on the one the broker started in
+        let mut last_minute = minute_of(SystemTime::now());
+        loop {
+            let now = SystemTime::now();
+            let mut due = Vec::new();
+            if minute_of(now) != last_minute {
+                last_minute = minute_of(now);
+                for schedule in schedules.iter().filter(|s| s.cron.matches(now)) {
+                    event!(Level::INFO, "SCHED: Publishing schedule {}", schedule.name);
+                    due.push(schedule.to_publish_packet());
+                }
+            }
+
+            core.call(move |core| {
+                due.extend(core.delayed.take_due(now));
+                for publish in due.iter() {
+                    core.publish(publish);
+                }
+            });
+
+            if let Some(delayed_file) = &delayed_file {
+                save_delayed_messages(&core, delayed_file);
+            }
+
+            if stop_processing.wait_timeout(SCHEDULER_TICK) {
+                break;
+            }
+        }
+    })
+}
+
+/// Writes the delayed messages to their file if they changed since the last time
+/// # Arguments
+///
+/// * `core` - The delayed messages
+/// * `delayed_file` - The file where the delayed messages are saved
+///
+pub fn save_delayed_messages(core: &CoreHandle, delayed_file: &str) {
+    let contents = match core.call(|core| {
+        if core.delayed.take_changed() {
+            Some(core.delayed.to_json())
+        } else {
+            None
+        }
+    }) {
+        Some(contents) => contents,
+        None => return,
+    };
+
+    if let Err(e) = DelayedManager::save(delayed_file, &contents) {
+        event!(
+            Level::ERROR,
+            "SCHED: Could not save the delayed messages to {}: {}",
+            delayed_file,
+            e
+        );
+    }
+}
+
+/// Returns the minutes since the epoch of a moment
+fn minute_of(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / 60
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::schedule::{CronSchedule, UtcTime};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    fn at(seconds: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    #[test]
+    fn test_utc_time() {
+        // 2024-02-29 13:45:10, a Thursday
+        assert_eq!(
+            UtcTime::from(at(1_709_214_310)),
+            UtcTime {
+                minute: 45,
+                hour: 13,
+                day: 29,
+                month: 2,
+                weekday: 4,
+            }
+        );
+    }
+
+    #[test]
+    fn test_cron_schedule() {
+        let every_quarter: CronSchedule = "*/15 * * * *".parse().unwrap();
+        assert!(every_quarter.matches(at(1_709_214_300 + 15 * 60)));
+        assert!(!every_quarter.matches(at(1_709_214_360)));
+
+        // 22:00 on weekdays
+        let weeknights: CronSchedule = "0 22 * * 1-5".parse().unwrap();
+        assert!(weeknights.matches(at(1_709_244_000)));
+        assert!(!weeknights.matches(at(1_709_244_000 + 2 * 86400)));
+
+        // The first of the month or any Sunday, 7 being Sunday too
+        let either_day: CronSchedule = "0 0 1 * 7".parse().unwrap();
+        assert!(either_day.matches(at(1_709_251_200)));
+        assert!(either_day.matches(at(1_709_424_000)));
+        assert!(!either_day.matches(at(1_709_337_600)));
+
+        assert!("* * * *".parse::<CronSchedule>().is_err());
+        assert!("60 * * * *".parse::<CronSchedule>().is_err());
+        assert!("*/0 * * * *".parse::<CronSchedule>().is_err());
+        assert!("5-1 * * * *".parse::<CronSchedule>().is_err());
+    }
+}
@@ -0,0 +1,67 @@
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+/// This struct represents a request to stop, shared by the threads that must observe it
+pub struct Shutdown {
+    requested: Mutex<bool>,
+    condvar: Condvar,
+}
+
+impl Shutdown {
+    /// Returns a Shutdown that has not been requested yet
+    pub fn new() -> Shutdown {
+        Shutdown {
+            requested: Mutex::new(false),
+            condvar: Condvar::new(),
+        }
+    }
+
+    /// Requests the stop, waking every thread blocked in wait_timeout
+    pub fn request(&self) {
+        *self.requested.lock().unwrap() = true;
+        self.condvar.notify_all();
+    }
+
+    pub fn is_requested(&self) -> bool {
+        *self.requested.lock().unwrap()
+    }
+
+    /// Sleeps until the timeout elapses or the stop is requested, returning whether it was requested
+    /// # Arguments
+    ///
+    /// * `timeout` - The longest time to sleep
+    ///
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let requested = self.requested.lock().unwrap();
+        let (requested, _) = self
+            .condvar
+            .wait_timeout_while(requested, timeout, |requested| !*requested)
+            .unwrap();
+        *requested
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::shutdown::Shutdown;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_wait_timeout_returns_when_requested() {
+        let shutdown = Arc::new(Shutdown::new());
+        assert!(!shutdown.wait_timeout(Duration::from_millis(10)));
+
+        let requester = Arc::clone(&shutdown);
+        let start = Instant::now();
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            requester.request();
+        });
+        assert!(shutdown.wait_timeout(Duration::from_secs(10)));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(shutdown.is_requested());
+        handle.join().unwrap();
+    }
+}
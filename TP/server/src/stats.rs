@@ -0,0 +1,355 @@
+use crate::lockorder::{self, HeldLock, LockName};
+use crate::ratelimit::LimitKind;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// This struct represents a lock taken through BrokerStats::lock, released when dropped
+pub struct LockGuard<'a, T: ?Sized> {
+    guard: MutexGuard<'a, T>,
+    /// Dropped after the guard, once the lock is released
+    _held: HeldLock,
+}
+
+impl<T: ?Sized> Deref for LockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> DerefMut for LockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+/// This struct represents how often a lock was taken and how long callers waited for it
+#[derive(Debug, Default)]
+struct LockStats {
+    acquisitions: AtomicU64,
+    wait_nanos: AtomicU64,
+}
+
+/// This struct represents the counters of the broker, shared by every connection
+#[derive(Debug)]
+pub struct BrokerStats {
+    started: Instant,
+    /// Packets read from clients, indexed by packet type
+    packets_received: [AtomicU64; 16],
+    /// PUBLISH packets read from clients, indexed by QoS
+    publishes_received: [AtomicU64; 3],
+    /// PUBLISH packets written to clients, indexed by QoS
+    publishes_sent: [AtomicU64; 3],
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    connections_accepted: AtomicU64,
+    connections_rejected: AtomicU64,
+    auth_failures: AtomicU64,
+    retransmissions: AtomicU64,
+    /// Messages dropped from full outbound queues
+    outbound_dropped: AtomicU64,
+    slow_consumer_disconnects: AtomicU64,
+    /// Pending and retained messages dropped because they expired
+    expired_messages: AtomicU64,
+    /// QoS 1 messages resent by their publisher that were acknowledged but not delivered again
+    duplicate_publishes: AtomicU64,
+    /// Clients that went over a limit, indexed by LimitKind
+    rate_limit_violations: [AtomicU64; 3],
+    queue_depth: AtomicUsize,
+    locks: [LockStats; 3],
+}
+
+impl BrokerStats {
+    /// Returns a BrokerStats with every counter at zero
+    pub fn new() -> BrokerStats {
+        BrokerStats {
+            started: Instant::now(),
+            packets_received: Default::default(),
+            publishes_received: Default::default(),
+            publishes_sent: Default::default(),
+            bytes_received: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            connections_accepted: AtomicU64::new(0),
+            connections_rejected: AtomicU64::new(0),
+            auth_failures: AtomicU64::new(0),
+            retransmissions: AtomicU64::new(0),
+            outbound_dropped: AtomicU64::new(0),
+            slow_consumer_disconnects: AtomicU64::new(0),
+            expired_messages: AtomicU64::new(0),
+            duplicate_publishes: AtomicU64::new(0),
+            rate_limit_violations: Default::default(),
+            queue_depth: AtomicUsize::new(0),
+            locks: Default::default(),
+        }
+    }
+
+    /// Takes a shared lock, recording how long the caller waited for it. Panics when the lock
+    /// is taken out of order, which could deadlock.
+    /// # Arguments
+    ///
+    /// * `name` - The lock being taken
+    /// * `mutex` - The mutex that holds the lock
+    ///
+    pub fn lock<'a, T: ?Sized>(&self, name: LockName, mutex: &'a Mutex<T>) -> LockGuard<'a, T> {
+        let held = lockorder::acquire(name);
+        let start = Instant::now();
+        let guard = mutex.lock().unwrap();
+        self.add_lock_wait(name, start.elapsed());
+        LockGuard { guard, _held: held }
+    }
+
+    /// Counts an acquisition of a lock and how long the caller waited for it
+    /// # Arguments
+    ///
+    /// * `name` - The lock that was taken
+    /// * `wait` - The time between asking for the lock and getting it
+    ///
+    pub fn add_lock_wait(&self, name: LockName, wait: Duration) {
+        let lock = &self.locks[name as usize];
+        lock.acquisitions.fetch_add(1, Ordering::Relaxed);
+        lock.wait_nanos
+            .fetch_add(wait.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// Counts a packet read from a client
+    /// # Arguments
+    ///
+    /// * `packet_type` - The packet type from the fixed header
+    ///
+    pub fn add_packet_received(&self, packet_type: u8) {
+        if let Some(counter) = self.packets_received.get(packet_type as usize) {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Counts a PUBLISH received from a client
+    /// # Arguments
+    ///
+    /// * `qos` - The QoS of the received message
+    ///
+    pub fn add_publish_received(&self, qos: u8) {
+        if let Some(counter) = self.publishes_received.get(qos as usize) {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Counts a PUBLISH sent to a client
+    /// # Arguments
+    ///
+    /// * `qos` - The QoS the message was delivered with
+    ///
+    pub fn add_publish_sent(&self, qos: u8) {
+        if let Some(counter) = self.publishes_sent.get(qos as usize) {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Counts bytes read from a client
+    /// # Arguments
+    ///
+    /// * `bytes` - The amount of bytes read
+    ///
+    pub fn add_bytes_received(&self, bytes: usize) {
+        self.bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Counts bytes written to a client
+    /// # Arguments
+    ///
+    /// * `bytes` - The amount of bytes written
+    ///
+    pub fn add_bytes_sent(&self, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_connection_accepted(&self) {
+        self.connections_accepted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_connection_rejected(&self) {
+        self.connections_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_auth_failure(&self) {
+        self.auth_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a pending message sent again because it was not acknowledged
+    pub fn add_retransmission(&self) {
+        self.retransmissions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a message dropped because the outbound queue of its subscriber was full
+    pub fn add_outbound_dropped(&self) {
+        self.outbound_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a client disconnected because its outbound queue was full
+    pub fn add_slow_consumer_disconnect(&self) {
+        self.slow_consumer_disconnects
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts messages dropped because they expired before they were delivered
+    /// # Arguments
+    ///
+    /// * `amount` - The amount of pending and retained messages dropped
+    ///
+    pub fn add_expired_messages(&self, amount: usize) {
+        self.expired_messages
+            .fetch_add(amount as u64, Ordering::Relaxed);
+    }
+
+    /// Counts a resent QoS 1 message that was not delivered again
+    pub fn add_duplicate_publish(&self) {
+        self.duplicate_publishes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a client that went over one of its limits
+    /// # Arguments
+    ///
+    /// * `kind` - The limit the client went over
+    ///
+    pub fn add_rate_limit_violation(&self, kind: LimitKind) {
+        self.rate_limit_violations[kind as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records the amount of jobs waiting for a thread of the pool
+    pub fn set_queue_depth(&self, depth: usize) {
+        self.queue_depth.store(depth, Ordering::Relaxed);
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn packets_received(&self, packet_type: u8) -> u64 {
+        self.packets_received
+            .get(packet_type as usize)
+            .map_or(0, |counter| counter.load(Ordering::Relaxed))
+    }
+
+    pub fn publishes_received(&self, qos: u8) -> u64 {
+        self.publishes_received
+            .get(qos as usize)
+            .map_or(0, |counter| counter.load(Ordering::Relaxed))
+    }
+
+    pub fn publishes_sent(&self, qos: u8) -> u64 {
+        self.publishes_sent
+            .get(qos as usize)
+            .map_or(0, |counter| counter.load(Ordering::Relaxed))
+    }
+
+    /// Returns the PUBLISH packets received, whatever their QoS
+    pub fn messages_received(&self) -> u64 {
+        (0..3).map(|qos| self.publishes_received(qos)).sum()
+    }
+
+    /// Returns the PUBLISH packets sent, whatever their QoS
+    pub fn messages_sent(&self) -> u64 {
+        (0..3).map(|qos| self.publishes_sent(qos)).sum()
+    }
+
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    pub fn connections_accepted(&self) -> u64 {
+        self.connections_accepted.load(Ordering::Relaxed)
+    }
+
+    pub fn connections_rejected(&self) -> u64 {
+        self.connections_rejected.load(Ordering::Relaxed)
+    }
+
+    pub fn auth_failures(&self) -> u64 {
+        self.auth_failures.load(Ordering::Relaxed)
+    }
+
+    pub fn retransmissions(&self) -> u64 {
+        self.retransmissions.load(Ordering::Relaxed)
+    }
+
+    pub fn outbound_dropped(&self) -> u64 {
+        self.outbound_dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn slow_consumer_disconnects(&self) -> u64 {
+        self.slow_consumer_disconnects.load(Ordering::Relaxed)
+    }
+
+    pub fn expired_messages(&self) -> u64 {
+        self.expired_messages.load(Ordering::Relaxed)
+    }
+
+    pub fn duplicate_publishes(&self) -> u64 {
+        self.duplicate_publishes.load(Ordering::Relaxed)
+    }
+
+    pub fn rate_limit_violations(&self, kind: LimitKind) -> u64 {
+        self.rate_limit_violations[kind as usize].load(Ordering::Relaxed)
+    }
+
+    pub fn queue_depth(&self) -> usize {
+        self.queue_depth.load(Ordering::Relaxed)
+    }
+
+    pub fn lock_acquisitions(&self, name: LockName) -> u64 {
+        self.locks[name as usize]
+            .acquisitions
+            .load(Ordering::Relaxed)
+    }
+
+    pub fn lock_wait(&self, name: LockName) -> Duration {
+        Duration::from_nanos(self.locks[name as usize].wait_nanos.load(Ordering::Relaxed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lockorder::LockName;
+    use crate::stats::BrokerStats;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_messages_add_up_every_qos() {
+        let stats = BrokerStats::new();
+        stats.add_publish_received(0);
+        stats.add_publish_received(2);
+        stats.add_publish_received(3);
+        stats.add_publish_sent(1);
+        assert_eq!(stats.publishes_received(2), 1);
+        assert_eq!(stats.messages_received(), 2);
+        assert_eq!(stats.messages_sent(), 1);
+    }
+
+    #[test]
+    fn test_lock_counts_acquisitions() {
+        let stats = BrokerStats::new();
+        let mutex = Mutex::new(1);
+        *stats.lock(LockName::Streams, &mutex) += 1;
+        let value = *stats.lock(LockName::Streams, &mutex);
+        assert_eq!(value, 2);
+        assert_eq!(stats.lock_acquisitions(LockName::Streams), 2);
+        assert_eq!(stats.lock_acquisitions(LockName::Core), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Lock credentials taken while holding [\"streams\"]")]
+    fn test_lock_taken_out_of_order_panics() {
+        let stats = BrokerStats::new();
+        let credentials = Mutex::new(());
+        let streams = Mutex::new(());
+        let _streams = stats.lock(LockName::Streams, &streams);
+        let _credentials = stats.lock(LockName::Credentials, &credentials);
+    }
+}
@@ -0,0 +1,140 @@
+use crate::lockorder::LockName;
+use crate::managers::brokercore::CoreHandle;
+use crate::managers::sessionmanager::Socket;
+use crate::stats::BrokerStats;
+use crate::transport::client_stream::ConnectionId;
+use shared::packages::publish::Publish;
+use std::sync::{Arc, Mutex};
+
+static SYS_PREFIX: &str = "$SYS/broker/";
+
+/// This struct represents the state of the managers at the moment the $SYS topics are published
+#[derive(Debug, Clone, PartialEq)]
+pub struct SysSnapshot {
+    pub clients_connected: usize,
+    pub clients_total: usize,
+    pub retained_messages: usize,
+    pub subscriptions: usize,
+    pub inflight_messages: usize,
+}
+
+impl SysSnapshot {
+    /// Reads the counts from each manager, waiting for a single lock at a time
+    /// # Arguments
+    ///
+    /// * `core` - The sessions, subscriptions, retained and pending messages of the broker
+    /// * `active_streams` - The sockets of the connected clients
+    /// * `stats` - The broker counters, which measure the wait for each lock
+    ///
+    pub fn collect(
+        core: &CoreHandle,
+        active_streams: &Arc<Mutex<Vec<Socket>>>,
+        stats: &BrokerStats,
+    ) -> SysSnapshot {
+        let active_connections: Vec<ConnectionId> = stats
+            .lock(LockName::Streams, active_streams)
+            .iter()
+            .map(|socket| socket.connection_id)
+            .collect();
+
+        core.call(move |core| SysSnapshot {
+            clients_connected: active_connections
+                .iter()
+                .filter(|connection_id| core.sessions.has_connection(connection_id))
+                .count(),
+            clients_total: core.sessions.session_count(),
+            retained_messages: core.topics.retained_count(),
+            subscriptions: core.topics.subscription_count(),
+            inflight_messages: core.messages.pending_count(),
+        })
+    }
+
+    /// Returns the retained publish packets of every $SYS topic
+    /// # Arguments
+    ///
+    /// * `stats` - The traffic counters of the broker
+    ///
+    pub fn to_publish_packets(&self, stats: &BrokerStats) -> Vec<Publish> {
+        let values = [
+            ("version", env!("CARGO_PKG_VERSION").to_string()),
+            ("uptime", stats.uptime().as_secs().to_string()),
+            ("clients/connected", self.clients_connected.to_string()),
+            (
+                "clients/disconnected",
+                self.clients_total
+                    .saturating_sub(self.clients_connected)
+                    .to_string(),
+            ),
+            ("clients/total", self.clients_total.to_string()),
+            ("messages/received", stats.messages_received().to_string()),
+            ("messages/sent", stats.messages_sent().to_string()),
+            ("messages/retained", self.retained_messages.to_string()),
+            ("messages/inflight", self.inflight_messages.to_string()),
+            ("bytes/received", stats.bytes_received().to_string()),
+            ("bytes/sent", stats.bytes_sent().to_string()),
+            ("subscriptions/count", self.subscriptions.to_string()),
+        ];
+
+        values
+            .iter()
+            .map(|(topic, value)| Publish {
+                topic_name: SYS_PREFIX.to_owned() + topic,
+                payload: value.to_string(),
+                packet_id: 0_u16,
+                qos: 0_u8,
+                retain_flag: 1_u8,
+                dup_flag: 0_u8,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stats::BrokerStats;
+    use crate::sys::SysSnapshot;
+
+    #[test]
+    fn test_sys_topics() {
+        let stats = BrokerStats::new();
+        stats.add_publish_received(1);
+        stats.add_bytes_sent(42);
+        let snapshot = SysSnapshot {
+            clients_connected: 2,
+            clients_total: 5,
+            retained_messages: 1,
+            subscriptions: 7,
+            inflight_messages: 3,
+        };
+
+        let packets = snapshot.to_publish_packets(&stats);
+        let value = |topic: &str| {
+            packets
+                .iter()
+                .find(|packet| packet.topic_name == topic)
+                .map(|packet| packet.payload.to_string())
+        };
+        assert_eq!(
+            value("$SYS/broker/clients/connected"),
+            Some("2".to_string())
+        );
+        assert_eq!(
+            value("$SYS/broker/clients/disconnected"),
+            Some("3".to_string())
+        );
+        assert_eq!(
+            value("$SYS/broker/messages/received"),
+            Some("1".to_string())
+        );
+        assert_eq!(value("$SYS/broker/bytes/sent"), Some("42".to_string()));
+        assert_eq!(
+            value("$SYS/broker/messages/inflight"),
+            Some("3".to_string())
+        );
+        assert_eq!(
+            value("$SYS/broker/subscriptions/count"),
+            Some("7".to_string())
+        );
+        assert!(packets.iter().all(|packet| packet.retain_flag == 1));
+    }
+}
@@ -1,2 +1,3 @@
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod tests {}
@@ -1,8 +1,10 @@
 use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc;
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::thread;
+use tracing::{event, Level};
 
 /// This struct represents a thread pool creation error
 #[derive(Debug, Clone)]
@@ -19,6 +21,8 @@ impl fmt::Display for PoolCreationError {
 pub struct ThreadPool {
     workers: Vec<Worker>,
     sender: mpsc::Sender<Message>,
+    /// Amount of jobs sent that no worker has taken yet
+    queued: Arc<AtomicUsize>,
 }
 
 impl ThreadPool {
@@ -29,7 +33,7 @@ impl ThreadPool {
     ///
     /// # Examples
     ///
-    /// ```
+    /// ```ignore
     /// use threadpool::ThreadPool;
     ///
     /// let pool = threadpool::ThreadPool::new(4);
@@ -41,11 +45,16 @@ impl ThreadPool {
             let mut workers = Vec::with_capacity(size);
             let (sender, receiver) = mpsc::channel();
             let receiver = Arc::new(Mutex::new(receiver));
+            let queued = Arc::new(AtomicUsize::new(0));
 
             for id in 0..size {
-                workers.push(Worker::new(id, Arc::clone(&receiver)));
+                workers.push(Worker::new(id, Arc::clone(&receiver), Arc::clone(&queued)));
             }
-            Ok(ThreadPool { workers, sender })
+            Ok(ThreadPool {
+                workers,
+                sender,
+                queued,
+            })
         }
     }
 
@@ -56,7 +65,7 @@ impl ThreadPool {
     ///
     /// # Examples
     ///
-    /// ```
+    /// ```ignore
     /// use threadpool::ThreadPool;
     ///
     /// let pool = threadpool::ThreadPool::new(4);
@@ -71,25 +80,34 @@ impl ThreadPool {
     {
         let job = Box::new(f);
 
+        self.queued.fetch_add(1, Ordering::Relaxed);
         self.sender.send(Message::NewJob(job)).unwrap();
     }
+
+    /// Returns the amount of jobs waiting for a free worker
+    pub fn queue_depth(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
 }
 
 impl Drop for ThreadPool {
     fn drop(&mut self) {
-        println!("Sending terminate message to all workers.");
+        event!(Level::DEBUG, "Sending terminate message to all workers.");
 
+        // Jobs sent before the Terminate messages are still run, which drains the queue
         for _ in &self.workers {
             self.sender.send(Message::Terminate).unwrap();
         }
 
-        println!("Shutting down all workers.");
+        event!(Level::DEBUG, "Shutting down all workers.");
 
         for worker in &mut self.workers {
-            println!("Join and shutdown worker {}", worker.id);
+            event!(Level::DEBUG, "Join and shutdown worker {}", worker.id);
 
             if let Some(thread) = worker.thread.take() {
-                thread.join().unwrap();
+                if thread.join().is_err() {
+                    event!(Level::ERROR, "Worker {} panicked", worker.id);
+                }
             }
         }
     }
@@ -109,21 +127,27 @@ impl Worker {
     ///
     /// * `id` - A numeric identifier
     /// * `receiver` - An Arc that wraps a receiver containing a message to process by the worker
+    /// * `queued` - The counter of jobs waiting for a worker, decremented when a job is taken
     ///
     /// # Examples
     ///
-    /// ```
+    /// ```ignore
     /// let (sender, receiver) = mpsc::channel();
     /// let receiver = Arc::new(Mutex::new(receiver));
-    /// let worker = Worker::new(id, Arc::clone(&receiver))
+    /// let worker = Worker::new(id, Arc::clone(&receiver), Arc::new(AtomicUsize::new(0)))
     /// ```
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Message>>>) -> Worker {
+    fn new(
+        id: usize,
+        receiver: Arc<Mutex<mpsc::Receiver<Message>>>,
+        queued: Arc<AtomicUsize>,
+    ) -> Worker {
         let thread = thread::spawn(move || loop {
             // Change this unwrap to an expect with an error message if needed
             let message = receiver.lock().unwrap().recv().unwrap();
 
             match message {
                 Message::NewJob(job) => {
+                    queued.fetch_sub(1, Ordering::Relaxed);
                     // println!("Worker {} got a job; executing.", id);
 
                     job();
@@ -0,0 +1,617 @@
+use crate::config::{ListenerSettings, OutboundSettings, TenancySettings};
+use crate::hooks::Hooks;
+use crate::managers::topicmanager::MountPoint;
+use crate::ratelimit::{ClientQuota, RateLimiter};
+use crate::stats::BrokerStats;
+use crate::transport::outbound::{Enqueued, OutboundQueue};
+use crate::transport::tls::TlsStream;
+use crate::transport::websocket::WsStream;
+use shared::packages::packet::WritablePacket;
+use shared::packages::publish::Publish;
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{event, Level};
+
+/// Each connection gets its id from this counter, so ids are never reused while the broker runs
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// This struct represents the id the broker assigns to each connection it accepts. Unlike
+/// the peer port, it tells apart clients on different hosts that use the same port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ConnectionId(u64);
+
+impl ConnectionId {
+    /// Returns an id no other connection has
+    pub fn next() -> ConnectionId {
+        ConnectionId(NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+/// This enum represents the transport a connection was accepted on
+#[derive(Debug)]
+pub enum Transport {
+    /// A plain TCP connection
+    Tcp(TcpStream),
+    /// A TLS connection over TCP
+    Tls(TlsStream),
+    /// An MQTT over WebSocket connection
+    Ws(WsStream),
+    /// A plain connection over a Unix domain socket
+    Unix(UnixStream),
+}
+
+impl Transport {
+    /// Returns a new handle to the same transport
+    fn try_clone(&self) -> io::Result<Transport> {
+        Ok(match self {
+            Transport::Tcp(stream) => Transport::Tcp(stream.try_clone()?),
+            Transport::Tls(stream) => Transport::Tls(stream.try_clone()?),
+            Transport::Ws(stream) => Transport::Ws(stream.try_clone()?),
+            Transport::Unix(stream) => Transport::Unix(stream.try_clone()?),
+        })
+    }
+}
+
+/// This struct represents the outbound queue as seen by the handles of a connection. Once
+/// the last handle is dropped, the writer finishes the queued packets and closes the transport.
+#[derive(Debug)]
+struct Outbound(Arc<OutboundQueue>);
+
+impl Drop for Outbound {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+/// This struct represents when a connection was last heard from and whether it is being read
+#[derive(Debug)]
+struct Activity {
+    /// When the broker accepted the connection
+    accepted: Instant,
+    /// When the last packet of the connection was handled
+    last_packet: Instant,
+    /// The client sent its CONNECT and was accepted
+    connected: bool,
+    /// The keep alive the client asked for, None until it connects or if it asked for none
+    keep_alive: Option<Duration>,
+    /// A packet of the connection is being handled, so no one else may read from it
+    busy: bool,
+    /// The connection is not read until then, so a throttled client waits without using a worker
+    paused_until: Option<Instant>,
+}
+
+/// This struct represents a packet of a connection being handled. Dropping it ends the handling,
+/// which counts as activity of the client, even if the handler panicked.
+#[derive(Debug)]
+#[must_use = "the connection is not read again until the Handling is dropped"]
+pub struct Handling {
+    activity: Arc<Mutex<Activity>>,
+}
+
+impl Drop for Handling {
+    fn drop(&mut self) {
+        // Panicking here while a panicking handler unwinds would abort the broker
+        let mut activity = self.activity.lock().unwrap_or_else(PoisonError::into_inner);
+        activity.busy = false;
+        activity.last_packet = Instant::now();
+    }
+}
+
+/// This struct represents a connection accepted by the broker, regardless of its transport
+#[derive(Debug)]
+pub struct ClientStream {
+    transport: Transport,
+    /// The id the broker assigned to this connection
+    connection_id: ConnectionId,
+    /// The address of the peer, used to tell clients apart in the logs
+    peer_addr: String,
+    /// The listener that accepted this connection
+    listener: Arc<ListenerSettings>,
+    /// The broker counters updated with the traffic of this connection
+    stats: Arc<BrokerStats>,
+    /// The configured limits, applied once the client authenticates
+    rate_limits: Arc<RateLimiter>,
+    /// The configured tenants, whose namespace is applied once the client authenticates
+    tenancy: Arc<TenancySettings>,
+    /// The limits of this connection, shared by every handle to it and by the other connections
+    /// of its user if the user has limits of its own
+    quota: Arc<Mutex<Arc<Mutex<ClientQuota>>>>,
+    /// The user the client authenticated as, shared by every handle to it
+    username: Arc<Mutex<String>>,
+    /// The namespace the topics of the client are mounted in, shared by every handle to it
+    mount_point: Arc<Mutex<Option<MountPoint>>>,
+    /// The hooks called by the packet handlers of this connection
+    hooks: Arc<Hooks>,
+    /// The packets waiting for the writer of this connection, shared by every handle to it
+    outbound: Arc<Outbound>,
+    /// When the client was last heard from, shared by every handle to it
+    activity: Arc<Mutex<Activity>>,
+}
+
+impl ClientStream {
+    /// Returns a ClientStream for an established transport and starts the thread that writes
+    /// its outbound queue
+    /// # Arguments
+    ///
+    /// * `transport` - The transport of the connection, after any handshake
+    /// * `listener` - The settings of the listener that accepted the connection
+    /// * `stats` - The broker counters to update with the traffic of the connection
+    /// * `rate_limits` - The limits of every client and of each user
+    /// * `tenancy` - The tenants the users are mounted in
+    /// * `outbound` - The size of the outbound queue and what happens when it is full
+    /// * `hooks` - The hooks of the broker
+    ///
+    pub fn new(
+        transport: Transport,
+        listener: Arc<ListenerSettings>,
+        stats: Arc<BrokerStats>,
+        rate_limits: Arc<RateLimiter>,
+        tenancy: Arc<TenancySettings>,
+        outbound: OutboundSettings,
+        hooks: Arc<Hooks>,
+    ) -> io::Result<ClientStream> {
+        let connection_id = ConnectionId::next();
+        let peer_addr = match &transport {
+            Transport::Tcp(stream) => stream.peer_addr()?.to_string(),
+            Transport::Tls(stream) => stream.peer_addr()?.to_string(),
+            Transport::Ws(stream) => stream.peer_addr()?.to_string(),
+            Transport::Unix(_) => format!("unix{}", connection_id),
+        };
+        let queue = Arc::new(OutboundQueue::new(outbound));
+        let writer = transport.try_clone()?;
+        let writer_queue = Arc::clone(&queue);
+        let writer_stats = Arc::clone(&stats);
+        let writer_peer = peer_addr.to_string();
+        thread::spawn(move || write_outbound(writer, &writer_queue, &writer_stats, &writer_peer));
+        Ok(ClientStream {
+            transport,
+            connection_id,
+            peer_addr,
+            listener,
+            stats,
+            rate_limits,
+            tenancy,
+            quota: Arc::new(Mutex::new(Arc::new(Mutex::new(ClientQuota::unlimited())))),
+            username: Arc::new(Mutex::new(String::new())),
+            mount_point: Arc::new(Mutex::new(None)),
+            hooks,
+            outbound: Arc::new(Outbound(queue)),
+            activity: Arc::new(Mutex::new(Activity {
+                accepted: Instant::now(),
+                last_packet: Instant::now(),
+                connected: false,
+                keep_alive: None,
+                busy: false,
+                paused_until: None,
+            })),
+        })
+    }
+
+    /// Returns a new handle to the same connection
+    pub fn try_clone(&self) -> io::Result<ClientStream> {
+        Ok(ClientStream {
+            transport: self.transport.try_clone()?,
+            connection_id: self.connection_id,
+            peer_addr: self.peer_addr.to_string(),
+            listener: Arc::clone(&self.listener),
+            stats: Arc::clone(&self.stats),
+            rate_limits: Arc::clone(&self.rate_limits),
+            tenancy: Arc::clone(&self.tenancy),
+            quota: Arc::clone(&self.quota),
+            username: Arc::clone(&self.username),
+            mount_point: Arc::clone(&self.mount_point),
+            hooks: Arc::clone(&self.hooks),
+            outbound: Arc::clone(&self.outbound),
+            activity: Arc::clone(&self.activity),
+        })
+    }
+
+    /// Returns the id the broker assigned to this connection, shared by every handle to it
+    pub fn connection_id(&self) -> ConnectionId {
+        self.connection_id
+    }
+
+    /// Returns the address of the peer, or unix#<connection id> for Unix domain sockets
+    pub fn peer_addr(&self) -> &str {
+        &self.peer_addr
+    }
+
+    /// Returns the settings of the listener that accepted this connection
+    pub fn listener(&self) -> &ListenerSettings {
+        &self.listener
+    }
+
+    /// Returns the broker counters updated by this connection
+    pub fn stats(&self) -> &BrokerStats {
+        &self.stats
+    }
+
+    /// Returns the hooks called by the packet handlers of this connection
+    pub fn hooks(&self) -> &Hooks {
+        &self.hooks
+    }
+
+    /// Returns the user the client authenticated as, empty until it does or for anonymous clients
+    pub fn username(&self) -> String {
+        self.username.lock().unwrap().to_string()
+    }
+
+    /// Records the user the client authenticated as
+    /// # Arguments
+    ///
+    /// * `username` - The user the client authenticated as, empty for anonymous clients
+    ///
+    pub fn set_username(&self, username: &str) {
+        *self.username.lock().unwrap() = username.to_string();
+    }
+
+    /// Starts enforcing the limits of a user on this connection
+    /// # Arguments
+    ///
+    /// * `username` - The user the client authenticated as, empty for anonymous clients
+    ///
+    pub fn apply_rate_limits(&self, username: &str) {
+        *self.quota.lock().unwrap() = self.rate_limits.quota_for(username, Instant::now());
+    }
+
+    /// Mounts the topics of this connection in the namespace of a user, if it has one
+    /// # Arguments
+    ///
+    /// * `username` - The user the client authenticated as, empty for anonymous clients
+    ///
+    pub fn apply_mount_point(&self, username: &str) -> Result<(), String> {
+        *self.mount_point.lock().unwrap() = self.tenancy.mount_point_for(username)?;
+        Ok(())
+    }
+
+    /// Returns a topic or filter sent by the client as it is known to the broker, inside the
+    /// namespace of the client if it has one. Returns None if the namespace refuses it.
+    /// # Arguments
+    ///
+    /// * `topic` - A string slice containing the topic or filter sent by the client
+    ///
+    pub fn mount(&self, topic: &str) -> Option<String> {
+        match &*self.mount_point.lock().unwrap() {
+            Some(mount_point) => mount_point.mount(topic),
+            None => Some(topic.to_string()),
+        }
+    }
+
+    /// Returns the limits of this connection and the tokens it has left
+    pub fn quota(&self) -> Arc<Mutex<ClientQuota>> {
+        Arc::clone(&self.quota.lock().unwrap())
+    }
+
+    /// Records that the client connected and the keep alive it asked for in its CONNECT
+    /// # Arguments
+    ///
+    /// * `keep_alive` - The longest time between two packets of the client, zero turns the check off
+    ///
+    pub fn set_keep_alive(&self, keep_alive: Duration) {
+        let mut activity = self.activity.lock().unwrap();
+        activity.connected = true;
+        activity.keep_alive = Some(keep_alive).filter(|k| !k.is_zero());
+    }
+
+    /// Returns whether the connection was accepted longer than a timeout ago and the client has
+    /// not connected yet
+    /// # Arguments
+    ///
+    /// * `now` - The current time
+    /// * `connect_timeout` - The time a new connection has to send its CONNECT
+    ///
+    pub fn connect_expired(&self, now: Instant, connect_timeout: Duration) -> bool {
+        let activity = self.activity.lock().unwrap();
+        !activity.connected
+            && !activity.busy
+            && now.duration_since(activity.accepted) > connect_timeout
+    }
+
+    /// Returns whether the client sent nothing for one and a half times its keep alive
+    /// # Arguments
+    ///
+    /// * `now` - The current time
+    ///
+    pub fn keep_alive_expired(&self, now: Instant) -> bool {
+        let activity = self.activity.lock().unwrap();
+        match activity.keep_alive {
+            Some(keep_alive) if !activity.busy => {
+                now.duration_since(activity.last_packet) > keep_alive * 3 / 2
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns whether a packet of the connection is being handled
+    pub fn is_busy(&self) -> bool {
+        self.activity.lock().unwrap().busy
+    }
+
+    /// Stops reading the packets of the connection until an instant
+    /// # Arguments
+    ///
+    /// * `until` - The instant the connection can be read again
+    ///
+    pub fn pause_reading(&self, until: Instant) {
+        self.activity.lock().unwrap().paused_until = Some(until);
+    }
+
+    /// Returns whether the packets of the connection are not read yet
+    /// # Arguments
+    ///
+    /// * `now` - The current time
+    ///
+    pub fn is_paused(&self, now: Instant) -> bool {
+        let mut activity = self.activity.lock().unwrap();
+        match activity.paused_until {
+            Some(until) if now < until => true,
+            _ => {
+                activity.paused_until = None;
+                false
+            }
+        }
+    }
+
+    /// Marks the connection as being read until the returned Handling is dropped
+    pub fn start_handling(&self) -> Handling {
+        self.activity.lock().unwrap().busy = true;
+        Handling {
+            activity: Arc::clone(&self.activity),
+        }
+    }
+
+    /// Sets the read timeout of the underlying socket
+    /// # Arguments
+    ///
+    /// * `timeout` - The timeout to set, None means blocking reads
+    ///
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match &self.transport {
+            Transport::Tcp(stream) => stream.set_read_timeout(timeout),
+            Transport::Tls(stream) => stream.set_read_timeout(timeout),
+            Transport::Ws(stream) => stream.set_read_timeout(timeout),
+            Transport::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    /// Queues a packet that must reach the client, written after the ones queued before it
+    /// # Arguments
+    ///
+    /// * `packet` - The packet to send
+    ///
+    pub fn send(&self, packet: &dyn WritablePacket) -> io::Result<()> {
+        let mut bytes = Vec::new();
+        packet.write_to(&mut bytes)?;
+        self.outbound.0.push_response(bytes)
+    }
+
+    /// Queues a message for the client. When the queue is full the oldest message is dropped,
+    /// or the queue is closed and the client marked as a slow consumer, depending on the policy.
+    /// # Arguments
+    ///
+    /// * `publish` - The message to send, with its topic inside the namespace of the client
+    ///
+    pub fn send_message(&self, publish: &Publish) -> io::Result<()> {
+        let mut bytes = Vec::new();
+        match &*self.mount_point.lock().unwrap() {
+            Some(mount_point) => match mount_point.unmount(&publish.topic_name) {
+                Some(topic_name) => Publish {
+                    topic_name: topic_name.to_string(),
+                    ..publish.clone()
+                }
+                .write_to(&mut bytes)?,
+                // A hook may have moved the message out of the namespace of the client
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "{:?} is outside the namespace of the client",
+                            publish.topic_name
+                        ),
+                    ))
+                }
+            },
+            None => publish.write_to(&mut bytes)?,
+        }
+        match self.outbound.0.push_message(bytes) {
+            Ok(Enqueued::Queued) => {}
+            Ok(Enqueued::DroppedOldest) => {
+                self.stats.add_outbound_dropped();
+                event!(
+                    Level::WARN,
+                    "Outbound queue of {} is full, its oldest message was dropped",
+                    self.peer_addr
+                );
+            }
+            Err(e) => return Err(e),
+        }
+        Ok(())
+    }
+
+    /// Returns whether the client did not keep up with its messages and must be disconnected
+    pub fn is_slow_consumer(&self) -> bool {
+        self.outbound.0.is_slow_consumer()
+    }
+
+    /// Returns the amount of packets waiting to be written to the client
+    pub fn outbound_len(&self) -> usize {
+        self.outbound.0.len()
+    }
+
+    /// Closes both directions of the connection, every handle to it sees the end of stream.
+    /// The packets still queued are dropped.
+    pub fn shutdown(&self) -> io::Result<()> {
+        self.outbound.0.abort();
+        match &self.transport {
+            Transport::Tcp(stream) => stream.shutdown(Shutdown::Both),
+            Transport::Tls(stream) => stream.shutdown(),
+            Transport::Ws(stream) => stream.shutdown(),
+            Transport::Unix(stream) => stream.shutdown(Shutdown::Both),
+        }
+    }
+
+    /// Receives data without removing it from the connection
+    /// # Arguments
+    ///
+    /// * `buf` - A buffer to copy the available data into
+    ///
+    pub fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        match &self.transport {
+            Transport::Tcp(stream) => stream.peek(buf),
+            Transport::Tls(stream) => stream.peek(buf),
+            Transport::Ws(stream) => stream.peek(buf),
+            Transport::Unix(stream) => unix_peek(stream, buf),
+        }
+    }
+
+    /// Returns the username taken from the verified client certificate, if any
+    pub fn certificate_username(&self) -> Option<&str> {
+        match &self.transport {
+            Transport::Tls(stream) => stream.certificate_username(),
+            _ => None,
+        }
+    }
+}
+
+/// UnixStream::peek is not stable yet, so it is done with recv and MSG_PEEK
+fn unix_peek(stream: &UnixStream, buf: &mut [u8]) -> io::Result<usize> {
+    let size = unsafe {
+        libc::recv(
+            stream.as_raw_fd(),
+            buf.as_mut_ptr() as *mut libc::c_void,
+            buf.len(),
+            libc::MSG_PEEK,
+        )
+    };
+    if size < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(size as usize)
+    }
+}
+
+impl Read for ClientStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = match &mut self.transport {
+            Transport::Tcp(stream) => stream.read(buf),
+            Transport::Tls(stream) => stream.read(buf),
+            Transport::Ws(stream) => stream.read(buf),
+            Transport::Unix(stream) => stream.read(buf),
+        }?;
+        self.stats.add_bytes_received(size);
+        Ok(size)
+    }
+}
+
+impl Write for Transport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Transport::Tcp(stream) => stream.write(buf),
+            Transport::Tls(stream) => stream.write(buf),
+            Transport::Ws(stream) => stream.write(buf),
+            Transport::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Transport::Tcp(stream) => stream.flush(),
+            Transport::Tls(stream) => stream.flush(),
+            Transport::Ws(stream) => stream.flush(),
+            Transport::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Writes the packets of an outbound queue to the transport until the queue is closed or the
+/// connection fails, so a client that reads slowly only delays its own packets
+/// # Arguments
+///
+/// * `transport` - A handle to the transport of the connection
+/// * `queue` - The packets to write
+/// * `stats` - The broker counters updated with the bytes written
+/// * `peer_addr` - The address of the peer, used in the logs
+///
+fn write_outbound(
+    mut transport: Transport,
+    queue: &OutboundQueue,
+    stats: &BrokerStats,
+    peer_addr: &str,
+) {
+    while let Some(bytes) = queue.pop() {
+        if let Err(e) = transport.write_all(&bytes).and_then(|_| transport.flush()) {
+            event!(
+                Level::DEBUG,
+                "Could not write to {}, its queued packets are dropped. Reason: {:?}",
+                peer_addr,
+                e
+            );
+            queue.abort();
+            return;
+        }
+        stats.add_bytes_sent(bytes.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Config, ListenerSettings};
+    use crate::hooks::Hooks;
+    use crate::ratelimit::RateLimiter;
+    use crate::stats::BrokerStats;
+    use crate::transport::client_stream::{ClientStream, Transport};
+    use std::os::unix::net::UnixStream;
+    use std::sync::Arc;
+    use std::thread;
+
+    fn unix_stream() -> (ClientStream, UnixStream) {
+        let config = Config::default();
+        let (broker_end, client_end) = UnixStream::pair().unwrap();
+        let stream = ClientStream::new(
+            Transport::Unix(broker_end),
+            Arc::new(ListenerSettings::tcp("test", "127.0.0.1:0")),
+            Arc::new(BrokerStats::new()),
+            Arc::new(RateLimiter::new(config.rate_limits)),
+            Arc::new(config.tenancy),
+            config.outbound,
+            Arc::new(Hooks::default()),
+        )
+        .unwrap();
+        (stream, client_end)
+    }
+
+    #[test]
+    fn test_handling_ends_when_dropped() {
+        let (stream, _client_end) = unix_stream();
+        let handling = stream.start_handling();
+        assert!(stream.is_busy());
+        drop(handling);
+        assert!(!stream.is_busy());
+    }
+
+    #[test]
+    fn test_handling_ends_when_the_handler_panics() {
+        let (stream, _client_end) = unix_stream();
+        let handling = stream.start_handling();
+        let handler = thread::spawn(move || {
+            let _handling = handling;
+            panic!("handler failed");
+        });
+        assert!(handler.join().is_err());
+        assert!(!stream.is_busy());
+    }
+}
@@ -0,0 +1,4 @@
+pub mod client_stream;
+pub mod outbound;
+pub mod tls;
+pub mod websocket;
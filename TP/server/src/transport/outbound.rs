@@ -0,0 +1,220 @@
+use crate::config::{OutboundSettings, SlowConsumerPolicy};
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Condvar, Mutex};
+
+/// This enum represents what happened to a message pushed to an outbound queue
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Enqueued {
+    /// The message is waiting to be written
+    Queued,
+    /// The queue was full, so its oldest message was dropped to make room
+    DroppedOldest,
+}
+
+/// This struct represents a packet waiting to be written, already serialized
+#[derive(Debug)]
+struct Frame {
+    bytes: Vec<u8>,
+    /// Only messages can be dropped, responses to the client are always written
+    droppable: bool,
+}
+
+#[derive(Debug)]
+struct QueueState {
+    frames: VecDeque<Frame>,
+    /// Messages in frames, the other frames do not count against the size of the queue
+    messages: usize,
+    /// No more packets are accepted once the queue is closed
+    closed: bool,
+    /// The queue was closed because the client did not keep up with its messages
+    slow_consumer: bool,
+}
+
+/// This struct represents the packets waiting to be written to a connection. Packet handlers
+/// push to it without blocking, and the writer of the connection pops in the same order.
+#[derive(Debug)]
+pub struct OutboundQueue {
+    state: Mutex<QueueState>,
+    ready: Condvar,
+    settings: OutboundSettings,
+}
+
+impl OutboundQueue {
+    /// Returns an empty OutboundQueue
+    /// # Arguments
+    ///
+    /// * `settings` - The size of the queue and what happens when it is full
+    ///
+    pub fn new(settings: OutboundSettings) -> OutboundQueue {
+        OutboundQueue {
+            state: Mutex::new(QueueState {
+                frames: VecDeque::new(),
+                messages: 0,
+                closed: false,
+                slow_consumer: false,
+            }),
+            ready: Condvar::new(),
+            settings,
+        }
+    }
+
+    /// Queues a packet that must reach the client, such as a CONNACK or a PUBACK
+    /// # Arguments
+    ///
+    /// * `bytes` - The serialized packet
+    ///
+    pub fn push_response(&self, bytes: Vec<u8>) -> io::Result<()> {
+        let mut state = self.open_state()?;
+        state.frames.push_back(Frame {
+            bytes,
+            droppable: false,
+        });
+        self.ready.notify_one();
+        Ok(())
+    }
+
+    /// Queues a message for the client, applying the slow consumer policy if the queue is full
+    /// # Arguments
+    ///
+    /// * `bytes` - The serialized PUBLISH packet
+    ///
+    pub fn push_message(&self, bytes: Vec<u8>) -> io::Result<Enqueued> {
+        let mut state = self.open_state()?;
+        let mut enqueued = Enqueued::Queued;
+        if state.messages >= self.settings.queue_size {
+            match self.settings.policy {
+                SlowConsumerPolicy::DropOldest => {
+                    if let Some(index) = state.frames.iter().position(|frame| frame.droppable) {
+                        state.frames.remove(index);
+                        state.messages -= 1;
+                    }
+                    enqueued = Enqueued::DroppedOldest;
+                }
+                SlowConsumerPolicy::Disconnect => {
+                    state.slow_consumer = true;
+                    Self::close_state(&mut state);
+                    self.ready.notify_all();
+                    return Err(io::Error::other(
+                        "slow consumer, its outbound queue is full",
+                    ));
+                }
+            }
+        }
+        state.frames.push_back(Frame {
+            bytes,
+            droppable: true,
+        });
+        state.messages += 1;
+        self.ready.notify_one();
+        Ok(enqueued)
+    }
+
+    /// Returns the next packet to write, waiting until there is one. Returns None once the
+    /// queue is closed, after the packets queued until then were returned.
+    pub fn pop(&self) -> Option<Vec<u8>> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(frame) = state.frames.pop_front() {
+                if frame.droppable {
+                    state.messages -= 1;
+                }
+                return Some(frame.bytes);
+            }
+            if state.closed {
+                return None;
+            }
+            state = self.ready.wait(state).unwrap();
+        }
+    }
+
+    /// Stops accepting packets, the writer finishes with the ones already queued
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.ready.notify_all();
+    }
+
+    /// Stops accepting packets and drops the queued ones, used when the connection is lost
+    pub fn abort(&self) {
+        Self::close_state(&mut self.state.lock().unwrap());
+        self.ready.notify_all();
+    }
+
+    /// Returns whether the queue was closed because the client did not keep up
+    pub fn is_slow_consumer(&self) -> bool {
+        self.state.lock().unwrap().slow_consumer
+    }
+
+    /// Returns the amount of packets waiting to be written
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().frames.len()
+    }
+
+    fn open_state(&self) -> io::Result<std::sync::MutexGuard<'_, QueueState>> {
+        let state = self.state.lock().unwrap();
+        if state.closed {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "the connection is closed",
+            ));
+        }
+        Ok(state)
+    }
+
+    fn close_state(state: &mut QueueState) {
+        state.closed = true;
+        state.frames.clear();
+        state.messages = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{OutboundSettings, SlowConsumerPolicy};
+    use crate::transport::outbound::{Enqueued, OutboundQueue};
+
+    fn queue(policy: SlowConsumerPolicy) -> OutboundQueue {
+        OutboundQueue::new(OutboundSettings {
+            queue_size: 2,
+            policy,
+        })
+    }
+
+    #[test]
+    fn test_full_queue_drops_the_oldest_message_but_keeps_responses() {
+        let queue = queue(SlowConsumerPolicy::DropOldest);
+        queue.push_response(b"connack".to_vec()).unwrap();
+        assert_eq!(queue.push_message(b"a".to_vec()).unwrap(), Enqueued::Queued);
+        assert_eq!(queue.push_message(b"b".to_vec()).unwrap(), Enqueued::Queued);
+        queue.push_response(b"suback".to_vec()).unwrap();
+        assert_eq!(
+            queue.push_message(b"c".to_vec()).unwrap(),
+            Enqueued::DroppedOldest
+        );
+
+        queue.close();
+        assert!(queue.push_response(b"puback".to_vec()).is_err());
+        let written: Vec<Vec<u8>> = std::iter::from_fn(|| queue.pop()).collect();
+        assert_eq!(
+            written,
+            vec![
+                b"connack".to_vec(),
+                b"b".to_vec(),
+                b"suback".to_vec(),
+                b"c".to_vec()
+            ]
+        );
+        assert!(!queue.is_slow_consumer());
+    }
+
+    #[test]
+    fn test_full_queue_closes_with_the_disconnect_policy() {
+        let queue = queue(SlowConsumerPolicy::Disconnect);
+        queue.push_message(b"a".to_vec()).unwrap();
+        queue.push_message(b"b".to_vec()).unwrap();
+        assert!(queue.push_message(b"c".to_vec()).is_err());
+        assert!(queue.is_slow_consumer());
+        assert_eq!(queue.len(), 0);
+        assert_eq!(queue.pop(), None);
+    }
+}
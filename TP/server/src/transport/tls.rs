@@ -0,0 +1,581 @@
+use crate::config::{CertificateUsername, TlsSettings};
+use rustls::server::AllowAnyAuthenticatedClient;
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig, ServerConnection};
+use std::cmp;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use x509_parser::extensions::GeneralName;
+
+static HANDSHAKE_TIMEOUT: u64 = 10000;
+
+/// This struct represents an error while setting up TLS
+#[derive(Debug, Clone)]
+pub struct TlsError {
+    reason: String,
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Error setting up TLS: {}", self.reason)
+    }
+}
+
+impl std::error::Error for TlsError {}
+
+impl TlsError {
+    fn new(reason: &str) -> TlsError {
+        TlsError {
+            reason: reason.to_string(),
+        }
+    }
+}
+
+/// This struct represents the TLS state shared by every handle of a connection. It is only
+/// locked to encrypt or decrypt, never across a blocking read or write of the socket.
+struct TlsConnection {
+    connection: ServerConnection,
+    /// Plaintext that was decrypted and has not been consumed yet
+    pending: Vec<u8>,
+    /// The peer sent its close_notify or closed the socket
+    closed: bool,
+}
+
+impl TlsConnection {
+    /// Moves the plaintext rustls decrypted to the pending bytes
+    fn take_plaintext(&mut self) -> io::Result<()> {
+        let mut buffer = [0u8; 4096];
+        loop {
+            match self.connection.reader().read(&mut buffer) {
+                Ok(0) => {
+                    self.closed = true;
+                    return Ok(());
+                }
+                Ok(size) => self.pending.extend_from_slice(&buffer[..size]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Returns the records rustls queued for the peer
+    fn take_records(&mut self) -> io::Result<Vec<u8>> {
+        let mut records = Vec::new();
+        while self.connection.wants_write() {
+            self.connection.write_tls(&mut records)?;
+        }
+        Ok(records)
+    }
+}
+
+/// This struct represents a TLS connection accepted by the broker
+pub struct TlsStream {
+    connection: Arc<Mutex<TlsConnection>>,
+    /// The read side, locked across blocking reads
+    reader: Arc<Mutex<TcpStream>>,
+    /// The write side, locked while the records of a write are sent so they are not interleaved
+    writer: Arc<Mutex<TcpStream>>,
+    socket: TcpStream,
+    certificate_username: Option<String>,
+}
+
+impl fmt::Debug for TlsStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TlsStream")
+            .field("socket", &self.socket)
+            .field("certificate_username", &self.certificate_username)
+            .finish()
+    }
+}
+
+impl TlsStream {
+    /// Performs the server side of a TLS handshake and returns the established connection
+    /// # Arguments
+    ///
+    /// * `socket` - An accepted TcpStream
+    /// * `config` - The rustls configuration of the listener
+    /// * `username_field` - The certificate field used as username when a client certificate was presented
+    ///
+    pub fn accept(
+        socket: TcpStream,
+        config: Arc<ServerConfig>,
+        username_field: &CertificateUsername,
+    ) -> io::Result<TlsStream> {
+        let mut connection = ServerConnection::new(config).map_err(io::Error::other)?;
+
+        let mut handshake_socket = socket.try_clone()?;
+        handshake_socket.set_read_timeout(Some(Duration::from_millis(HANDSHAKE_TIMEOUT)))?;
+        while connection.is_handshaking() {
+            connection.complete_io(&mut handshake_socket)?;
+        }
+
+        let certificate_username = match connection.peer_certificates() {
+            Some(certificates) if !certificates.is_empty() => {
+                match certificate_username(&certificates[0].0, username_field) {
+                    Some(username) => Some(username),
+                    None => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::PermissionDenied,
+                            "Client certificate does not contain a username",
+                        ))
+                    }
+                }
+            }
+            _ => None,
+        };
+
+        let mut connection = TlsConnection {
+            connection,
+            pending: Vec::new(),
+            closed: false,
+        };
+        // Data the client sent right after its last handshake message was already decrypted
+        connection.take_plaintext()?;
+
+        Ok(TlsStream {
+            connection: Arc::new(Mutex::new(connection)),
+            reader: Arc::new(Mutex::new(socket.try_clone()?)),
+            writer: Arc::new(Mutex::new(socket.try_clone()?)),
+            socket,
+            certificate_username,
+        })
+    }
+
+    /// Returns a new handle to the same TLS connection
+    pub fn try_clone(&self) -> io::Result<TlsStream> {
+        Ok(TlsStream {
+            connection: Arc::clone(&self.connection),
+            reader: Arc::clone(&self.reader),
+            writer: Arc::clone(&self.writer),
+            socket: self.socket.try_clone()?,
+            certificate_username: self.certificate_username.clone(),
+        })
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.peer_addr()
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(timeout)
+    }
+
+    /// Sends the close_notify alert and closes both directions of the connection. The alert is
+    /// skipped when the write side is blocked on a peer that does not read.
+    pub fn shutdown(&self) -> io::Result<()> {
+        if let Ok(mut writer) = self.writer.try_lock() {
+            let records = {
+                let mut connection = self.connection.lock().unwrap();
+                connection.connection.send_close_notify();
+                connection.take_records()
+            };
+            if let Ok(records) = records {
+                let _ = writer.write_all(&records);
+            }
+        }
+        self.socket.shutdown(Shutdown::Both)
+    }
+
+    /// Receives plaintext without consuming it, so the next read returns it again
+    pub fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut reader = self.reader.lock().unwrap();
+        if self.is_drained() {
+            self.fill(&mut reader)?;
+        }
+        let connection = self.connection.lock().unwrap();
+        if connection.pending.is_empty() && !connection.closed {
+            // Part of a record arrived, the rest is still on its way
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "Incomplete TLS record",
+            ));
+        }
+        let size = cmp::min(buf.len(), connection.pending.len());
+        buf[..size].copy_from_slice(&connection.pending[..size]);
+        Ok(size)
+    }
+
+    pub fn certificate_username(&self) -> Option<&str> {
+        self.certificate_username.as_deref()
+    }
+
+    /// Returns whether there is no plaintext left and the peer may still send more
+    fn is_drained(&self) -> bool {
+        let connection = self.connection.lock().unwrap();
+        connection.pending.is_empty() && !connection.closed
+    }
+
+    /// Reads once from the socket, without the TLS state locked, and decrypts what arrived
+    /// # Arguments
+    ///
+    /// * `reader` - The read side of the connection, locked by the caller
+    ///
+    fn fill(&self, reader: &mut TcpStream) -> io::Result<()> {
+        let mut buffer = [0u8; 4096];
+        let size = reader.read(&mut buffer)?;
+        let mut connection = self.connection.lock().unwrap();
+        if size == 0 {
+            connection.closed = true;
+            return Ok(());
+        }
+        let mut received = &buffer[..size];
+        while !received.is_empty() {
+            connection.connection.read_tls(&mut received)?;
+            connection
+                .connection
+                .process_new_packets()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            connection.take_plaintext()?;
+        }
+        let wants_write = connection.connection.wants_write();
+        drop(connection);
+
+        // Alerts and key updates produced by the records just read
+        if wants_write {
+            self.send_records(|_| Ok(()))?;
+        }
+        Ok(())
+    }
+
+    /// Encrypts with the TLS state and sends the records it queued, holding the write side so
+    /// the records of different writes are not interleaved
+    /// # Arguments
+    ///
+    /// * `encrypt` - Queues plaintext on the TLS state, if any
+    ///
+    fn send_records(
+        &self,
+        encrypt: impl FnOnce(&mut ServerConnection) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        let records = {
+            let mut connection = self.connection.lock().unwrap();
+            encrypt(&mut connection.connection)?;
+            connection.take_records()?
+        };
+        writer.write_all(&records)
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut reader = self.reader.lock().unwrap();
+        loop {
+            {
+                let mut connection = self.connection.lock().unwrap();
+                if !connection.pending.is_empty() {
+                    let size = cmp::min(buf.len(), connection.pending.len());
+                    buf[..size].copy_from_slice(&connection.pending[..size]);
+                    connection.pending.drain(..size);
+                    return Ok(size);
+                }
+                if connection.closed {
+                    return Ok(0);
+                }
+            }
+            self.fill(&mut reader)?;
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.send_records(|connection| connection.writer().write_all(buf))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.lock().unwrap().flush()
+    }
+}
+
+/// Builds the rustls configuration for a TLS listener
+/// # Arguments
+///
+/// * `settings` - The TLS settings read from the config file
+///
+pub fn build_server_config(settings: &TlsSettings) -> Result<Arc<ServerConfig>, TlsError> {
+    let certificates = load_certificates(&settings.cert_file)?;
+    let key = load_private_key(&settings.key_file)?;
+
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = if settings.require_client_cert {
+        let ca_file = match &settings.client_ca_file {
+            Some(ca_file) => ca_file,
+            None => return Err(TlsError::new("Client certificates require a CA bundle")),
+        };
+        let mut roots = RootCertStore::empty();
+        for ca in load_certificates(ca_file)? {
+            roots
+                .add(&ca)
+                .map_err(|e| TlsError::new(&format!("Invalid CA in {}: {}", ca_file, e)))?;
+        }
+        builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+    } else {
+        builder.with_no_client_auth()
+    };
+
+    let config = builder
+        .with_single_cert(certificates, key)
+        .map_err(|e| TlsError::new(&e.to_string()))?;
+
+    Ok(Arc::new(config))
+}
+
+fn load_certificates(path: &str) -> Result<Vec<Certificate>, TlsError> {
+    let file = File::open(path).map_err(|e| TlsError::new(&format!("{}: {}", path, e)))?;
+    let certificates = rustls_pemfile::certs(&mut BufReader::new(file))
+        .map_err(|e| TlsError::new(&format!("{}: {}", path, e)))?;
+    if certificates.is_empty() {
+        return Err(TlsError::new(&format!("No certificates found in {}", path)));
+    }
+    Ok(certificates.into_iter().map(Certificate).collect())
+}
+
+fn load_private_key(path: &str) -> Result<PrivateKey, TlsError> {
+    let file = File::open(path).map_err(|e| TlsError::new(&format!("{}: {}", path, e)))?;
+    let mut reader = BufReader::new(file);
+    loop {
+        match rustls_pemfile::read_one(&mut reader)
+            .map_err(|e| TlsError::new(&format!("{}: {}", path, e)))?
+        {
+            Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => return Ok(PrivateKey(key)),
+            Some(_) => continue,
+            None => return Err(TlsError::new(&format!("No private key found in {}", path))),
+        }
+    }
+}
+
+/// Returns the username carried by a DER encoded client certificate
+/// # Arguments
+///
+/// * `der` - The DER encoded certificate
+/// * `field` - Whether to use the subject common name or the first subject alternative name
+///
+pub fn certificate_username(der: &[u8], field: &CertificateUsername) -> Option<String> {
+    let (_, certificate) = x509_parser::parse_x509_certificate(der).ok()?;
+    match field {
+        CertificateUsername::CommonName => certificate
+            .subject()
+            .iter_common_name()
+            .next()
+            .and_then(|common_name| common_name.as_str().ok())
+            .map(|common_name| common_name.to_string()),
+        CertificateUsername::SubjectAltName => {
+            let alt_names = certificate.subject_alternative_name().ok()??;
+            alt_names
+                .value
+                .general_names
+                .iter()
+                .find_map(|name| match name {
+                    GeneralName::DNSName(name)
+                    | GeneralName::RFC822Name(name)
+                    | GeneralName::URI(name) => Some(name.to_string()),
+                    _ => None,
+                })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{CertificateUsername, TlsSettings};
+    use crate::transport::tls::{build_server_config, certificate_username, TlsStream};
+    use rcgen::{BasicConstraints, Certificate, CertificateParams, DnType, IsCa};
+    use std::convert::TryFrom;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn generate_certificate(common_name: &str, alt_names: Vec<String>) -> Certificate {
+        let mut params = CertificateParams::new(alt_names);
+        params
+            .distinguished_name
+            .push(DnType::CommonName, common_name);
+        Certificate::from_params(params).unwrap()
+    }
+
+    fn write_temp_file(name: &str, content: &str) -> String {
+        let path = std::env::temp_dir().join(format!("server-tls-test-{}", name));
+        std::fs::write(&path, content).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_certificate_username_from_common_name() {
+        let certificate = generate_certificate("device-01", vec!["device-01.local".to_string()]);
+        let der = certificate.serialize_der().unwrap();
+        assert_eq!(
+            certificate_username(&der, &CertificateUsername::CommonName),
+            Some("device-01".to_string())
+        );
+    }
+
+    #[test]
+    fn test_certificate_username_from_subject_alt_name() {
+        let certificate = generate_certificate("device-01", vec!["device-01.local".to_string()]);
+        let der = certificate.serialize_der().unwrap();
+        assert_eq!(
+            certificate_username(&der, &CertificateUsername::SubjectAltName),
+            Some("device-01.local".to_string())
+        );
+    }
+
+    #[test]
+    fn test_certificate_username_without_subject_alt_name() {
+        let certificate = generate_certificate("device-01", Vec::new());
+        let der = certificate.serialize_der().unwrap();
+        assert_eq!(
+            certificate_username(&der, &CertificateUsername::SubjectAltName),
+            None
+        );
+    }
+
+    #[test]
+    fn test_handshake_with_client_certificate() {
+        let mut ca_params = CertificateParams::new(Vec::new());
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = Certificate::from_params(ca_params).unwrap();
+        let server_certificate = generate_certificate("broker", vec!["localhost".to_string()]);
+        let client_certificate = generate_certificate("device-01", Vec::new());
+
+        let settings = TlsSettings {
+            cert_file: write_temp_file(
+                "server.pem",
+                &server_certificate.serialize_pem_with_signer(&ca).unwrap(),
+            ),
+            key_file: write_temp_file(
+                "server.key",
+                &server_certificate.serialize_private_key_pem(),
+            ),
+            client_ca_file: Some(write_temp_file("ca.pem", &ca.serialize_pem().unwrap())),
+            require_client_cert: true,
+            username_field: CertificateUsername::CommonName,
+        };
+        let server_config = build_server_config(&settings).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots
+            .add(&rustls::Certificate(ca.serialize_der().unwrap()))
+            .unwrap();
+        let client_config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_client_auth_cert(
+                vec![rustls::Certificate(
+                    client_certificate.serialize_der_with_signer(&ca).unwrap(),
+                )],
+                rustls::PrivateKey(client_certificate.serialize_private_key_der()),
+            )
+            .unwrap();
+
+        let client = thread::spawn(move || {
+            let connection = rustls::ClientConnection::new(
+                Arc::new(client_config),
+                rustls::ServerName::try_from("localhost").unwrap(),
+            )
+            .unwrap();
+            let socket = TcpStream::connect(address).unwrap();
+            let mut stream = rustls::StreamOwned::new(connection, socket);
+            stream.write_all(b"ping").unwrap();
+            let mut response = [0u8; 4];
+            stream.read_exact(&mut response).unwrap();
+            response
+        });
+
+        let (socket, _) = listener.accept().unwrap();
+        let mut stream =
+            TlsStream::accept(socket, server_config, &CertificateUsername::CommonName).unwrap();
+        assert_eq!(stream.certificate_username(), Some("device-01"));
+
+        let mut request = [0u8; 4];
+        assert_eq!(stream.peek(&mut request).unwrap(), 4);
+        stream.read_exact(&mut request).unwrap();
+        assert_eq!(&request, b"ping");
+        stream.write_all(b"pong").unwrap();
+
+        assert_eq!(&client.join().unwrap(), b"pong");
+    }
+
+    #[test]
+    fn test_writes_do_not_wait_for_a_blocked_read_and_shutdown_sends_close_notify() {
+        let ca = Certificate::from_params(CertificateParams::new(Vec::new())).unwrap();
+        let server_certificate = generate_certificate("broker", vec!["localhost".to_string()]);
+        let settings = TlsSettings {
+            cert_file: write_temp_file(
+                "blocked-server.pem",
+                &server_certificate.serialize_pem_with_signer(&ca).unwrap(),
+            ),
+            key_file: write_temp_file(
+                "blocked-server.key",
+                &server_certificate.serialize_private_key_pem(),
+            ),
+            client_ca_file: None,
+            require_client_cert: false,
+            username_field: CertificateUsername::CommonName,
+        };
+        let server_config = build_server_config(&settings).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (socket, _) = listener.accept().unwrap();
+            TlsStream::accept(socket, server_config, &CertificateUsername::CommonName).unwrap()
+        });
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots
+            .add(&rustls::Certificate(ca.serialize_der().unwrap()))
+            .unwrap();
+        let client_config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connection = rustls::ClientConnection::new(
+            Arc::new(client_config),
+            rustls::ServerName::try_from("localhost").unwrap(),
+        )
+        .unwrap();
+        let mut client = rustls::StreamOwned::new(connection, TcpStream::connect(address).unwrap());
+        while client.conn.is_handshaking() {
+            client.conn.complete_io(&mut client.sock).unwrap();
+        }
+        let mut stream = server.join().unwrap();
+
+        // A read waits for the client while the broker answers it
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let reader = stream.try_clone().unwrap();
+        let blocked_read = thread::spawn(move || {
+            let mut request = [0u8; 4];
+            reader.peek(&mut request).unwrap();
+            request
+        });
+        thread::sleep(Duration::from_millis(100));
+        let start = Instant::now();
+        stream.write_all(b"pong").unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1));
+        let mut response = [0u8; 4];
+        client.read_exact(&mut response).unwrap();
+        assert_eq!(&response, b"pong");
+
+        client.write_all(b"ping").unwrap();
+        assert_eq!(&blocked_read.join().unwrap(), b"ping");
+
+        // Without the close_notify the client would see an unexpected end of file
+        stream.shutdown().unwrap();
+        let mut rest = Vec::new();
+        assert_eq!(client.read_to_end(&mut rest).unwrap(), 0);
+    }
+}
@@ -0,0 +1,720 @@
+use std::cmp;
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+static HANDSHAKE_TIMEOUT: u64 = 10000;
+static MAX_HANDSHAKE_SIZE: usize = 8192;
+static MQTT_SUBPROTOCOL: &str = "mqtt";
+static WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// Longest payload of a frame or of a fragmented message, the largest MQTT packet
+static MAX_FRAME_SIZE: usize = 268_435_455 + 5;
+/// Status codes sent in the close frame when the client breaks the protocol
+static CLOSE_PROTOCOL_ERROR: u16 = 1002;
+static CLOSE_MESSAGE_TOO_BIG: u16 = 1009;
+
+enum Opcode {
+    Continuation = 0x0,
+    Text = 0x1,
+    Binary = 0x2,
+    Close = 0x8,
+    Ping = 0x9,
+    Pong = 0xA,
+}
+
+/// This struct represents a decoded WebSocket frame
+#[derive(Debug, PartialEq)]
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// This enum represents why the frames of a client cannot be handled
+#[derive(Debug)]
+enum FrameError {
+    /// The socket failed while answering a frame
+    Io(io::Error),
+    /// The frame breaks the protocol
+    Protocol(&'static str),
+    /// The frame or its message is longer than MAX_FRAME_SIZE
+    TooBig(u64),
+}
+
+impl FrameError {
+    /// Returns the status code of the close frame sent to the client
+    fn close_code(&self) -> u16 {
+        match self {
+            FrameError::Io(_) | FrameError::Protocol(_) => CLOSE_PROTOCOL_ERROR,
+            FrameError::TooBig(_) => CLOSE_MESSAGE_TOO_BIG,
+        }
+    }
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FrameError::Io(e) => write!(f, "{}", e),
+            FrameError::Protocol(reason) => write!(f, "{}", reason),
+            FrameError::TooBig(length) => write!(
+                f,
+                "WebSocket message of {} bytes is over the limit of {}",
+                length, MAX_FRAME_SIZE
+            ),
+        }
+    }
+}
+
+impl From<io::Error> for FrameError {
+    fn from(e: io::Error) -> FrameError {
+        FrameError::Io(e)
+    }
+}
+
+/// This struct represents the read side of a connection, shared by every handle to it. It is
+/// locked across blocking reads, so writes go through their own lock.
+struct WsReader {
+    socket: TcpStream,
+    /// The write side, for the frames that answer pings and closes
+    writer: Arc<Mutex<TcpStream>>,
+    /// Bytes read from the socket that do not form a complete frame yet
+    raw: Vec<u8>,
+    /// MQTT bytes decoded from binary frames and not consumed yet
+    pending: Vec<u8>,
+    /// Payload bytes of the binary message being fragmented, None when no message started
+    message_length: Option<usize>,
+    closed: bool,
+}
+
+/// This struct represents an MQTT over WebSocket connection accepted by the broker
+pub struct WsStream {
+    reader: Arc<Mutex<WsReader>>,
+    writer: Arc<Mutex<TcpStream>>,
+    socket: TcpStream,
+}
+
+impl fmt::Debug for WsStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WsStream")
+            .field("socket", &self.socket)
+            .finish()
+    }
+}
+
+impl WsStream {
+    /// Performs the server side of the WebSocket opening handshake
+    /// # Arguments
+    ///
+    /// * `socket` - An accepted TcpStream
+    ///
+    pub fn accept(mut socket: TcpStream) -> io::Result<WsStream> {
+        socket.set_read_timeout(Some(Duration::from_millis(HANDSHAKE_TIMEOUT)))?;
+
+        let request = read_handshake_request(&mut socket)?;
+        match handshake_response(&request) {
+            Ok(response) => socket.write_all(response.as_bytes())?,
+            Err(reason) => {
+                let response = "HTTP/1.1 400 Bad Request\r\nConnection: close\r\n\r\n";
+                socket.write_all(response.as_bytes())?;
+                return Err(io::Error::new(io::ErrorKind::InvalidData, reason));
+            }
+        }
+
+        let writer = Arc::new(Mutex::new(socket.try_clone()?));
+        Ok(WsStream {
+            reader: Arc::new(Mutex::new(WsReader {
+                socket: socket.try_clone()?,
+                writer: Arc::clone(&writer),
+                raw: Vec::new(),
+                pending: Vec::new(),
+                message_length: None,
+                closed: false,
+            })),
+            writer,
+            socket,
+        })
+    }
+
+    /// Returns a new handle to the same WebSocket connection
+    pub fn try_clone(&self) -> io::Result<WsStream> {
+        Ok(WsStream {
+            reader: Arc::clone(&self.reader),
+            writer: Arc::clone(&self.writer),
+            socket: self.socket.try_clone()?,
+        })
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.peer_addr()
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(timeout)
+    }
+
+    pub fn shutdown(&self) -> io::Result<()> {
+        self.socket.shutdown(Shutdown::Both)
+    }
+
+    /// Receives MQTT bytes without consuming them, so the next read returns them again
+    pub fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut reader = self.reader.lock().unwrap();
+        if reader.pending.is_empty() && !reader.closed {
+            reader.fill()?;
+        }
+        if reader.pending.is_empty() && !reader.closed {
+            // Part of a frame or only control frames arrived, the MQTT bytes are still on their way
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "Incomplete WebSocket frame",
+            ));
+        }
+        let size = cmp::min(buf.len(), reader.pending.len());
+        buf[..size].copy_from_slice(&reader.pending[..size]);
+        Ok(size)
+    }
+}
+
+impl WsReader {
+    /// Reads once from the socket and decodes every complete frame received so far
+    fn fill(&mut self) -> io::Result<()> {
+        let mut buffer = [0u8; 4096];
+        let size = self.socket.read(&mut buffer)?;
+        if size == 0 {
+            self.closed = true;
+            return Ok(());
+        }
+        self.raw.extend_from_slice(&buffer[..size]);
+
+        loop {
+            let decoded = decode_frame(&self.raw).and_then(|decoded| match decoded {
+                Some((frame, frame_length)) => {
+                    self.raw.drain(..frame_length);
+                    self.handle_frame(frame).map(|_| true)
+                }
+                None => Ok(false),
+            });
+            match decoded {
+                Ok(true) => {}
+                Ok(false) => return Ok(()),
+                Err(FrameError::Io(e)) => return Err(e),
+                Err(e) => {
+                    // The client learns why before the connection is closed
+                    let _ = self.send(Opcode::Close, &e.close_code().to_be_bytes());
+                    self.closed = true;
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string()));
+                }
+            }
+        }
+    }
+
+    /// Writes a control frame through the write side of the connection
+    fn send(&self, opcode: Opcode, payload: &[u8]) -> io::Result<()> {
+        let frame = encode_frame(opcode as u8, payload);
+        self.writer.lock().unwrap().write_all(&frame)
+    }
+
+    fn handle_frame(&mut self, frame: Frame) -> Result<(), FrameError> {
+        if frame.opcode >= Opcode::Close as u8 && !frame.fin {
+            return Err(FrameError::Protocol(
+                "WebSocket control frames must not be fragmented",
+            ));
+        }
+        match frame.opcode {
+            op if op == Opcode::Binary as u8 || op == Opcode::Continuation as u8 => {
+                let length = match (op == Opcode::Binary as u8, self.message_length) {
+                    (true, None) => frame.payload.len(),
+                    (false, Some(length)) => length + frame.payload.len(),
+                    (true, Some(_)) => {
+                        return Err(FrameError::Protocol(
+                            "WebSocket binary frame inside a fragmented message",
+                        ))
+                    }
+                    (false, None) => {
+                        return Err(FrameError::Protocol(
+                            "WebSocket continuation frame without a message to continue",
+                        ))
+                    }
+                };
+                if length > MAX_FRAME_SIZE {
+                    return Err(FrameError::TooBig(length as u64));
+                }
+                self.message_length = Some(length).filter(|_| !frame.fin);
+                self.pending.extend_from_slice(&frame.payload);
+            }
+            op if op == Opcode::Ping as u8 => {
+                self.send(Opcode::Pong, &frame.payload)?;
+            }
+            op if op == Opcode::Pong as u8 => {}
+            op if op == Opcode::Close as u8 => {
+                let _ = self.send(Opcode::Close, &frame.payload);
+                self.closed = true;
+            }
+            op if op == Opcode::Text as u8 => {
+                return Err(FrameError::Protocol(
+                    "MQTT over WebSocket requires binary frames",
+                ));
+            }
+            _ => return Err(FrameError::Protocol("Unknown WebSocket opcode")),
+        }
+        Ok(())
+    }
+}
+
+impl Read for WsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut reader = self.reader.lock().unwrap();
+        while reader.pending.is_empty() {
+            if reader.closed {
+                return Ok(0);
+            }
+            reader.fill()?;
+        }
+        let size = cmp::min(buf.len(), reader.pending.len());
+        buf[..size].copy_from_slice(&reader.pending[..size]);
+        reader.pending.drain(..size);
+        Ok(size)
+    }
+}
+
+impl Write for WsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let frame = encode_frame(Opcode::Binary as u8, buf);
+        self.writer.lock().unwrap().write_all(&frame)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.lock().unwrap().flush()
+    }
+}
+
+fn read_handshake_request(socket: &mut TcpStream) -> io::Result<String> {
+    let mut request = Vec::new();
+    let mut buffer = [0u8; 1];
+    while !request.ends_with(b"\r\n\r\n") {
+        if request.len() >= MAX_HANDSHAKE_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "WebSocket handshake too large",
+            ));
+        }
+        socket.read_exact(&mut buffer)?;
+        request.push(buffer[0]);
+    }
+    String::from_utf8(request)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid WebSocket handshake"))
+}
+
+/// Validates an HTTP upgrade request and returns the 101 response to send back
+fn handshake_response(request: &str) -> Result<String, String> {
+    let mut lines = request.split("\r\n");
+    let request_line = lines.next().unwrap_or_default();
+    if !request_line.starts_with("GET ") {
+        return Err(format!("Unexpected request line {:?}", request_line));
+    }
+
+    let mut key = None;
+    let mut upgrade = false;
+    let mut protocols = Vec::new();
+    for line in lines {
+        if let Some((name, value)) = line.split_once(':') {
+            let value = value.trim();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "upgrade" => upgrade = value.eq_ignore_ascii_case("websocket"),
+                "sec-websocket-key" => key = Some(value.to_string()),
+                "sec-websocket-protocol" => {
+                    protocols.extend(value.split(',').map(|protocol| protocol.trim().to_string()))
+                }
+                _ => {}
+            }
+        }
+    }
+
+    if !upgrade {
+        return Err("Missing Upgrade: websocket header".to_string());
+    }
+    let key = key.ok_or_else(|| "Missing Sec-WebSocket-Key header".to_string())?;
+    if !protocols
+        .iter()
+        .any(|protocol| protocol == MQTT_SUBPROTOCOL)
+    {
+        return Err("Client did not offer the mqtt subprotocol".to_string());
+    }
+
+    Ok(format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\
+         Sec-WebSocket-Protocol: {}\r\n\r\n",
+        accept_key(&key),
+        MQTT_SUBPROTOCOL
+    ))
+}
+
+/// Returns the Sec-WebSocket-Accept value for a client key
+fn accept_key(key: &str) -> String {
+    let mut input = key.as_bytes().to_vec();
+    input.extend_from_slice(WEBSOCKET_GUID.as_bytes());
+    base64_encode(&sha1(&input))
+}
+
+/// Decodes one frame from the start of a buffer
+/// Returns None when the buffer does not contain a complete frame yet
+fn decode_frame(buffer: &[u8]) -> Result<Option<(Frame, usize)>, FrameError> {
+    if buffer.len() < 2 {
+        return Ok(None);
+    }
+    let fin = (buffer[0] & 0x80) != 0;
+    let opcode = buffer[0] & 0x0F;
+    let masked = (buffer[1] & 0x80) != 0;
+    if !masked {
+        return Err(FrameError::Protocol(
+            "Client WebSocket frames must be masked",
+        ));
+    }
+
+    let mut offset = 2;
+    let payload_length = match buffer[1] & 0x7F {
+        126 => {
+            if buffer.len() < offset + 2 {
+                return Ok(None);
+            }
+            let length = u16::from_be_bytes([buffer[2], buffer[3]]) as usize;
+            offset += 2;
+            length
+        }
+        127 => {
+            if buffer.len() < offset + 8 {
+                return Ok(None);
+            }
+            let mut length_bytes = [0u8; 8];
+            length_bytes.copy_from_slice(&buffer[2..10]);
+            offset += 8;
+            let length = u64::from_be_bytes(length_bytes);
+            // Refused before waiting for the payload, so it is never buffered
+            if length > MAX_FRAME_SIZE as u64 {
+                return Err(FrameError::TooBig(length));
+            }
+            length as usize
+        }
+        length => length as usize,
+    };
+    match (offset + 4).checked_add(payload_length) {
+        Some(frame_length) if buffer.len() >= frame_length => {}
+        _ => return Ok(None),
+    }
+    let mask = [
+        buffer[offset],
+        buffer[offset + 1],
+        buffer[offset + 2],
+        buffer[offset + 3],
+    ];
+    offset += 4;
+
+    let payload = buffer[offset..offset + payload_length]
+        .iter()
+        .enumerate()
+        .map(|(index, byte)| byte ^ mask[index % 4])
+        .collect();
+
+    Ok(Some((
+        Frame {
+            fin,
+            opcode,
+            payload,
+        },
+        offset + payload_length,
+    )))
+}
+
+/// Encodes an unmasked server frame with the FIN bit set
+fn encode_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x80 | opcode];
+    if payload.len() < 126 {
+        frame.push(payload.len() as u8);
+    } else if payload.len() <= u16::MAX as usize {
+        frame.push(126);
+        frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    } else {
+        frame.push(127);
+        frame.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+fn sha1(input: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+
+    let mut message = input.to_vec();
+    let bit_length = (input.len() as u64).wrapping_mul(8);
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&bit_length.to_be_bytes());
+
+    for chunk in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in chunk.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let (mut a, mut b, mut c, mut d, mut e) = (h[0], h[1], h[2], h[3], h[4]);
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+
+        h[0] = h[0].wrapping_add(a);
+        h[1] = h[1].wrapping_add(b);
+        h[2] = h[2].wrapping_add(c);
+        h[3] = h[3].wrapping_add(d);
+        h[4] = h[4].wrapping_add(e);
+    }
+
+    let mut digest = [0u8; 20];
+    for (i, word) in h.iter().enumerate() {
+        digest[i * 4..i * 4 + 4].copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+fn base64_encode(input: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut output = String::new();
+    for chunk in input.chunks(3) {
+        let b0 = chunk[0] as u32;
+        let b1 = *chunk.get(1).unwrap_or(&0) as u32;
+        let b2 = *chunk.get(2).unwrap_or(&0) as u32;
+        let triple = (b0 << 16) | (b1 << 8) | b2;
+
+        output.push(ALPHABET[(triple >> 18) as usize & 0x3F] as char);
+        output.push(ALPHABET[(triple >> 12) as usize & 0x3F] as char);
+        if chunk.len() > 1 {
+            output.push(ALPHABET[(triple >> 6) as usize & 0x3F] as char);
+        } else {
+            output.push('=');
+        }
+        if chunk.len() > 2 {
+            output.push(ALPHABET[triple as usize & 0x3F] as char);
+        } else {
+            output.push('=');
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::transport::websocket::{
+        accept_key, base64_encode, decode_frame, encode_frame, handshake_response, sha1, Frame,
+        FrameError, WsStream,
+    };
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn mask_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![0x80 | opcode, 0x80 | payload.len() as u8];
+        frame.extend_from_slice(&mask);
+        for (index, byte) in payload.iter().enumerate() {
+            frame.push(byte ^ mask[index % 4]);
+        }
+        frame
+    }
+
+    #[test]
+    fn test_sha1_known_digest() {
+        let digest = sha1(b"abc");
+        let expected = [
+            0xa9, 0x99, 0x3e, 0x36, 0x47, 0x06, 0x81, 0x6a, 0xba, 0x3e, 0x25, 0x71, 0x78, 0x50,
+            0xc2, 0x6c, 0x9c, 0xd0, 0xd8, 0x9d,
+        ];
+        assert_eq!(digest, expected);
+    }
+
+    #[test]
+    fn test_base64_encode_with_padding() {
+        assert_eq!(base64_encode(b"mqtt"), "bXF0dA==");
+        assert_eq!(base64_encode(b"mqt"), "bXF0");
+    }
+
+    #[test]
+    fn test_accept_key_from_rfc6455() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn test_handshake_requires_mqtt_subprotocol() {
+        let request = "GET /mqtt HTTP/1.1\r\nHost: broker\r\nUpgrade: websocket\r\n\
+                       Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                       Sec-WebSocket-Version: 13\r\n\r\n";
+        assert!(handshake_response(request).is_err());
+    }
+
+    #[test]
+    fn test_decode_masked_frame() {
+        let frame = mask_frame(0x2, &[0xC0, 0x00]);
+        let decoded = decode_frame(&frame).unwrap();
+        assert_eq!(
+            decoded,
+            Some((
+                Frame {
+                    fin: true,
+                    opcode: 0x2,
+                    payload: vec![0xC0, 0x00],
+                },
+                frame.len()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_decode_incomplete_frame() {
+        let frame = mask_frame(0x2, &[0xC0, 0x00]);
+        assert_eq!(decode_frame(&frame[..frame.len() - 1]).unwrap(), None);
+    }
+
+    #[test]
+    fn test_decode_frame_over_the_size_limit() {
+        // A 64 bit length that would overflow the bounds check is refused from its header
+        let mut frame = vec![0x82, 0x80 | 127];
+        frame.extend_from_slice(&u64::MAX.to_be_bytes());
+        assert!(matches!(
+            decode_frame(&frame),
+            Err(FrameError::TooBig(u64::MAX))
+        ));
+        assert_eq!(FrameError::TooBig(u64::MAX).close_code(), 1009);
+    }
+
+    #[test]
+    fn test_websocket_stream_refuses_a_continuation_without_a_message() {
+        let (mut client, mut stream) = connected_pair();
+        client.write_all(&mask_frame(0x0, &[0xC0, 0x00])).unwrap();
+        let mut pingreq = [0u8; 2];
+        assert!(stream.read(&mut pingreq).is_err());
+
+        // The broker closes with 1002 before the connection ends
+        let mut close = [0u8; 4];
+        client.read_exact(&mut close).unwrap();
+        assert_eq!(close, [0x88, 0x02, 0x03, 0xEA]);
+    }
+
+    #[test]
+    fn test_encode_frame_with_extended_length() {
+        let payload = vec![0u8; 300];
+        let frame = encode_frame(0x2, &payload);
+        assert_eq!(&frame[..4], &[0x82, 126, 0x01, 0x2C]);
+        assert_eq!(frame.len(), 304);
+    }
+
+    #[test]
+    fn test_websocket_writes_do_not_wait_for_a_blocked_read() {
+        let (mut client, mut stream) = connected_pair();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let reader = stream.try_clone().unwrap();
+        let blocked_read = thread::spawn(move || reader.peek(&mut [0u8; 2]).unwrap());
+        thread::sleep(Duration::from_millis(100));
+
+        let start = Instant::now();
+        stream.write_all(&[0xD0, 0x00]).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1));
+        let mut pingresp = [0u8; 4];
+        client.read_exact(&mut pingresp).unwrap();
+        assert_eq!(pingresp, [0x82, 0x02, 0xD0, 0x00]);
+
+        client.write_all(&mask_frame(0x2, &[0xC0, 0x00])).unwrap();
+        assert_eq!(blocked_read.join().unwrap(), 2);
+    }
+
+    /// Returns a client socket and the broker side of a WebSocket connection between them
+    fn connected_pair() -> (TcpStream, WsStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let request = "GET /mqtt HTTP/1.1\r\nHost: broker\r\nUpgrade: websocket\r\n\
+                       Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                       Sec-WebSocket-Protocol: mqtt\r\nSec-WebSocket-Version: 13\r\n\r\n";
+        client.write_all(request.as_bytes()).unwrap();
+        let (socket, _) = listener.accept().unwrap();
+        let stream = WsStream::accept(socket).unwrap();
+
+        let mut response = Vec::new();
+        let mut byte = [0u8; 1];
+        while !response.ends_with(b"\r\n\r\n") {
+            client.read_exact(&mut byte).unwrap();
+            response.push(byte[0]);
+        }
+        (client, stream)
+    }
+
+    #[test]
+    fn test_websocket_stream_round_trip() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let client = thread::spawn(move || {
+            let mut socket = TcpStream::connect(address).unwrap();
+            let request = "GET /mqtt HTTP/1.1\r\nHost: broker\r\nUpgrade: websocket\r\n\
+                           Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                           Sec-WebSocket-Protocol: mqtt\r\nSec-WebSocket-Version: 13\r\n\r\n";
+            socket.write_all(request.as_bytes()).unwrap();
+
+            let mut response = Vec::new();
+            let mut byte = [0u8; 1];
+            while !response.ends_with(b"\r\n\r\n") {
+                socket.read_exact(&mut byte).unwrap();
+                response.push(byte[0]);
+            }
+            let response = String::from_utf8(response).unwrap();
+            assert!(response.starts_with("HTTP/1.1 101"));
+            assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+
+            socket.write_all(&mask_frame(0x2, &[0xC0, 0x00])).unwrap();
+            let mut frame = [0u8; 4];
+            socket.read_exact(&mut frame).unwrap();
+            frame
+        });
+
+        let (socket, _) = listener.accept().unwrap();
+        let mut stream = WsStream::accept(socket).unwrap();
+        let mut pingreq = [0u8; 2];
+        assert_eq!(stream.peek(&mut pingreq).unwrap(), 2);
+        stream.read_exact(&mut pingreq).unwrap();
+        assert_eq!(pingreq, [0xC0, 0x00]);
+        stream.write_all(&[0xD0, 0x00]).unwrap();
+
+        assert_eq!(client.join().unwrap(), [0x82, 0x02, 0xD0, 0x00]);
+    }
+}
@@ -0,0 +1,1023 @@
+//! End-to-end scenarios that exercise each packet handler of the broker over a real socket
+
+mod support;
+
+use server::config::{
+    Config, ExpirySettings, LimitPolicy, ListenerProtocol, ListenerSettings, OutboundSettings,
+    RateLimitSettings, RateLimits, SlowConsumerPolicy, TenancySettings,
+};
+use server::hooks::{BrokerHook, ClientInfo, HookVerdict};
+use server::{CredentialManager, Publish};
+use shared::packages::connect::Connect;
+use shared::packages::disconnect::Disconnect;
+use shared::packages::pingreq::Pingreq;
+use shared::packages::subscribe::Subscribe;
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::thread;
+use std::time::{Duration, Instant};
+use support::{
+    connect_packet, publish_packet, test_config, Packet, TestBroker, TestClient, WsClient,
+    PASSWORD, USERNAME,
+};
+
+/// Time given to the broker to handle a packet that has no response
+const SETTLE: Duration = Duration::from_millis(200);
+
+#[test]
+fn test_connect_accepts_valid_credentials() {
+    let broker = TestBroker::start();
+    let mut client = broker.client();
+    let connack = client.connect(&connect_packet("sensor"));
+    assert_eq!(connack.return_code, 0);
+    assert_eq!(connack.session_present, 0);
+}
+
+#[test]
+fn test_connect_refuses_a_wrong_password() {
+    let broker = TestBroker::start();
+    let mut client = broker.client();
+    let connack = client.connect(&Connect {
+        password: format!("{}5", PASSWORD),
+        ..connect_packet("sensor")
+    });
+    assert_eq!(connack.return_code, 4);
+}
+
+#[test]
+fn test_pingreq_gets_a_pingresp() {
+    let broker = TestBroker::start();
+    let mut client = broker.connect("sensor");
+    client.ping();
+    client.ping();
+}
+
+#[test]
+fn test_clients_with_the_same_port_on_different_hosts_keep_their_sessions() {
+    let broker = TestBroker::start();
+    let mut first = broker.client_from(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0));
+    let port = first.local_addr().port();
+    let mut second = broker.client_from(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 2), port));
+    assert_eq!(first.connect(&connect_packet("first")).return_code, 0);
+    assert_eq!(second.connect(&connect_packet("second")).return_code, 0);
+
+    first.subscribe(1, &[("first/inbox", 1)]);
+    second.subscribe(1, &[("second/inbox", 1)]);
+    let mut publisher = broker.connect("publisher");
+    publisher.publish(&publish_packet("first/inbox", "hello", 1, 1));
+
+    let received = first.expect_publish();
+    assert_eq!(received.payload, "hello");
+    first.ack(received.packet_id);
+    second.expect_nothing(SETTLE);
+    // The PUBACK was attributed to the first client, so the message is not resent
+    first.expect_nothing(Duration::from_secs(1));
+}
+
+#[test]
+fn test_subscribe_fans_out_to_every_subscriber() {
+    let broker = TestBroker::start();
+    let mut first = broker.connect("first");
+    let mut second = broker.connect("second");
+    assert_eq!(
+        first.subscribe(1, &[("house/kitchen", 0)]).return_codes,
+        vec![0]
+    );
+    assert_eq!(
+        second
+            .subscribe(1, &[("house/kitchen", 1), ("garden", 0)])
+            .return_codes,
+        vec![1, 0]
+    );
+
+    let mut publisher = broker.connect("publisher");
+    publisher.publish(&publish_packet("house/kitchen", "21", 0, 0));
+
+    let received = first.expect_publish();
+    assert_eq!(
+        (received.topic_name.as_str(), received.payload.as_str()),
+        ("house/kitchen", "21")
+    );
+    assert_eq!(received.qos, 0);
+    assert_eq!(second.expect_publish().payload, "21");
+    publisher.expect_nothing(SETTLE);
+}
+
+#[test]
+fn test_publish_qos_1_is_acknowledged_and_delivered_with_the_lowest_qos() {
+    let broker = TestBroker::start();
+    let mut exact = broker.connect("exact");
+    let mut downgraded = broker.connect("downgraded");
+    exact.subscribe(1, &[("alarms", 1)]);
+    downgraded.subscribe(1, &[("alarms", 0)]);
+
+    let mut publisher = broker.connect("publisher");
+    publisher.publish(&publish_packet("alarms", "smoke", 1, 7));
+
+    let received = exact.expect_publish();
+    assert_eq!((received.qos, received.dup_flag), (1, 0));
+    exact.ack(received.packet_id);
+    assert_eq!(downgraded.expect_publish().qos, 0);
+}
+
+#[test]
+fn test_subscribe_refuses_an_invalid_qos_and_downgrades_qos_2() {
+    let broker = TestBroker::start();
+    let mut subscriber = broker.connect("subscriber");
+    let suback = subscriber.subscribe(1, &[("a", 0), ("b", 1), ("c", 2), ("d", 3)]);
+    assert_eq!(suback.return_codes, vec![0, 1, 1, 0x80]);
+}
+
+#[test]
+fn test_max_qos_downgrades_the_subscriptions_and_their_delivery() {
+    let listener = ListenerSettings {
+        max_qos: 0,
+        ..ListenerSettings::tcp("display", "127.0.0.1:0")
+    };
+    let broker = TestBroker::start_on(listener, |builder| builder);
+    let mut subscriber = broker.connect("subscriber");
+    assert_eq!(
+        subscriber.subscribe(1, &[("alarms", 1)]).return_codes,
+        vec![0]
+    );
+
+    let mut publisher = broker.connect("publisher");
+    publisher.publish(&publish_packet("alarms", "smoke", 1, 7));
+    assert_eq!(subscriber.expect_publish().qos, 0);
+}
+
+#[test]
+fn test_throttled_publisher_does_not_hold_a_worker() {
+    let config = test_config();
+    let broker = TestBroker::start_with(|builder| {
+        builder.config(Config {
+            pool_size: 1,
+            rate_limits: RateLimitSettings {
+                policy: LimitPolicy::Throttle,
+                default: RateLimits {
+                    publish_rate: Some(1),
+                    ..RateLimits::default()
+                },
+                ..config.rate_limits.clone()
+            },
+            ..config
+        })
+    });
+    let mut subscriber = broker.connect("subscriber");
+    subscriber.subscribe(1, &[("meters", 0)]);
+    let mut other = broker.connect("other");
+
+    // The second publish is over the limit, so the publisher is not read for a second
+    let mut publisher = broker.connect("publisher");
+    publisher.publish(&publish_packet("meters", "1", 0, 0));
+    publisher.publish(&publish_packet("meters", "2", 0, 0));
+    assert_eq!(subscriber.expect_publish().payload, "1");
+    assert_eq!(subscriber.expect_publish().payload, "2");
+
+    // The only worker of the pool keeps serving the other clients meanwhile
+    let start = Instant::now();
+    other.ping();
+    assert!(start.elapsed() < Duration::from_millis(500));
+
+    publisher.publish(&publish_packet("meters", "3", 0, 0));
+    subscriber.expect_nothing(Duration::from_millis(300));
+    assert_eq!(subscriber.expect_publish().payload, "3");
+}
+
+#[test]
+fn test_connections_of_a_user_share_its_publish_rate() {
+    let config = test_config();
+    let broker = TestBroker::start_with(|builder| {
+        let mut users = HashMap::new();
+        users.insert(
+            USERNAME.to_string(),
+            RateLimits {
+                publish_rate: Some(1),
+                ..RateLimits::default()
+            },
+        );
+        builder.config(Config {
+            rate_limits: RateLimitSettings {
+                policy: LimitPolicy::Drop,
+                users,
+                ..config.rate_limits.clone()
+            },
+            ..config
+        })
+    });
+    let mut subscriber = broker.connect("subscriber");
+    subscriber.subscribe(1, &[("meters", 0)]);
+
+    let mut first = broker.connect("first");
+    let mut second = broker.connect("second");
+    first.publish(&publish_packet("meters", "first", 0, 0));
+    assert_eq!(subscriber.expect_publish().payload, "first");
+    second.publish(&publish_packet("meters", "second", 0, 0));
+    subscriber.expect_nothing(SETTLE);
+}
+
+#[test]
+fn test_anonymous_client_does_not_use_the_quota_of_the_user_it_claims() {
+    let listener = ListenerSettings {
+        require_auth: false,
+        ..ListenerSettings::tcp("open", "127.0.0.1:0")
+    };
+    let config = test_config();
+    let broker = TestBroker::start_on(listener, |builder| {
+        let mut users = HashMap::new();
+        users.insert(
+            USERNAME.to_string(),
+            RateLimits {
+                publish_rate: Some(1),
+                ..RateLimits::default()
+            },
+        );
+        builder.config(Config {
+            rate_limits: RateLimitSettings {
+                policy: LimitPolicy::Drop,
+                users,
+                ..config.rate_limits.clone()
+            },
+            ..config
+        })
+    });
+    let mut subscriber = broker.connect("subscriber");
+    subscriber.subscribe(1, &[("meters", 0)]);
+
+    // Without the password the client is anonymous and gets the limits of everyone else
+    let mut intruder = broker.client();
+    let connack = intruder.connect(&Connect {
+        password: String::new(),
+        ..connect_packet("intruder")
+    });
+    assert_eq!(connack.return_code, 0);
+    for payload in ["one", "two"] {
+        intruder.publish(&publish_packet("meters", payload, 0, 0));
+        assert_eq!(subscriber.expect_publish().payload, payload);
+    }
+
+    let mut user = broker.connect("user");
+    user.publish(&publish_packet("meters", "user", 0, 0));
+    assert_eq!(subscriber.expect_publish().payload, "user");
+}
+
+#[test]
+fn test_retained_message_is_sent_to_new_subscribers() {
+    let broker = TestBroker::start();
+    let mut publisher = broker.connect("publisher");
+    let mut retained = publish_packet("house/door", "open", 0, 0);
+    retained.retain_flag = 1;
+    publisher.publish(&retained);
+    thread::sleep(SETTLE);
+
+    // The retained message is sent before the SUBACK
+    let mut subscriber = broker.connect("subscriber");
+    subscriber.send(&Subscribe {
+        packet_id: 1,
+        topic_filters: vec!["house/door".to_string()],
+        requested_qos: vec![0],
+    });
+    let publish = subscriber.expect_publish();
+    assert_eq!((publish.payload.as_str(), publish.retain_flag), ("open", 1));
+    assert!(matches!(subscriber.recv(), Packet::Suback(_)));
+}
+
+/// Rewrites the payload of every message the broker delivers
+struct UppercaseDelivery;
+
+impl BrokerHook for UppercaseDelivery {
+    fn name(&self) -> &str {
+        "uppercase_delivery"
+    }
+
+    fn on_deliver(&self, _subscriber: &ClientInfo, publish: &mut Publish) -> HookVerdict {
+        publish.payload = publish.payload.to_uppercase();
+        HookVerdict::Continue
+    }
+}
+
+#[test]
+fn test_retained_message_is_delivered_through_the_hooks_until_acknowledged() {
+    let broker = TestBroker::start_with(|builder| builder.hook(UppercaseDelivery));
+    let mut publisher = broker.connect("publisher");
+    let mut retained = publish_packet("house/door", "open", 1, 4);
+    retained.retain_flag = 1;
+    publisher.publish(&retained);
+    thread::sleep(SETTLE);
+
+    let mut subscriber = broker.connect("subscriber");
+    subscriber.send(&Subscribe {
+        packet_id: 1,
+        topic_filters: vec!["house/door".to_string()],
+        requested_qos: vec![1],
+    });
+    let first = subscriber.expect_publish();
+    assert_eq!((first.payload.as_str(), first.qos), ("OPEN", 1));
+    assert!(matches!(subscriber.recv(), Packet::Suback(_)));
+
+    // It is pending like any other QoS 1 delivery, so it is resent until the PUBACK
+    let resent = subscriber.expect_publish();
+    assert_eq!(
+        (resent.payload.as_str(), resent.dup_flag, resent.packet_id),
+        ("OPEN", 1, first.packet_id)
+    );
+    subscriber.ack(resent.packet_id);
+}
+
+#[test]
+fn test_unsubscribe_stops_the_delivery() {
+    let broker = TestBroker::start();
+    let mut subscriber = broker.connect("subscriber");
+    subscriber.subscribe(1, &[("news", 0)]);
+    assert_eq!(subscriber.unsubscribe(2, &["news"]).packet_id, 2);
+
+    let mut publisher = broker.connect("publisher");
+    publisher.publish(&publish_packet("news", "headline", 0, 0));
+    subscriber.expect_nothing(SETTLE);
+}
+
+#[test]
+fn test_wildcard_subscription_covers_topics_created_later() {
+    let broker = TestBroker::start();
+    let mut subscriber = broker.connect("subscriber");
+    subscriber.subscribe(1, &[("sensors/#", 0)]);
+
+    let mut publisher = broker.connect("publisher");
+    publisher.publish(&publish_packet("sensors/kitchen/temp", "21", 0, 0));
+    assert_eq!(
+        subscriber.expect_publish().topic_name,
+        "sensors/kitchen/temp"
+    );
+}
+
+#[test]
+fn test_unsubscribe_with_a_wildcard_filter_stops_the_delivery() {
+    let broker = TestBroker::start();
+    let mut publisher = broker.connect("publisher");
+    publisher.publish(&publish_packet("sensors/temp", "21", 0, 0));
+    thread::sleep(SETTLE);
+
+    let mut subscriber = broker.connect("subscriber");
+    subscriber.subscribe(1, &[("sensors/#", 0)]);
+    publisher.publish(&publish_packet("sensors/temp", "22", 0, 0));
+    assert_eq!(subscriber.expect_publish().payload, "22");
+
+    subscriber.unsubscribe(2, &["sensors/#"]);
+    publisher.publish(&publish_packet("sensors/temp", "23", 0, 0));
+    subscriber.expect_nothing(SETTLE);
+}
+
+#[test]
+fn test_publishes_of_a_client_are_delivered_in_order() {
+    let broker = TestBroker::start();
+    let mut subscriber = broker.connect("subscriber");
+    subscriber.subscribe(1, &[("orders", 0)]);
+
+    // Sent without waiting, so several of them are read in the same poll
+    let mut publisher = broker.connect("publisher");
+    for index in 0..50 {
+        publisher.send(&publish_packet("orders", &index.to_string(), 0, 0));
+    }
+    for index in 0..50 {
+        assert_eq!(subscriber.expect_publish().payload, index.to_string());
+    }
+}
+
+#[test]
+fn test_packet_id_is_reused_by_a_new_message() {
+    let broker = TestBroker::start();
+    let mut subscriber = broker.connect("subscriber");
+    subscriber.subscribe(1, &[("orders", 0)]);
+
+    let mut publisher = broker.connect("publisher");
+    publisher.publish(&publish_packet("orders", "42", 1, 5));
+    assert_eq!(subscriber.expect_publish().payload, "42");
+    let mut resent = publish_packet("orders", "42", 1, 5);
+    resent.dup_flag = 1;
+    publisher.publish(&resent);
+    subscriber.expect_nothing(SETTLE);
+
+    // Without the dup flag the packet id belongs to a new message
+    publisher.publish(&publish_packet("orders", "43", 1, 5));
+    assert_eq!(subscriber.expect_publish().payload, "43");
+    let mut resent = publish_packet("orders", "43", 1, 5);
+    resent.dup_flag = 1;
+    publisher.publish(&resent);
+    subscriber.expect_nothing(SETTLE);
+}
+
+#[test]
+fn test_resend_after_a_reconnect_is_not_delivered_again() {
+    let broker = TestBroker::start();
+    let mut subscriber = broker.connect("subscriber");
+    subscriber.subscribe(1, &[("orders", 0)]);
+
+    let persistent = Connect {
+        clean_session: 0,
+        ..connect_packet("publisher")
+    };
+    let mut publisher = broker.client();
+    publisher.connect(&persistent);
+    publisher.publish(&publish_packet("orders", "42", 1, 9));
+    assert_eq!(subscriber.expect_publish().payload, "42");
+    // The client lost the connection before it read the PUBACK
+    publisher.reset();
+    thread::sleep(SETTLE);
+
+    let mut publisher = broker.client();
+    assert_eq!(publisher.connect(&persistent).session_present, 1);
+    let mut resent = publish_packet("orders", "42", 1, 9);
+    resent.dup_flag = 1;
+    publisher.publish(&resent);
+    subscriber.expect_nothing(SETTLE);
+}
+
+#[test]
+fn test_unacknowledged_message_is_resent_until_the_puback() {
+    let broker = TestBroker::start();
+    let mut subscriber = broker.connect("subscriber");
+    subscriber.subscribe(1, &[("orders", 1)]);
+
+    let mut publisher = broker.connect("publisher");
+    publisher.publish(&publish_packet("orders", "42", 1, 3));
+
+    let first = subscriber.expect_publish();
+    assert_eq!(first.dup_flag, 0);
+    let resent = subscriber.expect_publish();
+    assert_eq!(resent.dup_flag, 1);
+    assert_eq!(
+        (resent.packet_id, resent.payload.as_str()),
+        (first.packet_id, "42")
+    );
+
+    subscriber.ack(resent.packet_id);
+    subscriber.expect_nothing(Duration::from_secs(1));
+}
+
+/// Starts a broker whose clients can have a few messages queued
+fn broker_with_outbound_queues(policy: SlowConsumerPolicy) -> TestBroker {
+    TestBroker::start_with(|builder| {
+        builder.config(Config {
+            outbound: OutboundSettings {
+                queue_size: 4,
+                policy,
+            },
+            ..test_config()
+        })
+    })
+}
+
+#[test]
+fn test_stalled_subscriber_does_not_block_the_publishers() {
+    let broker = broker_with_outbound_queues(SlowConsumerPolicy::DropOldest);
+    // Never reads, so its socket buffers fill up after a few messages
+    let mut stalled = broker.connect("stalled");
+    stalled.subscribe(1, &[("firehose", 0)]);
+    let mut fast = broker.connect("fast");
+    fast.subscribe(1, &[("firehose", 0)]);
+
+    // Enough to fill the socket buffers of the stalled subscriber many times over
+    let mut publisher = broker.connect("publisher");
+    let payload = "x".repeat(64 * 1024);
+    for packet_id in 1..=100 {
+        publisher.publish(&publish_packet("firehose", &payload, 1, packet_id));
+        assert_eq!(fast.expect_publish().payload.len(), payload.len());
+    }
+    publisher.ping();
+}
+
+#[test]
+fn test_slow_subscriber_is_disconnected_with_the_disconnect_policy() {
+    let broker = broker_with_outbound_queues(SlowConsumerPolicy::Disconnect);
+    let mut slow = broker.connect("slow");
+    slow.subscribe(1, &[("firehose", 0)]);
+
+    let mut publisher = broker.connect("publisher");
+    let payload = "x".repeat(64 * 1024);
+    for packet_id in 1..=100 {
+        publisher.publish(&publish_packet("firehose", &payload, 1, packet_id));
+    }
+    publisher.ping();
+    assert!(slow.drain_until_closed());
+}
+
+/// Starts a broker where the messages of the sensors/# topics expire quickly
+fn broker_with_expiring_sensors() -> TestBroker {
+    TestBroker::start_with(|builder| {
+        builder.config(Config {
+            expiry: ExpirySettings {
+                default: None,
+                topics: vec![("sensors/#".to_string(), Some(Duration::from_millis(300)))],
+            },
+            ..test_config()
+        })
+    })
+}
+
+#[test]
+fn test_expired_retained_message_is_not_sent() {
+    let broker = broker_with_expiring_sensors();
+    let mut publisher = broker.connect("publisher");
+    for topic in ["sensors/kitchen", "house/door"].iter() {
+        let mut retained = publish_packet(topic, "stale", 0, 0);
+        retained.retain_flag = 1;
+        publisher.publish(&retained);
+    }
+    thread::sleep(Duration::from_millis(500));
+
+    let mut subscriber = broker.connect("subscriber");
+    subscriber.send(&Subscribe {
+        packet_id: 1,
+        topic_filters: vec!["sensors/kitchen".to_string(), "house/door".to_string()],
+        requested_qos: vec![0, 0],
+    });
+    assert_eq!(subscriber.expect_publish().topic_name, "house/door");
+    assert!(matches!(subscriber.recv(), Packet::Suback(_)));
+}
+
+#[test]
+fn test_expired_message_is_not_resent_after_a_reconnect() {
+    let broker = broker_with_expiring_sensors();
+    let persistent = Connect {
+        clean_session: 0,
+        ..connect_packet("dashboard")
+    };
+    let mut dashboard = broker.client();
+    dashboard.connect(&persistent);
+    dashboard.subscribe(1, &[("sensors/kitchen", 1)]);
+
+    let mut publisher = broker.connect("publisher");
+    publisher.publish(&publish_packet("sensors/kitchen", "21", 1, 1));
+    assert_eq!(dashboard.expect_publish().payload, "21");
+    dashboard.reset();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut dashboard = broker.client();
+    assert_eq!(dashboard.connect(&persistent).session_present, 1);
+    dashboard.expect_nothing(Duration::from_secs(1));
+}
+
+#[test]
+fn test_delayed_publish_is_published_after_the_delay() {
+    let broker = TestBroker::start();
+    let mut subscriber = broker.connect("subscriber");
+    subscriber.subscribe(1, &[("lights/kitchen", 0)]);
+
+    let mut controller = broker.connect("controller");
+    controller.publish(&publish_packet("$delayed/1/lights/kitchen", "off", 1, 1));
+    subscriber.expect_nothing(SETTLE);
+    let publish = subscriber.expect_publish();
+    assert_eq!(
+        (publish.topic_name.as_str(), publish.payload.as_str()),
+        ("lights/kitchen", "off")
+    );
+}
+
+#[test]
+fn test_delayed_publish_survives_a_restart() {
+    let delayed_file = std::env::temp_dir()
+        .join(format!("scenario-delayed-{}.json", std::process::id()))
+        .to_string_lossy()
+        .to_string();
+    let start_broker = || {
+        let delayed_file = delayed_file.clone();
+        TestBroker::start_with(move |builder| {
+            builder.config(Config {
+                delayed_messages_file: Some(delayed_file),
+                ..test_config()
+            })
+        })
+    };
+
+    let broker = start_broker();
+    let mut controller = broker.connect("controller");
+    let mut delayed = publish_packet("$delayed/1/lights/kitchen", "off", 0, 0);
+    delayed.retain_flag = 1;
+    controller.publish(&delayed);
+    thread::sleep(SETTLE);
+    broker.shutdown();
+
+    // Retained, so it is found once the restarted broker publishes it
+    let broker = start_broker();
+    thread::sleep(Duration::from_secs(2));
+    let mut subscriber = broker.connect("subscriber");
+    subscriber.send(&Subscribe {
+        packet_id: 1,
+        topic_filters: vec!["lights/kitchen".to_string()],
+        requested_qos: vec![0],
+    });
+    assert_eq!(subscriber.expect_publish().payload, "off");
+    std::fs::remove_file(&delayed_file).unwrap();
+}
+
+#[test]
+fn test_disconnect_does_not_publish_the_will() {
+    let broker = TestBroker::start();
+    let mut watcher = broker.connect("watcher");
+    watcher.subscribe(1, &[("status/sensor", 0)]);
+
+    let mut sensor = broker.client();
+    sensor.connect(&with_will(
+        connect_packet("sensor"),
+        "status/sensor",
+        "offline",
+    ));
+    sensor.disconnect();
+    watcher.expect_nothing(Duration::from_millis(500));
+}
+
+#[test]
+fn test_connection_loss_publishes_the_will() {
+    let broker = TestBroker::start();
+    let mut watcher = broker.connect("watcher");
+    watcher.subscribe(1, &[("status/sensor", 0)]);
+
+    let mut sensor = broker.client();
+    sensor.connect(&with_will(
+        connect_packet("sensor"),
+        "status/sensor",
+        "offline",
+    ));
+    sensor.ping();
+    sensor.reset();
+
+    let will = watcher.expect_publish();
+    assert_eq!(
+        (will.topic_name.as_str(), will.payload.as_str()),
+        ("status/sensor", "offline")
+    );
+}
+
+#[test]
+fn test_closed_connection_publishes_the_will_once() {
+    let broker = TestBroker::start();
+    let mut watcher = broker.connect("watcher");
+    watcher.subscribe(1, &[("status/sensor", 0)]);
+
+    let mut sensor = broker.client();
+    sensor.connect(&with_will(
+        connect_packet("sensor"),
+        "status/sensor",
+        "offline",
+    ));
+    sensor.ping();
+    sensor.close();
+
+    assert_eq!(watcher.expect_publish().payload, "offline");
+    watcher.expect_nothing(Duration::from_secs(1));
+}
+
+#[test]
+fn test_keep_alive_timeout_publishes_the_will_and_closes_the_connection() {
+    let broker = TestBroker::start();
+    let mut watcher = broker.connect("watcher");
+    watcher.subscribe(1, &[("status/sensor", 0)]);
+
+    let mut sensor = broker.client();
+    sensor.connect(&with_will(
+        Connect {
+            keep_alive: 1,
+            ..connect_packet("sensor")
+        },
+        "status/sensor",
+        "offline",
+    ));
+
+    // Nothing is sent for longer than one and a half times the keep alive
+    assert_eq!(watcher.expect_publish().payload, "offline");
+    assert!(sensor.is_closed());
+}
+
+#[test]
+fn test_connection_without_connect_is_closed() {
+    let config = test_config();
+    let broker = TestBroker::start_with(|builder| {
+        builder.config(Config {
+            connect_timeout: Duration::from_millis(500),
+            ..config
+        })
+    });
+
+    // The socket is opened but never sends its CONNECT
+    let mut idle = broker.client();
+    assert!(idle.is_closed());
+    // Clients that connect in time are still served
+    broker.connect("late").ping();
+}
+
+#[test]
+fn test_second_connect_is_a_protocol_violation() {
+    let broker = TestBroker::start();
+    let mut watcher = broker.connect("watcher");
+    watcher.subscribe(1, &[("status/sensor", 0)]);
+
+    let mut sensor = broker.client();
+    let connect = with_will(connect_packet("sensor"), "status/sensor", "offline");
+    sensor.connect(&connect);
+    sensor.send(&connect);
+
+    assert_eq!(watcher.expect_publish().payload, "offline");
+    assert!(sensor.is_closed());
+}
+
+#[test]
+fn test_lost_connection_removes_a_clean_session() {
+    let broker = TestBroker::start();
+    let mut logger = broker.connect("logger");
+    logger.subscribe(1, &[("logs/app", 0)]);
+    logger.reset();
+    thread::sleep(SETTLE);
+
+    let mut logger = broker.client();
+    let connack = logger.connect(&Connect {
+        clean_session: 0,
+        ..connect_packet("logger")
+    });
+    assert_eq!((connack.return_code, connack.session_present), (0, 0));
+}
+
+#[test]
+fn test_disconnect_keeps_a_persistent_session() {
+    let broker = TestBroker::start();
+    let persistent = Connect {
+        clean_session: 0,
+        ..connect_packet("logger")
+    };
+    let mut logger = broker.client();
+    logger.connect(&persistent);
+    logger.subscribe(1, &[("logs/app", 0)]);
+    logger.disconnect();
+    thread::sleep(SETTLE);
+
+    let mut logger = broker.client();
+    assert_eq!(logger.connect(&persistent).session_present, 1);
+    let mut publisher = broker.connect("publisher");
+    publisher.publish(&publish_packet("logs/app", "started", 0, 0));
+    assert_eq!(logger.expect_publish().payload, "started");
+}
+
+#[test]
+fn test_disconnect_closes_the_connection_of_a_persistent_session() {
+    let broker = TestBroker::start();
+    let mut logger = broker.client();
+    logger.connect(&Connect {
+        clean_session: 0,
+        ..connect_packet("logger")
+    });
+    logger.send(&Disconnect {});
+    assert!(logger.drain_until_closed());
+}
+
+#[test]
+fn test_persistent_session_keeps_the_subscriptions() {
+    let broker = TestBroker::start();
+    let persistent = Connect {
+        clean_session: 0,
+        ..connect_packet("logger")
+    };
+    let mut logger = broker.client();
+    assert_eq!(logger.connect(&persistent).session_present, 0);
+    logger.subscribe(1, &[("logs/app", 0)]);
+    logger.reset();
+    thread::sleep(SETTLE);
+
+    let mut logger = broker.client();
+    let connack = logger.connect(&persistent);
+    assert_eq!((connack.return_code, connack.session_present), (0, 1));
+
+    let mut publisher = broker.connect("publisher");
+    publisher.publish(&publish_packet("logs/app", "started", 0, 0));
+    assert_eq!(logger.expect_publish().payload, "started");
+}
+
+#[test]
+fn test_clean_session_drops_the_subscriptions() {
+    let broker = TestBroker::start();
+    let mut logger = broker.client();
+    logger.connect(&Connect {
+        clean_session: 0,
+        ..connect_packet("logger")
+    });
+    logger.subscribe(1, &[("logs/app", 0)]);
+    logger.reset();
+    thread::sleep(SETTLE);
+
+    let mut logger = broker.connect("logger");
+    let mut publisher = broker.connect("publisher");
+    publisher.publish(&publish_packet("logs/app", "started", 0, 0));
+    logger.expect_nothing(SETTLE);
+}
+
+#[test]
+fn test_shutdown_closes_the_connections() {
+    let broker = TestBroker::start();
+    let mut client = broker.connect("sensor");
+    client.ping();
+    broker.shutdown();
+    assert!(client.is_closed());
+}
+
+#[test]
+fn test_tenants_do_not_see_each_others_topics() {
+    let broker = tenant_broker();
+    let mut acme = connect_as(&broker, "acme-subscriber", "acme");
+    acme.subscribe(1, &[("#", 0)]);
+    let mut globex = connect_as(&broker, "globex-subscriber", "globex");
+    globex.subscribe(1, &[("#", 0), ("lights", 0)]);
+
+    let mut publisher = connect_as(&broker, "acme-publisher", "acme");
+    publisher.publish(&publish_packet("lights", "on", 0, 0));
+
+    // The topic is delivered without the tenant prefix, and only inside the tenant
+    assert_eq!(acme.expect_publish().topic_name, "lights");
+    globex.expect_nothing(SETTLE);
+}
+
+#[test]
+fn test_unmounted_clients_see_the_mounted_topics() {
+    let broker = tenant_broker();
+    let mut observer = broker.connect("observer");
+    observer.subscribe(1, &[("tenant/+/lights", 0)]);
+
+    let mut publisher = connect_as(&broker, "globex-publisher", "globex");
+    publisher.publish(&publish_packet("lights", "off", 0, 0));
+    assert_eq!(observer.expect_publish().topic_name, "tenant/globex/lights");
+}
+
+#[test]
+fn test_tenant_shared_subscriptions_stay_in_the_tenant() {
+    let broker = tenant_broker();
+    let mut acme = connect_as(&broker, "acme-worker", "acme");
+    let suback = acme.subscribe(1, &[("$share/workers/jobs", 0), ("$share/jobs", 0)]);
+    // The filter without a group cannot be mounted, so it is refused instead of passed through
+    assert_eq!(suback.return_codes, vec![0, 0x80]);
+
+    let mut globex = connect_as(&broker, "globex-publisher", "globex");
+    globex.publish(&publish_packet("jobs", "build", 0, 0));
+    acme.expect_nothing(SETTLE);
+    let mut publisher = connect_as(&broker, "acme-publisher", "acme");
+    publisher.publish(&publish_packet("jobs", "deploy", 0, 0));
+    assert_eq!(acme.expect_publish().topic_name, "jobs");
+}
+
+#[test]
+fn test_anonymous_client_cannot_claim_the_name_of_a_tenant_user() {
+    let listener = ListenerSettings {
+        require_auth: false,
+        ..ListenerSettings::tcp("open", "127.0.0.1:0")
+    };
+    let broker = tenant_broker_on(listener);
+    let mut acme = connect_as(&broker, "acme-subscriber", "acme");
+    acme.subscribe(1, &[("lights", 0)]);
+
+    // Without the password of acme the client is anonymous, which tenants do not allow
+    let mut intruder = broker.client();
+    let connack = intruder.connect(&Connect {
+        username: "acme".to_string(),
+        password: String::new(),
+        ..connect_packet("intruder")
+    });
+    assert_eq!(connack.return_code, 5);
+    assert!(intruder.is_closed());
+
+    let mut publisher = connect_as(&broker, "acme-publisher", "acme");
+    publisher.publish(&publish_packet("lights", "on", 0, 0));
+    assert_eq!(acme.expect_publish().payload, "on");
+}
+
+#[test]
+fn test_websocket_frames_split_across_reads_keep_the_client_connected() {
+    let broker = ws_broker();
+    let mut subscriber = ws_connect(&broker, "dashboard");
+    subscriber.write(&WsClient::packet_frame(&Subscribe {
+        packet_id: 1,
+        topic_filters: vec!["camera".to_string()],
+        requested_qos: vec![0],
+    }));
+    assert!(matches!(subscriber.recv(), Packet::Suback(_)));
+
+    // The broker polls the socket while only the start of the CONNECT has arrived
+    let mut client = broker.ws_client();
+    let connect = WsClient::packet_frame(&connect_packet("browser"));
+    client.write(&connect[..3]);
+    thread::sleep(SETTLE);
+    client.write(&connect[3..]);
+    match client.recv() {
+        Packet::Connack(connack) => assert_eq!(connack.return_code, 0),
+        packet => panic!("Expected a CONNACK, got {:?}", packet),
+    }
+
+    // A frame larger than a single read of the broker
+    let snapshot = "x".repeat(6000);
+    let publish = WsClient::packet_frame(&publish_packet("camera", &snapshot, 0, 0));
+    client.write(&publish[..4096]);
+    thread::sleep(SETTLE);
+    client.write(&publish[4096..]);
+    match subscriber.recv() {
+        Packet::Publish(publish) => assert_eq!(publish.payload, snapshot),
+        packet => panic!("Expected a PUBLISH, got {:?}", packet),
+    }
+}
+
+#[test]
+fn test_websocket_ping_keeps_the_client_connected() {
+    let broker = ws_broker();
+    let mut client = ws_connect(&broker, "browser");
+    client.write(&WsClient::frame(0x9, b"alive"));
+    assert_eq!(client.recv_frame(), (0xA, b"alive".to_vec()));
+
+    thread::sleep(SETTLE);
+    client.write(&WsClient::packet_frame(&Pingreq {}));
+    assert_eq!(client.recv(), Packet::Pingresp);
+}
+
+#[test]
+fn test_websocket_handshakes_count_against_the_connection_limit() {
+    let listener = ListenerSettings {
+        protocol: ListenerProtocol::Ws,
+        max_connections: Some(1),
+        ..ListenerSettings::tcp("ws", "127.0.0.1:0")
+    };
+    let broker = TestBroker::start_on(listener, |builder| builder);
+
+    // The first client never sends its upgrade request, so it holds the only place
+    let stalled = broker.client();
+    thread::sleep(SETTLE);
+    let mut refused = broker.client();
+    assert!(refused.drain_until_closed());
+
+    stalled.close();
+    thread::sleep(SETTLE);
+    ws_connect(&broker, "browser");
+}
+
+/// Starts a broker whose clients connect over WebSocket
+fn ws_broker() -> TestBroker {
+    let listener = ListenerSettings {
+        protocol: ListenerProtocol::Ws,
+        ..ListenerSettings::tcp("ws", "127.0.0.1:0")
+    };
+    TestBroker::start_on(listener, |builder| builder)
+}
+
+/// Connects a client with a clean session over WebSocket
+fn ws_connect(broker: &TestBroker, client_id: &str) -> WsClient {
+    let mut client = broker.ws_client();
+    client.write(&WsClient::packet_frame(&connect_packet(client_id)));
+    match client.recv() {
+        Packet::Connack(connack) => assert_eq!(connack.return_code, 0),
+        packet => panic!("Expected a CONNACK, got {:?}", packet),
+    }
+    client
+}
+
+/// Starts a broker where the users acme and globex are mounted in a tenant named after them,
+/// and the user of connect_packet is not mounted
+fn tenant_broker() -> TestBroker {
+    tenant_broker_on(ListenerSettings::tcp("test", "127.0.0.1:0"))
+}
+
+/// Starts the broker of tenant_broker with its clients connecting through a listener
+/// # Arguments
+///
+/// * `listener` - The settings of the listener the clients connect to, bound to port 0
+///
+fn tenant_broker_on(listener: ListenerSettings) -> TestBroker {
+    let config = test_config();
+    TestBroker::start_on(listener, |builder| {
+        let mut credentials = CredentialManager::new();
+        credentials.add_credential(USERNAME, PASSWORD);
+        let mut users = HashMap::new();
+        for tenant in ["acme", "globex"] {
+            credentials.add_credential(tenant, PASSWORD);
+            users.insert(tenant.to_string(), tenant.to_string());
+        }
+        builder.authenticator(credentials).config(Config {
+            tenancy: TenancySettings {
+                mount_point: None,
+                users,
+            },
+            ..config
+        })
+    })
+}
+
+/// Connects a client with a clean session as a user whose password is PASSWORD
+fn connect_as(broker: &TestBroker, client_id: &str, username: &str) -> TestClient {
+    let mut client = broker.client();
+    let connect = Connect {
+        username: username.to_string(),
+        ..connect_packet(client_id)
+    };
+    assert_eq!(client.connect(&connect).return_code, 0);
+    client
+}
+
+fn with_will(connect: Connect, topic: &str, message: &str) -> Connect {
+    Connect {
+        last_will_flag: 1,
+        last_will_topic: topic.to_string(),
+        last_will_message: message.to_string(),
+        ..connect
+    }
+}
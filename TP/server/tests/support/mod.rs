@@ -0,0 +1,525 @@
+//! Starts the broker in-process on a free localhost port and drives it with scripted
+//! clients that speak MQTT through the packets of the shared crate.
+
+// Each test file uses only part of the support module
+#![allow(dead_code)]
+
+use server::config::{Config, ListenerSettings};
+use server::{Broker, BrokerBuilder, BrokerHandle, CredentialManager};
+use shared::packages::connack::Connack;
+use shared::packages::connect::Connect;
+use shared::packages::disconnect::Disconnect;
+use shared::packages::packet::{FixedHeader, PacketType, ReadablePacket, WritablePacket};
+use shared::packages::pingreq::Pingreq;
+use shared::packages::pingresp::Pingresp;
+use shared::packages::puback::Puback;
+use shared::packages::publish::Publish;
+use shared::packages::suback::Suback;
+use shared::packages::subscribe::Subscribe;
+use shared::packages::unsuback::Unsuback;
+use shared::packages::unsubscribe::Unsubscribe;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddrV4, TcpStream};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::time::Duration;
+
+/// The only user the test brokers accept
+pub const USERNAME: &str = "jleyes";
+pub const PASSWORD: &str = "1234";
+
+/// How long a client waits for a packet the broker must send
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Returns the config of the test brokers, which poll often so the scenarios run quickly
+pub fn test_config() -> Config {
+    Config {
+        request_poll_interval: Duration::from_millis(10),
+        socket_read_timeout: Duration::from_millis(10),
+        retry_interval: Duration::from_millis(500),
+        sys_interval: Duration::ZERO,
+        ..Config::default()
+    }
+}
+
+/// This struct represents a broker running in the test process, stopped when dropped
+pub struct TestBroker {
+    handle: Option<BrokerHandle>,
+    port: u16,
+}
+
+impl TestBroker {
+    /// Starts a broker with the test config on a free port
+    pub fn start() -> TestBroker {
+        TestBroker::start_with(|builder| builder)
+    }
+
+    /// Starts a broker on a free port after changing its builder, which starts with the test
+    /// config and an authenticator that only accepts USERNAME and PASSWORD
+    /// # Arguments
+    ///
+    /// * `configure` - Changes the builder before the listener is added
+    ///
+    pub fn start_with(configure: impl FnOnce(BrokerBuilder) -> BrokerBuilder) -> TestBroker {
+        TestBroker::start_on(ListenerSettings::tcp("test", "127.0.0.1:0"), configure)
+    }
+
+    /// Starts a broker whose clients connect through a listener, after changing its builder
+    /// # Arguments
+    ///
+    /// * `listener` - The settings of the listener the clients connect to, bound to port 0
+    /// * `configure` - Changes the builder before the listener is added
+    ///
+    pub fn start_on(
+        listener: ListenerSettings,
+        configure: impl FnOnce(BrokerBuilder) -> BrokerBuilder,
+    ) -> TestBroker {
+        let name = listener.name.to_string();
+        let mut credentials = CredentialManager::new();
+        credentials.add_credential(USERNAME, PASSWORD);
+        let builder = Broker::builder()
+            .config(test_config())
+            .authenticator(credentials);
+        let handle = configure(builder)
+            .listener(listener)
+            .build()
+            .unwrap()
+            .start()
+            .unwrap();
+        let port = handle.local_addr(&name).unwrap().port();
+        TestBroker {
+            handle: Some(handle),
+            port,
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Returns a client connected over TCP that has not sent its CONNECT yet
+    pub fn client(&self) -> TestClient {
+        TestClient::new(TcpStream::connect(("127.0.0.1", self.port)).unwrap())
+    }
+
+    /// Returns a client that connects from the given local address and has not sent its
+    /// CONNECT yet. Every 127.x.x.x address is local, so clients can share a port.
+    /// # Arguments
+    ///
+    /// * `local` - The address the client binds before connecting, port 0 picks a free one
+    ///
+    pub fn client_from(&self, local: SocketAddrV4) -> TestClient {
+        let broker = SocketAddrV4::new(Ipv4Addr::LOCALHOST, self.port);
+        TestClient::new(connect_from(local, broker).unwrap())
+    }
+
+    /// Returns a client with a clean session that the broker accepted
+    /// # Arguments
+    ///
+    /// * `client_id` - The id of the client
+    ///
+    pub fn connect(&self, client_id: &str) -> TestClient {
+        let mut client = self.client();
+        let connack = client.connect(&connect_packet(client_id));
+        assert_eq!(connack.return_code, 0, "{} was refused", client_id);
+        client
+    }
+
+    /// Returns a client that completed the WebSocket handshake, for brokers started on a
+    /// WebSocket listener
+    pub fn ws_client(&self) -> WsClient {
+        let mut socket = TcpStream::connect(("127.0.0.1", self.port)).unwrap();
+        socket.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+        socket.set_nodelay(true).unwrap();
+        let request = "GET /mqtt HTTP/1.1\r\nHost: broker\r\nUpgrade: websocket\r\n\
+                       Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                       Sec-WebSocket-Protocol: mqtt\r\nSec-WebSocket-Version: 13\r\n\r\n";
+        socket.write_all(request.as_bytes()).unwrap();
+        let mut response = Vec::new();
+        let mut byte = [0u8; 1];
+        while !response.ends_with(b"\r\n\r\n") {
+            socket.read_exact(&mut byte).unwrap();
+            response.push(byte[0]);
+        }
+        assert!(response.starts_with(b"HTTP/1.1 101"));
+        WsClient { socket }
+    }
+
+    /// Stops the broker and waits until its threads finish
+    pub fn shutdown(mut self) {
+        if let Some(handle) = self.handle.take() {
+            handle.shutdown();
+        }
+    }
+}
+
+impl Drop for TestBroker {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            handle.shutdown();
+        }
+    }
+}
+
+/// This enum represents the packets the broker sends to its clients
+#[derive(Debug, PartialEq)]
+pub enum Packet {
+    Connack(Connack),
+    Publish(Publish),
+    Puback(Puback),
+    Suback(Suback),
+    Unsuback(Unsuback),
+    Pingresp,
+}
+
+/// This struct represents a client whose every step is scripted by a test
+pub struct TestClient {
+    stream: TcpStream,
+}
+
+impl TestClient {
+    fn new(stream: TcpStream) -> TestClient {
+        stream.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+        stream.set_nodelay(true).unwrap();
+        TestClient { stream }
+    }
+
+    /// Returns the address the client connected from
+    pub fn local_addr(&self) -> SocketAddrV4 {
+        match self.stream.local_addr().unwrap() {
+            std::net::SocketAddr::V4(address) => address,
+            address => panic!("Expected an IPv4 address, got {}", address),
+        }
+    }
+
+    /// Writes a packet to the broker in a single write, as MQTT client libraries do
+    pub fn send(&mut self, packet: &dyn WritablePacket) {
+        let mut bytes = Vec::new();
+        packet.write_to(&mut bytes).unwrap();
+        self.stream.write_all(&bytes).unwrap();
+    }
+
+    /// Returns the next packet the broker sends, panicking if none arrives in time
+    pub fn recv(&mut self) -> Packet {
+        read_packet(&mut self.stream)
+    }
+
+    /// Sends a CONNECT and returns the CONNACK
+    pub fn connect(&mut self, connect: &Connect) -> Connack {
+        self.send(connect);
+        match self.recv() {
+            Packet::Connack(connack) => connack,
+            packet => panic!("Expected a CONNACK, got {:?}", packet),
+        }
+    }
+
+    /// Sends a SUBSCRIBE and returns the SUBACK
+    /// # Arguments
+    ///
+    /// * `packet_id` - The id of the SUBSCRIBE
+    /// * `filters` - The topic filters and the QoS requested for each one
+    ///
+    pub fn subscribe(&mut self, packet_id: u16, filters: &[(&str, u8)]) -> Suback {
+        self.send(&Subscribe {
+            packet_id,
+            topic_filters: filters
+                .iter()
+                .map(|(filter, _)| filter.to_string())
+                .collect(),
+            requested_qos: filters.iter().map(|(_, qos)| *qos).collect(),
+        });
+        match self.recv() {
+            Packet::Suback(suback) => suback,
+            packet => panic!("Expected a SUBACK, got {:?}", packet),
+        }
+    }
+
+    /// Sends an UNSUBSCRIBE and returns the UNSUBACK
+    pub fn unsubscribe(&mut self, packet_id: u16, filters: &[&str]) -> Unsuback {
+        self.send(&Unsubscribe {
+            packet_id,
+            topic_filters: filters.iter().map(|filter| filter.to_string()).collect(),
+        });
+        match self.recv() {
+            Packet::Unsuback(unsuback) => unsuback,
+            packet => panic!("Expected an UNSUBACK, got {:?}", packet),
+        }
+    }
+
+    /// Publishes a message, waiting for its PUBACK when the QoS is 1
+    pub fn publish(&mut self, publish: &Publish) {
+        self.send(publish);
+        if publish.qos == 1 {
+            assert_eq!(self.recv(), Packet::Puback(puback(publish.packet_id)));
+        }
+    }
+
+    /// Sends a PUBACK for a message the broker delivered
+    pub fn ack(&mut self, packet_id: u16) {
+        self.send(&puback(packet_id));
+    }
+
+    /// Sends a PINGREQ and waits for the PINGRESP
+    pub fn ping(&mut self) {
+        self.send(&Pingreq {});
+        assert_eq!(self.recv(), Packet::Pingresp);
+    }
+
+    /// Returns the next message the broker delivers
+    pub fn expect_publish(&mut self) -> Publish {
+        match self.recv() {
+            Packet::Publish(publish) => publish,
+            packet => panic!("Expected a PUBLISH, got {:?}", packet),
+        }
+    }
+
+    /// Panics if the broker sends anything during a while
+    pub fn expect_nothing(&mut self, wait: Duration) {
+        self.stream.set_read_timeout(Some(wait)).unwrap();
+        let mut buf = [0u8; 1];
+        match self.stream.peek(&mut buf) {
+            Ok(0) => {}
+            Ok(_) => panic!("Expected nothing, got {:?}", self.recv()),
+            Err(e) => assert!(
+                matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ),
+                "Unexpected error {}",
+                e
+            ),
+        }
+        self.stream.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+    }
+
+    /// Returns whether the broker closed the connection, a connection still open after the read
+    /// timeout is not closed
+    pub fn is_closed(&mut self) -> bool {
+        let mut buf = [0u8; 1];
+        match self.stream.read(&mut buf) {
+            Ok(read) => read == 0,
+            Err(e) => !matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ),
+        }
+    }
+
+    /// Reads and discards everything the broker sent, returning whether it then closed the
+    /// connection or false if it kept it open
+    pub fn drain_until_closed(&mut self) -> bool {
+        let mut buf = [0u8; 64 * 1024];
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => return true,
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::ConnectionReset => return true,
+                Err(_) => return false,
+            }
+        }
+    }
+
+    /// Sends a DISCONNECT and closes the connection
+    pub fn disconnect(mut self) {
+        self.send(&Disconnect {});
+        self.stream.shutdown(Shutdown::Both).unwrap();
+    }
+
+    /// Closes the connection without a DISCONNECT, the broker reads the end of stream
+    pub fn close(self) {
+        self.stream.shutdown(Shutdown::Both).unwrap();
+    }
+
+    /// Resets the connection without a DISCONNECT, as a client that lost its network would
+    pub fn reset(self) {
+        let linger = libc::linger {
+            l_onoff: 1,
+            l_linger: 0,
+        };
+        // A zero linger makes close send a RST instead of a FIN
+        let result = unsafe {
+            libc::setsockopt(
+                self.stream.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_LINGER,
+                &linger as *const libc::linger as *const libc::c_void,
+                std::mem::size_of::<libc::linger>() as libc::socklen_t,
+            )
+        };
+        assert_eq!(result, 0, "{}", io::Error::last_os_error());
+    }
+}
+
+/// This struct represents a WebSocket client that writes its frames byte by byte as the
+/// test chooses, so frames can arrive split across reads
+pub struct WsClient {
+    socket: TcpStream,
+}
+
+impl WsClient {
+    /// Returns the masked frame a client sends
+    /// # Arguments
+    ///
+    /// * `opcode` - The opcode of the frame, 0x2 for MQTT bytes and 0x9 for a ping
+    /// * `payload` - The payload of the frame
+    ///
+    pub fn frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![0x80 | opcode];
+        match payload.len() {
+            length if length < 126 => frame.push(0x80 | length as u8),
+            length if length <= u16::MAX as usize => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(length as u16).to_be_bytes());
+            }
+            length => {
+                frame.push(0x80 | 127);
+                frame.extend_from_slice(&(length as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(
+            payload
+                .iter()
+                .enumerate()
+                .map(|(index, byte)| byte ^ mask[index % 4]),
+        );
+        frame
+    }
+
+    /// Returns the binary frame that carries a packet
+    pub fn packet_frame(packet: &dyn WritablePacket) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        packet.write_to(&mut bytes).unwrap();
+        WsClient::frame(0x2, &bytes)
+    }
+
+    /// Writes raw bytes to the broker, which may hold only part of a frame
+    pub fn write(&mut self, bytes: &[u8]) {
+        self.socket.write_all(bytes).unwrap();
+    }
+
+    /// Returns the opcode and the payload of the next frame the broker sends
+    pub fn recv_frame(&mut self) -> (u8, Vec<u8>) {
+        let mut header = [0u8; 2];
+        self.socket.read_exact(&mut header).unwrap();
+        let length = match header[1] & 0x7F {
+            126 => {
+                let mut length = [0u8; 2];
+                self.socket.read_exact(&mut length).unwrap();
+                u16::from_be_bytes(length) as usize
+            }
+            127 => {
+                let mut length = [0u8; 8];
+                self.socket.read_exact(&mut length).unwrap();
+                u64::from_be_bytes(length) as usize
+            }
+            length => length as usize,
+        };
+        let mut payload = vec![0u8; length];
+        self.socket.read_exact(&mut payload).unwrap();
+        (header[0] & 0x0F, payload)
+    }
+
+    /// Returns the packet the broker sends in the next frame, which must be a binary one
+    pub fn recv(&mut self) -> Packet {
+        let (opcode, payload) = self.recv_frame();
+        assert_eq!(opcode, 0x2, "Expected a binary frame");
+        read_packet(&mut &payload[..])
+    }
+}
+
+/// Reads the next packet the broker sends, panicking if none arrives in time
+fn read_packet(stream: &mut dyn Read) -> Packet {
+    let header = FixedHeader::read_fixed_header(stream).unwrap();
+    match PacketType::from_u8(header.packet_type) {
+        Some(PacketType::Connack) => Packet::Connack(Connack::read_from(stream, header).unwrap()),
+        Some(PacketType::Publish) => Packet::Publish(Publish::read_from(stream, header).unwrap()),
+        Some(PacketType::Puback) => Packet::Puback(Puback::read_from(stream, header).unwrap()),
+        Some(PacketType::Suback) => Packet::Suback(Suback::read_from(stream, header).unwrap()),
+        Some(PacketType::Unsuback) => {
+            Packet::Unsuback(Unsuback::read_from(stream, header).unwrap())
+        }
+        Some(PacketType::Pingresp) => {
+            Pingresp::read_from(stream, header).unwrap();
+            Packet::Pingresp
+        }
+        _ => panic!("Unexpected packet type {}", header.packet_type),
+    }
+}
+
+/// TcpStream cannot bind before connecting, so the socket is made with libc
+fn connect_from(local: SocketAddrV4, remote: SocketAddrV4) -> io::Result<TcpStream> {
+    let check = |result: libc::c_int| {
+        if result < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(result)
+        }
+    };
+    let fd = check(unsafe { libc::socket(libc::AF_INET, libc::SOCK_STREAM, 0) })?;
+    // The stream owns the socket from now on, so it is closed on every error
+    let stream = unsafe { TcpStream::from_raw_fd(fd) };
+    let reuse: libc::c_int = 1;
+    check(unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_REUSEADDR,
+            &reuse as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    })?;
+    for address in [local, remote].iter() {
+        let sockaddr = libc::sockaddr_in {
+            sin_family: libc::AF_INET as libc::sa_family_t,
+            sin_port: address.port().to_be(),
+            sin_addr: libc::in_addr {
+                s_addr: u32::from(*address.ip()).to_be(),
+            },
+            sin_zero: [0; 8],
+        };
+        let pointer = &sockaddr as *const libc::sockaddr_in as *const libc::sockaddr;
+        let length = std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+        if address == &local {
+            check(unsafe { libc::bind(fd, pointer, length) })?;
+        } else {
+            check(unsafe { libc::connect(fd, pointer, length) })?;
+        }
+    }
+    Ok(stream)
+}
+
+/// Returns the CONNECT of a client with a clean session and the valid credentials
+pub fn connect_packet(client_id: &str) -> Connect {
+    Connect {
+        client_id: client_id.to_string(),
+        username: USERNAME.to_string(),
+        password: PASSWORD.to_string(),
+        last_will_topic: String::new(),
+        last_will_message: String::new(),
+        keep_alive: 60,
+        last_will_qos: 0,
+        clean_session: 1,
+        last_will_retain: 0,
+        last_will_flag: 0,
+    }
+}
+
+/// Returns a PUBLISH, the packet id is only sent for QoS 1
+pub fn publish_packet(topic: &str, payload: &str, qos: u8, packet_id: u16) -> Publish {
+    Publish {
+        topic_name: topic.to_string(),
+        payload: payload.to_string(),
+        packet_id,
+        qos,
+        retain_flag: 0,
+        dup_flag: 0,
+    }
+}
+
+pub fn puback(packet_id: u16) -> Puback {
+    Puback {
+        acknowledged_packet_id: packet_id,
+    }
+}
@@ -5,7 +5,7 @@ use crate::packages::packet::WritablePacket;
 use std::io::Read;
 use std::io::Write;
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 /// This struct represents a Connack packet
 pub struct Connack {
     /// This flag contains a return code that tells the client whether the connection attempt was successful or not.
@@ -111,7 +111,7 @@ impl FixedHeader {
 
             stream.write_all(&num_buffer)?;
 
-            if !pending_remaining_length > 0 {
+            if pending_remaining_length == 0 {
                 break;
             }
         }
@@ -172,4 +172,17 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_encode_remaining_length_multibyte_valid() {
+        for expected_length in [127_u32, 128, 16_383, 32_768, 268_435_455].iter() {
+            let mut buffer = Vec::new();
+            FixedHeader::encode_remaining_length(&mut buffer, *expected_length).unwrap();
+
+            let mut reader = BufReader::new(&buffer[..]);
+            let remaining_length = FixedHeader::decode_remaining_length(&mut reader).unwrap();
+            assert_eq!(remaining_length, *expected_length);
+            assert!(reader.buffer().is_empty());
+        }
+    }
 }
@@ -7,7 +7,7 @@ use std::io::Read;
 use std::io::Write;
 
 /// This struct represents a Publish packet
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Publish {
     /// A String containing the topic to publish to
     pub topic_name: String,